@@ -1,57 +1,379 @@
 use anyhow::{Context, Result};
+use log::warn;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
 use std::env;
-use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     #[serde(default = "default_http_port")]
     pub http_port: u16,
+    // Interface the HTTP server listens on, e.g. 127.0.0.1 behind a reverse proxy.  All of them
+    // (0.0.0.0) when unset.
+    pub http_bind_addr: Option<String>,
     // the address at which the hierophant can reach this magister to make drop requests.  This
     // will get passed into the contemplant who will then notify the Hierophant that this is the
     // contemplant's managing Magister
     pub this_magister_addr: String,
+    // Identifies this Magister in its log lines.  Defaults to this_magister_addr.
+    pub magister_id: Option<String>,
+    // text for people, or json for one object per line for log collectors
+    #[serde(default)]
+    pub log_format: LogFormat,
     // Passed into Contemplants to tell them which Hierophant to connect to.  Needs to be publically
     // accessible.
     pub hierophant_ip: String,
     // HTTP port the Hierophant (at above ip) is running at.
     pub hierophant_http_port: u16,
     pub vast_query: VastQueryConfig,
+    // Tried in order when vast_query doesn't find enough offers, e.g. a pricier or different GPU
+    // during shortages.  The disk to rent and bidding always come from vast_query.
+    #[serde(default)]
+    pub vast_query_fallbacks: Vec<VastQueryConfig>,
+    #[serde(default)]
     pub vast_api_key: String,
+    // A file holding vast_api_key, e.g. a mounted secret.  Takes precedence over vast_api_key.
+    pub vast_api_key_file: Option<String>,
+    // When set, state-changing endpoints require `Authorization: Bearer <api_token>` (or a
+    // `?token=<api_token>` query parameter, which is how Contemplants reach /drop).
+    pub api_token: Option<String>,
+    // A file holding api_token.  Takes precedence over api_token.
+    pub api_token_file: Option<String>,
+    // Also require api_token on read-only endpoints like /summary and /instances
+    #[serde(default)]
+    pub api_token_covers_reads: bool,
     // how many seconds to wait between each vast api call so we don't get rate limited
     #[serde(default = "vast_api_call_backoff_secs")]
     pub vast_api_call_backoff_secs: u64,
+    // Minimum milliseconds between the start of any two Vast API calls, shared by everything that
+    // calls Vast
+    #[serde(default = "default_vast_api_min_interval_ms")]
+    pub vast_api_min_interval_ms: u64,
+    // After this many Vast API calls in a row fail to connect or get a 5xx, stop calling Vast for
+    // vast_circuit_cooldown_secs, then let one call through to see if it's back.  The cooldown
+    // doubles each time that call fails too.  0 disables it.
+    #[serde(default = "default_vast_circuit_failures")]
+    pub vast_circuit_failures: u32,
+    #[serde(default = "default_vast_circuit_cooldown_secs")]
+    pub vast_circuit_cooldown_secs: u64,
+    // Give up on a Vast API call that can't connect, or doesn't finish, within these many seconds
+    #[serde(default = "default_vast_connect_timeout_secs")]
+    pub vast_connect_timeout_secs: u64,
+    #[serde(default = "default_vast_request_timeout_secs")]
+    pub vast_request_timeout_secs: u64,
+    // http://, https://, socks5://, or socks5h:// proxy every Vast API call is sent through, e.g.
+    // an egress proxy
+    pub https_proxy: Option<String>,
+    // How long offer search results are reused before Vast is searched again.  0 disables the
+    // cache.
+    #[serde(default = "default_offer_cache_ttl_secs")]
+    pub offer_cache_ttl_secs: u64,
+    // Times a destroy request is tried before giving up until the next polling cycle.  Only
+    // network errors and 5xx/429 responses are retried, and only while the waits between them add
+    // up to at most a fifth of task_polling_interval_secs.
+    #[serde(default = "default_drop_retry_attempts")]
+    pub drop_retry_attempts: u32,
+    // Seconds between destroy attempts, growing by this much after each failure
+    #[serde(default = "default_drop_retry_backoff_secs")]
+    pub drop_retry_backoff_secs: u64,
+    // Polling cycles in which destroying an instance may fail before it's forgotten, so it's
+    // replaced, and its machine quarantined
+    #[serde(default = "default_max_drop_attempts")]
+    pub max_drop_attempts: u32,
+    // How many of the most recent drops /drops remembers
+    #[serde(default = "default_drop_history_size")]
+    pub drop_history_size: usize,
+    // How many of the most recent scaling decisions /scale/history remembers
+    #[serde(default = "default_scale_history_size")]
+    pub scale_history_size: usize,
+    // Times the startup query validation is tried before giving up when Vast can't be reached.
+    // A query that reaches Vast but finds too few offers isn't retried.
+    #[serde(default = "default_validate_query_attempts")]
+    pub validate_query_attempts: u32,
+    // Seconds between validation attempts, growing by this much after each failure
+    #[serde(default = "default_validate_query_backoff_secs")]
+    pub validate_query_backoff_secs: u64,
+    // Create requests that may be in flight at once while creating the initial instances
+    #[serde(default = "default_create_concurrency")]
+    pub create_concurrency: usize,
+    // Seconds between successful instance launches, plus up to a fifth more of random jitter, so
+    // a batch of Contemplants doesn't hit the Hierophant all at once.  0 disables it.
+    #[serde(default)]
+    pub instance_launch_stagger_secs: u64,
+    // Start with however many instances could be created when Vast can't supply
+    // number_instances, and keep requesting the rest every polling cycle, instead of exiting
+    #[serde(default = "default_allow_partial_startup")]
+    pub allow_partial_startup: bool,
+    // How the rate limit backoff in initial instance creation shrinks once requests succeed again
+    #[serde(default)]
+    pub backoff_reset_policy: BackoffResetPolicy,
+    // Requests in a row that must avoid the rate limit before the "never" policy resets backoff
+    #[serde(default = "default_backoff_reset_streak")]
+    pub backoff_reset_streak: u32,
     #[serde(default = "default_task_polling_interval_secs")]
     pub task_polling_interval_secs: u64,
     // How long to wait for verification from the contemplant before dropping this instance.
     // Contemplant verification happens on startup
     #[serde(default = "default_contemplant_verification_timeout_secs")]
     pub contemplant_verification_timeout_secs: u64,
+    // How long Vast may report an instance as anything other than running (loading, exited...)
+    // before it's considered stuck and dropped
+    #[serde(default = "default_stuck_instance_timeout_secs")]
+    pub stuck_instance_timeout_secs: u64,
+    // Polling cycles in a row an instance must be missing from Vast's instance list before it's
+    // treated as removed outside this Magister.  Vast's listing occasionally drops instances
+    // that are still running.
+    #[serde(default = "default_missing_instance_polls")]
+    pub missing_instance_polls: u32,
+    // Instances older than this are dropped and replaced, since long-lived instances tend to
+    // degrade (full disks, driver issues...).  No maximum when unset.
+    pub max_instance_lifetime_secs: Option<u64>,
+    // How often verified Contemplants' health servers are probed.  Checked each polling interval,
+    // so it's effectively rounded up to a multiple of task_polling_interval_secs.  No health
+    // checks when unset.
+    pub health_check_interval_secs: Option<u64>,
+    // Health checks in a row a Contemplant must fail before it's dropped
+    #[serde(default = "default_health_check_failures")]
+    pub health_check_failures: u32,
+    // How long a connection to a Contemplant is kept open between health checks so the next one
+    // can reuse it.  Only saves reconnecting if it's longer than health_check_interval_secs.
+    #[serde(default = "default_contemplant_probe_pool_idle_secs")]
+    pub contemplant_probe_pool_idle_secs: u64,
+    // Idle connections kept open to each Contemplant.  Each is only probed once at a time.
+    #[serde(default = "default_contemplant_probe_pool_max_idle_per_host")]
+    pub contemplant_probe_pool_max_idle_per_host: usize,
+    // How many instances may be rotated out for age each polling interval, so a fleet created
+    // all at once isn't replaced all at once
+    #[serde(default = "default_max_rotations_per_tick")]
+    pub max_rotations_per_tick: usize,
+    // How long a machine is skipped after repeatedly failing create requests.  Doubles each time
+    // the same machine is quarantined again.
+    #[serde(default = "default_machine_quarantine_secs")]
+    pub machine_quarantine_secs: u64,
+    // Upper bound on how long graceful shutdown may take before the process exits anyway, logging
+    // the instances whose destroy requests it abandoned
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    // Save tracked instances to state_file whenever they change and adopt them again on startup,
+    // so a restart doesn't orphan every instance.  Disable for ephemeral deployments.
+    #[serde(default = "default_persist_state")]
+    pub persist_state: bool,
+    // Log the instances that would be rented or destroyed instead of calling Vast, handing out
+    // synthetic instance ids.  Offers are still searched for real.  Also set by --dry-run.
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
     // Id of the template that magister will be making instances of.
-    // Find the id at the Vast.ai web console
-    pub template_hash: String,
+    // Find the id at the Vast.ai web console.  Either a single hash, or a list of
+    // { hash, weight } to split new instances between templates in proportion to their weights.
+    #[serde(deserialize_with = "one_or_weighted")]
+    pub template_hash: Vec<WeightedTemplate>,
+    // Label given to every instance this Magister creates.  Only instances with this label are
+    // considered ours, so give each Magister sharing a Vast account its own.
+    #[serde(default = "default_instance_label")]
+    pub instance_label: String,
+    // Sent as the create request's extra_env.  Only for templates that don't set their own ENV,
+    // which Vast doesn't merge with it.  MAGISTER_DROP_ENDPOINT and HIEROPHANT_WS_ADDRESS are
+    // still set through onstart either way.
+    pub extra_env: Option<HashMap<String, String>>,
+    // Command Vast runs when the instance starts, replacing the template's own onstart.
+    // {env_exports} is substituted with `export NAME="value"` statements for
+    // MAGISTER_DROP_ENDPOINT, HIEROPHANT_WS_ADDRESS, and the Contemplant's config.
+    // {magister_drop_endpoint} and {hierophant_ws_address} are substituted with the bare values,
+    // escaped for a double-quoted string.  Must contain {magister_drop_endpoint} or
+    // {env_exports}, and every placeholder must be inside a single-quoted argument, which the
+    // values are escaped for.
+    #[serde(default = "default_onstart_template")]
+    pub onstart_template: String,
     // how many instances of the template this Magister will make sure are allocated
     pub number_instances: usize,
+    // Order in which acceptable offers are tried when provisioning.  Also accepted as
+    // offer_selection.
+    #[serde(default, alias = "offer_selection")]
+    pub provisioning_strategy: ProvisioningStrategy,
     // Won't use a machine if its in bad_hosts OR bad_machines
     pub bad_hosts: Option<Vec<u64>>,
     pub bad_machines: Option<Vec<u64>>,
     // Will prioritize a machine if its in good_hosts OR good_machines
     pub good_hosts: Option<Vec<u64>>,
     pub good_machines: Option<Vec<u64>>,
+    // Hard cap on the combined USD per hour of every instance.  Offers that would push spend over
+    // it are skipped, even if that leaves us short of number_instances.
+    pub max_total_dph: Option<f64>,
+    // Safety ceiling on instances running at once, including ones being dropped.  Nothing,
+    // including a runtime desired count change, ever creates instances past it.
+    pub hard_max_instances: Option<usize>,
+    // Warn when the Vast account balance will run out in fewer than this many hours at the
+    // current spend.  Checked every polling interval when set.
+    pub runway_alert_hours: Option<f64>,
+    // Alerts (low runway, under capacity) are also POSTed here as Slack-compatible JSON,
+    // { "text": "..." }.  They're only logged when unset.
+    pub alert_webhook_url: Option<String>,
+    // Alert when there have been fewer live instances than desired for this long, and again once
+    // back at capacity
+    #[serde(default = "default_under_capacity_alert_secs")]
+    pub under_capacity_alert_secs: u64,
+    // Spread instances across at least this many distinct hosts when provisioning so a single
+    // host going down can't take out the whole fleet.  /readyz waits for verified instances to
+    // span this many too.
+    pub min_distinct_hosts: Option<usize>,
+    // Never run more than one instance on the same machine (or, stricter, the same host), even
+    // when it offers several GPU slices.
+    #[serde(default)]
+    pub one_instance_per_machine: bool,
+    #[serde(default)]
+    pub one_instance_per_host: bool,
+    // The general form of the two above: never run more than this many instances on the same
+    // host or machine.  The one_instance_per_* settings win when they're stricter.
+    pub max_instances_per_host: Option<usize>,
+    pub max_instances_per_machine: Option<usize>,
+    // Never run more than this many instances in the same geolocation, so a regional outage
+    // can't take out the whole fleet
+    pub max_instances_per_geolocation: Option<usize>,
+    // Only rent offers whose geolocation matches one of these, and never one matching
+    // denied_geolocations.  Entries are matched case-insensitively: two letters against the
+    // country code Vast ends geolocations with, anything longer as a substring ("Texas").
+    pub allowed_geolocations: Option<Vec<String>>,
+    pub denied_geolocations: Option<Vec<String>>,
+    // Name of a profile under [contemplant.profiles] whose values override the base [contemplant]
+    // table.  Lets several Magisters share most Contemplant settings.
+    pub contemplant_profile: Option<String>,
     // Configuration for Contemplants spawned by this Magister
     #[serde(default)]
     pub contemplant: ContemplantConfig,
+    // Where each field's effective value came from, filled in by `Config::load`
+    #[serde(skip)]
+    pub sources: HashMap<String, ConfigSource>,
 }
 
 fn default_contemplant_verification_timeout_secs() -> u64 {
     180
 }
 
+fn default_stuck_instance_timeout_secs() -> u64 {
+    900
+}
+
+fn default_missing_instance_polls() -> u32 {
+    2
+}
+
+fn default_health_check_failures() -> u32 {
+    3
+}
+
+fn default_contemplant_probe_pool_idle_secs() -> u64 {
+    5 * 60
+}
+
+fn default_contemplant_probe_pool_max_idle_per_host() -> usize {
+    1
+}
+
+fn default_under_capacity_alert_secs() -> u64 {
+    10 * 60
+}
+
+fn default_max_rotations_per_tick() -> usize {
+    1
+}
+
+fn default_machine_quarantine_secs() -> u64 {
+    30 * 60
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_instance_label() -> String {
+    "magister".to_string()
+}
+
+fn default_onstart_template() -> String {
+    r#"su contemplant -c 'export HOME=/home/contemplant; export TMUX_TMPDIR=/home/contemplant/.tmux; {env_exports}; /usr/local/bin/contemplant-entrypoint.sh'"#.to_string()
+}
+
+fn default_persist_state() -> bool {
+    true
+}
+
+fn default_state_file() -> String {
+    "magister_state.json".to_string()
+}
+
 fn vast_api_call_backoff_secs() -> u64 {
     10
 }
 
+fn default_vast_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_vast_api_min_interval_ms() -> u64 {
+    1000
+}
+
+fn default_vast_circuit_failures() -> u32 {
+    5
+}
+
+fn default_vast_circuit_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_offer_cache_ttl_secs() -> u64 {
+    60
+}
+
+fn default_drop_retry_attempts() -> u32 {
+    3
+}
+
+fn default_drop_retry_backoff_secs() -> u64 {
+    2
+}
+
+fn default_create_concurrency() -> usize {
+    3
+}
+
+fn default_allow_partial_startup() -> bool {
+    true
+}
+
+fn default_max_drop_attempts() -> u32 {
+    10
+}
+
+fn default_drop_history_size() -> usize {
+    200
+}
+
+fn default_scale_history_size() -> usize {
+    200
+}
+
+fn default_validate_query_attempts() -> u32 {
+    5
+}
+
+fn default_validate_query_backoff_secs() -> u64 {
+    5
+}
+
+fn default_vast_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_backoff_reset_streak() -> u32 {
+    3
+}
+
 fn default_task_polling_interval_secs() -> u64 {
     30
 }
@@ -60,6 +382,106 @@ fn default_http_port() -> u16 {
     8555
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackoffResetPolicy {
+    /// Drop straight back to no backoff after any request that isn't rate limited
+    #[default]
+    OnSuccess,
+    /// Halve the accumulated backoff after each request that isn't rate limited
+    Decay,
+    /// Keep the accumulated backoff until `backoff_reset_streak` requests in a row aren't rate
+    /// limited
+    Never,
+}
+
+impl BackoffResetPolicy {
+    /// The backoff in seconds to carry forward after a request that wasn't rate limited.
+    pub fn reset(&self, current_secs: u64, clean_streak: u32, required_streak: u32) -> u64 {
+        match self {
+            BackoffResetPolicy::OnSuccess => 0,
+            BackoffResetPolicy::Decay => current_secs / 2,
+            BackoffResetPolicy::Never => {
+                if clean_streak >= required_streak {
+                    0
+                } else {
+                    current_secs
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for BackoffResetPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "on_success" => Ok(BackoffResetPolicy::OnSuccess),
+            "decay" => Ok(BackoffResetPolicy::Decay),
+            "never" => Ok(BackoffResetPolicy::Never),
+            other => anyhow::bail!(
+                "unknown backoff reset policy \"{other}\", expected \"on_success\", \"decay\", or \"never\""
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// env_logger's human readable layout
+    #[default]
+    Text,
+    /// One JSON object per line with timestamp, level, target, message, and structured fields
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => anyhow::bail!("unknown log format \"{other}\", expected \"text\" or \"json\""),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisioningStrategy {
+    /// Vast's own score ordering
+    #[default]
+    #[serde(alias = "best_score")]
+    Score,
+    /// Lowest dph_total first
+    Cheapest,
+    /// Highest dlperf first
+    #[serde(alias = "best_perf")]
+    Fastest,
+    /// Highest dlperf_per_dphtotal first
+    #[serde(alias = "perf_per_dollar")]
+    BestValue,
+}
+
+impl std::str::FromStr for ProvisioningStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "score" | "best_score" => Ok(ProvisioningStrategy::Score),
+            "cheapest" => Ok(ProvisioningStrategy::Cheapest),
+            "fastest" | "best_perf" => Ok(ProvisioningStrategy::Fastest),
+            "best_value" | "perf_per_dollar" => Ok(ProvisioningStrategy::BestValue),
+            other => anyhow::bail!(
+                "unknown provisioning strategy \"{other}\", expected \"score\", \"cheapest\", \"fastest\", or \"best_value\""
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ContemplantConfig {
     /// Prover type: "cpu" or "cuda" (default: "cpu")
@@ -90,6 +512,24 @@ pub struct ContemplantConfig {
     /// Format: newline-separated SSH public keys
     #[serde(default)]
     pub ssh_authorized_keys: Option<String>,
+    /// Named sets of overrides, selected with the top level `contemplant_profile` (default: none)
+    #[serde(default)]
+    pub profiles: HashMap<String, ContemplantProfile>,
+}
+
+/// A partial ContemplantConfig.  Any field that is set overrides the base `[contemplant]` value
+/// when this profile is selected.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ContemplantProfile {
+    pub prover_type: Option<String>,
+    pub contemplant_name: Option<String>,
+    pub http_port: Option<u16>,
+    pub moongate_endpoint: Option<String>,
+    pub heartbeat_interval_seconds: Option<u64>,
+    pub max_proofs_stored: Option<usize>,
+    pub moongate_log_path: Option<String>,
+    pub watcher_polling_interval_ms: Option<u64>,
+    pub ssh_authorized_keys: Option<String>,
 }
 
 fn default_prover_type() -> String {
@@ -128,52 +568,305 @@ impl Default for ContemplantConfig {
             moongate_log_path: default_moongate_log_path(),
             watcher_polling_interval_ms: default_watcher_polling_interval_ms(),
             ssh_authorized_keys: None,
+            profiles: HashMap::new(),
         }
     }
 }
 
 impl ContemplantConfig {
+    /// Override base values with every field set in the named profile.
+    pub fn apply_profile(&mut self, name: &str) -> Result<()> {
+        let profile = self.profiles.get(name).cloned().with_context(|| {
+            format!("contemplant_profile \"{name}\" is not defined under [contemplant.profiles]")
+        })?;
+
+        if let Some(prover_type) = profile.prover_type {
+            self.prover_type = prover_type;
+        }
+        if profile.contemplant_name.is_some() {
+            self.contemplant_name = profile.contemplant_name;
+        }
+        if let Some(http_port) = profile.http_port {
+            self.http_port = http_port;
+        }
+        if profile.moongate_endpoint.is_some() {
+            self.moongate_endpoint = profile.moongate_endpoint;
+        }
+        if let Some(heartbeat_interval_seconds) = profile.heartbeat_interval_seconds {
+            self.heartbeat_interval_seconds = heartbeat_interval_seconds;
+        }
+        if let Some(max_proofs_stored) = profile.max_proofs_stored {
+            self.max_proofs_stored = max_proofs_stored;
+        }
+        if let Some(moongate_log_path) = profile.moongate_log_path {
+            self.moongate_log_path = moongate_log_path;
+        }
+        if let Some(watcher_polling_interval_ms) = profile.watcher_polling_interval_ms {
+            self.watcher_polling_interval_ms = watcher_polling_interval_ms;
+        }
+        if profile.ssh_authorized_keys.is_some() {
+            self.ssh_authorized_keys = profile.ssh_authorized_keys;
+        }
+
+        Ok(())
+    }
+
+    /// Normalize line endings in `ssh_authorized_keys` and drop (with a warning) every line that
+    /// isn't an SSH public key, so a bad paste doesn't break sshd on the Contemplant.
+    pub fn normalize_ssh_authorized_keys(&mut self) {
+        let Some(ref keys) = self.ssh_authorized_keys else {
+            return;
+        };
+
+        let keys = keys.replace("\r\n", "\n").replace('\r', "\n");
+        let mut valid_keys = Vec::new();
+        for (i, line) in keys.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if is_ssh_public_key(line) {
+                valid_keys.push(line);
+            } else {
+                warn!("Skipping line {} of ssh_authorized_keys because it isn't a valid SSH public key", i + 1);
+            }
+        }
+
+        self.ssh_authorized_keys = if valid_keys.is_empty() { None } else { Some(valid_keys.join("\n")) };
+    }
+
+    /// Lowercase `prover_type` and reject anything the Contemplant doesn't understand, since a typo
+    /// would otherwise only surface as a misbehaving Contemplant on a remote machine.
+    pub fn validate_prover_type(&mut self) -> Result<()> {
+        self.prover_type = self.prover_type.to_lowercase();
+        if !PROVER_TYPES.contains(&self.prover_type.as_str()) {
+            anyhow::bail!(
+                "contemplant prover_type \"{}\" is not one of {}",
+                self.prover_type,
+                PROVER_TYPES.join(", ")
+            );
+        }
+        if self.prover_type == "cuda" && self.moongate_endpoint.is_none() {
+            anyhow::bail!(
+                "contemplant moongate_endpoint is required when prover_type is \"cuda\". Provide it via config file or CONTEMPLANT_MOONGATE_ENDPOINT environment variable."
+            );
+        }
+
+        Ok(())
+    }
+
     /// Generate environment variable exports for the onstart command.
     /// These will be passed to Contemplants spawned on Vast.ai.
     pub fn to_env_exports(&self) -> String {
         let mut exports = Vec::new();
 
         // Always export prover type
-        exports.push(format!("export PROVER_TYPE=\\\"{}\\\"", self.prover_type));
+        exports.push(env_export("PROVER_TYPE", &self.prover_type));
 
         // Optional exports
         if let Some(ref name) = self.contemplant_name {
-            exports.push(format!("export CONTEMPLANT_NAME=\\\"{}\\\"", name));
+            exports.push(env_export("CONTEMPLANT_NAME", name));
         }
 
         // Always export http_port
-        exports.push(format!("export HTTP_PORT=\\\"{}\\\"", self.http_port));
+        exports.push(env_export("HTTP_PORT", &self.http_port.to_string()));
 
         if let Some(ref endpoint) = self.moongate_endpoint {
-            exports.push(format!("export MOONGATE_ENDPOINT=\\\"{}\\\"", endpoint));
+            exports.push(env_export("MOONGATE_ENDPOINT", endpoint));
         }
 
-        exports.push(format!("export HEARTBEAT_INTERVAL_SECONDS=\\\"{}\\\"", self.heartbeat_interval_seconds));
-        exports.push(format!("export MAX_PROOFS_STORED=\\\"{}\\\"", self.max_proofs_stored));
-        exports.push(format!("export MOONGATE_LOG_PATH=\\\"{}\\\"", self.moongate_log_path));
-        exports.push(format!("export WATCHER_POLLING_INTERVAL_MS=\\\"{}\\\"", self.watcher_polling_interval_ms));
+        exports.push(env_export("HEARTBEAT_INTERVAL_SECONDS", &self.heartbeat_interval_seconds.to_string()));
+        exports.push(env_export("MAX_PROOFS_STORED", &self.max_proofs_stored.to_string()));
+        exports.push(env_export("MOONGATE_LOG_PATH", &self.moongate_log_path));
+        exports.push(env_export("WATCHER_POLLING_INTERVAL_MS", &self.watcher_polling_interval_ms.to_string()));
 
         if let Some(ref keys) = self.ssh_authorized_keys {
-            // SSH keys can contain newlines, so we need to escape them for the shell
-            let escaped_keys = keys.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
-            exports.push(format!("export SSH_AUTHORIZED_KEYS=\\\"{}\\\"", escaped_keys));
+            exports.push(env_export("SSH_AUTHORIZED_KEYS", keys));
         }
 
         exports.join("; ")
     }
 }
 
+// this_magister_addr becomes `{this_magister_addr}:{http_port}/drop/{offer_id}` on the Contemplant,
+// so it must be a scheme and host and nothing else.  A loopback address parses fine but only
+// reaches the Contemplant's own machine, so it's warned about.
+fn validate_this_magister_addr(this_magister_addr: &str) -> Result<()> {
+    let addr = this_magister_addr
+        .strip_suffix('/')
+        .unwrap_or(this_magister_addr);
+    let url = reqwest::Url::parse(addr).with_context(|| {
+        format!("this_magister_addr \"{this_magister_addr}\" must look like http://host")
+    })?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("this_magister_addr \"{this_magister_addr}\" must start with http:// or https://");
+    }
+    let Some(host) = url.host_str() else {
+        anyhow::bail!("this_magister_addr \"{this_magister_addr}\" has no host");
+    };
+    if url.port().is_some() {
+        anyhow::bail!(
+            "this_magister_addr \"{this_magister_addr}\" must not include a port.  Set http_port instead."
+        );
+    }
+    if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+        anyhow::bail!(
+            "this_magister_addr \"{this_magister_addr}\" must not include a path like /drop"
+        );
+    }
+
+    if is_loopback_host(host) {
+        warn!(
+            "this_magister_addr {this_magister_addr} only reaches the machine it's used on.  Contemplants on Vast won't be able to reach /drop."
+        );
+    }
+    Ok(())
+}
+
+// whether host, as a URL has it, only ever means the machine it's used on
+fn is_loopback_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback() || ip.is_unspecified())
+}
+
+// Placeholders onstart_template may use, all substituted escaped by shell_escape
+const ONSTART_PLACEHOLDERS: &[&str] = &[
+    "{env_exports}",
+    "{magister_drop_endpoint}",
+    "{hierophant_ws_address}",
+];
+
+// Whether every placeholder in template is inside a single-quoted shell argument, which
+// shell_escape escapes for.  Outside one, its '\'' escapes would end up in the values.
+fn placeholders_single_quoted(template: &str) -> bool {
+    #[derive(PartialEq)]
+    enum Quoting {
+        None,
+        Single,
+        Double,
+    }
+
+    let mut quoting = Quoting::None;
+    let mut chars = template.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if quoting != Quoting::Single
+            && ONSTART_PLACEHOLDERS
+                .iter()
+                .any(|placeholder| template[i..].starts_with(placeholder))
+        {
+            return false;
+        }
+        match (&quoting, c) {
+            (Quoting::None | Quoting::Double, '\\') => {
+                chars.next();
+            }
+            (Quoting::None, '\'') => quoting = Quoting::Single,
+            (Quoting::Single, '\'') => quoting = Quoting::None,
+            (Quoting::None, '"') => quoting = Quoting::Double,
+            (Quoting::Double, '"') => quoting = Quoting::None,
+            _ => {}
+        }
+    }
+    true
+}
+
+// The secret in the file at path, without surrounding whitespace such as a trailing newline
+fn read_secret_file(path: &str) -> Result<String> {
+    let secret = std::fs::read_to_string(path).with_context(|| format!("Read {path}"))?;
+    Ok(secret.trim().to_string())
+}
+
+// `export NAME="value"` for the onstart command, with value escaped by shell_escape
+fn env_export(name: &str, value: &str) -> String {
+    format!("export {name}=\"{}\"", shell_escape(value))
+}
+
+// value for a double-quoted string in the onstart command.  It passes through two layers, each
+// escaped in turn: the double quotes in the Contemplant's shell, and the single-quoted
+// `su -c '...'` argument around them, which onstart_template is checked for at load.  The create
+// request escapes the whole command for JSON.
+fn shell_escape(value: &str) -> String {
+    let mut shell_escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | '"' | '$' | '`' => {
+                shell_escaped.push('\\');
+                shell_escaped.push(c);
+            }
+            // close the single-quoted su argument, add a literal quote, and reopen it
+            '\'' => shell_escaped.push_str("'\\''"),
+            _ => shell_escaped.push(c),
+        }
+    }
+    shell_escaped
+}
+
+// template with each placeholder replaced by its value in one pass, so a value that happens to
+// contain a placeholder isn't substituted into again
+fn substitute(template: &str, values: &[(&str, String)]) -> String {
+    let mut substituted = String::with_capacity(template.len());
+    let mut rest = template;
+    while !rest.is_empty() {
+        match values
+            .iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder))
+        {
+            Some((placeholder, value)) => {
+                substituted.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                let c = rest.chars().next().unwrap_or_default();
+                substituted.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    substituted
+}
+
+const PROVER_TYPES: &[&str] = &["cpu", "cuda"];
+
+const SSH_KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// `<key type> <base64 key> [comment]`, with no control characters anywhere in the line.
+fn is_ssh_public_key(line: &str) -> bool {
+    if line.chars().any(|c| c.is_control() && c != '\t') {
+        return false;
+    }
+
+    let mut parts = line.split_whitespace();
+    let (Some(key_type), Some(key)) = (parts.next(), parts.next()) else {
+        return false;
+    };
+
+    SSH_KEY_TYPES.contains(&key_type)
+        && key.len() % 4 == 0
+        && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/' || b == b'=')
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct VastQueryConfig {
     // in gb.  ex: 16
     pub allocated_storage: u16,
-    // ex: "RTX 4090"
-    pub gpu_name: String,
+    // ex: "RTX 4090" or ["RTX 4090", "RTX 3090"].  Offers with any of these GPUs are accepted.
+    // Required unless min_dlperf or max_dph_per_dlperf is set instead.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub gpu_name: Vec<String>,
     // percent 0-1 ex: 0.98
     pub reliability: f64,
     // ex: 12.8
@@ -182,38 +875,193 @@ pub struct VastQueryConfig {
     pub gpu_ram: u64,
     // in gb ex: 16
     pub disk_space: u64,
+    // GB of disk to rent on each instance.  Defaults to disk_space, but a query can filter on
+    // machines with plenty of free disk while renting less of it.
+    pub provision_disk_gb: Option<u64>,
     // ex: 192679
     pub duration: f64,
-    // Max cost per hour in USD ex: 0.53
+    // Max cost per hour in USD ex: 0.53.  This is a hard ceiling, never exceeded.
     pub cost_per_hour: f64,
+    // Preferred max cost per hour in USD ex: 0.40.  Offers under it are tried first; offers
+    // between this and cost_per_hour are only used (with a warning) when nothing cheaper is left.
+    pub soft_cost_per_hour: Option<f64>,
+    // Bounds on GPUs per machine, so a single GPU query doesn't also match (and overpay for)
+    // 8 GPU machines.  Unbounded when unset.
+    pub min_num_gpus: Option<u32>,
+    pub max_num_gpus: Option<u32>,
+    // Rent interruptible bid instances instead of on-demand ones.  They're cheaper but Vast may
+    // stop them at any time, after which they're cleaned up and replaced like any other instance
+    // removed outside this Magister.
+    #[serde(default)]
+    pub use_bid: bool,
+    // USD per hour to bid when use_bid is set.  Defaults to cost_per_hour.
+    pub bid_price: Option<f64>,
+    // Minimum upload and download bandwidth in Mbps.  Checked against offers after the search,
+    // since a machine that can't move data cripples its Contemplant.
+    pub min_inet_up: Option<f64>,
+    pub min_inet_down: Option<f64>,
+    // Minimum disk bandwidth in MB/s, and whether the disk must look like an NVMe drive or SSD
+    // from its name.  Proof artifacts are disk heavy.
+    pub min_disk_bw: Option<f64>,
+    #[serde(default)]
+    pub require_nvme: bool,
+    // Minimum of the offer's reliability score (0-1), which Vast computes differently from the
+    // reliability2 that the reliability field above searches on.  Checked after the search.
+    pub min_reliability: Option<f64>,
+    // Select by performance instead of by GPU model: any GPU with at least min_dlperf, and
+    // costing at most max_dph_per_dlperf USD per hour per unit of dlperf.  Can't be combined with
+    // gpu_name.
+    pub min_dlperf: Option<f64>,
+    pub max_dph_per_dlperf: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WeightedTemplate {
+    pub hash: String,
+    // share of new instances relative to the other templates' weights
+    #[serde(default = "default_template_weight")]
+    pub weight: u32,
+}
+
+fn default_template_weight() -> u32 {
+    1
+}
+
+/// Accepts either a single hash or a list of weighted templates, so `template_hash = "..."`
+/// keeps parsing.
+fn one_or_weighted<'de, D>(deserializer: D) -> Result<Vec<WeightedTemplate>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrWeighted {
+        One(String),
+        Weighted(Vec<WeightedTemplate>),
+    }
+
+    Ok(match OneOrWeighted::deserialize(deserializer)? {
+        OneOrWeighted::One(hash) => vec![WeightedTemplate {
+            hash,
+            weight: default_template_weight(),
+        }],
+        OneOrWeighted::Weighted(templates) => templates,
+    })
+}
+
+/// Parses `TEMPLATE_HASH`: `hash`, or comma-separated `hash:weight` pairs where the weight
+/// defaults to 1.
+fn parse_template_hashes(val: &str) -> Result<Vec<WeightedTemplate>> {
+    val.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((hash, weight)) => Ok(WeightedTemplate {
+                hash: hash.trim().to_string(),
+                weight: weight
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid weight in {entry}"))?,
+            }),
+            None => Ok(WeightedTemplate {
+                hash: entry.to_string(),
+                weight: default_template_weight(),
+            }),
+        })
+        .collect()
+}
+
+/// Accepts either a single string or a list of strings, so `gpu_name = "RTX 4090"` keeps parsing.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    })
 }
 
 impl VastQueryConfig {
+    pub fn over_soft_ceiling(&self, dph_total: f64) -> bool {
+        self.soft_cost_per_hour
+            .is_some_and(|soft_cost_per_hour| dph_total > soft_cost_per_hour)
+    }
+
+    // whether offers are selected by dlperf rather than gpu_name
+    pub fn targets_performance(&self) -> bool {
+        self.min_dlperf.is_some() || self.max_dph_per_dlperf.is_some()
+    }
+
+    // exactly one of gpu_name or the dlperf targets must be set
+    pub fn validate(&self) -> Result<()> {
+        match (self.gpu_name.is_empty(), self.targets_performance()) {
+            (false, true) => anyhow::bail!(
+                "gpu_name can't be combined with min_dlperf or max_dph_per_dlperf.  Set one or the other."
+            ),
+            (true, false) => anyhow::bail!(
+                "gpu_name is required unless min_dlperf or max_dph_per_dlperf is set."
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    // GB of disk to rent on create requests
+    pub fn provision_disk_gb(&self) -> u64 {
+        self.provision_disk_gb.unwrap_or(self.disk_space)
+    }
+
+    // the price to bid on create requests, or None for on-demand instances
+    pub fn bid_price(&self) -> Option<f64> {
+        self.use_bid
+            .then(|| self.bid_price.unwrap_or(self.cost_per_hour))
+    }
+
+    /// Build the Vast offer search query.  Built through serde_json so that any value (such as a
+    /// GPU name containing quotes) is escaped and the query is always valid JSON.
     pub fn to_query_string(&self) -> String {
-        let mut query = String::new();
-
-        write!(query, r#"{{"#).unwrap();
-        write!(query, r#""disk_space":{{"gte": {}}},"#, self.disk_space).unwrap();
-        write!(query, r#""reliability2":{{"gte":{}}},"#, self.reliability).unwrap();
-        write!(query, r#""duration":{{"gte":{}}},"#, self.duration).unwrap();
-        write!(query, r#""verified":{{"eq":true}}, "#).unwrap();
-        write!(query, r#""dph_total":{{"lte":{}}},"#, self.cost_per_hour).unwrap();
-        write!(query, r#""gpu_ram":{{"gte":{}000}},"#, self.gpu_ram).unwrap();
-        write!(query, r#""sort_option": {{"0":["score", "desc"]}},"#).unwrap();
-        write!(query, r#""rentable":{{"eq":true}}, "#).unwrap();
-        write!(
-            query,
-            r#""cuda_max_good":{{"gte":"{}"}},"#,
-            self.min_cuda_version
-        )
-        .unwrap();
-        write!(query, r#""gpu_name":{{"in":["{}"]}},"#, self.gpu_name).unwrap();
-        write!(query, r#""allocated_storage":{},"#, self.allocated_storage).unwrap();
-        write!(query, r#""order": [["score", "desc"]],"#).unwrap();
-        write!(query, r#""type":"ask""#).unwrap();
-        write!(query, "}}").unwrap();
+        let mut query = json!({
+            "disk_space": { "gte": self.disk_space },
+            "reliability2": { "gte": self.reliability },
+            "duration": { "gte": self.duration },
+            "verified": { "eq": true },
+            "dph_total": { "lte": self.cost_per_hour },
+            "gpu_ram": { "gte": self.gpu_ram * 1000 },
+            "sort_option": { "0": ["score", "desc"] },
+            "rentable": { "eq": true },
+            "cuda_max_good": { "gte": self.min_cuda_version.to_string() },
+            "allocated_storage": self.allocated_storage,
+            "order": [["score", "desc"]],
+            "type": if self.use_bid { "bid" } else { "ask" },
+        });
 
-        query
+        if !self.gpu_name.is_empty() {
+            query["gpu_name"] = json!({ "in": self.gpu_name });
+        }
+        // max_dph_per_dlperf is checked against the returned offers
+        if let Some(min_dlperf) = self.min_dlperf {
+            query["dlperf"] = json!({ "gte": min_dlperf });
+        }
+
+        let mut num_gpus = serde_json::Map::new();
+        if let Some(min_num_gpus) = self.min_num_gpus {
+            num_gpus.insert("gte".to_string(), json!(min_num_gpus));
+        }
+        if let Some(max_num_gpus) = self.max_num_gpus {
+            num_gpus.insert("lte".to_string(), json!(max_num_gpus));
+        }
+        if !num_gpus.is_empty() {
+            query["num_gpus"] = num_gpus.into();
+        }
+
+        query.to_string()
     }
 }
 
@@ -223,153 +1071,486 @@ impl Config {
     /// The .toml file is optional if all required fields are provided via environment variables.
     pub fn load(config_path: &str) -> Result<Self> {
         // Try to load from file if it exists
+        let mut file_table = toml::Table::new();
         let mut config = if Path::new(config_path).exists() {
             let contents = std::fs::read_to_string(config_path)
                 .context(format!("Failed to read config file: {}", config_path))?;
+            file_table = toml::from_str(&contents)
+                .context(format!("Failed to parse config file: {}", config_path))?;
             toml::from_str::<Config>(&contents)
                 .context(format!("Failed to parse config file: {}", config_path))?
         } else {
             // No file exists, create config with defaults (required fields will be empty)
             Config {
                 http_port: default_http_port(),
+                http_bind_addr: None,
                 this_magister_addr: String::new(),
+                magister_id: None,
+                log_format: LogFormat::default(),
                 hierophant_ip: String::new(),
                 hierophant_http_port: 0,
                 vast_query: VastQueryConfig {
                     allocated_storage: 0,
-                    gpu_name: String::new(),
+                    gpu_name: Vec::new(),
                     reliability: 0.0,
                     min_cuda_version: 0.0,
                     gpu_ram: 0,
                     disk_space: 0,
+                    provision_disk_gb: None,
                     duration: 0.0,
                     cost_per_hour: 0.0,
+                    soft_cost_per_hour: None,
+                    min_num_gpus: None,
+                    max_num_gpus: None,
+                    use_bid: false,
+                    bid_price: None,
+                    min_inet_up: None,
+                    min_inet_down: None,
+                    min_disk_bw: None,
+                    require_nvme: false,
+                    min_reliability: None,
+                    min_dlperf: None,
+                    max_dph_per_dlperf: None,
                 },
+                vast_query_fallbacks: Vec::new(),
                 vast_api_key: String::new(),
+                vast_api_key_file: None,
+                api_token: None,
+                api_token_file: None,
+                api_token_covers_reads: false,
                 vast_api_call_backoff_secs: vast_api_call_backoff_secs(),
+                vast_api_min_interval_ms: default_vast_api_min_interval_ms(),
+                vast_circuit_failures: default_vast_circuit_failures(),
+                vast_circuit_cooldown_secs: default_vast_circuit_cooldown_secs(),
+                vast_connect_timeout_secs: default_vast_connect_timeout_secs(),
+                vast_request_timeout_secs: default_vast_request_timeout_secs(),
+                https_proxy: None,
+                offer_cache_ttl_secs: default_offer_cache_ttl_secs(),
+                drop_retry_attempts: default_drop_retry_attempts(),
+                drop_retry_backoff_secs: default_drop_retry_backoff_secs(),
+                max_drop_attempts: default_max_drop_attempts(),
+                drop_history_size: default_drop_history_size(),
+                scale_history_size: default_scale_history_size(),
+                validate_query_attempts: default_validate_query_attempts(),
+                validate_query_backoff_secs: default_validate_query_backoff_secs(),
+                create_concurrency: default_create_concurrency(),
+                instance_launch_stagger_secs: 0,
+                allow_partial_startup: default_allow_partial_startup(),
+                backoff_reset_policy: BackoffResetPolicy::default(),
+                backoff_reset_streak: default_backoff_reset_streak(),
                 task_polling_interval_secs: default_task_polling_interval_secs(),
                 contemplant_verification_timeout_secs: default_contemplant_verification_timeout_secs(),
-                template_hash: String::new(),
+                stuck_instance_timeout_secs: default_stuck_instance_timeout_secs(),
+                missing_instance_polls: default_missing_instance_polls(),
+                max_instance_lifetime_secs: None,
+                health_check_interval_secs: None,
+                health_check_failures: default_health_check_failures(),
+                contemplant_probe_pool_idle_secs: default_contemplant_probe_pool_idle_secs(),
+                contemplant_probe_pool_max_idle_per_host: default_contemplant_probe_pool_max_idle_per_host(),
+                max_rotations_per_tick: default_max_rotations_per_tick(),
+                machine_quarantine_secs: default_machine_quarantine_secs(),
+                shutdown_timeout_secs: default_shutdown_timeout_secs(),
+                persist_state: default_persist_state(),
+                dry_run: false,
+                state_file: default_state_file(),
+                template_hash: Vec::new(),
+                instance_label: default_instance_label(),
+                onstart_template: default_onstart_template(),
+                extra_env: None,
                 number_instances: 0,
+                provisioning_strategy: ProvisioningStrategy::default(),
                 bad_hosts: None,
                 bad_machines: None,
                 good_hosts: None,
                 good_machines: None,
+                max_total_dph: None,
+                hard_max_instances: None,
+                runway_alert_hours: None,
+                alert_webhook_url: None,
+                under_capacity_alert_secs: default_under_capacity_alert_secs(),
+                min_distinct_hosts: None,
+                one_instance_per_machine: false,
+                one_instance_per_host: false,
+                max_instances_per_host: None,
+                max_instances_per_machine: None,
+                max_instances_per_geolocation: None,
+                allowed_geolocations: None,
+                denied_geolocations: None,
+                contemplant_profile: None,
                 contemplant: ContemplantConfig::default(),
+                sources: HashMap::new(),
             }
         };
+        // where each field assigned below came from, for the fields that aren't simply file or default
+        let mut sources = HashMap::new();
 
         // Override with environment variables if present
-        if let Ok(val) = env::var("HTTP_PORT") {
+        if let Ok(val) = env_override(&mut sources, "http_port", "HTTP_PORT") {
             config.http_port = val.parse().context("HTTP_PORT must be a valid u16")?;
         }
-        if let Ok(val) = env::var("THIS_MAGISTER_ADDR") {
+        if let Ok(val) = env_override(&mut sources, "http_bind_addr", "HTTP_BIND_ADDR") {
+            config.http_bind_addr = Some(val);
+        }
+        if let Ok(val) = env_override(&mut sources, "this_magister_addr", "THIS_MAGISTER_ADDR") {
             config.this_magister_addr = val;
         }
-        if let Ok(val) = env::var("HIEROPHANT_IP") {
+        if let Ok(val) = env_override(&mut sources, "magister_id", "MAGISTER_ID") {
+            config.magister_id = Some(val);
+        }
+        if let Ok(val) = env_override(&mut sources, "log_format", "LOG_FORMAT") {
+            config.log_format = val.parse().context("LOG_FORMAT must be one of text, json")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "hierophant_ip", "HIEROPHANT_IP") {
             config.hierophant_ip = val;
         }
-        if let Ok(val) = env::var("HIEROPHANT_HTTP_PORT") {
+        if let Ok(val) = env_override(&mut sources, "hierophant_http_port", "HIEROPHANT_HTTP_PORT") {
             config.hierophant_http_port = val.parse().context("HIEROPHANT_HTTP_PORT must be a valid u16")?;
         }
-        if let Ok(val) = env::var("VAST_API_KEY") {
+        if let Ok(val) = env_override(&mut sources, "vast_api_key", "VAST_API_KEY") {
             config.vast_api_key = val;
         }
-        if let Ok(val) = env::var("VAST_API_CALL_BACKOFF_SECS") {
+        if let Ok(val) = env_override(&mut sources, "vast_api_key_file", "VAST_API_KEY_FILE") {
+            config.vast_api_key_file = Some(val);
+        }
+        if let Ok(val) = env_override(&mut sources, "api_token", "API_TOKEN") {
+            config.api_token = Some(val);
+        }
+        if let Ok(val) = env_override(&mut sources, "api_token_file", "API_TOKEN_FILE") {
+            config.api_token_file = Some(val);
+        }
+        if let Ok(val) = env_override(&mut sources, "api_token_covers_reads", "API_TOKEN_COVERS_READS") {
+            config.api_token_covers_reads = val.parse().context("API_TOKEN_COVERS_READS must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_api_call_backoff_secs", "VAST_API_CALL_BACKOFF_SECS") {
             config.vast_api_call_backoff_secs = val.parse().context("VAST_API_CALL_BACKOFF_SECS must be a valid u64")?;
         }
-        if let Ok(val) = env::var("TASK_POLLING_INTERVAL_SECS") {
+        if let Ok(val) = env_override(&mut sources, "vast_api_min_interval_ms", "VAST_API_MIN_INTERVAL_MS") {
+            config.vast_api_min_interval_ms = val.parse().context("VAST_API_MIN_INTERVAL_MS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_circuit_failures", "VAST_CIRCUIT_FAILURES") {
+            config.vast_circuit_failures = val.parse().context("VAST_CIRCUIT_FAILURES must be a valid u32")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_circuit_cooldown_secs", "VAST_CIRCUIT_COOLDOWN_SECS") {
+            config.vast_circuit_cooldown_secs = val.parse().context("VAST_CIRCUIT_COOLDOWN_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_connect_timeout_secs", "VAST_CONNECT_TIMEOUT_SECS") {
+            config.vast_connect_timeout_secs = val.parse().context("VAST_CONNECT_TIMEOUT_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_request_timeout_secs", "VAST_REQUEST_TIMEOUT_SECS") {
+            config.vast_request_timeout_secs = val.parse().context("VAST_REQUEST_TIMEOUT_SECS must be a valid u64")?;
+        }
+        // the conventional proxy variables, HTTPS_PROXY winning over ALL_PROXY
+        if let Ok(val) = env_override(&mut sources, "https_proxy", "HTTPS_PROXY")
+            .or_else(|_| env_override(&mut sources, "https_proxy", "ALL_PROXY"))
+        {
+            config.https_proxy = Some(val);
+        }
+        if let Ok(val) = env_override(&mut sources, "offer_cache_ttl_secs", "OFFER_CACHE_TTL_SECS") {
+            config.offer_cache_ttl_secs = val.parse().context("OFFER_CACHE_TTL_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "drop_retry_attempts", "DROP_RETRY_ATTEMPTS") {
+            config.drop_retry_attempts = val.parse().context("DROP_RETRY_ATTEMPTS must be a valid u32")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "drop_retry_backoff_secs", "DROP_RETRY_BACKOFF_SECS") {
+            config.drop_retry_backoff_secs = val.parse().context("DROP_RETRY_BACKOFF_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "max_drop_attempts", "MAX_DROP_ATTEMPTS") {
+            config.max_drop_attempts = val.parse().context("MAX_DROP_ATTEMPTS must be a valid u32")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "drop_history_size", "DROP_HISTORY_SIZE") {
+            config.drop_history_size = val.parse().context("DROP_HISTORY_SIZE must be a valid usize")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "scale_history_size", "SCALE_HISTORY_SIZE") {
+            config.scale_history_size = val.parse().context("SCALE_HISTORY_SIZE must be a valid usize")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "validate_query_attempts", "VALIDATE_QUERY_ATTEMPTS") {
+            config.validate_query_attempts = val.parse().context("VALIDATE_QUERY_ATTEMPTS must be a valid u32")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "validate_query_backoff_secs", "VALIDATE_QUERY_BACKOFF_SECS") {
+            config.validate_query_backoff_secs = val.parse().context("VALIDATE_QUERY_BACKOFF_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "create_concurrency", "CREATE_CONCURRENCY") {
+            config.create_concurrency = val.parse().context("CREATE_CONCURRENCY must be a valid usize")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "instance_launch_stagger_secs", "INSTANCE_LAUNCH_STAGGER_SECS") {
+            config.instance_launch_stagger_secs = val.parse().context("INSTANCE_LAUNCH_STAGGER_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "allow_partial_startup", "ALLOW_PARTIAL_STARTUP") {
+            config.allow_partial_startup = val.parse().context("ALLOW_PARTIAL_STARTUP must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "backoff_reset_policy", "BACKOFF_RESET_POLICY") {
+            config.backoff_reset_policy = val.parse().context("BACKOFF_RESET_POLICY must be one of on_success, decay, never")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "backoff_reset_streak", "BACKOFF_RESET_STREAK") {
+            config.backoff_reset_streak = val.parse().context("BACKOFF_RESET_STREAK must be a valid u32")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "task_polling_interval_secs", "TASK_POLLING_INTERVAL_SECS") {
             config.task_polling_interval_secs = val.parse().context("TASK_POLLING_INTERVAL_SECS must be a valid u64")?;
         }
-        if let Ok(val) = env::var("CONTEMPLANT_VERIFICATION_TIMEOUT_SECS") {
+        if let Ok(val) = env_override(&mut sources, "contemplant_verification_timeout_secs", "CONTEMPLANT_VERIFICATION_TIMEOUT_SECS") {
             config.contemplant_verification_timeout_secs = val.parse().context("CONTEMPLANT_VERIFICATION_TIMEOUT_SECS must be a valid u64")?;
         }
-        if let Ok(val) = env::var("TEMPLATE_HASH") {
-            config.template_hash = val;
+        if let Ok(val) = env_override(&mut sources, "stuck_instance_timeout_secs", "STUCK_INSTANCE_TIMEOUT_SECS") {
+            config.stuck_instance_timeout_secs = val.parse().context("STUCK_INSTANCE_TIMEOUT_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "missing_instance_polls", "MISSING_INSTANCE_POLLS") {
+            config.missing_instance_polls = val.parse().context("MISSING_INSTANCE_POLLS must be a valid u32")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "max_instance_lifetime_secs", "MAX_INSTANCE_LIFETIME_SECS") {
+            config.max_instance_lifetime_secs = Some(val.parse().context("MAX_INSTANCE_LIFETIME_SECS must be a valid u64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "health_check_interval_secs", "HEALTH_CHECK_INTERVAL_SECS") {
+            config.health_check_interval_secs = Some(val.parse().context("HEALTH_CHECK_INTERVAL_SECS must be a valid u64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "health_check_failures", "HEALTH_CHECK_FAILURES") {
+            config.health_check_failures = val.parse().context("HEALTH_CHECK_FAILURES must be a valid u32")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "contemplant_probe_pool_idle_secs", "CONTEMPLANT_PROBE_POOL_IDLE_SECS") {
+            config.contemplant_probe_pool_idle_secs = val.parse().context("CONTEMPLANT_PROBE_POOL_IDLE_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "contemplant_probe_pool_max_idle_per_host", "CONTEMPLANT_PROBE_POOL_MAX_IDLE_PER_HOST") {
+            config.contemplant_probe_pool_max_idle_per_host = val.parse().context("CONTEMPLANT_PROBE_POOL_MAX_IDLE_PER_HOST must be a valid usize")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "max_rotations_per_tick", "MAX_ROTATIONS_PER_TICK") {
+            config.max_rotations_per_tick = val.parse().context("MAX_ROTATIONS_PER_TICK must be a valid usize")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "machine_quarantine_secs", "MACHINE_QUARANTINE_SECS") {
+            config.machine_quarantine_secs = val.parse().context("MACHINE_QUARANTINE_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "shutdown_timeout_secs", "SHUTDOWN_TIMEOUT_SECS") {
+            config.shutdown_timeout_secs = val.parse().context("SHUTDOWN_TIMEOUT_SECS must be a valid u64")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "persist_state", "PERSIST_STATE") {
+            config.persist_state = val.parse().context("PERSIST_STATE must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "dry_run", "DRY_RUN") {
+            config.dry_run = val.parse().context("DRY_RUN must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "state_file", "STATE_FILE") {
+            config.state_file = val;
+        }
+        if let Ok(val) = env_override(&mut sources, "template_hash", "TEMPLATE_HASH") {
+            config.template_hash = parse_template_hashes(&val).context("TEMPLATE_HASH must be a hash or comma-separated hash:weight pairs")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "instance_label", "INSTANCE_LABEL") {
+            config.instance_label = val;
         }
-        if let Ok(val) = env::var("NUMBER_INSTANCES") {
+        if let Ok(val) = env_override(&mut sources, "onstart_template", "ONSTART_TEMPLATE") {
+            config.onstart_template = val;
+        }
+        if let Ok(val) = env_override(&mut sources, "extra_env", "EXTRA_ENV") {
+            let extra_env: Option<HashMap<String, String>> = val
+                .split(',')
+                .filter(|pair| !pair.trim().is_empty())
+                .map(|pair| {
+                    pair.split_once('=')
+                        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                })
+                .collect();
+            config.extra_env = Some(extra_env.context("EXTRA_ENV must be comma-separated NAME=value pairs")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "number_instances", "NUMBER_INSTANCES") {
             config.number_instances = val.parse().context("NUMBER_INSTANCES must be a valid usize")?;
         }
 
+        if let Ok(val) = env_override(&mut sources, "provisioning_strategy", "OFFER_SELECTION") {
+            config.provisioning_strategy = val.parse().context("OFFER_SELECTION must be one of score, cheapest, fastest, best_value")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "provisioning_strategy", "PROVISIONING_STRATEGY") {
+            config.provisioning_strategy = val.parse().context("PROVISIONING_STRATEGY must be one of score, cheapest, fastest, best_value")?;
+        }
+
         // VastQueryConfig overrides
-        if let Ok(val) = env::var("VAST_QUERY_ALLOCATED_STORAGE") {
+        if let Ok(val) = env_override(&mut sources, "vast_query.allocated_storage", "VAST_QUERY_ALLOCATED_STORAGE") {
             config.vast_query.allocated_storage = val.parse().context("VAST_QUERY_ALLOCATED_STORAGE must be a valid u16")?;
         }
-        if let Ok(val) = env::var("VAST_QUERY_GPU_NAME") {
-            config.vast_query.gpu_name = val;
+        if let Ok(val) = env_override(&mut sources, "vast_query.gpu_name", "VAST_QUERY_GPU_NAME") {
+            config.vast_query.gpu_name = val.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect();
         }
-        if let Ok(val) = env::var("VAST_QUERY_RELIABILITY") {
+        if let Ok(val) = env_override(&mut sources, "vast_query.reliability", "VAST_QUERY_RELIABILITY") {
             config.vast_query.reliability = val.parse().context("VAST_QUERY_RELIABILITY must be a valid f64")?;
         }
-        if let Ok(val) = env::var("VAST_QUERY_MIN_CUDA_VERSION") {
+        if let Ok(val) = env_override(&mut sources, "vast_query.min_cuda_version", "VAST_QUERY_MIN_CUDA_VERSION") {
             config.vast_query.min_cuda_version = val.parse().context("VAST_QUERY_MIN_CUDA_VERSION must be a valid f64")?;
         }
-        if let Ok(val) = env::var("VAST_QUERY_GPU_RAM") {
+        if let Ok(val) = env_override(&mut sources, "vast_query.gpu_ram", "VAST_QUERY_GPU_RAM") {
             config.vast_query.gpu_ram = val.parse().context("VAST_QUERY_GPU_RAM must be a valid u64")?;
         }
-        if let Ok(val) = env::var("VAST_QUERY_DISK_SPACE") {
+        if let Ok(val) = env_override(&mut sources, "vast_query.disk_space", "VAST_QUERY_DISK_SPACE") {
             config.vast_query.disk_space = val.parse().context("VAST_QUERY_DISK_SPACE must be a valid u64")?;
         }
-        if let Ok(val) = env::var("VAST_QUERY_DURATION") {
+        if let Ok(val) = env_override(&mut sources, "vast_query.provision_disk_gb", "VAST_QUERY_PROVISION_DISK_GB") {
+            config.vast_query.provision_disk_gb = Some(val.parse().context("VAST_QUERY_PROVISION_DISK_GB must be a valid u64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.duration", "VAST_QUERY_DURATION") {
             config.vast_query.duration = val.parse().context("VAST_QUERY_DURATION must be a valid f64")?;
         }
-        if let Ok(val) = env::var("VAST_QUERY_COST_PER_HOUR") {
+        if let Ok(val) = env_override(&mut sources, "vast_query.cost_per_hour", "VAST_QUERY_COST_PER_HOUR") {
             config.vast_query.cost_per_hour = val.parse().context("VAST_QUERY_COST_PER_HOUR must be a valid f64")?;
         }
 
+        if let Ok(val) = env_override(&mut sources, "vast_query.soft_cost_per_hour", "VAST_QUERY_SOFT_COST_PER_HOUR") {
+            config.vast_query.soft_cost_per_hour = Some(val.parse().context("VAST_QUERY_SOFT_COST_PER_HOUR must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.min_num_gpus", "VAST_QUERY_MIN_NUM_GPUS") {
+            config.vast_query.min_num_gpus = Some(val.parse().context("VAST_QUERY_MIN_NUM_GPUS must be a valid u32")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.max_num_gpus", "VAST_QUERY_MAX_NUM_GPUS") {
+            config.vast_query.max_num_gpus = Some(val.parse().context("VAST_QUERY_MAX_NUM_GPUS must be a valid u32")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.use_bid", "VAST_QUERY_USE_BID") {
+            config.vast_query.use_bid = val.parse().context("VAST_QUERY_USE_BID must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.bid_price", "VAST_QUERY_BID_PRICE") {
+            config.vast_query.bid_price = Some(val.parse().context("VAST_QUERY_BID_PRICE must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.min_inet_up", "VAST_QUERY_MIN_INET_UP") {
+            config.vast_query.min_inet_up = Some(val.parse().context("VAST_QUERY_MIN_INET_UP must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.min_inet_down", "VAST_QUERY_MIN_INET_DOWN") {
+            config.vast_query.min_inet_down = Some(val.parse().context("VAST_QUERY_MIN_INET_DOWN must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.min_disk_bw", "VAST_QUERY_MIN_DISK_BW") {
+            config.vast_query.min_disk_bw = Some(val.parse().context("VAST_QUERY_MIN_DISK_BW must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.require_nvme", "VAST_QUERY_REQUIRE_NVME") {
+            config.vast_query.require_nvme = val.parse().context("VAST_QUERY_REQUIRE_NVME must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.min_reliability", "VAST_QUERY_MIN_RELIABILITY") {
+            config.vast_query.min_reliability = Some(val.parse().context("VAST_QUERY_MIN_RELIABILITY must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.min_dlperf", "VAST_QUERY_MIN_DLPERF") {
+            config.vast_query.min_dlperf = Some(val.parse().context("VAST_QUERY_MIN_DLPERF must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "vast_query.max_dph_per_dlperf", "VAST_QUERY_MAX_DPH_PER_DLPERF") {
+            config.vast_query.max_dph_per_dlperf = Some(val.parse().context("VAST_QUERY_MAX_DPH_PER_DLPERF must be a valid f64")?);
+        }
+
         // Optional list overrides
-        if let Ok(val) = env::var("BAD_HOSTS") {
+        if let Ok(val) = env_override(&mut sources, "bad_hosts", "BAD_HOSTS") {
             let hosts: Result<Vec<u64>, _> = val.split(',').map(|s| s.trim().parse()).collect();
             config.bad_hosts = Some(hosts.context("BAD_HOSTS must be comma-separated u64 values")?);
         }
-        if let Ok(val) = env::var("BAD_MACHINES") {
+        if let Ok(val) = env_override(&mut sources, "bad_machines", "BAD_MACHINES") {
             let machines: Result<Vec<u64>, _> = val.split(',').map(|s| s.trim().parse()).collect();
             config.bad_machines = Some(machines.context("BAD_MACHINES must be comma-separated u64 values")?);
         }
-        if let Ok(val) = env::var("GOOD_HOSTS") {
+        if let Ok(val) = env_override(&mut sources, "good_hosts", "GOOD_HOSTS") {
             let hosts: Result<Vec<u64>, _> = val.split(',').map(|s| s.trim().parse()).collect();
             config.good_hosts = Some(hosts.context("GOOD_HOSTS must be comma-separated u64 values")?);
         }
-        if let Ok(val) = env::var("GOOD_MACHINES") {
+        if let Ok(val) = env_override(&mut sources, "good_machines", "GOOD_MACHINES") {
             let machines: Result<Vec<u64>, _> = val.split(',').map(|s| s.trim().parse()).collect();
             config.good_machines = Some(machines.context("GOOD_MACHINES must be comma-separated u64 values")?);
         }
 
+        if let Ok(val) = env_override(&mut sources, "max_total_dph", "MAX_TOTAL_DPH") {
+            config.max_total_dph = Some(val.parse().context("MAX_TOTAL_DPH must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "hard_max_instances", "HARD_MAX_INSTANCES") {
+            config.hard_max_instances = Some(val.parse().context("HARD_MAX_INSTANCES must be a valid usize")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "min_distinct_hosts", "MIN_DISTINCT_HOSTS") {
+            config.min_distinct_hosts = Some(val.parse().context("MIN_DISTINCT_HOSTS must be a valid usize")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "one_instance_per_machine", "ONE_INSTANCE_PER_MACHINE") {
+            config.one_instance_per_machine = val.parse().context("ONE_INSTANCE_PER_MACHINE must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "one_instance_per_host", "ONE_INSTANCE_PER_HOST") {
+            config.one_instance_per_host = val.parse().context("ONE_INSTANCE_PER_HOST must be true or false")?;
+        }
+        if let Ok(val) = env_override(&mut sources, "max_instances_per_host", "MAX_INSTANCES_PER_HOST") {
+            config.max_instances_per_host = Some(val.parse().context("MAX_INSTANCES_PER_HOST must be a valid usize")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "max_instances_per_machine", "MAX_INSTANCES_PER_MACHINE") {
+            config.max_instances_per_machine = Some(val.parse().context("MAX_INSTANCES_PER_MACHINE must be a valid usize")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "max_instances_per_geolocation", "MAX_INSTANCES_PER_GEOLOCATION") {
+            config.max_instances_per_geolocation = Some(val.parse().context("MAX_INSTANCES_PER_GEOLOCATION must be a valid usize")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "allowed_geolocations", "ALLOWED_GEOLOCATIONS") {
+            config.allowed_geolocations = Some(val.split(',').map(|entry| entry.trim().to_string()).filter(|entry| !entry.is_empty()).collect());
+        }
+        if let Ok(val) = env_override(&mut sources, "denied_geolocations", "DENIED_GEOLOCATIONS") {
+            config.denied_geolocations = Some(val.split(',').map(|entry| entry.trim().to_string()).filter(|entry| !entry.is_empty()).collect());
+        }
+        if let Ok(val) = env_override(&mut sources, "runway_alert_hours", "RUNWAY_ALERT_HOURS") {
+            config.runway_alert_hours = Some(val.parse().context("RUNWAY_ALERT_HOURS must be a valid f64")?);
+        }
+        if let Ok(val) = env_override(&mut sources, "alert_webhook_url", "ALERT_WEBHOOK_URL") {
+            config.alert_webhook_url = Some(val);
+        }
+        if let Ok(val) = env_override(&mut sources, "under_capacity_alert_secs", "UNDER_CAPACITY_ALERT_SECS") {
+            config.under_capacity_alert_secs = val.parse().context("UNDER_CAPACITY_ALERT_SECS must be a valid u64")?;
+        }
+
+        // Resolve the contemplant profile before the individual CONTEMPLANT_* overrides so those
+        // still take priority over profile values
+        if let Ok(val) = env_override(&mut sources, "contemplant_profile", "CONTEMPLANT_PROFILE") {
+            config.contemplant_profile = Some(val);
+        }
+        if let Some(ref profile) = config.contemplant_profile {
+            config.contemplant.apply_profile(profile)?;
+        }
+
         // ContemplantConfig overrides
-        if let Ok(val) = env::var("CONTEMPLANT_PROVER_TYPE") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.prover_type", "CONTEMPLANT_PROVER_TYPE") {
             config.contemplant.prover_type = val;
         }
-        if let Ok(val) = env::var("CONTEMPLANT_NAME") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.contemplant_name", "CONTEMPLANT_NAME") {
             config.contemplant.contemplant_name = Some(val);
         }
-        if let Ok(val) = env::var("CONTEMPLANT_HTTP_PORT") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.http_port", "CONTEMPLANT_HTTP_PORT") {
             config.contemplant.http_port = val.parse().context("CONTEMPLANT_HTTP_PORT must be a valid u16")?;
         }
-        if let Ok(val) = env::var("CONTEMPLANT_MOONGATE_ENDPOINT") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.moongate_endpoint", "CONTEMPLANT_MOONGATE_ENDPOINT") {
             config.contemplant.moongate_endpoint = Some(val);
         }
-        if let Ok(val) = env::var("CONTEMPLANT_HEARTBEAT_INTERVAL_SECONDS") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.heartbeat_interval_seconds", "CONTEMPLANT_HEARTBEAT_INTERVAL_SECONDS") {
             config.contemplant.heartbeat_interval_seconds = val.parse().context("CONTEMPLANT_HEARTBEAT_INTERVAL_SECONDS must be a valid u64")?;
         }
-        if let Ok(val) = env::var("CONTEMPLANT_MAX_PROOFS_STORED") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.max_proofs_stored", "CONTEMPLANT_MAX_PROOFS_STORED") {
             config.contemplant.max_proofs_stored = val.parse().context("CONTEMPLANT_MAX_PROOFS_STORED must be a valid usize")?;
         }
-        if let Ok(val) = env::var("CONTEMPLANT_MOONGATE_LOG_PATH") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.moongate_log_path", "CONTEMPLANT_MOONGATE_LOG_PATH") {
             config.contemplant.moongate_log_path = val;
         }
-        if let Ok(val) = env::var("CONTEMPLANT_WATCHER_POLLING_INTERVAL_MS") {
+        if let Ok(val) = env_override(&mut sources, "contemplant.watcher_polling_interval_ms", "CONTEMPLANT_WATCHER_POLLING_INTERVAL_MS") {
             config.contemplant.watcher_polling_interval_ms = val.parse().context("CONTEMPLANT_WATCHER_POLLING_INTERVAL_MS must be a valid u64")?;
         }
-        if let Ok(val) = env::var("CONTEMPLANT_SSH_AUTHORIZED_KEYS") {
-            config.contemplant.ssh_authorized_keys = Some(val);
+        if let Ok(val) = env_override(&mut sources, "contemplant.ssh_authorized_keys", "CONTEMPLANT_SSH_AUTHORIZED_KEYS") {
+            // a literal \n separates keys in an environment variable
+            config.contemplant.ssh_authorized_keys = Some(val.replace("\\n", "\n"));
+        }
+        config.contemplant.normalize_ssh_authorized_keys();
+        config.contemplant.validate_prover_type()?;
+
+        // a secret read from a file comes from wherever the path to that file came from
+        if let Some(ref path) = config.vast_api_key_file {
+            config.vast_api_key = read_secret_file(path).context("Read vast_api_key_file")?;
+            let source = sources.get("vast_api_key_file").copied().unwrap_or(ConfigSource::File);
+            sources.insert("vast_api_key".to_string(), source);
+        }
+        if let Some(ref path) = config.api_token_file {
+            config.api_token = Some(read_secret_file(path).context("Read api_token_file")?);
+            let source = sources.get("api_token_file").copied().unwrap_or(ConfigSource::File);
+            sources.insert("api_token".to_string(), source);
         }
 
         // Validate required fields
+        if let Some(ref http_bind_addr) = config.http_bind_addr
+            && http_bind_addr.parse::<IpAddr>().is_err()
+        {
+            anyhow::bail!(
+                "http_bind_addr \"{http_bind_addr}\" isn't a valid IP address, e.g. 127.0.0.1 or ::1."
+            );
+        }
         if config.this_magister_addr.is_empty() {
             anyhow::bail!(
                 "this_magister_addr is required. Provide it via config file or THIS_MAGISTER_ADDR environment variable."
             );
         }
+        validate_this_magister_addr(&config.this_magister_addr)?;
         if config.hierophant_ip.is_empty() {
             anyhow::bail!(
                 "hierophant_ip is required. Provide it via config file or HIEROPHANT_IP environment variable."
@@ -382,7 +1563,7 @@ impl Config {
         }
         if config.vast_api_key.is_empty() {
             anyhow::bail!(
-                "vast_api_key is required. Provide it via config file or VAST_API_KEY environment variable."
+                "vast_api_key is required. Provide it via config file, VAST_API_KEY environment variable, or vast_api_key_file."
             );
         }
         if config.template_hash.is_empty() {
@@ -390,12 +1571,1074 @@ impl Config {
                 "template_hash is required. Provide it via config file or TEMPLATE_HASH environment variable."
             );
         }
+        if config.template_hash.iter().any(|template| template.hash.is_empty() || template.weight == 0) {
+            anyhow::bail!("Every template_hash entry needs a non-empty hash and a weight above 0.");
+        }
+        config.vast_query.validate().context("Invalid vast_query")?;
+        for (i, fallback) in config.vast_query_fallbacks.iter().enumerate() {
+            fallback
+                .validate()
+                .with_context(|| format!("Invalid vast_query_fallbacks[{i}]"))?;
+        }
+        // without the drop endpoint the Contemplant can't tell us to drop its instance
+        if !config.onstart_template.contains("{magister_drop_endpoint}")
+            && !config.onstart_template.contains("{env_exports}")
+        {
+            anyhow::bail!(
+                "onstart_template must contain {{magister_drop_endpoint}} or {{env_exports}} so the Contemplant can ask to be dropped."
+            );
+        }
+        if !placeholders_single_quoted(&config.onstart_template) {
+            anyhow::bail!(
+                "onstart_template must have its placeholders inside a single-quoted argument, e.g. su contemplant -c '{{env_exports}}; ...', which their values are escaped for."
+            );
+        }
+        // the token is embedded in the Contemplant's drop URL and onstart command
+        if let Some(ref api_token) = config.api_token
+            && (api_token.is_empty() || !api_token.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c)))
+        {
+            anyhow::bail!(
+                "api_token must be non-empty and only contain letters, digits, '-', '.', '_', or '~'."
+            );
+        }
         if config.number_instances == 0 {
             anyhow::bail!(
                 "number_instances is required. Provide it via config file or NUMBER_INSTANCES environment variable."
             );
         }
+        config.number_instances = config.clamp_to_hard_max(config.number_instances);
+
+        config.sources = config.resolve_sources(&file_table, sources);
 
         Ok(config)
     }
+
+    /// `count`, or `hard_max_instances` with a warning if `count` is over it.
+    pub fn clamp_to_hard_max(&self, count: usize) -> usize {
+        match self.hard_max_instances {
+            Some(hard_max_instances) if count > hard_max_instances => {
+                warn!("{count} instances is over hard_max_instances.  Using {hard_max_instances} instead.");
+                hard_max_instances
+            }
+            _ => count,
+        }
+    }
+
+    /// The onstart command for a new instance: `onstart_template` with `{env_exports}` replaced
+    /// by an escaped export of every variable the Contemplant needs, and
+    /// `{magister_drop_endpoint}` and `{hierophant_ws_address}` by their escaped values.
+    pub fn onstart(&self, drop_endpoint: &str, hierophant_ws_address: &str) -> String {
+        let env_exports = [
+            env_export("MAGISTER_DROP_ENDPOINT", drop_endpoint),
+            env_export("HIEROPHANT_WS_ADDRESS", hierophant_ws_address),
+            self.contemplant.to_env_exports(),
+        ]
+        .join("; ");
+        substitute(
+            &self.onstart_template,
+            &[
+                ("{env_exports}", env_exports),
+                ("{magister_drop_endpoint}", shell_escape(drop_endpoint)),
+                ("{hierophant_ws_address}", shell_escape(hierophant_ws_address)),
+            ],
+        )
+    }
+
+    /// The address the HTTP server listens on.  Checked when the config was loaded.
+    pub fn http_bind_addr(&self) -> IpAddr {
+        self.http_bind_addr
+            .as_deref()
+            .and_then(|addr| addr.parse().ok())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    /// The identity this Magister tags its logs with.
+    pub fn magister_id(&self) -> String {
+        match self.magister_id {
+            Some(ref magister_id) => magister_id.clone(),
+            None => self
+                .this_magister_addr
+                .strip_suffix('/')
+                .unwrap_or(&self.this_magister_addr)
+                .to_string(),
+        }
+    }
+
+    /// Work out where each effective value came from, starting from the `assigned` sources
+    /// recorded while loading.  Keys are dotted field paths such as `vast_query.gpu_name`.
+    fn resolve_sources(
+        &self,
+        file_table: &toml::Table,
+        assigned: HashMap<String, ConfigSource>,
+    ) -> HashMap<String, ConfigSource> {
+        let profile = self
+            .contemplant_profile
+            .as_ref()
+            .and_then(|name| self.contemplant.profiles.get(name))
+            .and_then(|profile| serde_json::to_value(profile).ok());
+
+        let mut sources = HashMap::new();
+        for path in flatten_fields(&serde_json::to_value(self).unwrap_or_default()).keys() {
+            let in_file = path
+                .split('.')
+                .try_fold(file_table, |table, key| match table.get(key) {
+                    Some(toml::Value::Table(inner)) => Some(inner),
+                    Some(_) => Some(table),
+                    None => None,
+                })
+                .is_some();
+            let in_profile = path.strip_prefix("contemplant.").is_some_and(|field| {
+                profile
+                    .as_ref()
+                    .and_then(|profile| profile.get(field))
+                    .is_some_and(|value| !value.is_null())
+            });
+
+            // a whole table such as extra_env is assigned at once
+            let assigned_source = assigned.get(path).or_else(|| {
+                path.split_once('.')
+                    .and_then(|(table, _)| assigned.get(table))
+            });
+
+            let source = if let Some(&source) = assigned_source {
+                source
+            } else if in_profile {
+                ConfigSource::Profile
+            } else if in_file {
+                ConfigSource::File
+            } else {
+                ConfigSource::Default
+            };
+            sources.insert(path.clone(), source);
+        }
+
+        sources
+    }
+
+    /// The fully resolved configuration as `{ path: { value, source } }` with secrets redacted.
+    pub fn effective(&self) -> serde_json::Value {
+        let fields = flatten_fields(&self.redacted())
+            .into_iter()
+            .map(|(path, value)| {
+                let source = self
+                    .sources
+                    .get(&path)
+                    .copied()
+                    .unwrap_or(ConfigSource::Default);
+                (path, json!({ "value": value, "source": source }))
+            })
+            .collect();
+
+        serde_json::Value::Object(fields)
+    }
+
+    // vast_query followed by vast_query_fallbacks
+    pub fn vast_queries(&self) -> impl Iterator<Item = &VastQueryConfig> {
+        std::iter::once(&self.vast_query).chain(&self.vast_query_fallbacks)
+    }
+
+    /// This configuration as JSON with the Vast API key, API token, and extra_env values masked and
+    /// SSH keys removed.
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if !self.vast_api_key.is_empty() {
+            value["vast_api_key"] = json!(REDACTED);
+        }
+        if self.api_token.is_some() {
+            value["api_token"] = json!(REDACTED);
+        }
+        // proxy URLs may carry credentials
+        if self.https_proxy.is_some() {
+            value["https_proxy"] = json!(REDACTED);
+        }
+        // webhook URLs carry their own credentials
+        if self.alert_webhook_url.is_some() {
+            value["alert_webhook_url"] = json!(REDACTED);
+        }
+        // environment variables for the Contemplant often hold tokens, so only the names are shown
+        mask_extra_env(&mut value);
+        if let Some(contemplant) = value["contemplant"].as_object_mut() {
+            contemplant.remove("ssh_authorized_keys");
+            if let Some(profiles) = contemplant
+                .get_mut("profiles")
+                .and_then(|profiles| profiles.as_object_mut())
+            {
+                for profile in profiles.values_mut() {
+                    mask_extra_env(profile);
+                    if let Some(profile) = profile.as_object_mut() {
+                        profile.remove("ssh_authorized_keys");
+                    }
+                }
+            }
+        }
+        value
+    }
+
+    /// `text` with `api_token` masked, for logging things the token is embedded in, such as the
+    /// Contemplant's drop URL.
+    pub fn redact_api_token(&self, text: &str) -> String {
+        match self.api_token {
+            Some(ref api_token) if !api_token.is_empty() => text.replace(api_token.as_str(), REDACTED),
+            _ => text.to_string(),
+        }
+    }
+}
+
+const REDACTED: &str = "***";
+
+// replaces every value of value's extra_env, if it has one, with REDACTED
+fn mask_extra_env(value: &mut serde_json::Value) {
+    if let Some(extra_env) = value
+        .get_mut("extra_env")
+        .and_then(|extra_env| extra_env.as_object_mut())
+    {
+        for env_value in extra_env.values_mut() {
+            *env_value = json!(REDACTED);
+        }
+    }
+}
+
+/// Where an effective config value was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigSource {
+    Env,
+    Profile,
+    File,
+    Default,
+}
+
+// The environment variable name, if set, recording in sources that the field at path came from it
+fn env_override(
+    sources: &mut HashMap<String, ConfigSource>,
+    path: &str,
+    name: &str,
+) -> Result<String, env::VarError> {
+    let val = env::var(name)?;
+    sources.insert(path.to_string(), ConfigSource::Env);
+    Ok(val)
+}
+
+/// Flatten the top level tables of a serialized config into dotted paths.  Deeper maps (such as
+/// contemplant profiles) are kept as single values.
+fn flatten_fields(value: &serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields = serde_json::Map::new();
+    if let Some(object) = value.as_object() {
+        for (key, value) in object {
+            match value.as_object() {
+                Some(table) => {
+                    for (field, value) in table {
+                        fields.insert(format!("{key}.{field}"), value.clone());
+                    }
+                }
+                None => {
+                    fields.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+    fields
+}
+
+/// JSON Schema describing every field accepted in `magister.toml`, including the defaults used
+/// when a field is omitted.
+pub fn config_schema() -> serde_json::Value {
+    let contemplant = ContemplantConfig::default();
+
+    let contemplant_properties = schema_properties([
+        ("prover_type", schema_field("string", Some(json!(contemplant.prover_type)), "Prover type: \"cpu\" or \"cuda\"")),
+        ("contemplant_name", schema_field("string", None, "Human-readable name for Contemplants (generated from names.txt when unset)")),
+        ("http_port", schema_field("integer", Some(json!(contemplant.http_port)), "Port for the Contemplant HTTP health check server")),
+        ("moongate_endpoint", schema_field("string", None, "Moongate CUDA prover endpoint, only used and required when prover_type is \"cuda\"")),
+        ("heartbeat_interval_seconds", schema_field("integer", Some(json!(contemplant.heartbeat_interval_seconds)), "How often Contemplants tell Hierophant they are still alive")),
+        ("max_proofs_stored", schema_field("integer", Some(json!(contemplant.max_proofs_stored)), "Maximum number of finished proofs stored in memory")),
+        ("moongate_log_path", schema_field("string", Some(json!(contemplant.moongate_log_path)), "Path to the log file used for progress tracking")),
+        ("watcher_polling_interval_ms", schema_field("integer", Some(json!(contemplant.watcher_polling_interval_ms)), "How frequently to check the moongate log file for progress updates")),
+        ("ssh_authorized_keys", schema_field("string", None, "Newline-separated SSH public keys for debugging access")),
+    ]);
+
+    let mut profile_properties = contemplant_properties.clone();
+    for property in profile_properties.values_mut() {
+        if let Some(property) = property.as_object_mut() {
+            property.remove("default");
+        }
+    }
+
+    let mut contemplant_properties = contemplant_properties;
+    contemplant_properties.insert(
+        "profiles".to_string(),
+        json!({
+            "type": "object",
+            "description": "Named sets of overrides for the fields above, selected with contemplant_profile",
+            "additionalProperties": schema_object(profile_properties, &[]),
+        }),
+    );
+
+    let vast_query_properties = schema_properties([
+        ("allocated_storage", schema_field("integer", None, "Allocated storage in GB for the instance")),
+        ("gpu_name", json!({ "type": ["string", "array"], "items": { "type": "string" }, "description": "GPU model name, or list of names, to search for.  Required unless min_dlperf or max_dph_per_dlperf is set" })),
+        ("reliability", schema_field("number", None, "Minimum host reliability score (0.0 to 1.0)")),
+        ("min_cuda_version", schema_field("number", None, "Minimum CUDA version required")),
+        ("gpu_ram", schema_field("integer", None, "Minimum GPU RAM in GB")),
+        ("disk_space", schema_field("integer", None, "Minimum disk space in GB")),
+        ("provision_disk_gb", schema_field("integer", None, "GB of disk to rent on each instance, defaulting to disk_space")),
+        ("duration", schema_field("number", None, "Minimum rental duration in hours")),
+        ("cost_per_hour", schema_field("number", None, "Maximum cost per hour in USD, never exceeded")),
+        ("soft_cost_per_hour", schema_field("number", None, "Preferred maximum cost per hour in USD, exceeded with a warning only when nothing cheaper is available")),
+        ("min_num_gpus", schema_field("integer", None, "Minimum number of GPUs per machine")),
+        ("max_num_gpus", schema_field("integer", None, "Maximum number of GPUs per machine")),
+        ("use_bid", schema_field("boolean", Some(json!(false)), "Rent interruptible bid instances instead of on-demand ones")),
+        ("bid_price", schema_field("number", None, "USD per hour to bid when use_bid is set, defaulting to cost_per_hour")),
+        ("min_inet_up", schema_field("number", None, "Minimum upload bandwidth in Mbps")),
+        ("min_inet_down", schema_field("number", None, "Minimum download bandwidth in Mbps")),
+        ("min_disk_bw", schema_field("number", None, "Minimum disk bandwidth in MB/s")),
+        ("require_nvme", schema_field("boolean", Some(json!(false)), "Only accept offers whose disk name looks like an NVMe drive or SSD")),
+        ("min_reliability", schema_field("number", None, "Minimum offer reliability score (0.0 to 1.0), checked after the search in addition to reliability's reliability2 floor")),
+        ("min_dlperf", schema_field("number", None, "Accept any GPU with at least this dlperf, instead of searching by gpu_name")),
+        ("max_dph_per_dlperf", schema_field("number", None, "Accept any GPU costing at most this many USD per hour per unit of dlperf, instead of searching by gpu_name")),
+    ]);
+
+    let mut vast_query = schema_object(
+        vast_query_properties,
+        &[
+            "allocated_storage",
+            "reliability",
+            "min_cuda_version",
+            "gpu_ram",
+            "disk_space",
+            "duration",
+            "cost_per_hour",
+        ],
+    );
+    let vast_query_fallbacks = json!({
+        "type": "array",
+        "items": vast_query,
+        "default": [],
+        "description": "Queries tried in order when vast_query doesn't find enough offers",
+    });
+    vast_query["description"] = json!("Converted into the Vast.ai offer search query");
+
+    let mut contemplant = schema_object(contemplant_properties, &[]);
+    contemplant["description"] =
+        json!("Settings passed as environment variables to Contemplants spawned by this Magister");
+
+    let properties = schema_properties([
+        ("http_port", schema_field("integer", Some(json!(default_http_port())), "HTTP server port")),
+        ("http_bind_addr", schema_field("string", Some(json!("0.0.0.0")), "IP address of the interface the HTTP server listens on")),
+        ("this_magister_addr", schema_field("string", None, "Publicly accessible address where the Hierophant can reach this Magister, without port or trailing slash")),
+        ("magister_id", schema_field("string", None, "Identifies this Magister in its log lines (defaults to this_magister_addr)")),
+        ("log_format", schema_enum(&["text", "json"], json!(LogFormat::default()), "Human readable log lines, or one JSON object per line for log collectors")),
+        ("hierophant_ip", schema_field("string", None, "IP address or hostname where Contemplants can reach Hierophant")),
+        ("hierophant_http_port", schema_field("integer", None, "HTTP port where Hierophant is listening")),
+        ("vast_api_key", schema_field("string", None, "Vast.ai API key for managing instances.  Required unless vast_api_key_file is set")),
+        ("vast_api_key_file", schema_field("string", None, "File holding the Vast.ai API key, taking precedence over vast_api_key")),
+        ("api_token", schema_field("string", None, "Bearer token required on state-changing endpoints.  Letters, digits, '-', '.', '_', and '~' only")),
+        ("api_token_file", schema_field("string", None, "File holding api_token, taking precedence over api_token")),
+        ("api_token_covers_reads", schema_field("boolean", Some(json!(false)), "Also require api_token on read-only endpoints")),
+        ("vast_api_call_backoff_secs", schema_field("integer", Some(json!(vast_api_call_backoff_secs())), "Seconds to wait between Vast.ai API calls when rate limited")),
+        ("vast_api_min_interval_ms", schema_field("integer", Some(json!(default_vast_api_min_interval_ms())), "Minimum milliseconds between the start of any two Vast.ai API calls")),
+        ("vast_circuit_failures", schema_field("integer", Some(json!(default_vast_circuit_failures())), "Vast.ai API calls in a row that must fail to connect or get a 5xx before Vast is treated as down.  0 disables it")),
+        ("vast_circuit_cooldown_secs", schema_field("integer", Some(json!(default_vast_circuit_cooldown_secs())), "Seconds to stop calling Vast.ai once it's treated as down, doubling each time it's still down")),
+        ("vast_connect_timeout_secs", schema_field("integer", Some(json!(default_vast_connect_timeout_secs())), "Seconds to wait to connect to the Vast.ai API before giving up on a call")),
+        ("vast_request_timeout_secs", schema_field("integer", Some(json!(default_vast_request_timeout_secs())), "Seconds a Vast.ai API call may take in total before giving up on it")),
+        ("https_proxy", schema_field("string", None, "HTTP, HTTPS, or SOCKS5 proxy URL Vast.ai API calls are sent through")),
+        ("offer_cache_ttl_secs", schema_field("integer", Some(json!(default_offer_cache_ttl_secs())), "Seconds offer search results are reused before searching Vast again.  0 disables the cache")),
+        ("drop_retry_attempts", schema_field("integer", Some(json!(default_drop_retry_attempts())), "Times a failed destroy request is tried before waiting for the next polling cycle")),
+        ("drop_retry_backoff_secs", schema_field("integer", Some(json!(default_drop_retry_backoff_secs())), "Seconds between destroy attempts, growing by this much after each failure")),
+        ("max_drop_attempts", schema_field("integer", Some(json!(default_max_drop_attempts())), "Polling cycles in which destroying an instance may fail before it's forgotten and replaced, and its machine quarantined")),
+        ("drop_history_size", schema_field("integer", Some(json!(default_drop_history_size())), "How many of the most recent drops /drops remembers.  0 disables the history")),
+        ("scale_history_size", schema_field("integer", Some(json!(default_scale_history_size())), "How many of the most recent scaling decisions /scale/history remembers.  0 disables the history")),
+        ("validate_query_attempts", schema_field("integer", Some(json!(default_validate_query_attempts())), "Times startup query validation is tried when Vast can't be reached")),
+        ("validate_query_backoff_secs", schema_field("integer", Some(json!(default_validate_query_backoff_secs())), "Seconds between query validation attempts, growing by this much after each failure")),
+        ("create_concurrency", schema_field("integer", Some(json!(default_create_concurrency())), "Create requests that may be in flight at once while creating the initial instances")),
+        ("instance_launch_stagger_secs", schema_field("integer", Some(json!(0)), "Seconds between successful instance launches, plus up to a fifth more of random jitter.  0 disables it")),
+        ("allow_partial_startup", schema_field("boolean", Some(json!(default_allow_partial_startup())), "Start with fewer than number_instances when Vast can't supply them all, and keep requesting the rest, instead of exiting")),
+        ("backoff_reset_policy", schema_enum(&["on_success", "decay", "never"], json!(BackoffResetPolicy::default()), "How rate limit backoff during initial instance creation shrinks once requests stop being rate limited")),
+        ("backoff_reset_streak", schema_field("integer", Some(json!(default_backoff_reset_streak())), "Requests in a row that must avoid the rate limit before the \"never\" policy resets backoff")),
+        ("task_polling_interval_secs", schema_field("integer", Some(json!(default_task_polling_interval_secs())), "Seconds between instance polling checks")),
+        ("contemplant_verification_timeout_secs", schema_field("integer", Some(json!(default_contemplant_verification_timeout_secs())), "Seconds to wait for a new Contemplant to call /verify before dropping it")),
+        ("stuck_instance_timeout_secs", schema_field("integer", Some(json!(default_stuck_instance_timeout_secs())), "Seconds Vast may report an instance as anything other than running before it's dropped")),
+        ("missing_instance_polls", schema_field("integer", Some(json!(default_missing_instance_polls())), "Polling cycles in a row an instance must be missing from Vast's instance list before it's treated as removed outside this Magister")),
+        ("max_instance_lifetime_secs", schema_field("integer", None, "Seconds after which an instance is dropped and replaced")),
+        ("health_check_interval_secs", schema_field("integer", None, "Seconds between health checks of verified Contemplants.  No health checks when unset")),
+        ("health_check_failures", schema_field("integer", Some(json!(default_health_check_failures())), "Health checks in a row a Contemplant must fail before it's dropped")),
+        ("contemplant_probe_pool_idle_secs", schema_field("integer", Some(json!(default_contemplant_probe_pool_idle_secs())), "Seconds an idle connection to a Contemplant is kept open for the next health check")),
+        ("contemplant_probe_pool_max_idle_per_host", schema_field("integer", Some(json!(default_contemplant_probe_pool_max_idle_per_host())), "Idle connections kept open to each Contemplant for health checks")),
+        ("max_rotations_per_tick", schema_field("integer", Some(json!(default_max_rotations_per_tick())), "Instances that may be rotated out for age each polling interval")),
+        ("machine_quarantine_secs", schema_field("integer", Some(json!(default_machine_quarantine_secs())), "Seconds a machine is skipped after repeated failed create requests, doubling each time it's quarantined again")),
+        ("shutdown_timeout_secs", schema_field("integer", Some(json!(default_shutdown_timeout_secs())), "Seconds graceful shutdown may take before the process exits anyway")),
+        ("dry_run", schema_field("boolean", Some(json!(false)), "Log instances that would be rented or destroyed instead of calling Vast")),
+        ("persist_state", schema_field("boolean", Some(json!(default_persist_state())), "Save tracked instances to state_file and adopt them again on restart")),
+        ("state_file", schema_field("string", Some(json!(default_state_file())), "Path of the JSON file instance state is persisted to")),
+        ("template_hash", json!({
+            "type": ["string", "array"],
+            "items": schema_object(schema_properties([
+                ("hash", schema_field("string", None, "Vast.ai template hash")),
+                ("weight", schema_field("integer", Some(json!(default_template_weight())), "Share of new instances relative to the other templates' weights")),
+            ]), &["hash"]),
+            "description": "Vast.ai template hash to create instances from, or a list of weighted templates to split them between",
+        })),
+        ("extra_env", json!({ "type": "object", "additionalProperties": { "type": "string" }, "description": "Environment variables sent as the create request's extra_env, for templates that don't set their own ENV" })),
+        ("onstart_template", schema_field("string", Some(json!(default_onstart_template())), "Command run when an instance starts.  Must contain {magister_drop_endpoint} or {env_exports}.  {env_exports} is substituted with exports of MAGISTER_DROP_ENDPOINT, HIEROPHANT_WS_ADDRESS, and the Contemplant's config, and {magister_drop_endpoint} and {hierophant_ws_address} with the bare values")),
+        ("instance_label", schema_field("string", Some(json!(default_instance_label())), "Label given to instances this Magister creates.  Only instances with this label are considered its own")),
+        ("number_instances", schema_field("integer", None, "Number of instances to maintain")),
+        ("provisioning_strategy", schema_enum(&["score", "cheapest", "fastest", "best_value"], json!(ProvisioningStrategy::default()), "Order acceptable offers are tried in: Vast score, lowest cost, highest dlperf, or highest dlperf per dollar")),
+        ("bad_hosts", schema_list("integer", "Vast.ai host ids to avoid")),
+        ("bad_machines", schema_list("integer", "Vast.ai machine ids to avoid")),
+        ("good_hosts", schema_list("integer", "Vast.ai host ids to prioritize")),
+        ("good_machines", schema_list("integer", "Vast.ai machine ids to prioritize")),
+        ("max_total_dph", schema_field("number", None, "Maximum combined USD per hour of every instance")),
+        ("hard_max_instances", schema_field("integer", None, "Instances that may ever run at once, however number_instances or the desired count are set")),
+        ("runway_alert_hours", schema_field("number", None, "Warn when the Vast account balance will run out in fewer than this many hours")),
+        ("alert_webhook_url", schema_field("string", None, "URL alerts are POSTed to as Slack-compatible JSON")),
+        ("under_capacity_alert_secs", schema_field("integer", Some(json!(default_under_capacity_alert_secs())), "Alert after running fewer instances than desired for this many seconds")),
+        ("min_distinct_hosts", schema_field("integer", None, "Minimum number of distinct hosts to spread instances across.  /readyz waits until verified instances span this many")),
+        ("one_instance_per_machine", schema_field("boolean", Some(json!(false)), "Never run more than one instance on the same machine")),
+        ("one_instance_per_host", schema_field("boolean", Some(json!(false)), "Never run more than one instance on the same host")),
+        ("max_instances_per_host", schema_field("integer", None, "Never run more than this many instances on the same host")),
+        ("max_instances_per_machine", schema_field("integer", None, "Never run more than this many instances on the same machine")),
+        ("max_instances_per_geolocation", schema_field("integer", None, "Never run more than this many instances in the same geolocation")),
+        ("allowed_geolocations", schema_list("string", "Only rent offers in these geolocations.  Two letter entries match the country code, longer ones any part of the geolocation, ignoring case")),
+        ("denied_geolocations", schema_list("string", "Never rent offers in these geolocations, matched like allowed_geolocations")),
+        ("contemplant_profile", schema_field("string", None, "Name of a [contemplant.profiles] table to apply over [contemplant]")),
+        ("vast_query", vast_query),
+        ("vast_query_fallbacks", vast_query_fallbacks),
+        ("contemplant", contemplant),
+    ]);
+
+    let mut schema = schema_object(
+        properties,
+        &[
+            "this_magister_addr",
+            "hierophant_ip",
+            "hierophant_http_port",
+            "vast_query",
+            "template_hash",
+            "number_instances",
+        ],
+    );
+    schema["$schema"] = json!("https://json-schema.org/draft/2020-12/schema");
+    schema["title"] = json!("Magister configuration");
+    schema
+}
+
+fn schema_properties<const N: usize>(
+    properties: [(&str, serde_json::Value); N],
+) -> serde_json::Map<String, serde_json::Value> {
+    properties
+        .into_iter()
+        .map(|(name, property)| (name.to_string(), property))
+        .collect()
+}
+
+fn schema_object(
+    properties: serde_json::Map<String, serde_json::Value>,
+    required: &[&str],
+) -> serde_json::Value {
+    json!({
+        "type": "object",
+        "required": required,
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+fn schema_field(
+    ty: &str,
+    default: Option<serde_json::Value>,
+    description: &str,
+) -> serde_json::Value {
+    let mut field = json!({ "type": ty, "description": description });
+    if let Some(default) = default {
+        field["default"] = default;
+    }
+    field
+}
+
+fn schema_enum(variants: &[&str], default: serde_json::Value, description: &str) -> serde_json::Value {
+    json!({ "type": "string", "enum": variants, "default": default, "description": description })
+}
+
+fn schema_list(item_ty: &str, description: &str) -> serde_json::Value {
+    json!({ "type": "array", "items": { "type": item_ty }, "description": description })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Config::load reads the environment, so tests that load or set variables take turns
+    pub(crate) static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    pub(crate) const MINIMAL_CONFIG: &str = r#"
+this_magister_addr = "http://magister.example.com"
+hierophant_ip = "10.0.0.1"
+hierophant_http_port = 9010
+vast_api_key = "vast-key"
+template_hash = "template"
+number_instances = 2
+
+[vast_query]
+allocated_storage = 16
+gpu_name = "RTX 4090"
+reliability = 0.9
+min_cuda_version = 12.0
+gpu_ram = 24
+disk_space = 100
+duration = 1.0
+cost_per_hour = 0.5
+"#;
+
+    // Loads contents as a config file.  The caller must hold ENV_LOCK.
+    pub(crate) fn load_str(contents: &str) -> Result<Config> {
+        static NEXT_FILE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "magister-test-{}-{}.toml",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    pub(crate) fn test_config() -> Config {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        load_str(MINIMAL_CONFIG).unwrap()
+    }
+
+    #[test]
+    fn query_string_escapes_gpu_names() {
+        let mut vast_query = test_config().vast_query;
+        let gpu_name = r#"RTX "4090" \ Ti"#;
+        vast_query.gpu_name = vec![gpu_name.to_string(), "H100\nSXM".to_string()];
+
+        let query: serde_json::Value = serde_json::from_str(&vast_query.to_query_string()).unwrap();
+        assert_eq!(query["gpu_name"]["in"], json!([gpu_name, "H100\nSXM"]));
+    }
+
+    // runs the onstart command built from template in sh and returns what it prints
+    fn run_onstart(config: &Config, drop_endpoint: &str, hierophant_ws_address: &str) -> String {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(config.onstart(drop_endpoint, hierophant_ws_address))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn env_exports_survive_the_shell_unchanged() {
+        let mut config = test_config();
+        config.onstart_template = r#"sh -c '{env_exports}; printf "%s|%s|%s|%s|%s" "$CONTEMPLANT_NAME" "$MOONGATE_LOG_PATH" "$MOONGATE_ENDPOINT" "$MAGISTER_DROP_ENDPOINT" "$HIEROPHANT_WS_ADDRESS"'"#.to_string();
+        let tricky = [
+            r#"a "quoted" name"#,
+            "$HOME and ${PATH} and `id` and $(id)",
+            "it's got 'single' quotes",
+            "two\nlines\\ and a backslash",
+            r#"http://x/drop/1?token="$(reboot)""#,
+        ]
+        .map(str::to_string);
+        config.contemplant.contemplant_name = Some(tricky[0].clone());
+        config.contemplant.moongate_log_path = tricky[1].clone();
+        config.contemplant.moongate_endpoint = Some(tricky[2].clone());
+
+        let printed = run_onstart(&config, &tricky[3], &tricky[4]);
+        assert_eq!(printed, tricky.join("|"));
+    }
+
+    #[test]
+    fn env_export_escapes_shell_metacharacters() {
+        assert_eq!(
+            env_export("NAME", r#"a"b$c`d\e"#),
+            r#"export NAME="a\"b\$c\`d\\e""#
+        );
+    }
+
+    #[test]
+    fn onstart_substitutes_env_exports() {
+        let mut config = test_config();
+        config.contemplant.contemplant_name = Some("prover-1".to_string());
+
+        let onstart = config.onstart("http://magister/drop/7", "ws://hierophant:9010/ws");
+        assert!(!onstart.contains("{env_exports}"));
+        assert!(onstart.starts_with("su contemplant -c '"));
+        assert!(onstart.ends_with("; /usr/local/bin/contemplant-entrypoint.sh'"));
+        for export in [
+            r#"export MAGISTER_DROP_ENDPOINT="http://magister/drop/7""#,
+            r#"export HIEROPHANT_WS_ADDRESS="ws://hierophant:9010/ws""#,
+            r#"export CONTEMPLANT_NAME="prover-1""#,
+        ] {
+            assert!(onstart.contains(export), "{export} missing from {onstart}");
+        }
+    }
+
+    #[test]
+    fn onstart_template_is_validated() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let with_template =
+            |template: &str| load_str(&format!("onstart_template = '''{template}'''\n{MINIMAL_CONFIG}"));
+
+        assert!(with_template("sh -c '{env_exports}; /opt/bootstrap.sh'").is_ok());
+        assert!(with_template(r#"su me -c 'cd "/opt/my dir"; {env_exports}; ./run'"#).is_ok());
+        // nothing tells the Contemplant where to ask to be dropped
+        assert!(with_template("sh -c '/opt/bootstrap.sh'").is_err());
+        // the exports' '\'' escapes only work inside single quotes
+        assert!(with_template("{env_exports}; /opt/bootstrap.sh").is_err());
+        assert!(with_template(r#"sh -c "{env_exports}; /opt/bootstrap.sh""#).is_err());
+        assert!(with_template(r"sh -c \'{env_exports}").is_err());
+        // the drop endpoint on its own is enough
+        assert!(with_template(r#"sh -c 'export DROP="{magister_drop_endpoint}"; ./run'"#).is_ok());
+        assert!(with_template(r#"export DROP="{magister_drop_endpoint}"; sh -c '{env_exports}'"#).is_err());
+        let error = with_template(r#"sh -c 'export WS="{hierophant_ws_address}"; ./run'"#).unwrap_err();
+        assert!(format!("{error:#}").contains("{magister_drop_endpoint} or {env_exports}"), "{error:#}");
+    }
+
+    #[test]
+    fn onstart_substitutes_the_drop_endpoint_and_hierophant_address() {
+        let mut config = test_config();
+        config.onstart_template = r#"sh -c 'printf "%s|%s" "{magister_drop_endpoint}" "{hierophant_ws_address}"'"#.to_string();
+
+        let onstart = config.onstart("http://magister/drop/7", "ws://hierophant:9010/ws");
+        assert_eq!(
+            onstart,
+            r#"sh -c 'printf "%s|%s" "http://magister/drop/7" "ws://hierophant:9010/ws"'"#
+        );
+
+        // escaped like the exports, and not substituted into again
+        let tricky_endpoint = r#"http://x/drop/1?token="$(reboot)" it's {env_exports}"#;
+        let tricky_address = "ws://h/`id`\n";
+        assert_eq!(
+            run_onstart(&config, tricky_endpoint, tricky_address),
+            format!("{tricky_endpoint}|{tricky_address}")
+        );
+    }
+
+    #[test]
+    fn api_token_is_redacted_from_the_logged_onstart() {
+        let mut config = test_config();
+        config.api_token = Some("s3cret-token".to_string());
+
+        let onstart = config.onstart("http://magister:8555/drop/7?token=s3cret-token", "ws://h/ws");
+        let logged = config.redact_api_token(&onstart);
+        assert!(onstart.contains("s3cret-token"));
+        assert!(!logged.contains("s3cret-token"));
+        assert!(logged.contains("/drop/7?token=***"));
+    }
+
+    #[test]
+    fn effective_reports_where_each_value_came_from() {
+        let config = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            // SAFETY: ENV_LOCK keeps other tests from reading or writing the environment meanwhile
+            unsafe { env::set_var("NUMBER_INSTANCES", "5") };
+            unsafe { env::set_var("OFFER_SELECTION", "cheapest") };
+            unsafe { env::set_var("EXTRA_ENV", "HF_HOME=/data") };
+            let config = load_str(MINIMAL_CONFIG);
+            unsafe { env::remove_var("NUMBER_INSTANCES") };
+            unsafe { env::remove_var("OFFER_SELECTION") };
+            unsafe { env::remove_var("EXTRA_ENV") };
+            config.unwrap()
+        };
+        let effective = config.effective();
+
+        assert_eq!(effective["number_instances"]["value"], 5);
+        assert_eq!(effective["number_instances"]["source"], "env");
+        assert_eq!(effective["hierophant_ip"]["value"], "10.0.0.1");
+        assert_eq!(effective["hierophant_ip"]["source"], "file");
+        assert_eq!(effective["vast_query.cost_per_hour"]["source"], "file");
+        assert_eq!(effective["task_polling_interval_secs"]["value"], 30);
+        assert_eq!(effective["task_polling_interval_secs"]["source"], "default");
+        assert_eq!(effective["vast_api_key"]["value"], REDACTED);
+        assert_eq!(effective["vast_api_key"]["source"], "file");
+        // set through an alias, and a whole table from one variable
+        assert_eq!(effective["provisioning_strategy"]["source"], "env");
+        assert_eq!(effective["extra_env.HF_HOME"]["source"], "env");
+    }
+
+    #[test]
+    fn selected_profile_overrides_feed_env_exports() {
+        let contents = format!(
+            r#"contemplant_profile = "eu"
+{MINIMAL_CONFIG}
+[contemplant]
+heartbeat_interval_seconds = 10
+max_proofs_stored = 4
+
+[contemplant.profiles.eu]
+contemplant_name = "eu-prover"
+http_port = 9111
+
+[contemplant.profiles.us]
+http_port = 9222
+"#
+        );
+        let config = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            load_str(&contents).unwrap()
+        };
+
+        let exports = config.contemplant.to_env_exports();
+        assert!(exports.contains(r#"export CONTEMPLANT_NAME="eu-prover""#), "{exports}");
+        assert!(exports.contains(r#"export HTTP_PORT="9111""#), "{exports}");
+        // base values the profile doesn't set are kept
+        assert!(exports.contains(r#"export HEARTBEAT_INTERVAL_SECONDS="10""#), "{exports}");
+        assert!(exports.contains(r#"export MAX_PROOFS_STORED="4""#), "{exports}");
+        assert_eq!(config.effective()["contemplant.http_port"]["source"], "profile");
+    }
+
+    #[test]
+    fn unknown_profile_is_rejected() {
+        let contents = format!("contemplant_profile = \"asia\"\n{MINIMAL_CONFIG}");
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let error = load_str(&contents).unwrap_err();
+        assert!(format!("{error:#}").contains("\"asia\" is not defined"), "{error:#}");
+    }
+
+    const ED25519_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl operator@laptop";
+    const RSA_KEY: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC7 ops";
+
+    #[test]
+    fn valid_ssh_keys_are_kept() {
+        let mut contemplant = ContemplantConfig {
+            ssh_authorized_keys: Some(format!("{ED25519_KEY}\n\n# a comment\n{RSA_KEY}\n")),
+            ..Default::default()
+        };
+        contemplant.normalize_ssh_authorized_keys();
+        assert_eq!(contemplant.ssh_authorized_keys, Some(format!("{ED25519_KEY}\n{RSA_KEY}")));
+    }
+
+    #[test]
+    fn invalid_ssh_key_lines_are_skipped() {
+        let mut contemplant = ContemplantConfig {
+            ssh_authorized_keys: Some(format!(
+                "{ED25519_KEY}\nnot a key\nssh-ed25519 has-no-valid-base64!\nssh-rsa AAAA\u{7}AAA\n"
+            )),
+            ..Default::default()
+        };
+        contemplant.normalize_ssh_authorized_keys();
+        assert_eq!(contemplant.ssh_authorized_keys.as_deref(), Some(ED25519_KEY));
+
+        let mut contemplant = ContemplantConfig {
+            ssh_authorized_keys: Some("ssh-dss".to_string()),
+            ..Default::default()
+        };
+        contemplant.normalize_ssh_authorized_keys();
+        assert_eq!(contemplant.ssh_authorized_keys, None);
+    }
+
+    #[test]
+    fn crlf_ssh_keys_are_normalized() {
+        let mut contemplant = ContemplantConfig {
+            ssh_authorized_keys: Some(format!("{ED25519_KEY}\r\n{RSA_KEY}\r\n")),
+            ..Default::default()
+        };
+        contemplant.normalize_ssh_authorized_keys();
+        assert_eq!(contemplant.ssh_authorized_keys, Some(format!("{ED25519_KEY}\n{RSA_KEY}")));
+    }
+
+    #[test]
+    fn a_single_gpu_name_is_queried_alone() {
+        let config = test_config();
+        assert_eq!(config.vast_query.gpu_name, vec!["RTX 4090".to_string()]);
+        let query: serde_json::Value = serde_json::from_str(&config.vast_query.to_query_string()).unwrap();
+        assert_eq!(query["gpu_name"]["in"], json!(["RTX 4090"]));
+    }
+
+    #[test]
+    fn several_gpu_names_are_all_queried() {
+        let contents = MINIMAL_CONFIG.replace(r#"gpu_name = "RTX 4090""#, r#"gpu_name = ["RTX 4090", "RTX 3090"]"#);
+        let config = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            load_str(&contents).unwrap()
+        };
+        let query: serde_json::Value = serde_json::from_str(&config.vast_query.to_query_string()).unwrap();
+        assert_eq!(query["gpu_name"]["in"], json!(["RTX 4090", "RTX 3090"]));
+    }
+
+    #[test]
+    fn gpu_name_env_var_takes_a_comma_list() {
+        let config = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            // SAFETY: ENV_LOCK keeps other tests from reading or writing the environment meanwhile
+            unsafe { env::set_var("VAST_QUERY_GPU_NAME", "RTX 4090, RTX 3090,,") };
+            let config = load_str(MINIMAL_CONFIG);
+            unsafe { env::remove_var("VAST_QUERY_GPU_NAME") };
+            config.unwrap()
+        };
+        assert_eq!(config.vast_query.gpu_name, vec!["RTX 4090".to_string(), "RTX 3090".to_string()]);
+    }
+
+    #[test]
+    fn vast_timeouts_default_and_take_env_overrides() {
+        let config = test_config();
+        assert_eq!(config.vast_connect_timeout_secs, 10);
+        assert_eq!(config.vast_request_timeout_secs, 30);
+
+        let config = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            // SAFETY: ENV_LOCK keeps other tests from reading or writing the environment meanwhile
+            unsafe {
+                env::set_var("VAST_CONNECT_TIMEOUT_SECS", "3");
+                env::set_var("VAST_REQUEST_TIMEOUT_SECS", "7");
+            }
+            let config = load_str(MINIMAL_CONFIG);
+            unsafe {
+                env::remove_var("VAST_CONNECT_TIMEOUT_SECS");
+                env::remove_var("VAST_REQUEST_TIMEOUT_SECS");
+            }
+            config.unwrap()
+        };
+        assert_eq!(config.vast_connect_timeout_secs, 3);
+        assert_eq!(config.vast_request_timeout_secs, 7);
+    }
+
+    #[test]
+    fn bid_mode_searches_bids_at_the_bid_price() {
+        let mut vast_query = test_config().vast_query;
+        let query: serde_json::Value = serde_json::from_str(&vast_query.to_query_string()).unwrap();
+        assert_eq!(query["type"], "ask");
+        assert_eq!(vast_query.bid_price(), None);
+
+        vast_query.use_bid = true;
+        let query: serde_json::Value = serde_json::from_str(&vast_query.to_query_string()).unwrap();
+        assert_eq!(query["type"], "bid");
+        // cost_per_hour unless a bid price is given
+        assert_eq!(vast_query.bid_price(), Some(0.5));
+        vast_query.bid_price = Some(0.2);
+        assert_eq!(vast_query.bid_price(), Some(0.2));
+    }
+
+    // loads MINIMAL_CONFIG with contemplant appended as its [contemplant] table
+    fn load_with_contemplant(contemplant: &str) -> Result<Config> {
+        let contents = format!("{MINIMAL_CONFIG}\n[contemplant]\n{contemplant}");
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        load_str(&contents)
+    }
+
+    #[test]
+    fn prover_type_is_normalized_to_lowercase() {
+        assert_eq!(test_config().contemplant.prover_type, "cpu");
+        let config = load_with_contemplant(
+            "prover_type = \"CUDA\"\nmoongate_endpoint = \"http://moongate:3000\"",
+        )
+        .unwrap();
+        assert_eq!(config.contemplant.prover_type, "cuda");
+    }
+
+    #[test]
+    fn unknown_prover_type_is_rejected() {
+        let error = load_with_contemplant("prover_type = \"gpu\"").unwrap_err();
+        assert!(format!("{error:#}").contains("prover_type \"gpu\" is not one of"), "{error:#}");
+    }
+
+    #[test]
+    fn cuda_requires_a_moongate_endpoint() {
+        let error = load_with_contemplant("prover_type = \"cuda\"").unwrap_err();
+        assert!(format!("{error:#}").contains("moongate_endpoint is required"), "{error:#}");
+    }
+
+    #[test]
+    fn offer_selection_is_accepted_for_provisioning_strategy() {
+        assert_eq!(test_config().provisioning_strategy, ProvisioningStrategy::Score);
+
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let contents = format!("offer_selection = \"perf_per_dollar\"\n{MINIMAL_CONFIG}");
+        let config = load_str(&contents).unwrap();
+        assert_eq!(config.provisioning_strategy, ProvisioningStrategy::BestValue);
+
+        for (value, strategy) in [
+            ("best_score", ProvisioningStrategy::Score),
+            ("cheapest", ProvisioningStrategy::Cheapest),
+            ("best_perf", ProvisioningStrategy::Fastest),
+            ("perf_per_dollar", ProvisioningStrategy::BestValue),
+        ] {
+            assert_eq!(value.parse::<ProvisioningStrategy>().unwrap(), strategy);
+        }
+        assert!("cheapest_first".parse::<ProvisioningStrategy>().is_err());
+    }
+
+    #[test]
+    fn num_gpus_bounds_are_queried_only_when_set() {
+        let query_for = |vast_query: &VastQueryConfig| -> serde_json::Value {
+            serde_json::from_str(&vast_query.to_query_string()).unwrap()
+        };
+        let mut vast_query = test_config().vast_query;
+        assert!(query_for(&vast_query).get("num_gpus").is_none());
+
+        vast_query.min_num_gpus = Some(1);
+        assert_eq!(query_for(&vast_query)["num_gpus"], json!({ "gte": 1 }));
+
+        vast_query.max_num_gpus = Some(2);
+        assert_eq!(query_for(&vast_query)["num_gpus"], json!({ "gte": 1, "lte": 2 }));
+
+        vast_query.min_num_gpus = None;
+        assert_eq!(query_for(&vast_query)["num_gpus"], json!({ "lte": 2 }));
+    }
+
+    #[test]
+    fn fallback_queries_parse_alongside_the_primary() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(load_str(MINIMAL_CONFIG).unwrap().vast_query_fallbacks.is_empty());
+
+        let contents = format!(
+            r#"{MINIMAL_CONFIG}
+[[vast_query_fallbacks]]
+allocated_storage = 16
+gpu_name = "RTX 3090"
+reliability = 0.9
+min_cuda_version = 12.0
+gpu_ram = 24
+disk_space = 100
+duration = 1.0
+cost_per_hour = 0.9
+"#
+        );
+        let config = load_str(&contents).unwrap();
+        assert_eq!(config.vast_query.gpu_name, vec!["RTX 4090".to_string()]);
+        assert_eq!(config.vast_query_fallbacks.len(), 1);
+        assert_eq!(config.vast_query_fallbacks[0].gpu_name, vec!["RTX 3090".to_string()]);
+        assert_eq!(config.vast_query_fallbacks[0].cost_per_hour, 0.9);
+    }
+
+    // ALL_PROXY is left alone, since reqwest would send other tests' plain http requests to it
+    #[test]
+    fn https_proxy_env_var_sets_the_proxy() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(load_str(MINIMAL_CONFIG).unwrap().https_proxy.is_none());
+
+        // SAFETY: ENV_LOCK keeps other tests from reading or writing the environment meanwhile
+        unsafe { env::set_var("HTTPS_PROXY", "http://https-proxy:3128") };
+        let config = load_str(MINIMAL_CONFIG);
+        unsafe { env::remove_var("HTTPS_PROXY") };
+        assert_eq!(config.unwrap().https_proxy.as_deref(), Some("http://https-proxy:3128"));
+    }
+
+    #[test]
+    fn number_instances_is_clamped_to_hard_max_instances() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let config = load_str(&format!("hard_max_instances = 1\n{MINIMAL_CONFIG}")).unwrap();
+        assert_eq!(config.number_instances, 1);
+        assert_eq!(config.clamp_to_hard_max(5), 1);
+        assert_eq!(config.clamp_to_hard_max(0), 0);
+    }
+
+    #[test]
+    fn perf_targets_replace_gpu_name() {
+        let load = |vast_query_lines: &str| {
+            let contents = MINIMAL_CONFIG.replace("gpu_name = \"RTX 4090\"\n", vast_query_lines);
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            load_str(&contents)
+        };
+
+        let config = load("min_dlperf = 50.0\nmax_dph_per_dlperf = 0.01\n").unwrap();
+        assert!(config.vast_query.targets_performance());
+        let query: serde_json::Value = serde_json::from_str(&config.vast_query.to_query_string()).unwrap();
+        assert_eq!(query["dlperf"]["gte"], json!(50.0));
+        assert!(query.get("gpu_name").is_none());
+
+        // one mode or the other
+        assert!(load("gpu_name = \"RTX 4090\"\nmin_dlperf = 50.0\n").is_err());
+        assert!(load("gpu_name = \"RTX 4090\"\nmax_dph_per_dlperf = 0.01\n").is_err());
+        assert!(load("").is_err());
+    }
+
+    #[test]
+    fn http_bind_addr_defaults_to_every_interface_and_must_parse() {
+        let load = |http_bind_addr: &str| {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            load_str(&format!("http_bind_addr = \"{http_bind_addr}\"\n{MINIMAL_CONFIG}"))
+        };
+
+        assert_eq!(test_config().http_bind_addr(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(load("127.0.0.1").unwrap().http_bind_addr(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(load("::1").unwrap().http_bind_addr(), "::1".parse::<IpAddr>().unwrap());
+
+        let error = load("localhost").unwrap_err();
+        assert!(format!("{error:#}").contains("http_bind_addr \"localhost\""), "{error:#}");
+    }
+
+    #[test]
+    fn secrets_are_read_from_files_and_trimmed() {
+        let dir = env::temp_dir();
+        let key_path = dir.join(format!("magister-test-{}-vast-key", std::process::id()));
+        let token_path = dir.join(format!("magister-test-{}-api-token", std::process::id()));
+        std::fs::write(&key_path, "file-key\n").unwrap();
+        std::fs::write(&token_path, "  file-token\r\n").unwrap();
+        let (key_path, token_path) = (key_path.to_str().unwrap(), token_path.to_str().unwrap());
+
+        let (from_config, from_env, over_env, missing) = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let from_config = load_str(&format!(
+                "vast_api_key_file = \"{key_path}\"\napi_token = \"inline-token\"\napi_token_file = \"{token_path}\"\n{MINIMAL_CONFIG}"
+            ));
+            // SAFETY: ENV_LOCK keeps other tests from reading or writing the environment meanwhile
+            unsafe { env::set_var("VAST_API_KEY_FILE", key_path) };
+            let from_env = load_str(MINIMAL_CONFIG);
+            // the file named in the config file still wins over VAST_API_KEY
+            unsafe { env::remove_var("VAST_API_KEY_FILE") };
+            unsafe { env::set_var("VAST_API_KEY", "env-key") };
+            let over_env = load_str(&format!("vast_api_key_file = \"{key_path}\"\n{MINIMAL_CONFIG}"));
+            unsafe { env::remove_var("VAST_API_KEY") };
+            unsafe { env::set_var("VAST_API_KEY_FILE", "/nonexistent/magister-vast-key") };
+            let missing = load_str(MINIMAL_CONFIG);
+            unsafe { env::remove_var("VAST_API_KEY_FILE") };
+            (from_config, from_env, over_env, missing)
+        };
+        std::fs::remove_file(key_path).unwrap();
+        std::fs::remove_file(token_path).unwrap();
+
+        // the files win over vast_api_key and api_token set inline
+        let from_config = from_config.unwrap();
+        assert_eq!(from_config.vast_api_key, "file-key");
+        assert_eq!(from_config.api_token.as_deref(), Some("file-token"));
+        assert_eq!(from_config.effective()["api_token"]["source"], "file");
+        let from_env = from_env.unwrap();
+        assert_eq!(from_env.vast_api_key, "file-key");
+        assert_eq!(from_env.effective()["vast_api_key"]["source"], "env");
+        let over_env = over_env.unwrap();
+        assert_eq!(over_env.vast_api_key, "file-key");
+        assert_eq!(over_env.effective()["vast_api_key"]["source"], "file");
+
+        let error = missing.unwrap_err();
+        assert!(format!("{error:#}").contains("vast_api_key_file"), "{error:#}");
+    }
+
+    #[test]
+    fn this_magister_addr_must_be_a_bare_scheme_and_host() {
+        for good in ["http://magister.example.com", "https://magister.example.com/", "http://203.0.113.7", "http://[2001:db8::1]"] {
+            assert!(validate_this_magister_addr(good).is_ok(), "{good}");
+            let host = reqwest::Url::parse(good).unwrap().host_str().unwrap().to_string();
+            assert!(!is_loopback_host(&host), "{good}");
+        }
+
+        // accepted, but warned about since a Contemplant can't reach them
+        for loopback in ["http://localhost", "http://LOCALHOST/", "http://127.0.0.1", "http://[::1]", "http://0.0.0.0"] {
+            assert!(validate_this_magister_addr(loopback).is_ok(), "{loopback}");
+            let host = reqwest::Url::parse(loopback).unwrap().host_str().unwrap().to_string();
+            assert!(is_loopback_host(&host), "{loopback}");
+        }
+
+        for malformed in [
+            "magister.example.com",
+            "ftp://magister.example.com",
+            "http://magister.example.com:8080",
+            "http://magister.example.com/drop",
+            "http://magister.example.com?x=1",
+            "http://",
+        ] {
+            assert!(validate_this_magister_addr(malformed).is_err(), "{malformed}");
+        }
+
+        // and it's checked at load
+        let contents = MINIMAL_CONFIG.replace("http://magister.example.com", "magister.example.com");
+        let error = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            load_str(&contents).unwrap_err()
+        };
+        assert!(format!("{error:#}").contains("this_magister_addr"), "{error:#}");
+    }
 }
@@ -0,0 +1,111 @@
+use std::{collections::VecDeque, fmt};
+
+use log::Level;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    logging::log_instance,
+    types::{VastInstance, unix_now},
+};
+
+// Why an instance was dropped
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    // requested through /drop, /instances/:instance_id, or /drop-all
+    Manual,
+    // the Contemplant never called /verify in time
+    VerificationTimeout,
+    // removed outside of this Magister, usually through the Vast console
+    Zombie,
+    // Vast reported it as something other than running for too long
+    Stuck,
+    // up for longer than max_instance_lifetime_secs
+    Lifetime,
+    // failed health_check_failures health checks in a row
+    Unhealthy,
+    // one of the most expensive instances when the desired count was lowered
+    ScaleDown,
+    // one of the most expensive instances while live cost was over max_total_dph
+    OverBudget,
+}
+
+impl DropReason {
+    // the reason named by a /drop request body, if it names one, e.g. "zombie"
+    pub fn parse(reason: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(reason.trim().to_string())).ok()
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DropEvent {
+    pub offer_id: u64,
+    pub instance_id: u64,
+    pub machine_id: u64,
+    pub reason: DropReason,
+    // unix seconds
+    pub timestamp: u64,
+}
+
+// The most recent drops, oldest first, kept so churn can be diagnosed after the fact.  Holds up
+// to capacity entries.  Only held in memory.
+pub struct DropHistory {
+    events: VecDeque<DropEvent>,
+    capacity: usize,
+}
+
+impl DropHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // Marks the instance to be dropped, recording why unless it already was.  Returns whether it
+    // was newly marked.
+    pub fn mark_for_drop(&mut self, instance: &mut VastInstance, reason: DropReason) -> bool {
+        if instance.should_drop {
+            return false;
+        }
+        instance.should_drop = true;
+        log_instance!(
+            Level::Debug,
+            instance,
+            "Marking {instance} to be dropped.  Reason: {reason}"
+        );
+        self.record(instance, reason);
+        true
+    }
+
+    pub fn record(&mut self, instance: &VastInstance, reason: DropReason) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(DropEvent {
+            offer_id: instance.offer.id,
+            instance_id: instance.instance_id,
+            machine_id: instance.offer.machine_id,
+            reason,
+            timestamp: unix_now(),
+        });
+    }
+
+    pub fn events(&self) -> Vec<DropEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
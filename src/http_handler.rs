@@ -1,79 +1,383 @@
 use axum::{
     Router,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get},
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+};
+use log::{error, info, warn};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
 };
-use log::{error, info};
-use std::sync::Arc;
 
-use crate::types::{MagisterState, SummaryResponse, VastInstance};
+use crate::config::VastQueryConfig;
+use crate::drop_history::{DropEvent, DropReason};
+use crate::scale_history::ScaleEvent;
+use crate::types::{
+    ApiError, ContemplantInfo, DesiredCount, DropAllQuery, DropAllResponse, ImportResponse,
+    InstanceResponse, MagisterState, Manifest, MaxDph, QueryResponse, RunwayResponse, ScaleRequest,
+    SshResponse, SummaryQuery, SummaryResponse, SummarySort, VastInstance, group_instances,
+    total_cost_per_hour,
+};
+use crate::vast::{VastOperation, VastOperationStatus};
 
 pub fn create_router(state: Arc<MagisterState>) -> Router {
-    Router::new()
+    let require_api_token = middleware::from_fn_with_state(state.clone(), require_api_token);
+
+    // routes that change state or expose config always require api_token when it's set
+    let protected = Router::new()
+        .route("/config", get(config))
+        .route("/config/effective", get(effective_config))
+        .route("/desired-count", put(set_desired_count))
         .route("/drop/:id", delete(drop))
+        .route("/drop-all", delete(drop_all))
+        .route(
+            "/good-hosts/:id",
+            post(mark_good_host).delete(unmark_good_host),
+        )
+        .route(
+            "/good-machines/:id",
+            post(mark_good_machine).delete(unmark_good_machine),
+        )
+        .route("/instances/:instance_id", delete(drop_by_instance_id))
+        .route("/instances/:instance_id/reboot", post(reboot))
+        .route("/manifest/import", post(import_manifest))
+        .route("/max-dph", put(set_max_dph))
+        .route("/pause", post(pause))
+        .route("/scale", post(scale))
+        .route("/resume", post(resume))
+        .route("/verify/:id", get(verify).post(verify))
+        .route_layer(require_api_token.clone());
+
+    let reads = Router::new()
+        .route("/drops", get(drops))
         .route("/instances", get(instances))
+        .route("/instances/:instance_id/ssh", get(instance_ssh))
+        .route("/manifest", get(manifest))
+        .route("/query", get(query))
+        .route("/runway", get(runway))
+        .route("/scale/history", get(scale_history))
         .route("/summary", get(summary))
-        .route("/verify/:id", get(verify))
-        .with_state(state)
+        .route("/vast-status", get(vast_status));
+    let reads = if state.config.api_token_covers_reads {
+        reads.route_layer(require_api_token)
+    } else {
+        reads
+    };
+
+    // probes for orchestrators, never behind api_token
+    let probes = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+
+    protected.merge(reads).merge(probes).with_state(state)
+}
+
+// Rejects requests without config.api_token as a bearer token or `token` query parameter.  Lets
+// everything through when no api_token is configured.
+async fn require_api_token(
+    State(state): State<Arc<MagisterState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(ref api_token) = state.config.api_token else {
+        return Ok(next.run(request).await);
+    };
+
+    let bearer_token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query_token = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    });
+
+    let matches =
+        |token: Option<&str>| token.is_some_and(|token| constant_time_eq(token, api_token));
+    if matches(bearer_token) | matches(query_token) {
+        Ok(next.run(request).await)
+    } else {
+        let err = format!(
+            "Rejected {} {} without a valid api token",
+            request.method(),
+            request.uri().path()
+        );
+        warn!("{err}");
+        Err(ApiError::new(StatusCode::UNAUTHORIZED, err))
+    }
+}
+
+// Compares without stopping at the first difference, so response times don't reveal how much of
+// a guessed token was right.  Only the length can be told apart.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
 }
 
 // called by Hierophant to let the Magister know a Contemplant instance successfully initialized
 async fn verify(
     State(state): State<Arc<MagisterState>>,
     Path(id): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
+    body: Option<String>,
+) -> Result<impl IntoResponse, ApiError> {
     let offer_id: u64 = match id.parse() {
         Ok(id) => id,
         Err(e) => {
-            error!("Error parsing {id} as u64 in drop request: {e}");
-            return Err(StatusCode::BAD_REQUEST);
+            let err = format!("Error parsing {id} as u64 in verify request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
         }
     };
 
-    match state.instance_controller_client.verify(offer_id).await {
+    // older Contemplants verify without a body
+    let contemplant_info: Option<ContemplantInfo> = match body
+        .filter(|body| !body.trim().is_empty())
+    {
+        Some(body) => match serde_json::from_str(&body) {
+            Ok(contemplant_info) => Some(contemplant_info),
+            Err(e) => {
+                let err = format!("Error parsing verify request body for offer {offer_id}: {e}");
+                error!("{err}");
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
+            }
+        },
+        None => None,
+    };
+
+    match state
+        .instance_controller_client
+        .verify(offer_id, contemplant_info)
+        .await
+    {
         Ok(_) => Ok(()),
         Err(e) => {
-            error!("Error verifying instance: {e}");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            let err = format!("Error verifying instance: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// prioritize offers on this host until restart, without editing good_hosts in config
+async fn mark_good_host(
+    State(state): State<Arc<MagisterState>>,
+    Path(id): Path<String>,
+) -> Result<String, ApiError> {
+    set_good_host(state, id, true).await
+}
+
+async fn unmark_good_host(
+    State(state): State<Arc<MagisterState>>,
+    Path(id): Path<String>,
+) -> Result<String, ApiError> {
+    set_good_host(state, id, false).await
+}
+
+async fn set_good_host(
+    state: Arc<MagisterState>,
+    id: String,
+    good: bool,
+) -> Result<String, ApiError> {
+    let host_id: u64 = match id.parse() {
+        Ok(id) => id,
+        Err(e) => {
+            let err = format!("Error parsing {id} as u64 in good host request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
+        }
+    };
+
+    match state
+        .instance_controller_client
+        .set_good_host(host_id, good)
+        .await
+    {
+        Ok(true) => Ok(format!("Host {host_id} good: {good}")),
+        Ok(false) => Ok(format!("Host {host_id} was already good: {good}")),
+        Err(e) => {
+            let err = format!("Error updating good hosts: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// prioritize offers on this machine until restart, without editing good_machines in config
+async fn mark_good_machine(
+    State(state): State<Arc<MagisterState>>,
+    Path(id): Path<String>,
+) -> Result<String, ApiError> {
+    set_good_machine(state, id, true).await
+}
+
+async fn unmark_good_machine(
+    State(state): State<Arc<MagisterState>>,
+    Path(id): Path<String>,
+) -> Result<String, ApiError> {
+    set_good_machine(state, id, false).await
+}
+
+async fn set_good_machine(
+    state: Arc<MagisterState>,
+    id: String,
+    good: bool,
+) -> Result<String, ApiError> {
+    let machine_id: u64 = match id.parse() {
+        Ok(id) => id,
+        Err(e) => {
+            let err = format!("Error parsing {id} as u64 in good machine request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
+        }
+    };
+
+    match state
+        .instance_controller_client
+        .set_good_machine(machine_id, good)
+        .await
+    {
+        Ok(true) => Ok(format!("Machine {machine_id} good: {good}")),
+        Ok(false) => Ok(format!("Machine {machine_id} was already good: {good}")),
+        Err(e) => {
+            let err = format!("Error updating good machines: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// the config this Magister is running with, after env overrides, with secrets masked
+async fn config(State(state): State<Arc<MagisterState>>) -> axum::Json<serde_json::Value> {
+    axum::Json(state.config.redacted())
+}
+
+// the fully resolved config with where each value came from (env, profile, file, or default)
+async fn effective_config(
+    State(state): State<Arc<MagisterState>>,
+) -> axum::Json<serde_json::Value> {
+    axum::Json(state.config.effective())
+}
+
+// recent drops and why they happened, for diagnosing churn after the fact
+async fn drops(
+    State(state): State<Arc<MagisterState>>,
+) -> Result<axum::Json<Vec<DropEvent>>, ApiError> {
+    match state.instance_controller_client.drops().await {
+        Ok(drops) => Ok(axum::Json(drops)),
+        Err(e) => {
+            let err = format!("Error getting drops: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
         }
     }
 }
 
 async fn instances(
     State(state): State<Arc<MagisterState>>,
-) -> Result<axum::Json<Vec<VastInstance>>, StatusCode> {
+) -> Result<axum::Json<Vec<InstanceResponse>>, ApiError> {
     match state.instance_controller_client.instances().await {
-        Ok(instances) => Ok(axum::Json(instances)),
+        Ok(instances) => Ok(axum::Json(
+            instances.into_iter().map(InstanceResponse::from).collect(),
+        )),
         Err(e) => {
-            error!("Error getting instances: {e}");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            let err = format!("Error getting instances: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
         }
     }
 }
 
 async fn summary(
     State(state): State<Arc<MagisterState>>,
-) -> Result<axum::Json<SummaryResponse>, StatusCode> {
+    Query(params): Query<SummaryQuery>,
+) -> Result<axum::Json<SummaryResponse>, ApiError> {
+    let sort: Option<SummarySort> = match params.sort.as_deref().map(str::parse).transpose() {
+        Ok(sort) => sort,
+        Err(e) => {
+            let err = format!("Error parsing sort in summary request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
+        }
+    };
+
     let mut instances = match state.instance_controller_client.instances().await {
         Ok(instances) => instances,
         Err(e) => {
-            error!("Error getting instances: {e}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            let err = format!("Error getting instances: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err));
         }
     };
 
+    // including instances about to be dropped, which still say how long verification takes
+    let times_to_verification: Vec<f64> = instances
+        .iter()
+        .filter_map(|instance| instance.time_to_verification)
+        .map(|duration| duration.as_secs_f64())
+        .collect();
+    let avg_time_to_verification_secs = (!times_to_verification.is_empty())
+        .then(|| times_to_verification.iter().sum::<f64>() / times_to_verification.len() as f64);
+    let max_time_to_verification_secs = times_to_verification.into_iter().reduce(f64::max);
+
     // only keep instances that we aren't about to drop
     instances.retain(|instance| !instance.should_drop);
 
-    let total_dph = instances
-        .iter()
-        .map(|instance| instance.offer.dph_total)
-        .sum();
+    let total_dph = total_cost_per_hour(&instances);
 
     let num_instances = instances.len();
 
+    let distinct_hosts = instances
+        .iter()
+        .map(|instance| instance.offer.host_id)
+        .collect::<HashSet<_>>()
+        .len();
+
+    let total_accumulated_cost = instances.iter().map(VastInstance::accumulated_cost).sum();
+
+    let by_gpu = group_instances(&instances, |instance| &instance.offer.gpu_name);
+    let by_region = group_instances(&instances, |instance| &instance.offer.geolocation);
+
+    let paused = match state.instance_controller_client.is_paused().await {
+        Ok(paused) => paused,
+        Err(e) => {
+            let err = format!("Error getting paused state: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err));
+        }
+    };
+
+    let template_stats = match state.instance_controller_client.template_stats().await {
+        Ok(template_stats) => template_stats.into_iter().collect(),
+        Err(e) => {
+            let err = format!("Error getting template stats: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err));
+        }
+    };
+
+    // the totals above still cover every instance
+    if let Some(ref geolocation) = params.geolocation {
+        let geolocation = geolocation.to_lowercase();
+        instances.retain(|instance| {
+            instance
+                .offer
+                .geolocation
+                .to_lowercase()
+                .contains(&geolocation)
+        });
+    }
+    if let Some(sort) = sort {
+        sort.sort(&mut instances);
+    }
+
     let instance_overview = instances
         .into_iter()
         .map(|instance| instance.into())
@@ -82,41 +386,1082 @@ async fn summary(
     let summary = SummaryResponse {
         total_cost_per_hour: total_dph,
         num_instances,
+        distinct_hosts,
+        total_accumulated_cost,
+        paused,
+        avg_time_to_verification_secs,
+        max_time_to_verification_secs,
+        by_gpu,
+        by_region,
+        template_stats,
         instance_overview,
     };
 
     Ok(axum::Json(summary))
 }
 
+// every tracked instance in a form another Magister can import
+async fn manifest(
+    State(state): State<Arc<MagisterState>>,
+) -> Result<axum::Json<Manifest>, ApiError> {
+    match state.instance_controller_client.instances().await {
+        Ok(instances) => Ok(axum::Json(Manifest {
+            instances: instances
+                .into_iter()
+                .map(|instance| instance.into())
+                .collect(),
+        })),
+        Err(e) => {
+            let err = format!("Error getting instances: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// adopt every instance in a manifest exported from another Magister
+async fn import_manifest(
+    State(state): State<Arc<MagisterState>>,
+    axum::Json(manifest): axum::Json<Manifest>,
+) -> Result<axum::Json<ImportResponse>, ApiError> {
+    let instances = manifest
+        .instances
+        .into_iter()
+        .map(|instance| instance.into())
+        .collect();
+
+    match state.instance_controller_client.import(instances).await {
+        Ok(imported) => {
+            info!("Imported {imported} instances from manifest");
+            Ok(axum::Json(ImportResponse { imported }))
+        }
+        Err(e) => {
+            let err = format!("Error importing manifest: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// liveness: the server is up and answering
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+// readiness: number_instances instances have been verified at least once.  Until then, a 503
+// with an ApiError body saying what it's waiting for.
+async fn readyz(State(state): State<Arc<MagisterState>>) -> Result<StatusCode, ApiError> {
+    if state.instance_controller_client.is_ready() {
+        Ok(StatusCode::OK)
+    } else {
+        Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Waiting for instances to be verified",
+        ))
+    }
+}
+
+// the Vast offer search query, for pasting into the Vast console
+async fn query(State(state): State<Arc<MagisterState>>) -> axum::Json<QueryResponse> {
+    axum::Json(QueryResponse {
+        query: state.vast_client.vast_query().to_query_string(),
+        fallback_queries: state
+            .config
+            .vast_query_fallbacks
+            .iter()
+            .map(VastQueryConfig::to_query_string)
+            .collect(),
+        last_offer_count: state.vast_client.last_offer_count(),
+    })
+}
+
+// how the last Vast call of each kind went, to tell "no offers" apart from "Vast is erroring"
+async fn vast_status(
+    State(state): State<Arc<MagisterState>>,
+) -> axum::Json<BTreeMap<VastOperation, VastOperationStatus>> {
+    axum::Json(state.vast_client.operation_status())
+}
+
+// scale the number of instances up or down without restarting
+async fn set_desired_count(
+    State(state): State<Arc<MagisterState>>,
+    axum::Json(desired): axum::Json<DesiredCount>,
+) -> Result<axum::Json<DesiredCount>, ApiError> {
+    match state
+        .instance_controller_client
+        .set_desired_count(desired.count, None)
+        .await
+    {
+        Ok(Ok(count)) => Ok(axum::Json(DesiredCount { count })),
+        Ok(Err(err)) => {
+            warn!("Rejected desired count {}: {err}", desired.count);
+            Err(ApiError::new(StatusCode::BAD_REQUEST, err))
+        }
+        Err(e) => {
+            let err = format!("Error setting desired count: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// Like /desired-count, with a reason recorded alongside the change.  For external autoscalers.
+async fn scale(
+    State(state): State<Arc<MagisterState>>,
+    axum::Json(request): axum::Json<ScaleRequest>,
+) -> Result<axum::Json<DesiredCount>, ApiError> {
+    match state
+        .instance_controller_client
+        .set_desired_count(request.count, request.reason)
+        .await
+    {
+        Ok(Ok(count)) => Ok(axum::Json(DesiredCount { count })),
+        Ok(Err(err)) => {
+            warn!("Rejected scaling to {}: {err}", request.count);
+            Err(ApiError::new(StatusCode::BAD_REQUEST, err))
+        }
+        Err(e) => {
+            let err = format!("Error scaling: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+async fn scale_history(
+    State(state): State<Arc<MagisterState>>,
+) -> Result<axum::Json<Vec<ScaleEvent>>, ApiError> {
+    match state.instance_controller_client.scale_events().await {
+        Ok(scale_events) => Ok(axum::Json(scale_events)),
+        Err(e) => {
+            let err = format!("Error getting scale history: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// raise or lower the primary query's cost_per_hour without restarting, e.g. during a shortage
+async fn set_max_dph(
+    State(state): State<Arc<MagisterState>>,
+    axum::Json(max_dph): axum::Json<MaxDph>,
+) -> Result<axum::Json<MaxDph>, ApiError> {
+    match state
+        .instance_controller_client
+        .set_max_dph(max_dph.cost_per_hour)
+        .await
+    {
+        Ok(Ok(cost_per_hour)) => Ok(axum::Json(MaxDph { cost_per_hour })),
+        Ok(Err(err)) => {
+            warn!("Rejected cost_per_hour {}: {err}", max_dph.cost_per_hour);
+            Err(ApiError::new(StatusCode::BAD_REQUEST, err))
+        }
+        Err(e) => {
+            let err = format!("Error setting cost_per_hour: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// stop creating instances and dropping unverified ones, e.g. while debugging a misbehaving one
+async fn pause(State(state): State<Arc<MagisterState>>) -> Result<(), ApiError> {
+    set_paused(state, true).await
+}
+
+async fn resume(State(state): State<Arc<MagisterState>>) -> Result<(), ApiError> {
+    set_paused(state, false).await
+}
+
+async fn set_paused(state: Arc<MagisterState>, paused: bool) -> Result<(), ApiError> {
+    match state.instance_controller_client.set_paused(paused).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let err = format!("Error setting paused to {paused}: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// how long the Vast account balance will last at the current hourly spend
+async fn runway(
+    State(state): State<Arc<MagisterState>>,
+) -> Result<axum::Json<RunwayResponse>, ApiError> {
+    let instances = match state.instance_controller_client.instances().await {
+        Ok(instances) => instances,
+        Err(e) => {
+            let err = format!("Error getting instances: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err));
+        }
+    };
+
+    let total_dph = total_cost_per_hour(&instances);
+
+    let balance = match state.vast_client.get_balance().await {
+        Ok(balance) => balance,
+        Err(e) => {
+            let err = format!("Error getting Vast account balance: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_GATEWAY, err));
+        }
+    };
+
+    Ok(axum::Json(RunwayResponse::new(balance, total_dph)))
+}
+
 // called by Hierophant to let the Magister know a Contemplant instance should be deallocated
 // (dropped)
 async fn drop(
     State(state): State<Arc<MagisterState>>,
     Path(id): Path<String>,
     body: Option<String>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, ApiError> {
     let offer_id: u64 = match id.parse() {
         Ok(id) => id,
         Err(e) => {
-            error!("Error parsing {id} as u64 in drop request: {e}");
-            return Err(StatusCode::BAD_REQUEST);
+            let err = format!("Error parsing {id} as u64 in drop request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
         }
     };
 
+    // a body naming a DropReason, e.g. "unhealthy", is recorded as that reason.  Anything else is
+    // free text, logged and recorded as manual.
+    let reason = body
+        .as_deref()
+        .and_then(DropReason::parse)
+        .unwrap_or(DropReason::Manual);
     match body {
-        Some(reason) => {
-            info!("Received request to drop instance of offer {offer_id} with reason: {reason}");
+        Some(body) => {
+            info!("Received request to drop instance of offer {offer_id} with reason: {body}");
         }
         None => {
             info!("Received request to drop instance of offer {offer_id}");
         }
     }
 
-    match state.instance_controller_client.drop(offer_id).await {
-        Ok(resp) => resp,
+    match state
+        .instance_controller_client
+        .drop(offer_id, reason)
+        .await
+    {
+        Ok(resp) => resp.map_err(|status| {
+            ApiError::new(
+                status,
+                format!("offer_id {offer_id} isn't known to this magister"),
+            )
+        }),
+        Err(e) => {
+            let err = format!("Error getting instances: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// the ready-to-paste SSH command for an instance, for debugging with ssh_authorized_keys
+async fn instance_ssh(
+    State(state): State<Arc<MagisterState>>,
+    Path(id): Path<String>,
+) -> Result<axum::Json<SshResponse>, ApiError> {
+    let instance_id: u64 = match id.parse() {
+        Ok(id) => id,
+        Err(e) => {
+            let err = format!("Error parsing {id} as u64 in ssh request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
+        }
+    };
+
+    let instances = match state.instance_controller_client.instances().await {
+        Ok(instances) => instances,
+        Err(e) => {
+            let err = format!("Error getting instances: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err));
+        }
+    };
+
+    let Some(instance) = instances
+        .iter()
+        .find(|instance| instance.instance_id == instance_id)
+    else {
+        let err = format!("Instance {instance_id} isn't tracked by this Magister");
+        warn!("{err}");
+        return Err(ApiError::new(StatusCode::NOT_FOUND, err));
+    };
+
+    match instance.ssh_info() {
+        Some(ssh) => Ok(axum::Json(ssh)),
+        None => {
+            let err = format!(
+                "Vast hasn't reported a public IP and SSH port for instance {instance_id} yet"
+            );
+            warn!("{err}");
+            Err(ApiError::new(StatusCode::CONFLICT, err))
+        }
+    }
+}
+
+// reboot a wedged Contemplant's instance rather than dropping it and renting another
+async fn reboot(
+    State(state): State<Arc<MagisterState>>,
+    Path(id): Path<String>,
+) -> Result<(), ApiError> {
+    let instance_id: u64 = match id.parse() {
+        Ok(id) => id,
         Err(e) => {
-            error!("Error getting instances: {e}");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            let err = format!("Error parsing {id} as u64 in reboot request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
+        }
+    };
+
+    info!("Received request to reboot instance {instance_id}");
+
+    match state.instance_controller_client.reboot(instance_id).await {
+        Ok(resp) => resp.map_err(|(status, err)| ApiError::new(status, err)),
+        Err(e) => {
+            let err = format!("Error rebooting instance: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// drop by the instance_id (contract id) shown in Vast's console, rather than by offer id like /drop
+async fn drop_by_instance_id(
+    State(state): State<Arc<MagisterState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let instance_id: u64 = match id.parse() {
+        Ok(id) => id,
+        Err(e) => {
+            let err = format!("Error parsing {id} as u64 in drop request: {e}");
+            error!("{err}");
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err));
+        }
+    };
+
+    info!("Received request to drop instance {instance_id}");
+
+    match state
+        .instance_controller_client
+        .drop_by_instance_id(instance_id)
+        .await
+    {
+        Ok(resp) => resp.map_err(|status| {
+            ApiError::new(
+                status,
+                format!("instance_id {instance_id} isn't known to this magister"),
+            )
+        }),
+        Err(e) => {
+            let err = format!("Error getting instances: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+// Tears down every instance for a maintenance window without stopping the Magister.  Provisioning
+// stays paused afterwards so they aren't immediately re-created.  ?confirm= must be how many
+// instances there are (as listed by /instances), otherwise nothing is dropped and it's 428.
+async fn drop_all(
+    State(state): State<Arc<MagisterState>>,
+    Query(query): Query<DropAllQuery>,
+) -> Result<axum::Json<DropAllResponse>, ApiError> {
+    info!("Received request to drop all instances");
+
+    // anything that isn't a count can't match
+    let confirm = query.confirm.map(|confirm| confirm.trim().parse().ok());
+    match state
+        .instance_controller_client
+        .drop_all(confirm.flatten())
+        .await
+    {
+        Ok(Ok(marked)) => Ok(axum::Json(DropAllResponse { marked })),
+        Ok(Err(instance_count)) => {
+            let err = match confirm {
+                Some(_) => format!(
+                    "Confirmation doesn't match the {instance_count} instances.  Nothing was dropped."
+                ),
+                None => format!(
+                    "Dropping all instances needs confirming.  Repeat the request with ?confirm={instance_count} to drop all {instance_count} instances."
+                ),
+            };
+            warn!("{err}");
+            Err(ApiError::new(StatusCode::PRECONDITION_REQUIRED, err))
+        }
+        Err(e) => {
+            let err = format!("Error dropping all instances: {e}");
+            error!("{err}");
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::{
+        config::Config,
+        instance_controller::tests::spawn_test_controller,
+        types::tests::test_instance,
+        vast::tests::{mock_config, mock_vast},
+    };
+
+    // Serves the API on a local port for a controller tracking instances.  Returns its base URL.
+    async fn serve(config: Config, instances: Vec<VastInstance>) -> String {
+        serve_with_vast(config, instances, Router::new()).await
+    }
+
+    // Like serve, with vast_router standing in for Vast
+    async fn serve_with_vast(
+        config: Config,
+        instances: Vec<VastInstance>,
+        vast_router: Router,
+    ) -> String {
+        let vast_client = mock_vast(config.clone(), vast_router).await;
+        let instance_controller_client =
+            spawn_test_controller(config.clone(), vast_client.clone(), instances);
+        let state = Arc::new(MagisterState {
+            instance_controller_client,
+            vast_client,
+            config,
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, create_router(state)).await });
+        base_url
+    }
+
+    fn two_instances() -> Vec<VastInstance> {
+        vec![test_instance(1, 0.3), test_instance(2, 0.5)]
+    }
+
+    #[tokio::test]
+    async fn drop_all_requires_confirmation() {
+        let base_url = serve(mock_config(), two_instances()).await;
+        let client = reqwest::Client::new();
+
+        for url in [
+            format!("{base_url}/drop-all"),
+            format!("{base_url}/drop-all?confirm=3"),
+            format!("{base_url}/drop-all?confirm=all"),
+        ] {
+            let response = client.delete(&url).send().await.unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::PRECONDITION_REQUIRED,
+                "{url}"
+            );
+        }
+
+        let instances: Vec<serde_json::Value> = client
+            .get(format!("{base_url}/instances"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(
+            instances
+                .iter()
+                .all(|instance| instance["should_drop"] == false)
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_all_proceeds_with_matching_confirmation() {
+        let base_url = serve(mock_config(), two_instances()).await;
+
+        let response = reqwest::Client::new()
+            .delete(format!("{base_url}/drop-all?confirm=2"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["marked"], 2);
+    }
+
+    #[tokio::test]
+    async fn api_token_is_required_when_set() {
+        let mut config = mock_config();
+        config.api_token = Some("s3cret-token".to_string());
+        let base_url = serve(config, two_instances()).await;
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("{base_url}{path}"));
+
+        let missing = get("/config").send().await.unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        let wrong = get("/config")
+            .bearer_auth("s3cret-tokeN")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        let wrong_length = get("/config?token=s3cret").send().await.unwrap();
+        assert_eq!(wrong_length.status(), StatusCode::UNAUTHORIZED);
+
+        let bearer = get("/config")
+            .bearer_auth("s3cret-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(bearer.status(), StatusCode::OK);
+        let query = get("/config?token=s3cret-token").send().await.unwrap();
+        assert_eq!(query.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_tokens() {
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "ab"));
+        assert!(!constant_time_eq("", "a"));
+    }
+
+    #[tokio::test]
+    async fn malformed_drop_id_gets_an_error_body() {
+        let base_url = serve(mock_config(), two_instances()).await;
+
+        let response = reqwest::Client::new()
+            .delete(format!("{base_url}/drop/abc"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], 400);
+        assert!(body["error"].as_str().unwrap().contains("abc"));
+        assert_eq!(body.as_object().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn readyz_waits_for_verified_instances() {
+        let client = reqwest::Client::new();
+
+        let base_url = serve(mock_config(), two_instances()).await;
+        let response = client
+            .get(format!("{base_url}/readyz"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], 503);
+        assert_eq!(body["error"], "Waiting for instances to be verified");
+
+        let mut instances = two_instances();
+        for instance in &mut instances {
+            instance.contemplant_verified = true;
+        }
+        let base_url = serve(mock_config(), instances).await;
+        let response = client
+            .get(format!("{base_url}/readyz"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn summary_sorts_and_filters_instances() {
+        let mut instances = vec![
+            test_instance(1, 0.3),
+            test_instance(2, 0.9),
+            test_instance(3, 0.5),
+        ];
+        instances[2].offer.geolocation = "Quebec, CA".to_string();
+        let base_url = serve(mock_config(), instances).await;
+        let client = reqwest::Client::new();
+        let overview_ids = |summary: &serde_json::Value| -> Vec<u64> {
+            summary["instance_overview"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|instance| instance["instance_id"].as_u64().unwrap())
+                .collect()
+        };
+
+        let summary: serde_json::Value = client
+            .get(format!("{base_url}/summary?sort=cost_desc"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(overview_ids(&summary), vec![2, 3, 1]);
+
+        let summary: serde_json::Value = client
+            .get(format!("{base_url}/summary?sort=cost_asc&geolocation=us"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(overview_ids(&summary), vec![1, 2]);
+        // the totals still cover every instance
+        assert_eq!(summary["num_instances"], 3);
+
+        let response = client
+            .get(format!("{base_url}/summary?sort=cheapest"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().contains("cheapest"));
+    }
+
+    #[tokio::test]
+    async fn config_leaves_out_secrets() {
+        let mut config = mock_config();
+        config.api_token = Some("s3cret-token".to_string());
+        config.contemplant.ssh_authorized_keys = Some("ssh-ed25519 AAAA operator".to_string());
+        config.extra_env = Some(HashMap::from([(
+            "HF_TOKEN".to_string(),
+            "hf_s3cret".to_string(),
+        )]));
+        let base_url = serve(config, two_instances()).await;
+        let client = reqwest::Client::new();
+
+        for path in ["/config", "/config/effective"] {
+            let body = client
+                .get(format!("{base_url}{path}"))
+                .bearer_auth("s3cret-token")
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            assert!(!body.contains("vast-key"), "{path}");
+            assert!(!body.contains("s3cret-token"), "{path}");
+            assert!(!body.contains("ssh-ed25519"), "{path}");
+            assert!(!body.contains("hf_s3cret"), "{path}");
+            assert!(body.contains("HF_TOKEN"), "{path}");
+        }
+
+        let config: serde_json::Value = client
+            .get(format!("{base_url}/config"))
+            .bearer_auth("s3cret-token")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(config["vast_api_key"], "***");
+        assert_eq!(config["api_token"], "***");
+        assert!(config["contemplant"].get("ssh_authorized_keys").is_none());
+        assert_eq!(config["extra_env"]["HF_TOKEN"], "***");
+    }
+
+    #[tokio::test]
+    async fn runway_divides_the_balance_by_live_spend() {
+        async fn current_user() -> axum::Json<serde_json::Value> {
+            axum::Json(serde_json::json!({ "credit": 96.0 }))
         }
+        let vast_router = Router::new().route("/users/current/", get(current_user));
+        let base_url = serve_with_vast(mock_config(), two_instances(), vast_router).await;
+
+        let runway: serde_json::Value = reqwest::get(format!("{base_url}/runway"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(runway["balance"], 96.0);
+        assert!((runway["total_cost_per_hour"].as_f64().unwrap() - 0.8).abs() < 1e-9);
+        assert!((runway["runway_hours"].as_f64().unwrap() - 120.0).abs() < 1e-9);
+        assert!((runway["runway_days"].as_f64().unwrap() - 5.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn manifest_round_trips_into_a_fresh_magister() {
+        let mut instances = two_instances();
+        instances[0].contemplant_verified = true;
+        instances[1].template_hash = Some("template".to_string());
+        let exporting_url = serve(mock_config(), instances).await;
+        let importing_url = serve(mock_config(), Vec::new()).await;
+        let client = reqwest::Client::new();
+        let get_manifest = |base_url: &str| {
+            let request = client.get(format!("{base_url}/manifest"));
+            async move {
+                let mut manifest: serde_json::Value =
+                    request.send().await.unwrap().json().await.unwrap();
+                let instances = manifest["instances"].as_array_mut().unwrap();
+                instances.sort_by_key(|instance| instance["instance_id"].as_u64());
+                // uptime is recomputed on each side, so may differ by a second
+                for instance in instances.iter_mut() {
+                    instance.as_object_mut().unwrap().remove("created_at");
+                }
+                manifest
+            }
+        };
+
+        let manifest = get_manifest(&exporting_url).await;
+        let imported: serde_json::Value = client
+            .post(format!("{importing_url}/manifest/import"))
+            .json(&manifest)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(imported["imported"], 2);
+
+        assert_eq!(get_manifest(&importing_url).await, manifest);
+        assert_eq!(manifest["instances"][0]["contemplant_verified"], true);
+        assert_eq!(manifest["instances"][1]["template_hash"], "template");
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_show_in_the_summary() {
+        let base_url = serve(mock_config(), two_instances()).await;
+        let client = reqwest::Client::new();
+        let paused = || async {
+            let summary: serde_json::Value = client
+                .get(format!("{base_url}/summary"))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            summary["paused"].as_bool().unwrap()
+        };
+
+        assert!(!paused().await);
+        let response = client
+            .post(format!("{base_url}/pause"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(paused().await);
+        let response = client
+            .post(format!("{base_url}/resume"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!paused().await);
+    }
+
+    #[tokio::test]
+    async fn query_returns_what_is_sent_to_vast() {
+        let mut config = mock_config();
+        let mut fallback = config.vast_query.clone();
+        fallback.gpu_name = vec!["RTX 3090".to_string()];
+        config.vast_query_fallbacks = vec![fallback.clone()];
+        let base_url = serve(config.clone(), Vec::new()).await;
+
+        let query: serde_json::Value = reqwest::get(format!("{base_url}/query"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(query["query"], config.vast_query.to_query_string());
+        assert_eq!(
+            query["fallback_queries"],
+            serde_json::json!([fallback.to_query_string()])
+        );
+        // nothing has been searched for yet
+        assert!(query["last_offer_count"].is_null());
+    }
+
+    #[tokio::test]
+    async fn drop_by_instance_id_marks_known_instances() {
+        let base_url = serve(mock_config(), two_instances()).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .delete(format!("{base_url}/instances/2"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "2 will be dropped");
+
+        let instances: Vec<serde_json::Value> = client
+            .get(format!("{base_url}/instances"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        for instance in instances {
+            let dropping = instance["instance_id"] == 2;
+            assert_eq!(instance["should_drop"], dropping, "{instance}");
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_by_unknown_instance_id_is_not_found() {
+        let base_url = serve(mock_config(), two_instances()).await;
+
+        let response = reqwest::Client::new()
+            .delete(format!("{base_url}/instances/99"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().contains("instance_id 99"));
+    }
+
+    #[tokio::test]
+    async fn summary_reports_how_long_verification_took() {
+        let created_ago = |id, secs| {
+            let mut instance = test_instance(id, 0.3);
+            instance.creation_time = tokio::time::Instant::now()
+                .checked_sub(std::time::Duration::from_secs(secs))
+                .unwrap();
+            instance
+        };
+        let instances = vec![created_ago(1, 30), created_ago(2, 90), created_ago(3, 10)];
+        let base_url = serve(mock_config(), instances).await;
+        let client = reqwest::Client::new();
+
+        for offer_id in [1, 2] {
+            let response = client
+                .get(format!("{base_url}/verify/{offer_id}"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let summary: serde_json::Value = client
+            .get(format!("{base_url}/summary"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let secs = |value: &serde_json::Value| value.as_f64().unwrap();
+        // 3 hasn't verified, so it doesn't count
+        assert!((60.0..61.0).contains(&secs(&summary["avg_time_to_verification_secs"])));
+        assert!((90.0..91.0).contains(&secs(&summary["max_time_to_verification_secs"])));
+        let overview = summary["instance_overview"].as_array().unwrap();
+        let first = overview
+            .iter()
+            .find(|instance| instance["instance_id"] == 1)
+            .unwrap();
+        assert!((30.0..31.0).contains(&secs(&first["time_to_verification_secs"])));
+    }
+
+    #[tokio::test]
+    async fn verify_works_with_and_without_contemplant_info() {
+        let base_url = serve(mock_config(), two_instances()).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("{base_url}/verify/1"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client
+            .post(format!("{base_url}/verify/2"))
+            .body(r#"{"name": "prover-2", "gpu": "RTX 4090", "moongate_version": "1.2.0"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client
+            .post(format!("{base_url}/verify/1"))
+            .body("not json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // both count as verified
+        let response = client
+            .get(format!("{base_url}/readyz"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let instances: Vec<serde_json::Value> = client
+            .get(format!("{base_url}/instances"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        for instance in instances {
+            match instance["instance_id"].as_u64().unwrap() {
+                1 => assert!(instance["contemplant_info"].is_null()),
+                _ => {
+                    assert_eq!(instance["contemplant_info"]["name"], "prover-2");
+                    assert_eq!(instance["contemplant_info"]["gpu"], "RTX 4090");
+                    assert_eq!(instance["contemplant_info"]["moongate_version"], "1.2.0");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn summary_groups_instances_by_gpu_and_region() {
+        let instance = |id, dph, gpu_name: &str, geolocation: &str| {
+            let mut instance = test_instance(id, dph);
+            instance.offer.gpu_name = gpu_name.to_string();
+            instance.offer.geolocation = geolocation.to_string();
+            instance
+        };
+        let mut dropping = instance(5, 2.0, "H100", "Oregon, US");
+        dropping.should_drop = true;
+        let instances = vec![
+            instance(1, 0.25, "RTX 4090", "Oregon, US"),
+            instance(2, 0.5, "RTX 4090", "Quebec, CA"),
+            instance(3, 0.75, "RTX 4090", "Oregon, US"),
+            instance(4, 1.5, "A100", "Quebec, CA"),
+            dropping,
+        ];
+        let base_url = serve(mock_config(), instances).await;
+
+        let summary: serde_json::Value = reqwest::get(format!("{base_url}/summary"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let group = |grouping: &str, key: &str| {
+            let group = &summary[grouping][key];
+            (
+                group["count"].as_u64().unwrap(),
+                group["dph"].as_f64().unwrap(),
+            )
+        };
+
+        // 5 is about to be dropped, so it isn't counted anywhere
+        assert_eq!(summary["by_gpu"].as_object().unwrap().len(), 2);
+        assert_eq!(group("by_gpu", "RTX 4090"), (3, 1.5));
+        assert_eq!(group("by_gpu", "A100"), (1, 1.5));
+        assert_eq!(summary["by_region"].as_object().unwrap().len(), 2);
+        assert_eq!(group("by_region", "Oregon, US"), (2, 1.0));
+        assert_eq!(group("by_region", "Quebec, CA"), (2, 2.0));
+    }
+
+    #[tokio::test]
+    async fn max_dph_changes_the_live_query() {
+        let config = mock_config();
+        let base_url = serve(config.clone(), Vec::new()).await;
+        let client = reqwest::Client::new();
+        let set_max_dph = |cost_per_hour: f64| {
+            client
+                .put(format!("{base_url}/max-dph"))
+                .json(&serde_json::json!({ "cost_per_hour": cost_per_hour }))
+                .send()
+        };
+
+        let response = set_max_dph(0.8).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["cost_per_hour"], 0.8);
+
+        for rejected in [0.0, -1.0] {
+            let response = set_max_dph(rejected).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{rejected}");
+        }
+
+        let query: serde_json::Value = reqwest::get(format!("{base_url}/query"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let mut expected = config.vast_query.clone();
+        expected.cost_per_hour = 0.8;
+        assert_eq!(query["query"], expected.to_query_string());
+        let sent: serde_json::Value =
+            serde_json::from_str(query["query"].as_str().unwrap()).unwrap();
+        assert_eq!(sent["dph_total"]["lte"], 0.8);
+    }
+
+    #[tokio::test]
+    async fn ssh_command_for_instances_vast_reported_a_port_for() {
+        let mut reachable = test_instance(1, 0.3);
+        reachable.offer.public_ipaddr = "5.6.7.8".to_string();
+        reachable.ports = Some(serde_json::json!({
+            "2222/tcp": [{ "HostIp": "0.0.0.0", "HostPort": "40022" }],
+        }));
+        let mut no_ports_yet = test_instance(2, 0.3);
+        no_ports_yet.offer.public_ipaddr = "5.6.7.9".to_string();
+        let base_url = serve(mock_config(), vec![reachable, no_ports_yet]).await;
+        let ssh =
+            |instance_id: &str| reqwest::get(format!("{base_url}/instances/{instance_id}/ssh"));
+
+        let response = ssh("1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["instance_id"], 1);
+        assert_eq!(body["host"], "5.6.7.8");
+        assert_eq!(body["port"], 40022);
+        assert_eq!(
+            body["command"],
+            format!(
+                "ssh -p 40022 {}@5.6.7.8",
+                crate::types::CONTEMPLANT_SSH_USER
+            )
+        );
+
+        assert_eq!(ssh("2").await.unwrap().status(), StatusCode::CONFLICT);
+        assert_eq!(ssh("99").await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(ssh("abc").await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn drop_reason_in_the_body_is_recorded() {
+        let base_url = serve(mock_config(), two_instances()).await;
+        let client = reqwest::Client::new();
+
+        for (offer_id, body) in [(1, " unhealthy\n"), (2, "host went away")] {
+            let response = client
+                .delete(format!("{base_url}/drop/{offer_id}"))
+                .body(body)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let drops: Vec<serde_json::Value> = reqwest::get(format!("{base_url}/drops"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let reasons: Vec<(u64, &str)> = drops
+            .iter()
+            .map(|drop| {
+                (
+                    drop["offer_id"].as_u64().unwrap(),
+                    drop["reason"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        // free text isn't a reason, so it's recorded as manual
+        assert_eq!(reasons, vec![(1, "unhealthy"), (2, "manual")]);
     }
 }
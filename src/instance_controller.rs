@@ -1,37 +1,122 @@
-use crate::{config::Config, types::VastInstance, vast::VastClient};
+use crate::{
+    config::Config,
+    drop_history::{DropEvent, DropHistory, DropReason},
+    logging::{log_instance, log_offer},
+    offer_filter::{LiveUsage, OfferFilter, VerificationStats, spread_across_hosts},
+    scale_history::{ScaleEvent, ScaleHistory},
+    state::StateFile,
+    types::{
+        ContemplantInfo, ManifestInstance, Offer, RunwayResponse, VastInstance,
+        VastResponseInstance, total_cost_per_hour,
+    },
+    vast::{CreateInstanceOutcome, VastClient},
+};
 use anyhow::{Context, Result};
 use axum::http::StatusCode;
-use log::{debug, error, info, warn};
-use std::collections::{HashMap, HashSet};
+use log::{Level, debug, error, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 use tokio::{
     sync::{mpsc, oneshot},
+    task::JoinSet,
     time::{Duration, Instant, interval},
 };
 
+// how long a Contemplant has to answer a health check
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// how often idle connections to Contemplants are checked for having silently died
+const PROBE_TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+// a template is warned about once at least this many of its instances have finished verifying
+// one way or the other, and fewer than BAD_TEMPLATE_SUCCESS_RATE of them verified
+const BAD_TEMPLATE_MIN_OUTCOMES: u32 = 5;
+const BAD_TEMPLATE_SUCCESS_RATE: f64 = 0.5;
+
 #[derive(Clone)]
 pub struct InstanceControllerClient {
     sender: mpsc::Sender<InstanceControllerCommand>,
+    // set by the controller, read without a round trip so probes stay cheap
+    ready: Arc<AtomicBool>,
 }
 
 impl InstanceControllerClient {
-    pub async fn new(config: Config) -> Result<Self> {
-        let vast_client = VastClient::new(config.clone());
-
+    pub async fn new(config: Config, vast_client: Arc<VastClient>) -> Result<Self> {
         let (sender, receiver) = mpsc::channel(100);
-        let controller = InstanceController::initialize(vast_client, config.clone(), receiver)
-            .await
-            .context("Initialize InstanceController")?;
+        let ready = Arc::new(AtomicBool::new(false));
+        let controller =
+            InstanceController::initialize(vast_client, config.clone(), receiver, ready.clone())
+                .await
+                .context("Initialize InstanceController")?;
 
         let sender_clone = sender.clone();
         tokio::task::spawn(async move { controller.background_event_loop(sender_clone).await });
 
-        Ok(Self { sender })
+        Ok(Self { sender, ready })
+    }
+
+    // Stops the controller once every command sent before this one has been handled, including a
+    // polling cycle that's already underway.  Returns when it has stopped.
+    pub async fn shutdown(&self) -> Result<()> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::Shutdown { resp_sender };
+        self.sender.send(command).await?;
+
+        receiver.await?;
+
+        Ok(())
+    }
+
+    // whether desired_instances instances have all been verified at some point since startup
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
     }
 
-    pub async fn drop(&self, offer_id: u64) -> Result<Result<String, StatusCode>> {
+    pub async fn drop(
+        &self,
+        offer_id: u64,
+        reason: DropReason,
+    ) -> Result<Result<String, StatusCode>> {
         let (resp_sender, receiver) = oneshot::channel();
         let command = InstanceControllerCommand::Drop {
             offer_id,
+            reason,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let resp = receiver.await?;
+
+        Ok(resp)
+    }
+
+    // Marks every instance to be dropped and stops provisioning replacements, if confirm matches
+    // how many instances there are.  Returns how many were newly marked, or Err with how many
+    // there are if confirm didn't match.
+    pub async fn drop_all(&self, confirm: Option<usize>) -> Result<Result<usize, usize>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::DropAll {
+            confirm,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let marked = receiver.await?;
+
+        Ok(marked)
+    }
+
+    // like drop, but addresses the instance by the instance_id (contract id) Vast's console shows
+    pub async fn drop_by_instance_id(
+        &self,
+        instance_id: u64,
+    ) -> Result<Result<String, StatusCode>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::DropByInstanceId {
+            instance_id,
             resp_sender,
         };
         self.sender.send(command).await?;
@@ -41,6 +126,39 @@ impl InstanceControllerClient {
         Ok(resp)
     }
 
+    // how instances from each template have verified, keyed by template hash
+    pub async fn template_stats(&self) -> Result<HashMap<String, VerificationStats>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::GetTemplateStats { resp_sender };
+        self.sender.send(command).await?;
+
+        let template_stats = receiver.await?;
+
+        Ok(template_stats)
+    }
+
+    // the most recent changes to the desired count, oldest first
+    pub async fn scale_events(&self) -> Result<Vec<ScaleEvent>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::GetScaleEvents { resp_sender };
+        self.sender.send(command).await?;
+
+        let scale_events = receiver.await?;
+
+        Ok(scale_events)
+    }
+
+    // the most recent drops, oldest first
+    pub async fn drops(&self) -> Result<Vec<DropEvent>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::GetDrops { resp_sender };
+        self.sender.send(command).await?;
+
+        let drops = receiver.await?;
+
+        Ok(drops)
+    }
+
     pub async fn instances(&self) -> Result<Vec<VastInstance>> {
         let (resp_sender, receiver) = oneshot::channel();
         let command = InstanceControllerCommand::GetAll { resp_sender };
@@ -51,8 +169,128 @@ impl InstanceControllerClient {
         Ok(instances)
     }
 
-    pub async fn verify(&self, offer_id: u64) -> Result<()> {
-        let command = InstanceControllerCommand::VerifyInstance { offer_id };
+    // adopt instances created by another Magister.  Returns how many weren't already tracked.
+    pub async fn import(&self, instances: Vec<VastInstance>) -> Result<usize> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::Import {
+            instances,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let imported = receiver.await?;
+
+        Ok(imported)
+    }
+
+    // mark (or with good = false, unmark) a host as good at runtime.  Returns whether anything
+    // changed.
+    pub async fn set_good_host(&self, host_id: u64, good: bool) -> Result<bool> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::SetGoodHost {
+            host_id,
+            good,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let changed = receiver.await?;
+
+        Ok(changed)
+    }
+
+    // mark (or with good = false, unmark) a machine as good at runtime.  Returns whether anything
+    // changed.
+    pub async fn set_good_machine(&self, machine_id: u64, good: bool) -> Result<bool> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::SetGoodMachine {
+            machine_id,
+            good,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let changed = receiver.await?;
+
+        Ok(changed)
+    }
+
+    // change how many instances to maintain.  Err holds why the count was rejected.
+    // returns the count actually applied, which hard_max_instances may have lowered
+    // reason is recorded with the change in the scale history
+    pub async fn set_desired_count(
+        &self,
+        count: usize,
+        reason: Option<String>,
+    ) -> Result<Result<usize, String>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::SetDesiredCount {
+            count,
+            reason,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let resp = receiver.await?;
+
+        Ok(resp)
+    }
+
+    // change the primary query's cost_per_hour until restarted.  Err holds why it was rejected.
+    pub async fn set_max_dph(&self, cost_per_hour: f64) -> Result<Result<f64, String>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::SetMaxDph {
+            cost_per_hour,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let resp = receiver.await?;
+
+        Ok(resp)
+    }
+
+    // Reboots the instance through Vast instead of dropping it, giving its Contemplant a fresh
+    // verification window.  Err holds the status and why it failed.
+    pub async fn reboot(&self, instance_id: u64) -> Result<Result<(), (StatusCode, String)>> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::Reboot {
+            instance_id,
+            resp_sender,
+        };
+        self.sender.send(command).await?;
+
+        let resp = receiver.await?;
+
+        Ok(resp)
+    }
+
+    pub async fn is_paused(&self) -> Result<bool> {
+        let (resp_sender, receiver) = oneshot::channel();
+        let command = InstanceControllerCommand::IsPaused { resp_sender };
+        self.sender.send(command).await?;
+
+        let paused = receiver.await?;
+
+        Ok(paused)
+    }
+
+    // pause or resume provisioning and verification drops
+    pub async fn set_paused(&self, paused: bool) -> Result<()> {
+        let command = InstanceControllerCommand::SetPaused(paused);
+        self.sender.send(command).await?;
+        Ok(())
+    }
+
+    pub async fn verify(
+        &self,
+        offer_id: u64,
+        contemplant_info: Option<ContemplantInfo>,
+    ) -> Result<()> {
+        let command = InstanceControllerCommand::VerifyInstance {
+            offer_id,
+            contemplant_info,
+        };
         self.sender.send(command).await?;
         Ok(())
     }
@@ -61,46 +299,208 @@ impl InstanceControllerClient {
 pub struct InstanceController {
     // mapping instance_id -> VastInstance
     instances: HashMap<u64, VastInstance>,
-    // Keeps track of the last dropped instance machine_id so it isn't re-requested in the common
-    // scenario where there is only 1 instance.
-    last_dropped: u64,
-    vast_client: VastClient,
+    // good/bad lists and the last dropped machine, used to filter and order offers
+    offer_filter: OfferFilter,
+    // the most recent config.drop_history_size instances marked to be dropped, and why
+    drop_history: DropHistory,
+    // the most recent config.scale_history_size changes to desired_instances
+    scale_history: ScaleHistory,
+    // how instances launched from each template have turned out, keyed by template hash
+    template_stats: HashMap<String, VerificationStats>,
+    // Whether we've already warned that the account balance is about to run out
+    runway_alerted: bool,
+    // How many instances to maintain.  Starts at config.number_instances and can be changed at
+    // runtime.
+    desired_instances: usize,
+    // When set, dropped instances aren't replaced, the instance count isn't topped up, and
+    // unverified or stuck instances aren't dropped.  Zombie cleanup and requested drops still run.
+    paused: bool,
+    // instances with a reboot request to Vast in flight
+    rebooting: HashSet<u64>,
+    // Set once desired_instances instances have been verified at the same time.  Never unset.
+    ready: Arc<AtomicBool>,
+    // when Contemplant health was last checked
+    last_health_check: Instant,
+    // whether polling cycles are being skipped because Vast looks down, so it's only logged once
+    skipping_while_vast_down: bool,
+    // When we first had fewer live instances than desired, and whether we've alerted about it
+    under_capacity_since: Option<Instant>,
+    under_capacity_alerted: bool,
+    // for alert webhooks
+    http_client: reqwest::Client,
+    // for Contemplant health checks, keeping connections open between checks
+    probe_client: reqwest::Client,
+    vast_client: Arc<VastClient>,
     receiver: mpsc::Receiver<InstanceControllerCommand>,
     config: Config,
 }
 
 impl InstanceController {
     pub async fn initialize(
-        vast_client: VastClient,
+        vast_client: Arc<VastClient>,
         config: Config,
         receiver: mpsc::Receiver<InstanceControllerCommand>,
+        ready: Arc<AtomicBool>,
     ) -> Result<Self> {
+        let mut offer_filter = OfferFilter::new(&config);
+        let mut instances = HashMap::new();
+        let mut template_stats = HashMap::new();
+
+        // adopt instances from before a restart instead of orphaning them
+        if config.persist_state
+            && let Some(state) =
+                StateFile::load(&config.state_file).context("Load persisted state")?
+        {
+            offer_filter.set_host_stats(state.host_stats);
+            template_stats = state.template_stats;
+            instances = Self::reconcile_persisted_instances(&vast_client, state.instances)
+                .await
+                .context("Reconcile persisted instances")?;
+            info!(
+                "Recovered {} instances from {}",
+                instances.len(),
+                config.state_file
+            );
+        }
+
         // create initial instances
-        let desired_instances = config.number_instances;
-        info!("Creating initial {desired_instances} instances.  Please wait...");
-        let start = Instant::now();
-        let instances = vast_client
-            .create_initial_instances(desired_instances)
-            .await
-            .context("Initial instance creation")?;
-        let instances = instances.into_iter().collect();
+        let desired_instances = config.number_instances.saturating_sub(instances.len());
+        if desired_instances > 0 {
+            info!("Creating initial {desired_instances} instances.  Please wait...");
+            let start = Instant::now();
+            let new_instances = vast_client
+                .create_initial_instances(desired_instances, &offer_filter)
+                .await
+                .context("Initial instance creation")?;
+            let created = new_instances.len();
+            instances.extend(new_instances);
 
-        let elapsed = start.elapsed().as_secs_f32();
-        info!("Created initial {desired_instances} instances in {elapsed:.2} seconds");
+            let elapsed = start.elapsed().as_secs_f32();
+            info!("Created initial {created} instances in {elapsed:.2} seconds");
+        }
+
+        let http_client = reqwest::Client::builder()
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .build()
+            .context("Build controller http client")?;
+        let probe_client = build_probe_client(&config).context("Build Contemplant probe client")?;
 
-        Ok(Self {
+        let controller = Self {
             instances,
-            last_dropped: 0,
+            offer_filter,
+            drop_history: DropHistory::new(config.drop_history_size),
+            scale_history: ScaleHistory::new(config.scale_history_size),
+            template_stats,
+            runway_alerted: false,
+            desired_instances: config.number_instances,
+            paused: false,
+            rebooting: HashSet::new(),
+            ready,
+            last_health_check: Instant::now(),
+            skipping_while_vast_down: false,
+            under_capacity_since: None,
+            under_capacity_alerted: false,
+            http_client,
+            probe_client,
             vast_client,
             receiver,
             config,
-        })
+        };
+        // instances recovered from before a restart may already be verified
+        controller.update_ready();
+        controller.persist_state();
+
+        Ok(controller)
+    }
+
+    // marks this Magister ready the first time enough instances are verified, across at least
+    // config.min_distinct_hosts hosts
+    fn update_ready(&self) {
+        if self.ready.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let verified: Vec<&VastInstance> = self
+            .instances
+            .values()
+            .filter(|instance| instance.contemplant_verified && !instance.should_drop)
+            .collect();
+        let verified_hosts = verified
+            .iter()
+            .map(|instance| instance.offer.host_id)
+            .collect::<HashSet<_>>()
+            .len();
+        if verified.len() >= self.desired_instances
+            && verified_hosts >= self.config.min_distinct_hosts.unwrap_or(0)
+        {
+            info!(
+                "{} instances verified across {verified_hosts} hosts.  Magister is ready",
+                verified.len()
+            );
+            self.ready.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // keeps persisted instances that Vast still knows about.  Like in
+    // correct_active_instance_count, any that are gone were dropped outside of this Magister.
+    async fn reconcile_persisted_instances(
+        vast_client: &VastClient,
+        persisted: Vec<ManifestInstance>,
+    ) -> Result<HashMap<u64, VastInstance>> {
+        let returned_instance_ids: HashSet<u64> = vast_client
+            .get_instances()
+            .await
+            .context("Get instances from Vast")?
+            .into_iter()
+            .map(|instance| instance.id)
+            .collect();
+
+        let mut instances = HashMap::new();
+        for persisted_instance in persisted {
+            let instance: VastInstance = persisted_instance.into();
+            let instance_id = instance.instance_id;
+            if returned_instance_ids.contains(&instance_id) {
+                instances.insert(instance_id, instance);
+            } else {
+                log_instance!(
+                    Level::Info,
+                    instance,
+                    "Instance id {instance_id} {instance} was dropped while this Magister was down.  Removing it from Magister state."
+                );
+            }
+        }
+
+        Ok(instances)
+    }
+
+    // save instances and host stats so a restart can pick up where we left off
+    fn persist_state(&self) {
+        if !self.config.persist_state {
+            return;
+        }
+
+        let state = StateFile {
+            instances: self
+                .instances
+                .values()
+                .cloned()
+                .map(|instance| instance.into())
+                .collect(),
+            host_stats: self.offer_filter.host_stats().clone(),
+            template_stats: self.template_stats.clone(),
+        };
+        if let Err(e) = state.save(&self.config.state_file) {
+            warn!("Error saving state to {}: {e}", self.config.state_file);
+        }
     }
 
     async fn background_event_loop(
-        mut self,
+        self,
         sender: mpsc::Sender<InstanceControllerCommand>,
     ) -> Result<()> {
+        // slow Vast calls made off the loop report back through this
+        let followup_sender = sender.clone();
+
         // runs a cleanup task every 30 seconds
         tokio::spawn(async move {
             let mut interval =
@@ -117,15 +517,56 @@ impl InstanceController {
             }
         });
 
+        self.handle_commands(followup_sender).await
+    }
+
+    // handles all tasks and holds state until shut down
+    async fn handle_commands(
+        mut self,
+        followup_sender: mpsc::Sender<InstanceControllerCommand>,
+    ) -> Result<()> {
+        // who to tell once the queue is drained after a shutdown request
+        let mut shutdown_resp_sender = None;
+
         // handles all tasks and holds state
         while let Some(command) = self.receiver.recv().await {
             match command {
+                InstanceControllerCommand::GetScaleEvents { resp_sender } => {
+                    if resp_sender.send(self.scale_history.events()).is_err() {
+                        error!("Get scale events response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
+                InstanceControllerCommand::GetTemplateStats { resp_sender } => {
+                    if resp_sender.send(self.template_stats.clone()).is_err() {
+                        error!("Get template stats response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
                 InstanceControllerCommand::HandleUnfinishedBusiness => {
+                    // every call would fail without reaching Vast, so wait for it to come back
+                    if self.vast_client.vast_down() {
+                        if !self.skipping_while_vast_down {
+                            warn!(
+                                "Vast appears down, backing off.  Skipping polling cycles until it's back"
+                            );
+                            self.skipping_while_vast_down = true;
+                        }
+                        continue;
+                    }
+                    if self.skipping_while_vast_down {
+                        info!("Resuming polling cycles");
+                        self.skipping_while_vast_down = false;
+                    }
+
                     self.correct_active_instance_count().await;
 
-                    self.check_contemplant_verification().await;
+                    self.shed_over_budget();
 
-                    let mut instances_dropped = Vec::new();
+                    if !self.paused {
+                        self.check_contemplant_verification().await;
+                        self.rotate_old_instances();
+                    }
 
                     let instances_clone = self.instances.clone();
                     for (instance_id, instance) in instances_clone {
@@ -136,41 +577,42 @@ impl InstanceController {
 
                         match self.vast_client.drop_instance(instance_id).await {
                             Ok(_) => {
-                                info!("Dropped {instance}");
-                                instances_dropped.push(instance_id);
-                            }
-                            Err(e) => {
-                                warn!(
-                                    "Error on attempt to drop {instance}.  Will try again later. {e}"
-                                );
+                                log_instance!(Level::Info, instance, "Dropped {instance}");
+                                self.instances.remove(&instance_id);
+                                self.replace_instance(instance).await;
                             }
+                            Err(e) => self.record_drop_failure(instance_id, e).await,
                         }
                     }
 
-                    self.instances
-                        .retain(|instance_id, _| !instances_dropped.contains(instance_id));
-
                     self.ensure_sufficient_instances().await;
+
+                    self.check_runway().await;
+
+                    self.check_capacity().await;
+
+                    self.persist_state();
                 }
                 InstanceControllerCommand::Drop {
                     offer_id,
+                    reason,
                     resp_sender,
                 } => {
                     let mut target_instance: Option<u64> = None;
                     // find the instance based on offer_id
                     for (instance_id, instance) in self.instances.iter_mut() {
                         if instance.offer.id == offer_id {
-                            instance.should_drop = true;
+                            self.drop_history.mark_for_drop(instance, reason);
                             target_instance = Some(*instance_id);
                             // This should probably happen after we successfully drop it
-                            self.last_dropped = instance.offer.machine_id;
+                            self.offer_filter.last_dropped = instance.offer.machine_id;
                             break;
                         }
                     }
 
                     let resp = match target_instance {
                         Some(instance_id) => {
-                            debug!("Marking {instance_id} to be dropped");
+                            debug!(instance_id; "Marking {instance_id} to be dropped");
                             Ok(format!("{instance_id} will be dropped"))
                         }
                         None => {
@@ -181,57 +623,426 @@ impl InstanceController {
                         }
                     };
 
+                    self.persist_state();
+
                     if resp_sender.send(resp).is_err() {
                         error!("Drop response receiver out of scope.  Exiting");
                         break;
                     }
                 }
+                InstanceControllerCommand::DropAll {
+                    confirm,
+                    resp_sender,
+                } => {
+                    let resp = self.drop_all(confirm);
+
+                    self.persist_state();
+
+                    if resp_sender.send(resp).is_err() {
+                        error!("Drop all response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
+                InstanceControllerCommand::DropByInstanceId {
+                    instance_id,
+                    resp_sender,
+                } => {
+                    let resp = match self.instances.get_mut(&instance_id) {
+                        Some(instance) => {
+                            self.drop_history
+                                .mark_for_drop(instance, DropReason::Manual);
+                            self.offer_filter.last_dropped = instance.offer.machine_id;
+                            debug!(instance_id; "Marking {instance_id} to be dropped");
+                            Ok(format!("{instance_id} will be dropped"))
+                        }
+                        None => {
+                            warn!(
+                                "Attempted to drop instance_id {instance_id} but it isn't known to this magister.  Skipping request."
+                            );
+                            Err(StatusCode::NOT_FOUND)
+                        }
+                    };
+
+                    self.persist_state();
+
+                    if resp_sender.send(resp).is_err() {
+                        error!("Drop by instance id response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
                 InstanceControllerCommand::GetAll { resp_sender } => {
                     if resp_sender.send(self.instances.clone()).is_err() {
                         error!("Get all instances response receiver dropped.  Exiting");
                         break;
                     }
                 }
-                InstanceControllerCommand::VerifyInstance { offer_id } => {
-                    for (_, instance) in self.instances.iter_mut() {
-                        if instance.offer.id == offer_id {
-                            debug!("Instance {instance} with offer_id {offer_id} verified!");
-                            instance.contemplant_verified = true;
-                            break;
+                InstanceControllerCommand::GetDrops { resp_sender } => {
+                    if resp_sender.send(self.drop_history.events()).is_err() {
+                        error!("Get drops response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
+                InstanceControllerCommand::Import {
+                    instances,
+                    resp_sender,
+                } => {
+                    let mut imported = 0;
+                    for instance in instances {
+                        let instance_id = instance.instance_id;
+                        if self.instances.contains_key(&instance_id) {
+                            log_instance!(
+                                Level::Debug,
+                                instance,
+                                "Instance {instance} is already tracked.  Skipping import."
+                            );
+                            continue;
                         }
+                        log_instance!(Level::Info, instance, "Imported {instance}");
+                        self.instances.insert(instance_id, instance);
+                        imported += 1;
+                    }
+
+                    self.persist_state();
+
+                    if resp_sender.send(imported).is_err() {
+                        error!("Import response receiver dropped.  Exiting");
+                        break;
                     }
                 }
-            }
-        }
+                InstanceControllerCommand::IsPaused { resp_sender } => {
+                    if resp_sender.send(self.paused).is_err() {
+                        error!("Is paused response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
+                InstanceControllerCommand::Reboot {
+                    instance_id,
+                    resp_sender,
+                } => {
+                    self.start_reboot(instance_id, resp_sender, followup_sender.clone());
+                }
+                InstanceControllerCommand::RebootFinished {
+                    instance_id,
+                    result,
+                    resp_sender,
+                } => {
+                    let resp = self.finish_reboot(instance_id, result);
 
-        Ok(())
-    }
+                    self.persist_state();
 
-    async fn check_contemplant_verification(&mut self) {
-        // If we haven't heard the initialization ping from the contemplant within
-        // <contemplant_verification_timeout_secs>, drop the instance
-        for (instance_id, instance) in self.instances.iter_mut() {
-            // if it's not verified
-            if !instance.contemplant_verified {
-                // and it's been longer than contemplant_verification_timeout_secs
-                let time_since_creation = instance.creation_time.elapsed();
-                if time_since_creation
-                    > Duration::from_secs(self.config.contemplant_verification_timeout_secs)
-                {
-                    warn!(
-                        "{instance} with id {instance_id} was created {:.2} seconds ago but hasn't yet been verified.  Dropping.",
-                        time_since_creation.as_secs_f32()
+                    // the caller may have hung up while Vast was rebooting it, which is fine
+                    if resp_sender.send(resp).is_err() {
+                        warn!(instance_id; "Reboot response receiver dropped");
+                    }
+                }
+                InstanceControllerCommand::SetPaused(paused) => {
+                    if paused {
+                        info!("Provisioning paused");
+                    } else {
+                        info!("Provisioning resumed");
+                    }
+                    self.paused = paused;
+                }
+                InstanceControllerCommand::SetDesiredCount {
+                    count,
+                    reason,
+                    resp_sender,
+                } => {
+                    self.start_set_desired_count(
+                        count,
+                        reason,
+                        resp_sender,
+                        followup_sender.clone(),
                     );
-                    instance.should_drop = true;
                 }
-            }
-        }
-    }
+                InstanceControllerCommand::DesiredCountOffersFound {
+                    count,
+                    reason,
+                    offers,
+                    resp_sender,
+                } => {
+                    let resp = self.finish_set_desired_count(count, reason, offers);
 
-    // compare our instances to the instances Vast is aware of
-    async fn correct_active_instance_count(&mut self) {
-        let returned_instance_ids: HashSet<u64> = match self.vast_client.get_instances().await {
-            Ok(x) => x.into_iter().collect(),
+                    self.persist_state();
+
+                    // the caller may have hung up while offers were being searched for
+                    if resp_sender.send(resp).is_err() {
+                        warn!("Set desired count response receiver dropped");
+                    }
+                }
+                InstanceControllerCommand::SetGoodHost {
+                    host_id,
+                    good,
+                    resp_sender,
+                } => {
+                    let changed = if good {
+                        self.offer_filter.mark_good_host(host_id)
+                    } else {
+                        self.offer_filter.unmark_good_host(host_id)
+                    };
+                    info!("Host {host_id} good: {good}");
+
+                    if resp_sender.send(changed).is_err() {
+                        error!("Set good host response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
+                InstanceControllerCommand::SetGoodMachine {
+                    machine_id,
+                    good,
+                    resp_sender,
+                } => {
+                    let changed = if good {
+                        self.offer_filter.mark_good_machine(machine_id)
+                    } else {
+                        self.offer_filter.unmark_good_machine(machine_id)
+                    };
+                    info!("Machine {machine_id} good: {good}");
+
+                    if resp_sender.send(changed).is_err() {
+                        error!("Set good machine response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
+                InstanceControllerCommand::SetMaxDph {
+                    cost_per_hour,
+                    resp_sender,
+                } => {
+                    let resp = if cost_per_hour.is_finite() && cost_per_hour > 0.0 {
+                        self.vast_client.set_cost_per_hour(cost_per_hour);
+                        info!("cost_per_hour is now ${cost_per_hour:.2}/hour");
+                        Ok(cost_per_hour)
+                    } else {
+                        Err(format!(
+                            "cost_per_hour must be above 0, not {cost_per_hour}"
+                        ))
+                    };
+
+                    if resp_sender.send(resp).is_err() {
+                        error!("Set max dph response receiver dropped.  Exiting");
+                        break;
+                    }
+                }
+                InstanceControllerCommand::Shutdown { resp_sender } => {
+                    info!("Instance controller shutting down once queued commands are handled");
+                    // recv keeps returning what's already queued, then None
+                    self.receiver.close();
+                    shutdown_resp_sender = Some(resp_sender);
+                }
+                InstanceControllerCommand::VerifyInstance {
+                    offer_id,
+                    contemplant_info,
+                } => {
+                    for (_, instance) in self.instances.iter_mut() {
+                        if instance.offer.id == offer_id {
+                            log_instance!(
+                                Level::Debug,
+                                instance,
+                                "Instance {instance} with offer_id {offer_id} verified!"
+                            );
+                            if !instance.contemplant_verified {
+                                self.offer_filter.record_verified(instance.offer.host_id);
+                                if let Some(ref template_hash) = instance.template_hash {
+                                    self.template_stats
+                                        .entry(template_hash.clone())
+                                        .or_default()
+                                        .verified += 1;
+                                }
+                                let time_to_verification = instance.uptime();
+                                instance.time_to_verification = Some(time_to_verification);
+                                log_instance!(
+                                    Level::Info,
+                                    instance,
+                                    "Instance {instance} verified {} seconds after creation",
+                                    time_to_verification.as_secs()
+                                );
+                            }
+                            instance.contemplant_verified = true;
+                            if contemplant_info.is_some() {
+                                instance.contemplant_info = contemplant_info;
+                            }
+                            break;
+                        }
+                    }
+
+                    self.update_ready();
+                    self.persist_state();
+                }
+            }
+        }
+
+        self.persist_state();
+        if let Some(resp_sender) = shutdown_resp_sender {
+            info!("Instance controller shutdown complete");
+            let _ = resp_sender.send(());
+        }
+
+        Ok(())
+    }
+
+    async fn check_contemplant_verification(&mut self) {
+        // If we haven't heard the initialization ping from the contemplant within
+        // <contemplant_verification_timeout_secs>, drop the instance
+        for (instance_id, instance) in self.instances.iter_mut() {
+            // if it's not verified
+            if !instance.contemplant_verified {
+                // and it's been longer than contemplant_verification_timeout_secs
+                let time_waiting = instance.verification_started.elapsed();
+                if time_waiting
+                    > Duration::from_secs(self.config.contemplant_verification_timeout_secs)
+                {
+                    log_instance!(
+                        Level::Warn,
+                        instance,
+                        "{instance} with id {instance_id} has waited {:.2} seconds but hasn't yet been verified.  Dropping.",
+                        time_waiting.as_secs_f32()
+                    );
+                    if self
+                        .drop_history
+                        .mark_for_drop(instance, DropReason::VerificationTimeout)
+                    {
+                        self.offer_filter.record_unverified(instance.offer.host_id);
+                        if let Some(ref template_hash) = instance.template_hash {
+                            let stats = self
+                                .template_stats
+                                .entry(template_hash.clone())
+                                .or_default();
+                            stats.unverified += 1;
+                            if stats.verified + stats.unverified >= BAD_TEMPLATE_MIN_OUTCOMES
+                                && stats.success_rate() < BAD_TEMPLATE_SUCCESS_RATE
+                            {
+                                warn!(
+                                    "Only {} of {} instances from template {template_hash} have verified.  The template may be broken.",
+                                    stats.verified,
+                                    stats.verified + stats.unverified
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // warn once when the account balance will run out within config.runway_alert_hours
+    async fn check_runway(&mut self) {
+        let Some(alert_hours) = self.config.runway_alert_hours else {
+            return;
+        };
+
+        let balance = match self.vast_client.get_balance().await {
+            Ok(balance) => balance,
+            Err(e) => {
+                warn!("Error getting Vast account balance: {e}.  Will try again later.");
+                return;
+            }
+        };
+
+        let total_dph = total_cost_per_hour(self.instances.values());
+        let runway = RunwayResponse::new(balance, total_dph);
+
+        match runway.runway_hours {
+            Some(hours) if hours < alert_hours => {
+                if !self.runway_alerted {
+                    self.alert(
+                        Level::Warn,
+                        format!(
+                            "Vast account balance ${balance:.2} will run out in {hours:.1} hours at ${total_dph:.2}/hour"
+                        ),
+                    )
+                    .await;
+                    self.runway_alerted = true;
+                }
+            }
+            _ => {
+                if self.runway_alerted {
+                    self.alert(
+                        Level::Info,
+                        format!("Vast account runway is back above {alert_hours} hours"),
+                    )
+                    .await;
+                    self.runway_alerted = false;
+                }
+            }
+        }
+    }
+
+    // Alerts once we've been below desired_instances for config.under_capacity_alert_secs, and
+    // again once we're back.  Being under capacity while paused is expected, so it isn't counted.
+    async fn check_capacity(&mut self) {
+        if self.paused {
+            self.under_capacity_since = None;
+            return;
+        }
+
+        let live_instances = self
+            .instances
+            .values()
+            .filter(|instance| !instance.should_drop)
+            .count();
+
+        if live_instances >= self.desired_instances {
+            self.under_capacity_since = None;
+            if self.under_capacity_alerted {
+                self.alert(
+                    Level::Info,
+                    format!(
+                        "Recovered: back at {live_instances} / {} instances",
+                        self.desired_instances
+                    ),
+                )
+                .await;
+                self.under_capacity_alerted = false;
+            }
+            return;
+        }
+
+        let under_capacity_since = *self.under_capacity_since.get_or_insert_with(Instant::now);
+        let threshold = Duration::from_secs(self.config.under_capacity_alert_secs);
+        if !self.under_capacity_alerted && under_capacity_since.elapsed() >= threshold {
+            self.alert(
+                Level::Warn,
+                format!(
+                    "Only {live_instances} / {} instances for over {} seconds.  Vast may not have enough acceptable offers.",
+                    self.desired_instances,
+                    threshold.as_secs()
+                ),
+            )
+            .await;
+            self.under_capacity_alerted = true;
+        }
+    }
+
+    // logs the message and, if config.alert_webhook_url is set, POSTs it there
+    async fn alert(&self, level: Level, message: String) {
+        log::log!(level, "{message}");
+
+        let Some(ref webhook_url) = self.config.alert_webhook_url else {
+            return;
+        };
+        let body = serde_json::json!({
+            "text": format!("[{}] {message}", self.config.magister_id()),
+        });
+        match self.http_client.post(webhook_url).json(&body).send().await {
+            Ok(response) if !response.status().is_success() => {
+                warn!("Alert webhook returned {}", response.status());
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Error sending alert to webhook: {e}"),
+        }
+    }
+
+    // compare our instances to the instances Vast is aware of
+    async fn correct_active_instance_count(&mut self) {
+        let returned_instances: HashMap<u64, VastResponseInstance> = match self
+            .vast_client
+            .get_instances()
+            .await
+        {
+            Ok(x) => x
+                .into_iter()
+                .map(|instance| (instance.id, instance))
+                .collect(),
             Err(e) => {
                 warn!(
                     "Error sending command to get updated instance count: {e}.  Will try again later."
@@ -241,86 +1052,789 @@ impl InstanceController {
         };
 
         let mut zombie_instances = Vec::new();
-        // This could return instances that are running that aren't for Magister.  Vast doesn't let
-        // us query by label, so we can only use this to remove instance ids that we have running
-        // but aren't returned by the above api call
-        for (instance_id, instance) in self.instances.clone() {
+        // Only instances labeled config.instance_label are returned, so anything we have locally
+        // that's missing was removed outside of this Magister
+        for (instance_id, instance) in self.instances.iter_mut() {
+            if returned_instances.contains_key(instance_id) {
+                instance.missing_polls = 0;
+                continue;
+            }
+
+            // Vast's listing occasionally leaves out instances that are still there, so only give
+            // up on one that's been missing config.missing_instance_polls times in a row
+            instance.missing_polls += 1;
+            if instance.missing_polls < self.config.missing_instance_polls {
+                log_instance!(
+                    Level::Debug,
+                    instance,
+                    "{instance} missing from Vast's instance list {} / {} times",
+                    instance.missing_polls,
+                    self.config.missing_instance_polls
+                );
+                continue;
+            }
+
             // if vast.ai didn't return an instance we have locally then the instance was
             // removed via the vast.ai frontend, not this magister.  We should remove this from our
             // state.  It doesn't need to be dropped because it already doesn't exist in vast
-            if !returned_instance_ids.contains(&instance_id) {
-                info!(
-                    "Instance id {instance_id} {instance} was dropped by somone via the Vast.ai frontend.  Removing it from Magister state."
-                );
-                zombie_instances.push(instance_id);
+            log_instance!(
+                Level::Info,
+                instance,
+                "Instance id {instance_id} {instance} was dropped by somone via the Vast.ai frontend.  Removing it from Magister state."
+            );
+            // already recorded if we had marked it ourselves
+            if !instance.should_drop {
+                self.drop_history.record(instance, DropReason::Zombie);
             }
+            zombie_instances.push(*instance_id);
         }
 
         // only retain instances that aren't in the list of zombie_instances
         self.instances
             .retain(|instance_id, _| !zombie_instances.contains(instance_id));
+
+        // keep costs current so max_total_dph is checked against what we're actually paying, and
+        // addresses current since an instance can come back from a reboot with a new public IP
+        for (instance_id, instance) in self.instances.iter_mut() {
+            let Some(returned) = returned_instances.get(instance_id) else {
+                continue;
+            };
+
+            if let Some(public_ipaddr) = returned.public_ipaddr.as_deref().map(str::trim)
+                && !public_ipaddr.is_empty()
+                && public_ipaddr != instance.offer.public_ipaddr
+            {
+                // the offer may not have listed one
+                if !instance.offer.public_ipaddr.is_empty() {
+                    log_instance!(
+                        Level::Info,
+                        instance,
+                        "{instance} has a new public IP {public_ipaddr}, was {}",
+                        instance.offer.public_ipaddr
+                    );
+                }
+                instance.offer.public_ipaddr = public_ipaddr.to_string();
+            }
+            if returned.ports.is_some() {
+                instance.ports.clone_from(&returned.ports);
+            }
+
+            if let Some(dph_total) = returned.dph_total
+                && dph_total != instance.offer.dph_total
+            {
+                log_instance!(
+                    Level::Info,
+                    instance,
+                    "{instance} now costs ${dph_total:.2}/hour, from ${:.2}/hour",
+                    instance.offer.dph_total
+                );
+                instance.offer.dph_total = dph_total;
+            }
+        }
+
+        if !self.paused {
+            self.check_stuck_instances(&returned_instances);
+            self.check_contemplant_health(&returned_instances).await;
+        }
+    }
+
+    // Every config.health_check_interval_secs, GETs /health from each verified Contemplant and
+    // drops the ones that have failed config.health_check_failures checks in a row.  Catches
+    // Contemplants that die after verifying, which Vast still reports as running.
+    async fn check_contemplant_health(
+        &mut self,
+        returned_instances: &HashMap<u64, VastResponseInstance>,
+    ) {
+        let Some(interval_secs) = self.config.health_check_interval_secs else {
+            return;
+        };
+        if self.last_health_check.elapsed() < Duration::from_secs(interval_secs) {
+            return;
+        }
+        self.last_health_check = Instant::now();
+
+        // probe concurrently so one slow Contemplant doesn't hold up the rest
+        let mut probes = JoinSet::new();
+        for (instance_id, instance) in &self.instances {
+            if !instance.contemplant_verified || instance.should_drop {
+                continue;
+            }
+            let Some(url) = returned_instances
+                .get(instance_id)
+                .and_then(|returned| returned.health_url(self.config.contemplant.http_port))
+            else {
+                continue;
+            };
+
+            let client = self.probe_client.clone();
+            let instance_id = *instance_id;
+            probes.spawn(async move { (instance_id, probe_contemplant(&client, &url).await) });
+        }
+
+        while let Some(probe) = probes.join_next().await {
+            let Ok((instance_id, result)) = probe else {
+                continue;
+            };
+            let Some(instance) = self.instances.get_mut(&instance_id) else {
+                continue;
+            };
+
+            match result {
+                Ok(()) => instance.health_failures = 0,
+                Err(e) => {
+                    instance.health_failures += 1;
+                    if instance.health_failures >= self.config.health_check_failures {
+                        log_instance!(
+                            Level::Warn,
+                            instance,
+                            "{instance} failed {} health checks in a row.  Dropping.  {e}",
+                            instance.health_failures
+                        );
+                        self.drop_history
+                            .mark_for_drop(instance, DropReason::Unhealthy);
+                    } else {
+                        log_instance!(
+                            Level::Debug,
+                            instance,
+                            "{instance} failed health check {} / {}.  {e}",
+                            instance.health_failures,
+                            self.config.health_check_failures
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Drop instances that Vast has reported as something other than running (loading, exited,
+    // crash looping...) for longer than config.stuck_instance_timeout_secs.  These are machines
+    // that accepted the rental but never managed to boot the Contemplant.
+    fn check_stuck_instances(&mut self, returned_instances: &HashMap<u64, VastResponseInstance>) {
+        let stuck_timeout = Duration::from_secs(self.config.stuck_instance_timeout_secs);
+        for (instance_id, instance) in self.instances.iter_mut() {
+            let Some(returned_instance) = returned_instances.get(instance_id) else {
+                continue;
+            };
+
+            if returned_instance.is_running() {
+                instance.not_running_since = None;
+                continue;
+            }
+
+            let not_running_since = *instance.not_running_since.get_or_insert_with(Instant::now);
+            if !instance.should_drop && not_running_since.elapsed() > stuck_timeout {
+                log_instance!(
+                    Level::Warn,
+                    instance,
+                    "{instance} has had status {} for over {} seconds.  Dropping.",
+                    returned_instance
+                        .actual_status
+                        .as_deref()
+                        .unwrap_or("unknown"),
+                    stuck_timeout.as_secs()
+                );
+                self.drop_history.mark_for_drop(instance, DropReason::Stuck);
+            }
+        }
+    }
+
+    // host ids of every instance we aren't about to drop
+    fn live_hosts(&self) -> HashSet<u64> {
+        self.instances
+            .values()
+            .filter(|instance| !instance.should_drop)
+            .map(|instance| instance.offer.host_id)
+            .collect()
+    }
+
+    // Asks Vast to reboot the instance from a separate task, so the wait on Vast doesn't hold up
+    // the event loop.  The task reports back with RebootFinished, which answers resp_sender.
+    fn start_reboot(
+        &mut self,
+        instance_id: u64,
+        resp_sender: oneshot::Sender<Result<(), (StatusCode, String)>>,
+        followup_sender: mpsc::Sender<InstanceControllerCommand>,
+    ) {
+        if let Err(resp) = self.check_rebootable(instance_id) {
+            if resp_sender.send(Err(resp)).is_err() {
+                warn!(instance_id; "Reboot response receiver dropped");
+            }
+            return;
+        }
+        self.rebooting.insert(instance_id);
+
+        let vast_client = self.vast_client.clone();
+        tokio::spawn(async move {
+            let result = vast_client
+                .reboot_instance(instance_id)
+                .await
+                .map_err(|e| e.to_string());
+            let command = InstanceControllerCommand::RebootFinished {
+                instance_id,
+                result,
+                resp_sender,
+            };
+            if followup_sender.send(command).await.is_err() {
+                error!(instance_id; "Instance controller exited before reboot of {instance_id} finished");
+            }
+        });
+    }
+
+    fn check_rebootable(&self, instance_id: u64) -> Result<(), (StatusCode, String)> {
+        let Some(instance) = self.instances.get(&instance_id) else {
+            let err = format!("instance_id {instance_id} isn't known to this magister");
+            warn!("Attempted to reboot {err}");
+            return Err((StatusCode::NOT_FOUND, err));
+        };
+        if instance.should_drop {
+            let err = format!("instance_id {instance_id} is already being dropped");
+            warn!("Attempted to reboot {err}");
+            return Err((StatusCode::CONFLICT, err));
+        }
+        if self.rebooting.contains(&instance_id) {
+            let err = format!("instance_id {instance_id} is already being rebooted");
+            warn!("Attempted to reboot {err}");
+            return Err((StatusCode::CONFLICT, err));
+        }
+        Ok(())
+    }
+
+    // Gives the instance a fresh verification window once Vast has rebooted it
+    fn finish_reboot(
+        &mut self,
+        instance_id: u64,
+        result: Result<(), String>,
+    ) -> Result<(), (StatusCode, String)> {
+        self.rebooting.remove(&instance_id);
+        if let Err(e) = result {
+            let err = format!("Error rebooting instance {instance_id}: {e}");
+            error!("{err}");
+            return Err((StatusCode::BAD_GATEWAY, err));
+        }
+
+        // it may have been dropped while waiting on Vast
+        if let Some(instance) = self.instances.get_mut(&instance_id) {
+            instance.restart_verification();
+            log_instance!(
+                Level::Info,
+                instance,
+                "Rebooted {instance}.  Waiting for its Contemplant to verify again"
+            );
+        }
+        Ok(())
+    }
+
+    // how many instances we aren't about to drop are on each host
+    fn live_host_counts(&self) -> HashMap<u64, usize> {
+        let mut hosts = HashMap::new();
+        for instance in self
+            .instances
+            .values()
+            .filter(|instance| !instance.should_drop)
+        {
+            *hosts.entry(instance.offer.host_id).or_default() += 1;
+        }
+        hosts
+    }
+
+    // how many instances we aren't about to drop are on each machine
+    fn live_machine_counts(&self) -> HashMap<u64, usize> {
+        let mut machines = HashMap::new();
+        for instance in self
+            .instances
+            .values()
+            .filter(|instance| !instance.should_drop)
+        {
+            *machines.entry(instance.offer.machine_id).or_default() += 1;
+        }
+        machines
+    }
+
+    fn live_usage(&self) -> LiveUsage {
+        LiveUsage {
+            hosts: self.live_host_counts(),
+            machines: self.live_machine_counts(),
+            geolocations: self.live_geolocations(),
+        }
+    }
+
+    // how many instances we aren't about to drop are in each geolocation
+    fn live_geolocations(&self) -> HashMap<String, usize> {
+        let mut geolocations = HashMap::new();
+        for instance in self
+            .instances
+            .values()
+            .filter(|instance| !instance.should_drop)
+        {
+            *geolocations
+                .entry(instance.offer.geolocation.clone())
+                .or_default() += 1;
+        }
+        geolocations
+    }
+
+    // Scaling up only changes the target, which ensure_sufficient_instances then provisions up to.
+    // It's rejected if there aren't enough offers to get there.  Scaling down marks the most
+    // expensive excess instances to be dropped.
+    // Scaling down is applied right away.  Scaling up is only applied if there are offers to
+    // reach the new count, which are searched for from a separate task so the wait on Vast doesn't
+    // hold up the event loop.  The task reports back with DesiredCountOffersFound, which answers
+    // resp_sender.
+    fn start_set_desired_count(
+        &mut self,
+        count: usize,
+        reason: Option<String>,
+        resp_sender: oneshot::Sender<Result<usize, String>>,
+        followup_sender: mpsc::Sender<InstanceControllerCommand>,
+    ) {
+        let count = self.config.clamp_to_hard_max(count);
+        let needed = count.saturating_sub(self.live_instance_count());
+        if needed == 0 {
+            let resp = Ok(self.apply_desired_count(count, reason));
+            self.persist_state();
+            if resp_sender.send(resp).is_err() {
+                warn!("Set desired count response receiver dropped");
+            }
+            return;
+        }
+
+        let vast_client = self.vast_client.clone();
+        let offer_filter = self.offer_filter.clone();
+        let live = self.live_usage();
+        tokio::spawn(async move {
+            let offers = vast_client
+                .find_offers(&offer_filter, needed, &live)
+                .await
+                .map_err(|e| e.to_string());
+            let command = InstanceControllerCommand::DesiredCountOffersFound {
+                count,
+                reason,
+                offers,
+                resp_sender,
+            };
+            if followup_sender.send(command).await.is_err() {
+                error!("Instance controller exited before the desired count was checked");
+            }
+        });
+    }
+
+    // Applies count if the offers found for it, once narrowed down the way request_instances
+    // does, are enough to reach it
+    fn finish_set_desired_count(
+        &mut self,
+        count: usize,
+        reason: Option<String>,
+        offers: Result<Vec<Offer>, String>,
+    ) -> Result<usize, String> {
+        let offers =
+            offers.map_err(|e| format!("Error finding offers to validate desired count: {e}"))?;
+        // instances may have come or gone while offers were being searched for
+        let needed = count.saturating_sub(self.live_instance_count());
+        let (offers, _) = self.usable_offers(offers);
+        if offers.len() < needed {
+            return Err(format!(
+                "Only {} offers are available but {needed} more instances are needed to reach {count}",
+                offers.len()
+            ));
+        }
+
+        Ok(self.apply_desired_count(count, reason))
+    }
+
+    // Sets desired_instances, dropping the most expensive instances if there are now too many
+    fn apply_desired_count(&mut self, count: usize, reason: Option<String>) -> usize {
+        let live_instances = self.live_instance_count();
+        if count < live_instances {
+            let mut by_cost: Vec<&mut VastInstance> = self
+                .instances
+                .values_mut()
+                .filter(|instance| !instance.should_drop)
+                .collect();
+            by_cost.sort_by(|a, b| b.offer.dph_total.total_cmp(&a.offer.dph_total));
+            for instance in by_cost.into_iter().take(live_instances - count) {
+                log_instance!(
+                    Level::Info,
+                    instance,
+                    "Dropping {instance} to scale down to {count} instances"
+                );
+                self.drop_history
+                    .mark_for_drop(instance, DropReason::ScaleDown);
+            }
+        }
+
+        match reason {
+            Some(ref reason) => info!(
+                "Desired instance count changed from {} to {count}.  Reason: {reason}",
+                self.desired_instances
+            ),
+            None => info!(
+                "Desired instance count changed from {} to {count}",
+                self.desired_instances
+            ),
+        }
+        self.scale_history
+            .record(self.desired_instances, count, reason);
+        self.desired_instances = count;
+
+        count
+    }
+
+    // Marks every instance to be dropped if confirm is how many instances there are, so a
+    // request that didn't look first can't tear down the fleet.  Err holds how many there are.
+    fn drop_all(&mut self, confirm: Option<usize>) -> Result<usize, usize> {
+        let instance_count = self.instances.len();
+        if confirm != Some(instance_count) {
+            warn!(
+                "Not dropping all instances.  Confirmation {confirm:?} doesn't match the {instance_count} instances"
+            );
+            return Err(instance_count);
+        }
+
+        let mut marked = 0;
+        for instance in self.instances.values_mut() {
+            if self
+                .drop_history
+                .mark_for_drop(instance, DropReason::Manual)
+            {
+                marked += 1;
+            }
+        }
+        // otherwise the next tick would immediately replace everything we're dropping
+        self.paused = true;
+        info!("Marked {marked} instances to be dropped.  Provisioning is paused.");
+        Ok(marked)
+    }
+
+    // how many instances we aren't about to drop
+    fn live_instance_count(&self) -> usize {
+        self.instances
+            .values()
+            .filter(|instance| !instance.should_drop)
+            .count()
+    }
+
+    // Narrows found offers down to the ones that can be rented alongside the instances we have,
+    // in the order to try them.  Also returns whether max_instances_per_geolocation removed any.
+    fn usable_offers(&self, offers: Vec<Offer>) -> (Vec<Offer>, bool) {
+        let offers = self.offer_filter.exclude_used(
+            offers,
+            &self.live_host_counts(),
+            &self.live_machine_counts(),
+        );
+
+        let offer_count = offers.len();
+        let offers = self
+            .offer_filter
+            .limit_per_geolocation(offers, &self.live_geolocations());
+        let capped_by_geolocation = offers.len() < offer_count;
+
+        let offers = match self.config.min_distinct_hosts {
+            Some(min_distinct_hosts) => {
+                spread_across_hosts(offers, &self.live_hosts(), min_distinct_hosts)
+            }
+            None => offers,
+        };
+
+        (offers, capped_by_geolocation)
+    }
+
+    // Marks the most expensive live instances to be dropped until their combined cost is back
+    // under config.max_total_dph.  The budget otherwise only stops new instances, so this catches
+    // price increases.  Only called on the polling tick, and replacements are held to the same
+    // budget, so a fleet right at the limit doesn't churn.
+    fn shed_over_budget(&mut self) {
+        let Some(max_total_dph) = self.config.max_total_dph else {
+            return;
+        };
+        let mut total_dph = total_cost_per_hour(self.instances.values());
+        if total_dph <= max_total_dph {
+            return;
+        }
+
+        let mut by_cost: Vec<&mut VastInstance> = self
+            .instances
+            .values_mut()
+            .filter(|instance| !instance.should_drop)
+            .collect();
+        by_cost.sort_by(|a, b| b.offer.dph_total.total_cmp(&a.offer.dph_total));
+        for instance in by_cost {
+            if total_dph <= max_total_dph {
+                break;
+            }
+            log_instance!(
+                Level::Warn,
+                instance,
+                "Live cost of ${total_dph:.2}/hour is over max_total_dph of ${max_total_dph:.2}/hour.  Dropping {instance}"
+            );
+            total_dph -= instance.offer.dph_total;
+            self.drop_history
+                .mark_for_drop(instance, DropReason::OverBudget);
+        }
+    }
+
+    // Marks the oldest instances past config.max_instance_lifetime_secs to be dropped, at most
+    // config.max_rotations_per_tick at a time.  They're replaced like any other dropped instance.
+    fn rotate_old_instances(&mut self) {
+        let Some(max_lifetime_secs) = self.config.max_instance_lifetime_secs else {
+            return;
+        };
+        let max_lifetime = Duration::from_secs(max_lifetime_secs);
+
+        let mut expired: Vec<&mut VastInstance> = self
+            .instances
+            .values_mut()
+            .filter(|instance| !instance.should_drop && instance.uptime() > max_lifetime)
+            .collect();
+        expired.sort_by_key(|instance| instance.creation_time);
+
+        for instance in expired.into_iter().take(self.config.max_rotations_per_tick) {
+            log_instance!(
+                Level::Info,
+                instance,
+                "{instance} has been up for {} seconds, over max_instance_lifetime_secs.  Rotating it out.",
+                instance.uptime().as_secs()
+            );
+            self.drop_history
+                .mark_for_drop(instance, DropReason::Lifetime);
+        }
+    }
+
+    // After config.max_drop_attempts polling cycles of failing to destroy an instance, stop
+    // tracking it so it no longer holds a slot against desired_instances and gets replaced.  Its
+    // machine is quarantined so the replacement isn't rented there.  Vast may still be running,
+    // and billing for, it.
+    async fn record_drop_failure(&mut self, instance_id: u64, e: anyhow::Error) {
+        let Some(instance) = self.instances.get_mut(&instance_id) else {
+            return;
+        };
+        instance.drop_failures += 1;
+        if instance.drop_failures < self.config.max_drop_attempts {
+            log_instance!(
+                Level::Warn,
+                instance,
+                "Error on attempt {} / {} to drop {instance}.  Will try again later. {e}",
+                instance.drop_failures,
+                self.config.max_drop_attempts
+            );
+            return;
+        }
+
+        let Some(instance) = self.instances.remove(&instance_id) else {
+            return;
+        };
+        let cooldown = self.offer_filter.quarantine(instance.offer.machine_id);
+        log_instance!(
+            Level::Error,
+            instance,
+            "Failed to drop {instance} {} times.  No longer tracking it and quarantining machine_id {} for {} seconds.  It may still be running, so destroy it from the Vast console. {e}",
+            instance.drop_failures,
+            instance.offer.machine_id,
+            cooldown.as_secs()
+        );
+        self.replace_instance(instance).await;
+    }
+
+    // requests one replacement for an instance that was just dropped instead of waiting for the
+    // next ensure_sufficient_instances.  If none can be had now, that periodic check retries.
+    async fn replace_instance(&mut self, dropped: VastInstance) {
+        if self.paused || self.instances.len() >= self.desired_instances {
+            return;
+        }
+
+        info!("Requesting a replacement for {dropped}");
+        if self.request_instances(1).await == 0 {
+            info!("No replacement for {dropped} yet.  Will try again later");
+        }
     }
 
-    // requests new instances if we're below config.number_instances
+    // requests new instances if we're below desired_instances
     async fn ensure_sufficient_instances(&mut self) {
-        if self.instances.len() < self.config.number_instances {
-            let required_instances = self.config.number_instances - self.instances.len();
+        if self.paused {
+            return;
+        }
+
+        if self.instances.len() < self.desired_instances {
+            let required_instances = self.desired_instances - self.instances.len();
             info!(
                 "Currently at {} / {} instances.  Requesting more...",
                 self.instances.len(),
-                self.config.number_instances
+                self.desired_instances
             );
 
-            let offers = match self.vast_client.find_offers(self.last_dropped).await {
-                Ok(offers) => offers,
-                Err(e) => {
+            self.request_instances(required_instances).await;
+
+            if let Some(min_distinct_hosts) = self.config.min_distinct_hosts {
+                let distinct_hosts = self.live_hosts().len();
+                if distinct_hosts < min_distinct_hosts {
                     warn!(
-                        "Error finding offers to request new instances.  Will try again later\n{e}"
+                        "Instances span {distinct_hosts} / {min_distinct_hosts} required distinct hosts"
                     );
-                    return;
                 }
-            };
+            }
+        }
+    }
 
-            let mut new_instances = Vec::new();
-            for offer in offers {
-                let offer_id = offer.id;
-                match self.vast_client.request_new_instance(offer_id).await {
-                    Ok(Some(instance_id)) => {
-                        let new_instance = VastInstance::new(instance_id, offer);
-                        info!("Accepted offer {offer_id} for {new_instance}");
-                        new_instances.push((instance_id, new_instance));
-                    }
-                    Ok(None) => {
-                        warn!("Reached Vast rate limit.  Will try to request more instances later");
-                        break;
-                    }
-                    Err(e) => {
-                        warn!(
-                            "Unable to request offer {offer_id} of a {} in {} with machine_id {} and host_id {} for ${:.2}/hour.\nError: {e}",
-                            offer.gpu_name,
-                            offer.geolocation,
-                            offer.machine_id,
-                            offer.host_id,
-                            offer.dph_total
-                        );
-                    }
+    // tries offers in order until required_instances are created, returning how many were
+    async fn request_instances(&mut self, required_instances: usize) -> usize {
+        // the last line of defense against runaway provisioning, whatever the caller asked for
+        let required_instances = match self.config.hard_max_instances {
+            Some(hard_max_instances) => {
+                let headroom = hard_max_instances.saturating_sub(self.instances.len());
+                if headroom < required_instances {
+                    warn!(
+                        "Only requesting {headroom} of {required_instances} instances to stay within hard_max_instances of {hard_max_instances}"
+                    );
                 }
+                headroom.min(required_instances)
+            }
+            None => required_instances,
+        };
+        if required_instances == 0 {
+            return 0;
+        }
 
-                if new_instances.len() == required_instances {
-                    break;
-                }
+        self.offer_filter.release_expired_quarantines();
+
+        let offers = match self
+            .vast_client
+            .find_offers(&self.offer_filter, required_instances, &self.live_usage())
+            .await
+        {
+            Ok(offers) => offers,
+            Err(e) => {
+                warn!("Error finding offers to request new instances.  Will try again later\n{e}");
+                return 0;
             }
+        };
 
-            for (new_instance_id, new_instance) in new_instances {
-                if let Some(old_instance) =
-                    self.instances.insert(new_instance_id, new_instance.clone())
-                {
-                    warn!(
-                        "Instance id {new_instance_id} was already registered: old instance {old_instance}, new_instance {new_instance}"
+        let (offers, capped_by_geolocation) = self.usable_offers(offers);
+
+        let mut new_instances = Vec::new();
+        let mut total_dph = total_cost_per_hour(self.instances.values());
+        let mut skipped_over_budget = false;
+        // an offer listed twice in one pass must only be rented once
+        let mut requested_offers = HashSet::new();
+        for offer in offers {
+            let offer_id = offer.id;
+            if !requested_offers.insert(offer_id) {
+                debug!(offer_id; "Offer {offer_id} was already requested this cycle.  Skipping it");
+                continue;
+            }
+            if self.vast_client.over_budget(total_dph, offer.dph_total) {
+                skipped_over_budget = true;
+                continue;
+            }
+
+            match self.vast_client.request_new_instance(offer_id).await {
+                Ok(CreateInstanceOutcome::Created {
+                    instance_id,
+                    template_hash,
+                    prover_type,
+                }) => {
+                    self.offer_filter.record_create_success(offer.machine_id);
+                    let new_instance = VastInstance::new(
+                        instance_id,
+                        offer,
+                        Some(template_hash),
+                        Some(prover_type),
+                    );
+                    log_instance!(
+                        Level::Info,
+                        new_instance,
+                        "Accepted offer {offer_id} for {new_instance}"
                     );
+                    self.vast_client.warn_if_over_soft_ceiling(&new_instance);
+                    total_dph += new_instance.offer.dph_total;
+                    new_instances.push((instance_id, new_instance));
                 }
+                Ok(CreateInstanceOutcome::RateLimited { retry_after }) => {
+                    match retry_after {
+                        Some(retry_after) => warn!(
+                            "Reached Vast rate limit.  Vast asked us to wait {} seconds.  Will try to request more instances later",
+                            retry_after.as_secs()
+                        ),
+                        None => warn!(
+                            "Reached Vast rate limit.  Will try to request more instances later"
+                        ),
+                    }
+                    break;
+                }
+                // logged by the VastClient
+                Ok(CreateInstanceOutcome::AlreadyRequested) => {}
+                Err(e) => {
+                    log_offer!(
+                        Level::Warn,
+                        offer,
+                        "Unable to request offer {offer_id} of a {} in {} with machine_id {} and host_id {} for ${:.2}/hour.\nError: {e}",
+                        offer.gpu_name,
+                        offer.geolocation,
+                        offer.machine_id,
+                        offer.host_id,
+                        offer.dph_total
+                    );
+                    self.offer_filter.record_create_failure(offer.machine_id);
+                }
+            }
+
+            if new_instances.len() == required_instances {
+                break;
+            }
+        }
+
+        let created = new_instances.len();
+        if created < required_instances && capped_by_geolocation {
+            warn!(
+                "max_instances_per_geolocation of {} is keeping this Magister below {} instances",
+                self.config
+                    .max_instances_per_geolocation
+                    .unwrap_or_default(),
+                self.desired_instances
+            );
+        }
+        if created < required_instances && skipped_over_budget {
+            warn!(
+                "max_total_dph of ${:.2}/hour is keeping this Magister below {} instances",
+                self.config.max_total_dph.unwrap_or_default(),
+                self.desired_instances
+            );
+        }
+
+        for (new_instance_id, new_instance) in new_instances {
+            if let Some(old_instance) = self.instances.insert(new_instance_id, new_instance.clone())
+            {
+                log_instance!(
+                    Level::Warn,
+                    new_instance,
+                    "Instance id {new_instance_id} was already registered: old instance {old_instance}, new_instance {new_instance}"
+                );
             }
         }
+
+        created
+    }
+}
+
+// A client for Contemplant health checks that keeps connections open between checks, so probing a
+// large fleet doesn't reconnect to every Contemplant each time
+fn build_probe_client(config: &Config) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(HEALTH_CHECK_TIMEOUT)
+        .pool_idle_timeout(Duration::from_secs(config.contemplant_probe_pool_idle_secs))
+        .pool_max_idle_per_host(config.contemplant_probe_pool_max_idle_per_host)
+        .tcp_keepalive(PROBE_TCP_KEEPALIVE)
+        .build()?)
+}
+
+// GETs a Contemplant's health url.  Err says why it's unhealthy.
+async fn probe_contemplant(client: &reqwest::Client, url: &str) -> Result<(), String> {
+    match client.get(url).send().await {
+        // read the body so the connection can be reused
+        Ok(response) if response.status().is_success() => {
+            let _ = response.bytes().await;
+            Ok(())
+        }
+        Ok(response) => Err(format!("{url} returned {}", response.status())),
+        Err(e) => Err(format!("{url} unreachable: {e}")),
     }
 }
 
@@ -328,13 +1842,1055 @@ impl InstanceController {
 pub enum InstanceControllerCommand {
     Drop {
         offer_id: u64,
+        reason: DropReason,
+        resp_sender: oneshot::Sender<Result<String, StatusCode>>,
+    },
+    DropAll {
+        confirm: Option<usize>,
+        resp_sender: oneshot::Sender<Result<usize, usize>>,
+    },
+    DropByInstanceId {
+        instance_id: u64,
         resp_sender: oneshot::Sender<Result<String, StatusCode>>,
     },
     GetAll {
         resp_sender: oneshot::Sender<HashMap<u64, VastInstance>>,
     },
+    GetDrops {
+        resp_sender: oneshot::Sender<Vec<DropEvent>>,
+    },
+    GetScaleEvents {
+        resp_sender: oneshot::Sender<Vec<ScaleEvent>>,
+    },
+    GetTemplateStats {
+        resp_sender: oneshot::Sender<HashMap<String, VerificationStats>>,
+    },
     HandleUnfinishedBusiness,
+    Import {
+        instances: Vec<VastInstance>,
+        resp_sender: oneshot::Sender<usize>,
+    },
+    IsPaused {
+        resp_sender: oneshot::Sender<bool>,
+    },
+    Reboot {
+        instance_id: u64,
+        resp_sender: oneshot::Sender<Result<(), (StatusCode, String)>>,
+    },
+    // sent by the task Reboot starts once Vast has answered
+    RebootFinished {
+        instance_id: u64,
+        result: Result<(), String>,
+        resp_sender: oneshot::Sender<Result<(), (StatusCode, String)>>,
+    },
+    SetPaused(bool),
+    SetDesiredCount {
+        count: usize,
+        reason: Option<String>,
+        resp_sender: oneshot::Sender<Result<usize, String>>,
+    },
+    // sent by the task SetDesiredCount starts once offers for scaling up have been searched for
+    DesiredCountOffersFound {
+        count: usize,
+        reason: Option<String>,
+        offers: Result<Vec<Offer>, String>,
+        resp_sender: oneshot::Sender<Result<usize, String>>,
+    },
+    SetGoodHost {
+        host_id: u64,
+        good: bool,
+        resp_sender: oneshot::Sender<bool>,
+    },
+    SetGoodMachine {
+        machine_id: u64,
+        good: bool,
+        resp_sender: oneshot::Sender<bool>,
+    },
+    SetMaxDph {
+        cost_per_hour: f64,
+        resp_sender: oneshot::Sender<Result<f64, String>>,
+    },
+    Shutdown {
+        resp_sender: oneshot::Sender<()>,
+    },
     VerifyInstance {
         offer_id: u64,
+        contemplant_info: Option<ContemplantInfo>,
     },
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        types::{
+            InstanceOverview,
+            tests::{test_instance, test_offer},
+        },
+        vast::tests::{mock_config, mock_vast, renting_router},
+    };
+    use axum::{Router, routing::put};
+
+    fn test_controller(
+        vast_client: Arc<VastClient>,
+        instances: Vec<VastInstance>,
+    ) -> InstanceController {
+        controller_with_config(mock_config(), vast_client, instances)
+    }
+
+    // A client for a controller handling commands with no polling ticks, so nothing happens
+    // unless a test asks for it
+    pub(crate) fn spawn_test_controller(
+        config: Config,
+        vast_client: Arc<VastClient>,
+        instances: Vec<VastInstance>,
+    ) -> InstanceControllerClient {
+        let (sender, receiver) = mpsc::channel(100);
+        let mut controller = controller_with_config(config, vast_client, instances);
+        controller.receiver = receiver;
+        controller.update_ready();
+        let ready = controller.ready.clone();
+        tokio::spawn(controller.handle_commands(sender.clone()));
+        InstanceControllerClient { sender, ready }
+    }
+
+    fn controller_with_config(
+        config: Config,
+        vast_client: Arc<VastClient>,
+        instances: Vec<VastInstance>,
+    ) -> InstanceController {
+        let (_, receiver) = mpsc::channel(1);
+        InstanceController {
+            instances: instances
+                .into_iter()
+                .map(|instance| (instance.instance_id, instance))
+                .collect(),
+            offer_filter: OfferFilter::new(&config),
+            drop_history: DropHistory::new(config.drop_history_size),
+            scale_history: ScaleHistory::new(config.scale_history_size),
+            template_stats: HashMap::new(),
+            runway_alerted: false,
+            desired_instances: config.number_instances,
+            paused: false,
+            rebooting: HashSet::new(),
+            ready: Arc::new(AtomicBool::new(false)),
+            last_health_check: Instant::now(),
+            skipping_while_vast_down: false,
+            under_capacity_since: None,
+            under_capacity_alerted: false,
+            http_client: reqwest::Client::new(),
+            probe_client: build_probe_client(&config).unwrap(),
+            vast_client,
+            receiver,
+            config,
+        }
+    }
+
+    // starts a reboot and hands what the spawned task reports back to finish_reboot
+    async fn reboot(
+        controller: &mut InstanceController,
+        instance_id: u64,
+    ) -> Result<(), (StatusCode, String)> {
+        let (resp_sender, mut resp_receiver) = oneshot::channel();
+        let (followup_sender, mut followup_receiver) = mpsc::channel(1);
+        controller.start_reboot(instance_id, resp_sender, followup_sender);
+        if let Ok(resp) = resp_receiver.try_recv() {
+            return resp;
+        }
+
+        let Some(InstanceControllerCommand::RebootFinished {
+            instance_id,
+            result,
+            resp_sender,
+        }) = followup_receiver.recv().await
+        else {
+            panic!("expected RebootFinished");
+        };
+        let _ = resp_sender.send(controller.finish_reboot(instance_id, result));
+        resp_receiver.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reboot_restarts_verification() {
+        let router = Router::new().route("/instances/reboot/:instance_id/", put(|| async { "{}" }));
+        let vast_client = mock_vast(mock_config(), router).await;
+        let mut instance = test_instance(7, 0.5);
+        instance.contemplant_verified = true;
+        instance.health_failures = 2;
+        let verification_started = instance.verification_started;
+        let mut controller = test_controller(vast_client, vec![instance]);
+
+        assert!(reboot(&mut controller, 7).await.is_ok());
+
+        let instance = &controller.instances[&7];
+        assert!(!instance.contemplant_verified);
+        assert_eq!(instance.health_failures, 0);
+        assert!(instance.verification_started >= verification_started);
+        assert!(controller.rebooting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reboot_failure_keeps_verification() {
+        let router = Router::new().route(
+            "/instances/reboot/:instance_id/",
+            put(|| async { (axum::http::StatusCode::NOT_FOUND, "no such instance") }),
+        );
+        let vast_client = mock_vast(mock_config(), router).await;
+        let mut instance = test_instance(7, 0.5);
+        instance.contemplant_verified = true;
+        let mut controller = test_controller(vast_client, vec![instance]);
+
+        let (status, _) = reboot(&mut controller, 7).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(controller.instances[&7].contemplant_verified);
+        assert!(controller.rebooting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reboot_rejects_unknown_and_dropping_instances() {
+        let vast_client = mock_vast(mock_config(), Router::new()).await;
+        let mut dropping = test_instance(8, 0.5);
+        dropping.should_drop = true;
+        let mut controller = test_controller(vast_client, vec![dropping]);
+
+        let (status, _) = reboot(&mut controller, 7).await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = reboot(&mut controller, 8).await.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn reboot_rejects_a_second_reboot_in_flight() {
+        let vast_client = mock_vast(mock_config(), Router::new()).await;
+        let mut controller = test_controller(vast_client, vec![test_instance(7, 0.5)]);
+        controller.rebooting.insert(7);
+
+        let (status, _) = reboot(&mut controller, 7).await.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    // starts changing the desired count and, if it searched for offers, finishes it
+    async fn set_desired_count(
+        controller: &mut InstanceController,
+        count: usize,
+    ) -> Result<usize, String> {
+        let (resp_sender, mut resp_receiver) = oneshot::channel();
+        let (followup_sender, mut followup_receiver) = mpsc::channel(1);
+        controller.start_set_desired_count(
+            count,
+            Some("test".to_string()),
+            resp_sender,
+            followup_sender,
+        );
+        if let Ok(resp) = resp_receiver.try_recv() {
+            return resp;
+        }
+
+        let Some(InstanceControllerCommand::DesiredCountOffersFound {
+            count,
+            reason,
+            offers,
+            resp_sender,
+        }) = followup_receiver.recv().await
+        else {
+            panic!("expected DesiredCountOffersFound");
+        };
+        let _ = resp_sender.send(controller.finish_set_desired_count(count, reason, offers));
+        resp_receiver.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn scale_down_drops_the_most_expensive_instances() {
+        let vast_client = mock_vast(mock_config(), Router::new()).await;
+        let instances = vec![
+            test_instance(1, 0.3),
+            test_instance(2, 0.9),
+            test_instance(3, 0.5),
+        ];
+        let mut controller = test_controller(vast_client, instances);
+
+        assert_eq!(set_desired_count(&mut controller, 1).await, Ok(1));
+
+        assert_eq!(controller.desired_instances, 1);
+        assert!(!controller.instances[&1].should_drop);
+        assert!(controller.instances[&2].should_drop);
+        assert!(controller.instances[&3].should_drop);
+        let scale_events = controller.scale_history.events();
+        assert_eq!(scale_events.len(), 1);
+        assert_eq!((scale_events[0].from, scale_events[0].to), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn scale_up_applies_with_enough_offers() {
+        let vast_client = mock_vast(mock_config(), Router::new()).await;
+        let mut controller = test_controller(vast_client, vec![test_instance(1, 0.3)]);
+        let offers = vec![test_offer(10, 10, 10, 0.4), test_offer(11, 11, 11, 0.4)];
+
+        assert_eq!(
+            controller.finish_set_desired_count(3, None, Ok(offers)),
+            Ok(3)
+        );
+        assert_eq!(controller.desired_instances, 3);
+    }
+
+    #[tokio::test]
+    async fn scale_up_counts_only_offers_request_instances_could_use() {
+        let mut config = mock_config();
+        config.max_instances_per_host = Some(1);
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let mut controller =
+            controller_with_config(config, vast_client, vec![test_instance(1, 0.3)]);
+        // host 1 is already full and host 2 only has room for one of its offers
+        let offers = vec![
+            test_offer(10, 10, 1, 0.4),
+            test_offer(11, 11, 2, 0.4),
+            test_offer(12, 12, 2, 0.4),
+        ];
+
+        assert!(
+            controller
+                .finish_set_desired_count(3, None, Ok(offers))
+                .is_err()
+        );
+        assert_eq!(controller.desired_instances, 2);
+        assert!(controller.scale_history.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn scale_up_is_rejected_when_vast_fails() {
+        let vast_client = mock_vast(mock_config(), Router::new()).await;
+        let mut controller = test_controller(vast_client, vec![test_instance(1, 0.3)]);
+
+        assert!(set_desired_count(&mut controller, 3).await.is_err());
+        assert_eq!(controller.desired_instances, 2);
+    }
+
+    #[tokio::test]
+    async fn probe_client_reuses_connections() {
+        use std::sync::atomic::AtomicUsize;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // a keep-alive HTTP server that counts the connections made to it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/health", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while stream.read(&mut buf).await.is_ok_and(|read| read > 0) {
+                        let response = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let client = build_probe_client(&mock_config()).unwrap();
+        for _ in 0..3 {
+            assert_eq!(probe_contemplant(&client, &url).await, Ok(()));
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn scaling_up_and_down_is_recorded_and_applied() {
+        let mut config = mock_config();
+        config.drop_history_size = 1;
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let instances = vec![test_instance(1, 0.3), test_instance(2, 0.9)];
+        let mut controller = controller_with_config(config, vast_client, instances);
+        let offers = vec![test_offer(10, 10, 10, 0.4), test_offer(11, 11, 11, 0.4)];
+
+        assert_eq!(
+            controller.finish_set_desired_count(4, Some("queue depth 40".to_string()), Ok(offers)),
+            Ok(4)
+        );
+        assert_eq!(controller.desired_instances, 4);
+        assert_eq!(set_desired_count(&mut controller, 1).await, Ok(1));
+        assert_eq!(controller.desired_instances, 1);
+        assert!(!controller.instances[&1].should_drop);
+        assert!(controller.instances[&2].should_drop);
+
+        // scale events are kept apart from drops, which only have room for one here
+        let scale_events = controller.scale_history.events();
+        assert_eq!(scale_events.len(), 2);
+        assert_eq!((scale_events[0].from, scale_events[0].to), (2, 4));
+        assert_eq!(scale_events[0].reason.as_deref(), Some("queue depth 40"));
+        assert_eq!((scale_events[1].from, scale_events[1].to), (4, 1));
+        assert_eq!(controller.drop_history.events().len(), 1);
+    }
+
+    fn verified_instance(id: u64, host_id: u64) -> VastInstance {
+        let mut instance = VastInstance::new(id, test_offer(id, id, host_id, 0.4), None, None);
+        instance.contemplant_verified = true;
+        instance
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_verified_instances_span_min_distinct_hosts() {
+        let mut config = mock_config();
+        config.min_distinct_hosts = Some(2);
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let instances = vec![verified_instance(1, 1), verified_instance(2, 1)];
+        let mut controller = controller_with_config(config, vast_client, instances);
+
+        controller.update_ready();
+        assert!(!controller.ready.load(Ordering::Relaxed));
+
+        controller.instances.insert(3, verified_instance(3, 2));
+        controller.update_ready();
+        assert!(controller.ready.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn good_host_marked_at_runtime_is_rented_first() {
+        // offer 1 on host 5 is first in Vast's order, offer 2 on host 9 second
+        let offers = vec![test_offer(1, 1, 5, 0.3), test_offer(2, 2, 9, 0.3)];
+
+        let vast_client = mock_vast(mock_config(), renting_router(offers.clone())).await;
+        let mut controller = test_controller(vast_client, Vec::new());
+        assert_eq!(controller.request_instances(1).await, 1);
+        assert!(controller.instances.contains_key(&1001));
+
+        let vast_client = mock_vast(mock_config(), renting_router(offers)).await;
+        let mut controller = test_controller(vast_client, Vec::new());
+        assert!(controller.offer_filter.mark_good_host(9));
+        assert_eq!(controller.request_instances(1).await, 1);
+        assert!(controller.instances.contains_key(&1002));
+    }
+
+    #[tokio::test]
+    async fn dropped_instance_is_replaced_straight_away() {
+        let offers = vec![test_offer(10, 10, 10, 0.3)];
+        let vast_client = mock_vast(mock_config(), renting_router(offers)).await;
+        // number_instances is 2, and instance 2 has just been destroyed
+        let mut controller = test_controller(vast_client, vec![test_instance(1, 0.3)]);
+
+        controller.replace_instance(test_instance(2, 0.3)).await;
+
+        assert_eq!(controller.instances.len(), 2);
+        assert!(controller.instances.contains_key(&1010));
+    }
+
+    #[tokio::test]
+    async fn no_replacement_at_the_desired_count_or_while_paused() {
+        let offers = vec![test_offer(10, 10, 10, 0.3)];
+        let vast_client = mock_vast(mock_config(), renting_router(offers)).await;
+        let instances = vec![test_instance(1, 0.3), test_instance(2, 0.3)];
+        let mut controller = test_controller(vast_client, instances);
+
+        controller.replace_instance(test_instance(3, 0.3)).await;
+        assert_eq!(controller.instances.len(), 2);
+
+        controller.instances.remove(&2);
+        controller.paused = true;
+        controller.replace_instance(test_instance(2, 0.3)).await;
+        assert_eq!(controller.instances.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn instances_stuck_past_the_timeout_are_dropped() {
+        let config = mock_config();
+        let long_ago = Instant::now()
+            .checked_sub(Duration::from_secs(config.stuck_instance_timeout_secs + 1))
+            .unwrap();
+        let vast_client = mock_vast(config, Router::new()).await;
+        let mut stuck = test_instance(1, 0.3);
+        stuck.not_running_since = Some(long_ago);
+        let mut recovered = test_instance(3, 0.3);
+        recovered.not_running_since = Some(long_ago);
+        let instances = vec![stuck, test_instance(2, 0.3), recovered];
+        let mut controller = test_controller(vast_client, instances);
+
+        let returned_instances: HashMap<u64, VastResponseInstance> =
+            [(1, "loading"), (2, "exited"), (3, "running")]
+                .into_iter()
+                .map(|(id, status)| {
+                    let returned = serde_json::json!({ "id": id, "actual_status": status });
+                    (id, serde_json::from_value(returned).unwrap())
+                })
+                .collect();
+        controller.check_stuck_instances(&returned_instances);
+
+        assert!(controller.instances[&1].should_drop);
+        // only just seen not running, so its clock starts now
+        assert!(!controller.instances[&2].should_drop);
+        assert!(controller.instances[&2].not_running_since.is_some());
+        // running again resets the clock
+        assert!(!controller.instances[&3].should_drop);
+        assert!(controller.instances[&3].not_running_since.is_none());
+    }
+
+    #[tokio::test]
+    async fn replacements_stop_at_max_total_dph() {
+        let offers = vec![test_offer(10, 10, 10, 0.3), test_offer(11, 11, 11, 0.3)];
+        let mut config = mock_config();
+        config.max_total_dph = Some(0.8);
+        let vast_client = mock_vast(config.clone(), renting_router(offers)).await;
+        let mut controller =
+            controller_with_config(config, vast_client, vec![test_instance(1, 0.3)]);
+        controller.desired_instances = 3;
+
+        // 0.3 live and 0.3 more fit under 0.8, but a third 0.3 doesn't
+        assert_eq!(controller.request_instances(2).await, 1);
+        assert!(controller.instances.contains_key(&1010));
+        assert!(!controller.instances.contains_key(&1011));
+    }
+
+    #[tokio::test]
+    async fn paused_controller_requests_no_instances() {
+        let offers = vec![test_offer(10, 10, 10, 0.3)];
+        let vast_client = mock_vast(mock_config(), renting_router(offers)).await;
+        // number_instances is 2
+        let mut controller = test_controller(vast_client, vec![test_instance(1, 0.3)]);
+
+        controller.paused = true;
+        controller.ensure_sufficient_instances().await;
+        assert_eq!(controller.instances.len(), 1);
+
+        controller.paused = false;
+        controller.ensure_sufficient_instances().await;
+        assert_eq!(controller.instances.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn old_instances_are_rotated_oldest_first_one_per_tick() {
+        let aged = |id, age_secs| {
+            let mut instance = test_instance(id, 0.3);
+            instance.creation_time = Instant::now()
+                .checked_sub(Duration::from_secs(age_secs))
+                .unwrap();
+            instance
+        };
+        let mut config = mock_config();
+        config.max_instance_lifetime_secs = Some(3600);
+        config.max_rotations_per_tick = 1;
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let instances = vec![aged(1, 2 * 3600), aged(2, 3 * 3600), aged(3, 10)];
+        let mut controller = controller_with_config(config, vast_client, instances);
+        let dropping = |controller: &InstanceController| -> Vec<u64> {
+            let mut dropping: Vec<u64> = controller
+                .instances
+                .values()
+                .filter(|instance| instance.should_drop)
+                .map(|instance| instance.instance_id)
+                .collect();
+            dropping.sort();
+            dropping
+        };
+
+        controller.rotate_old_instances();
+        assert_eq!(dropping(&controller), vec![2]);
+        controller.rotate_old_instances();
+        assert_eq!(dropping(&controller), vec![1, 2]);
+        // 3 is well under the lifetime
+        controller.rotate_old_instances();
+        assert_eq!(dropping(&controller), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn contemplant_failing_health_checks_is_dropped() {
+        use axum::{extract::State, routing::get};
+        use std::sync::atomic::Ordering;
+
+        async fn health(State(healthy): State<Arc<AtomicBool>>) -> StatusCode {
+            if healthy.load(Ordering::Relaxed) {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }
+        let healthy = Arc::new(AtomicBool::new(true));
+        let health_router = Router::new()
+            .route("/health", get(health))
+            .with_state(healthy.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let health_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, health_router).await });
+
+        let mut config = mock_config();
+        config.health_check_interval_secs = Some(0);
+        config.health_check_failures = 2;
+        let http_port = config.contemplant.http_port;
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let mut verified = test_instance(1, 0.3);
+        verified.contemplant_verified = true;
+        let instances = vec![verified, test_instance(2, 0.3)];
+        let mut controller = controller_with_config(config, vast_client, instances);
+        // Vast maps both Contemplants' health ports to the mock server
+        let returned_instances: HashMap<u64, VastResponseInstance> = [1, 2]
+            .into_iter()
+            .map(|id| {
+                let returned = serde_json::json!({
+                    "id": id,
+                    "actual_status": "running",
+                    "public_ipaddr": "127.0.0.1",
+                    "ports": { format!("{http_port}/tcp"): [{ "HostPort": health_port.to_string() }] },
+                });
+                (id, serde_json::from_value(returned).unwrap())
+            })
+            .collect();
+
+        controller
+            .check_contemplant_health(&returned_instances)
+            .await;
+        assert_eq!(controller.instances[&1].health_failures, 0);
+
+        healthy.store(false, Ordering::Relaxed);
+        controller
+            .check_contemplant_health(&returned_instances)
+            .await;
+        assert_eq!(controller.instances[&1].health_failures, 1);
+        assert!(!controller.instances[&1].should_drop);
+        controller
+            .check_contemplant_health(&returned_instances)
+            .await;
+        assert!(controller.instances[&1].should_drop);
+        // unverified Contemplants aren't probed
+        assert_eq!(controller.instances[&2].health_failures, 0);
+        assert!(!controller.instances[&2].should_drop);
+    }
+
+    #[tokio::test]
+    async fn drop_queued_before_shutdown_is_still_handled() {
+        let vast_client = mock_vast(mock_config(), Router::new()).await;
+        let (sender, receiver) = mpsc::channel(10);
+        let mut controller = test_controller(vast_client, vec![test_instance(1, 0.3)]);
+        controller.receiver = receiver;
+
+        // both are queued before the controller handles anything
+        let (drop_sender, drop_receiver) = oneshot::channel();
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        for command in [
+            InstanceControllerCommand::Drop {
+                offer_id: 1,
+                reason: DropReason::Manual,
+                resp_sender: drop_sender,
+            },
+            InstanceControllerCommand::Shutdown {
+                resp_sender: shutdown_sender,
+            },
+        ] {
+            sender.send(command).await.unwrap();
+        }
+        let controller_task = tokio::spawn(controller.handle_commands(sender.clone()));
+
+        assert_eq!(
+            drop_receiver.await.unwrap(),
+            Ok("1 will be dropped".to_string())
+        );
+        shutdown_receiver.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), controller_task)
+            .await
+            .expect("the controller should stop once its queue is drained")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replacements_respect_max_instances_per_geolocation() {
+        let mut quebec = test_offer(11, 11, 11, 0.3);
+        quebec.geolocation = "Quebec, CA".to_string();
+        // test_offer is in Oregon
+        let offers = vec![test_offer(10, 10, 10, 0.3), quebec];
+        let mut config = mock_config();
+        config.max_instances_per_geolocation = Some(1);
+        let vast_client = mock_vast(config.clone(), renting_router(offers)).await;
+        let mut controller =
+            controller_with_config(config, vast_client, vec![test_instance(1, 0.3)]);
+        controller.desired_instances = 3;
+
+        assert_eq!(controller.request_instances(2).await, 1);
+        assert!(controller.instances.contains_key(&1011));
+    }
+
+    #[tokio::test]
+    async fn under_capacity_alerts_once_then_on_recovery() {
+        use axum::{Json, routing::post};
+
+        let alerts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = alerts.clone();
+        let webhook = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                received
+                    .lock()
+                    .unwrap()
+                    .push(body["text"].as_str().unwrap().to_string());
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, webhook).await });
+
+        let mut config = mock_config();
+        config.alert_webhook_url = Some(webhook_url);
+        config.under_capacity_alert_secs = 600;
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        // number_instances is 2
+        let mut controller =
+            controller_with_config(config, vast_client, vec![test_instance(1, 0.3)]);
+
+        controller.check_capacity().await;
+        assert!(alerts.lock().unwrap().is_empty());
+
+        // as if it has been under capacity for longer than under_capacity_alert_secs
+        controller.under_capacity_since = Instant::now().checked_sub(Duration::from_secs(601));
+        controller.check_capacity().await;
+        controller.check_capacity().await;
+        assert_eq!(alerts.lock().unwrap().len(), 1);
+        assert!(alerts.lock().unwrap()[0].contains("Only 1 / 2 instances"));
+
+        controller.instances.insert(2, test_instance(2, 0.3));
+        controller.check_capacity().await;
+        controller.check_capacity().await;
+        let alerts = alerts.lock().unwrap();
+        assert_eq!(alerts.len(), 2);
+        assert!(
+            alerts[1].contains("Recovered: back at 2 / 2 instances"),
+            "{}",
+            alerts[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn launched_instances_show_their_template_and_prover_type() {
+        let offers = vec![test_offer(10, 10, 10, 0.3)];
+        let vast_client = mock_vast(mock_config(), renting_router(offers)).await;
+        let mut controller = test_controller(vast_client, Vec::new());
+
+        assert_eq!(controller.request_instances(1).await, 1);
+
+        let overview =
+            serde_json::to_value(InstanceOverview::from(controller.instances[&1010].clone()))
+                .unwrap();
+        assert_eq!(overview["template_hash"], "template");
+        assert_eq!(overview["prover_type"], "cpu");
+    }
+
+    #[tokio::test]
+    async fn instance_that_never_drops_is_forgotten_and_its_machine_quarantined() {
+        // offer 10 is on the failing instance's machine, offer 11 isn't
+        let offers = vec![test_offer(10, 1, 10, 0.3), test_offer(11, 11, 11, 0.3)];
+        let mut config = mock_config();
+        config.max_drop_attempts = 3;
+        let vast_client = mock_vast(config.clone(), renting_router(offers)).await;
+        let mut failing = test_instance(1, 0.3);
+        failing.should_drop = true;
+        let instances = vec![failing, test_instance(2, 0.3)];
+        let mut controller = controller_with_config(config, vast_client, instances);
+
+        for attempt in 1..3 {
+            controller
+                .record_drop_failure(1, anyhow::anyhow!("host offline"))
+                .await;
+            assert_eq!(controller.instances[&1].drop_failures, attempt);
+        }
+        controller
+            .record_drop_failure(1, anyhow::anyhow!("host offline"))
+            .await;
+
+        assert!(!controller.instances.contains_key(&1));
+        // replaced straight away, off the now quarantined machine
+        assert!(controller.instances.contains_key(&1011));
+        assert_eq!(controller.instances.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn hard_max_instances_holds_whatever_is_asked_for() {
+        let offers = (10..15).map(|id| test_offer(id, id, id, 0.3)).collect();
+        let mut config = mock_config();
+        config.hard_max_instances = Some(3);
+        let vast_client = mock_vast(config.clone(), renting_router(offers)).await;
+        let instances = vec![test_instance(1, 0.3), test_instance(2, 0.3)];
+        let mut controller = controller_with_config(config, vast_client, instances);
+
+        // a runtime count change is clamped
+        assert_eq!(set_desired_count(&mut controller, 5).await, Ok(3));
+        assert_eq!(controller.desired_instances, 3);
+
+        // even if desired_instances somehow got past the ceiling
+        controller.desired_instances = 10;
+        controller.ensure_sufficient_instances().await;
+        assert_eq!(controller.instances.len(), 3);
+        assert_eq!(controller.request_instances(5).await, 0);
+        assert_eq!(controller.instances.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn verification_timeout_is_recorded_as_the_drop_reason() {
+        let mut config = mock_config();
+        config.contemplant_verification_timeout_secs = 60;
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let mut late = test_instance(1, 0.3);
+        late.verification_started = Instant::now() - Duration::from_secs(120);
+        let mut verified = test_instance(2, 0.3);
+        verified.verification_started = Instant::now() - Duration::from_secs(120);
+        verified.contemplant_verified = true;
+        let mut controller = controller_with_config(config, vast_client, vec![late, verified]);
+
+        controller.check_contemplant_verification().await;
+
+        assert!(controller.instances[&1].should_drop);
+        assert!(!controller.instances[&2].should_drop);
+        let events = controller.drop_history.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].instance_id, 1);
+        assert_eq!(events[0].offer_id, controller.instances[&1].offer.id);
+        assert_eq!(
+            events[0].machine_id,
+            controller.instances[&1].offer.machine_id
+        );
+        assert_eq!(events[0].reason, DropReason::VerificationTimeout);
+
+        // checking again doesn't record the same drop twice
+        controller.check_contemplant_verification().await;
+        assert_eq!(controller.drop_history.events().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reloaded_instances_keep_uptime_and_unverified_ones_get_a_fresh_window() {
+        let mut config = mock_config();
+        config.contemplant_verification_timeout_secs = 60;
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let two_hours_ago = |id| {
+            let mut instance = test_instance(id, 0.3);
+            instance.creation_time = Instant::now() - Duration::from_secs(7200);
+            instance.verification_started = instance.creation_time;
+            instance
+        };
+        let mut verified = two_hours_ago(1);
+        verified.contemplant_verified = true;
+        let unverified = two_hours_ago(2);
+
+        // save and load them like the state file does
+        let saved = serde_json::to_string(&[
+            ManifestInstance::from(verified),
+            ManifestInstance::from(unverified),
+        ])
+        .unwrap();
+        let loaded: Vec<ManifestInstance> = serde_json::from_str(&saved).unwrap();
+        let reloaded = loaded.into_iter().map(VastInstance::from).collect();
+        let mut controller = controller_with_config(config, vast_client, reloaded);
+
+        for instance in controller.instances.values() {
+            assert!(instance.uptime() >= Duration::from_secs(7199));
+            assert!(instance.verification_started.elapsed() < Duration::from_secs(5));
+        }
+        assert!(controller.instances[&1].contemplant_verified);
+        assert!(!controller.instances[&2].contemplant_verified);
+
+        controller.check_contemplant_verification().await;
+        assert!(!controller.instances[&1].should_drop);
+        assert!(!controller.instances[&2].should_drop);
+
+        // once the fresh window runs out only the unverified one goes
+        for instance in controller.instances.values_mut() {
+            instance.verification_started = Instant::now() - Duration::from_secs(120);
+        }
+        controller.check_contemplant_verification().await;
+        assert!(!controller.instances[&1].should_drop);
+        assert!(controller.instances[&2].should_drop);
+    }
+
+    #[tokio::test]
+    async fn initializes_with_fewer_offers_than_instances() {
+        let offers: Vec<_> = (10..12).map(|id| test_offer(id, id, id, 0.3)).collect();
+        let mut config = mock_config();
+        config.persist_state = false;
+        config.number_instances = 4;
+        let initialize = |config: Config| {
+            let offers = offers.clone();
+            async move {
+                let vast_client = mock_vast(config.clone(), renting_router(offers)).await;
+                let (_, receiver) = mpsc::channel(1);
+                let ready = Arc::new(AtomicBool::new(false));
+                InstanceController::initialize(vast_client, config, receiver, ready).await
+            }
+        };
+
+        let controller = initialize(config.clone()).await.unwrap();
+        assert_eq!(controller.instances.len(), 2);
+        // still aiming for all 4, so the rest are requested as offers turn up
+        assert_eq!(controller.desired_instances, 4);
+
+        config.allow_partial_startup = false;
+        assert!(initialize(config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn priciest_instances_are_shed_when_over_budget() {
+        let mut config = mock_config();
+        config.max_total_dph = Some(1.4);
+        let vast_client = mock_vast(config.clone(), Router::new()).await;
+        let mut already_dropping = test_instance(5, 3.0);
+        already_dropping.should_drop = true;
+        let instances = vec![
+            test_instance(1, 0.25),
+            test_instance(2, 1.0),
+            test_instance(3, 0.5),
+            test_instance(4, 0.75),
+            already_dropping,
+        ];
+        let mut controller = controller_with_config(config, vast_client, instances);
+
+        // 5 is already on its way out, leaving 2.5/hour live
+        controller.shed_over_budget();
+        let mut shed: Vec<u64> = controller
+            .instances
+            .values()
+            .filter(|instance| instance.should_drop)
+            .map(|instance| instance.instance_id)
+            .collect();
+        shed.sort();
+        assert_eq!(shed, vec![2, 4, 5]);
+        assert_eq!(total_cost_per_hour(controller.instances.values()), 0.75);
+        let events = controller.drop_history.events();
+        assert_eq!(events.len(), 2);
+        assert!(
+            events
+                .iter()
+                .all(|event| event.reason == DropReason::OverBudget)
+        );
+
+        // back under budget, so nothing more goes
+        controller.shed_over_budget();
+        assert_eq!(controller.drop_history.events().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn an_instance_briefly_missing_from_vast_is_kept() {
+        use axum::{Json, routing::get};
+        use std::sync::atomic::AtomicUsize;
+
+        let mut config = mock_config();
+        config.missing_instance_polls = 2;
+        // 1 is left out of the first, third, and fourth listings
+        let polls = Arc::new(AtomicUsize::new(0));
+        let label = config.instance_label.clone();
+        let router = Router::new().route(
+            "/instances/",
+            get({
+                let polls = polls.clone();
+                move || async move {
+                    let listed = |id| serde_json::json!({ "id": id, "actual_status": "running", "label": label });
+                    let instances = match polls.fetch_add(1, Ordering::Relaxed) {
+                        1 => vec![listed(1), listed(2)],
+                        _ => vec![listed(2)],
+                    };
+                    Json(serde_json::json!({
+                        "instances_found": instances.len(),
+                        "instances": instances,
+                    }))
+                }
+            }),
+        );
+        let vast_client = mock_vast(config.clone(), router).await;
+        let instances = vec![test_instance(1, 0.3), test_instance(2, 0.3)];
+        let mut controller = controller_with_config(config, vast_client, instances);
+
+        controller.correct_active_instance_count().await;
+        assert_eq!(controller.instances[&1].missing_polls, 1);
+        controller.correct_active_instance_count().await;
+        assert_eq!(controller.instances[&1].missing_polls, 0);
+        controller.correct_active_instance_count().await;
+        assert!(controller.instances.contains_key(&1));
+        assert!(controller.drop_history.events().is_empty());
+
+        // missing twice in a row, so it's gone
+        controller.correct_active_instance_count().await;
+        assert!(!controller.instances.contains_key(&1));
+        assert!(controller.instances.contains_key(&2));
+        let events = controller.drop_history.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason, DropReason::Zombie);
+    }
+
+    #[tokio::test]
+    async fn an_offer_listed_twice_is_only_requested_once() {
+        use axum::{
+            Json,
+            extract::Path,
+            routing::{post, put},
+        };
+        use std::sync::atomic::AtomicUsize;
+
+        let offers = vec![
+            test_offer(10, 10, 10, 0.3),
+            test_offer(10, 10, 10, 0.3),
+            test_offer(11, 11, 11, 0.3),
+        ];
+        let creates = Arc::new(AtomicUsize::new(0));
+        let counted_creates = creates.clone();
+        let router = Router::new()
+            .route(
+                "/bundles/",
+                post(move || async move { Json(serde_json::json!({ "offers": offers })) }),
+            )
+            .route(
+                "/asks/:offer_id/",
+                put(move |Path(offer_id): Path<u64>| async move {
+                    counted_creates.fetch_add(1, Ordering::Relaxed);
+                    Json(serde_json::json!({ "success": true, "new_contract": 1000 + offer_id }))
+                }),
+            );
+        let vast_client = mock_vast(mock_config(), router).await;
+        let mut controller = test_controller(vast_client, Vec::new());
+
+        assert_eq!(controller.request_instances(3).await, 2);
+        assert_eq!(creates.load(Ordering::Relaxed), 2);
+        let mut instance_ids: Vec<u64> = controller.instances.keys().copied().collect();
+        instance_ids.sort();
+        assert_eq!(instance_ids, vec![1010, 1011]);
+    }
+
+    #[tokio::test]
+    async fn a_changed_public_ip_is_picked_up_from_the_listing() {
+        use axum::{Json, routing::get};
+
+        let config = mock_config();
+        let label = config.instance_label.clone();
+        let router = Router::new().route(
+            "/instances/",
+            get(move || async move {
+                Json(serde_json::json!({
+                    "instances_found": 2,
+                    "instances": [
+                        {
+                            "id": 1,
+                            "actual_status": "running",
+                            "label": label,
+                            "public_ipaddr": "5.6.7.8\n",
+                            "ports": { "2222/tcp": [{ "HostIp": "0.0.0.0", "HostPort": "40022" }] },
+                        },
+                        // nothing reported, so nothing changes
+                        { "id": 2, "actual_status": "running", "label": label },
+                    ],
+                }))
+            }),
+        );
+        let vast_client = mock_vast(config.clone(), router).await;
+        let mut instances = vec![test_instance(1, 0.3), test_instance(2, 0.3)];
+        for instance in &mut instances {
+            instance.offer.public_ipaddr = "1.2.3.4".to_string();
+        }
+        let mut controller = controller_with_config(config, vast_client, instances);
+        assert!(controller.instances[&1].ssh_info().is_none());
+
+        controller.correct_active_instance_count().await;
+
+        let refreshed = &controller.instances[&1];
+        assert_eq!(refreshed.offer.public_ipaddr, "5.6.7.8");
+        let ssh_info = refreshed.ssh_info().unwrap();
+        assert_eq!((ssh_info.host.as_str(), ssh_info.port), ("5.6.7.8", 40022));
+        let unchanged = &controller.instances[&2];
+        assert_eq!(unchanged.offer.public_ipaddr, "1.2.3.4");
+        assert!(unchanged.ports.is_none());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{fmt::Write as _, io::Write, sync::OnceLock};
+
+use log::{
+    Record,
+    kv::{self, Key, Value, VisitSource},
+};
+use serde_json::json;
+
+use crate::config::LogFormat;
+
+// Identity of this Magister, set once the config is loaded.  Log lines written before then are
+// untagged.
+static MAGISTER_ID: OnceLock<String> = OnceLock::new();
+// Also set once the config is loaded.  Log lines written before then are text.
+static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Initialize env_logger with the default layout plus this Magister's identity, so logs from
+/// several Magisters can be told apart once aggregated.  Structured key-values are appended to
+/// the message as ` key=value`, or with log_format json, become fields of the line's object.
+pub fn init() {
+    builder().init();
+}
+
+fn builder() -> env_logger::Builder {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.format(|buf, record| {
+        let timestamp = buf.timestamp();
+        if LOG_FORMAT.get() == Some(&LogFormat::Json) {
+            return writeln!(buf, "{}", json_line(&timestamp.to_string(), record));
+        }
+
+        let level_style = buf.default_level_style(record.level());
+        match MAGISTER_ID.get() {
+            Some(magister_id) => writeln!(
+                buf,
+                "[{timestamp} {level_style}{:<5}{level_style:#} {magister_id} {}] {}{}",
+                record.level(),
+                record.target(),
+                record.args(),
+                key_values(record)
+            ),
+            None => writeln!(
+                buf,
+                "[{timestamp} {level_style}{:<5}{level_style:#} {}] {}{}",
+                record.level(),
+                record.target(),
+                record.args(),
+                key_values(record)
+            ),
+        }
+    });
+    builder
+}
+
+pub fn set_magister_id(magister_id: String) {
+    let _ = MAGISTER_ID.set(magister_id);
+}
+
+pub fn set_format(log_format: LogFormat) {
+    let _ = LOG_FORMAT.set(log_format);
+}
+
+// The record as one JSON object.  Structured key-values sit alongside the standard fields,
+// keeping numbers and booleans as such.
+fn json_line(timestamp: &str, record: &Record) -> String {
+    struct Collect(serde_json::Map<String, serde_json::Value>);
+
+    impl<'kvs> VisitSource<'kvs> for Collect {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            let value = if let Some(value) = value.to_u64() {
+                json!(value)
+            } else if let Some(value) = value.to_i64() {
+                json!(value)
+            } else if let Some(value) = value.to_f64() {
+                json!(value)
+            } else if let Some(value) = value.to_bool() {
+                json!(value)
+            } else {
+                json!(value.to_string())
+            };
+            self.0.insert(key.to_string(), value);
+            Ok(())
+        }
+    }
+
+    let mut collect = Collect(serde_json::Map::new());
+    let _ = record.key_values().visit(&mut collect);
+    let mut line = collect.0;
+    line.insert("timestamp".to_string(), json!(timestamp));
+    line.insert("level".to_string(), json!(record.level().as_str()));
+    line.insert("target".to_string(), json!(record.target()));
+    line.insert("message".to_string(), json!(record.args().to_string()));
+    if let Some(magister_id) = MAGISTER_ID.get() {
+        line.insert("magister_id".to_string(), json!(magister_id));
+    }
+
+    serde_json::Value::Object(line).to_string()
+}
+
+// ` key=value` for every structured key-value on the record
+fn key_values(record: &Record) -> String {
+    struct Collect(String);
+
+    impl<'kvs> VisitSource<'kvs> for Collect {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            let _ = write!(self.0, " {key}={value}");
+            Ok(())
+        }
+    }
+
+    let mut collect = Collect(String::new());
+    let _ = record.key_values().visit(&mut collect);
+    collect.0
+}
+
+/// `log!` with a VastInstance's instance_id, offer_id, machine_id, and host_id attached as
+/// key-values, so its events can be found without parsing the message.
+macro_rules! log_instance {
+    ($level:expr, $instance:expr, $($arg:tt)+) => {{
+        let instance: &$crate::types::VastInstance = &$instance;
+        log::log!(
+            $level,
+            instance_id = instance.instance_id,
+            offer_id = instance.offer.id,
+            machine_id = instance.offer.machine_id,
+            host_id = instance.offer.host_id;
+            $($arg)+
+        )
+    }};
+}
+
+/// `log!` with an Offer's offer_id, machine_id, and host_id attached as key-values.
+macro_rules! log_offer {
+    ($level:expr, $offer:expr, $($arg:tt)+) => {{
+        let offer: &$crate::types::Offer = &$offer;
+        log::log!(
+            $level,
+            offer_id = offer.id,
+            machine_id = offer.machine_id,
+            host_id = offer.host_id;
+            $($arg)+
+        )
+    }};
+}
+
+pub(crate) use {log_instance, log_offer};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn json_lines_carry_the_magister_id() {
+        set_magister_id("magister-test".to_string());
+        let key_values: &[(&str, u64)] = &[("instance_id", 7)];
+
+        let line = json_line(
+            "2026-10-16T00:00:00Z",
+            &Record::builder()
+                .args(format_args!("Dropped instance 7"))
+                .level(Level::Info)
+                .target("magister")
+                .key_values(&key_values)
+                .build(),
+        );
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["magister_id"], "magister-test");
+        assert_eq!(line["message"], "Dropped instance 7");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["instance_id"], 7);
+    }
+
+    #[test]
+    fn json_format_writes_one_object_per_line() {
+        use log::Log;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        set_format(LogFormat::Json);
+        let output = Output::default();
+        let logger = builder()
+            .target(env_logger::Target::Pipe(Box::new(output.clone())))
+            .filter_level(log::LevelFilter::Trace)
+            .build();
+        let key_values: &[(&str, Value)] = &[
+            ("instance_id", Value::from(7u64)),
+            ("dph_total", Value::from(0.25)),
+            ("verified", Value::from(true)),
+            ("gpu_name", Value::from("RTX 4090")),
+        ];
+
+        for message in ["first", "second"] {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("{message}"))
+                    .level(Level::Warn)
+                    .target("magister::vast")
+                    .key_values(&key_values)
+                    .build(),
+            );
+        }
+        logger.flush();
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        let line = &lines[0];
+        assert_eq!(line["message"], "first");
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "magister::vast");
+        assert!(!line["timestamp"].as_str().unwrap().is_empty());
+        assert_eq!(line["instance_id"], 7);
+        assert_eq!(line["dph_total"], 0.25);
+        assert_eq!(line["verified"], true);
+        assert_eq!(line["gpu_name"], "RTX 4090");
+        assert_eq!(lines[1]["message"], "second");
+    }
+
+    // Every record logged in this test binary, as json_line would write it
+    fn captured_lines() -> &'static std::sync::Mutex<Vec<serde_json::Value>> {
+        struct Capture;
+
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                let line = serde_json::from_str(&json_line("", record)).unwrap();
+                captured_lines().lock().unwrap().push(line);
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LINES: OnceLock<std::sync::Mutex<Vec<serde_json::Value>>> = OnceLock::new();
+        LINES.get_or_init(|| {
+            log::set_logger(&Capture).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+            std::sync::Mutex::new(Vec::new())
+        })
+    }
+
+    #[test]
+    fn drop_events_carry_the_instance_fields() {
+        use crate::{
+            drop_history::{DropHistory, DropReason},
+            types::tests::test_instance,
+        };
+
+        let lines = captured_lines();
+        let mut instance = test_instance(4242, 0.3);
+        DropHistory::new(10).mark_for_drop(&mut instance, DropReason::Unhealthy);
+
+        let lines = lines.lock().unwrap();
+        let line = lines
+            .iter()
+            .find(|line| line["instance_id"] == 4242)
+            .expect("the drop should be logged with its instance_id");
+        let message = line["message"].as_str().unwrap();
+        assert!(message.contains("to be dropped"), "{line}");
+        assert!(message.contains("Reason: unhealthy"), "{line}");
+        assert_eq!(line["offer_id"], 4242);
+        assert_eq!(line["machine_id"], 4242);
+        assert_eq!(line["host_id"], 4242);
+    }
+}
@@ -1,22 +1,51 @@
 mod config;
+mod drop_history;
 mod http_handler;
 mod instance_controller;
+mod logging;
+mod offer_filter;
+mod scale_history;
+mod state;
 mod types;
 mod vast;
 
 use anyhow::{Context, Result, anyhow};
 pub use config::Config;
-use log::{error, info};
+use log::{error, info, warn};
+use offer_filter::{LiveUsage, OfferFilter};
 use std::{net::SocketAddr, sync::Arc};
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 use types::MagisterState;
 use vast::VastClient;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    if std::env::args().any(|arg| arg == "--print-config-schema") {
+        println!("{:#}", config::config_schema());
+        return Ok(());
+    }
+
+    logging::init();
 
-    let config = Config::load("magister.toml").context("load configuration")?;
+    let config_path = config_path(std::env::args(), std::env::var("MAGISTER_CONFIG").ok());
+    let mut config = Config::load(&config_path).context("load configuration")?;
+    if std::env::args().any(|arg| arg == "--dry-run") {
+        config.dry_run = true;
+    }
+    // synthetic instance ids must never be adopted as real instances after a restart
+    if config.dry_run {
+        warn!(
+            "Dry run: no instances will really be rented or destroyed, and state won't be persisted"
+        );
+        config.persist_state = false;
+    }
+    logging::set_magister_id(config.magister_id());
+    logging::set_format(config.log_format);
+
+    if std::env::args().any(|arg| arg == "--check") {
+        let passed = check(&config).await.context("Run checks")?;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
     // validate query.  Exit on query error or 0 (or less than desired instances) results returned
     match validate_query(config.clone()).await {
@@ -24,7 +53,7 @@ async fn main() -> Result<()> {
             info!("Query validated");
         }
         Err(e) => {
-            error!("Error validating query: {e}");
+            error!("Error validating query: {e:#}");
             error!("Couldn't validate query. Shutting down.");
             return Ok(());
         }
@@ -36,10 +65,13 @@ async fn main() -> Result<()> {
             .context("Create MagisterState")?,
     );
 
+    let instance_controller_client = state.instance_controller_client.clone();
+    let vast_client = state.vast_client.clone();
+
     // Create the axum router with all routes
     let app = http_handler::create_router(state);
 
-    let http_addr: SocketAddr = ([0, 0, 0, 0], config.http_port).into();
+    let http_addr = SocketAddr::new(config.http_bind_addr(), config.http_port);
 
     // Create a broadcast channel for shutdown signal
     let (shutdown_tx, _) = tokio::sync::broadcast::channel::<()>(1);
@@ -54,6 +86,14 @@ async fn main() -> Result<()> {
         let _ = shutdown_tx_clone.send(());
     });
 
+    // Starts counting down the shutdown timeout once shutdown is requested
+    let mut timeout_shutdown_rx = shutdown_tx.subscribe();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    let shutdown_deadline = async move {
+        let _ = timeout_shutdown_rx.recv().await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+
     // Create shutdown signal handler for HTTP server
     let mut http_shutdown_rx = shutdown_tx.subscribe();
     let http_shutdown_signal = async move {
@@ -78,31 +118,196 @@ async fn main() -> Result<()> {
 
     info!("Magister started. Press Ctrl+C to stop.");
 
-    http_server.await?;
-    info!("HTTP server shutdown complete");
+    // stop taking requests, then let the controller finish the commands they queued
+    let shutdown = async move {
+        http_server.await?;
+        info!("HTTP server shutdown complete");
+        instance_controller_client
+            .shutdown()
+            .await
+            .context("Shut down instance controller")
+    };
+
+    bounded_shutdown(shutdown, shutdown_deadline, shutdown_timeout, &vast_client).await?;
 
     Ok(())
 }
 
+// Waits for shutdown unless deadline comes first.  Returns the instances whose destroy requests
+// were abandoned, and so may be left running on Vast.
+async fn bounded_shutdown(
+    shutdown: impl Future<Output = Result<()>>,
+    deadline: impl Future<Output = ()>,
+    shutdown_timeout: Duration,
+    vast_client: &VastClient,
+) -> Result<Vec<u64>> {
+    tokio::select! {
+        result = shutdown => {
+            result?;
+            Ok(Vec::new())
+        }
+        _ = deadline => {
+            let abandoned = vast_client.drops_in_flight();
+            if abandoned.is_empty() {
+                warn!(
+                    "Graceful shutdown did not complete within {} seconds.  Exiting anyway.",
+                    shutdown_timeout.as_secs()
+                );
+            } else {
+                warn!(
+                    "Graceful shutdown did not complete within {} seconds.  Exiting anyway, abandoning the destroy requests for instances {abandoned:?}, which may be left running.",
+                    shutdown_timeout.as_secs()
+                );
+            }
+            Ok(abandoned)
+        }
+    }
+}
+
+// `--config <path>` or `--config=<path>`, then MAGISTER_CONFIG, then magister.toml
+fn config_path(args: impl IntoIterator<Item = String>, env_path: Option<String>) -> String {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            if let Some(path) = args.next() {
+                return path;
+            }
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return path.to_string();
+        }
+    }
+
+    env_path.unwrap_or_else(|| "magister.toml".to_string())
+}
+
+// For --check.  Confirms the API key works, the query finds offers, and every template exists,
+// without renting anything.  Each check runs even if an earlier one failed, so one run reports
+// everything that's wrong.  Returns whether they all passed.
+async fn check(config: &Config) -> Result<bool> {
+    let vast_client = VastClient::new(config.clone()).context("Create VastClient")?;
+    Ok(check_with(config, &vast_client).await)
+}
+
+// check with an existing VastClient
+async fn check_with(config: &Config, vast_client: &VastClient) -> bool {
+    let mut passed = true;
+
+    match vast_client.get_balance().await {
+        Ok(balance) => info!("vast_api_key: OK.  Account balance is ${balance:.2}"),
+        Err(e) => {
+            error!("vast_api_key: FAILED.  Vast rejected it or couldn't be reached.  {e:#}");
+            passed = false;
+        }
+    }
+
+    let offer_filter = OfferFilter::new(config);
+    match vast_client
+        .find_offers(
+            &offer_filter,
+            config.number_instances,
+            &LiveUsage::default(),
+        )
+        .await
+    {
+        Ok(offers) if offers.is_empty() => {
+            error!("vast_query: FAILED.  It matched 0 offers.  Loosen it.");
+            passed = false;
+        }
+        Ok(offers) if offers.len() < config.number_instances => warn!(
+            "vast_query: OK, but it matched {} offers and number_instances is {}",
+            offers.len(),
+            config.number_instances
+        ),
+        Ok(offers) => info!("vast_query: OK.  It matched {} offers", offers.len()),
+        Err(e) => {
+            error!("vast_query: FAILED.  {e:#}");
+            passed = false;
+        }
+    }
+
+    for template in &config.template_hash {
+        let template_hash = &template.hash;
+        match vast_client.template_exists(template_hash).await {
+            Ok(true) => info!("template_hash {template_hash}: OK"),
+            Ok(false) => {
+                error!(
+                    "template_hash {template_hash}: FAILED.  Vast has no template with this hash."
+                );
+                passed = false;
+            }
+            Err(e) => {
+                error!("template_hash {template_hash}: FAILED.  {e:#}");
+                passed = false;
+            }
+        }
+    }
+
+    if passed {
+        info!("All checks passed");
+    } else {
+        error!("Some checks failed");
+    }
+    passed
+}
+
 async fn validate_query(config: Config) -> Result<()> {
     info!("Validating query...");
-    let vast_client = VastClient::new(config.clone());
+    let vast_client = VastClient::new(config.clone()).context("Create VastClient")?;
+    validate_query_with(&config, &vast_client).await
+}
+
+// validate_query with an existing VastClient
+async fn validate_query_with(config: &Config, vast_client: &VastClient) -> Result<()> {
+    let offer_filter = OfferFilter::new(config);
     let start = Instant::now();
-    let offers = vast_client
-        .find_offers(0)
-        .await
-        .context("Call find_offers")?;
+
+    // not reaching Vast is worth retrying, unlike a query that finds too few offers
+    let attempts = config.validate_query_attempts.max(1);
+    let mut sleep_duration = 0;
+    let mut attempt = 1;
+    let offers = loop {
+        match vast_client
+            .find_offers(
+                &offer_filter,
+                config.number_instances,
+                &LiveUsage::default(),
+            )
+            .await
+        {
+            Ok(offers) => break offers,
+            Err(e) if attempt >= attempts => {
+                return Err(e.context(format!(
+                    "Couldn't reach Vast to validate the query after {attempts} attempts"
+                )));
+            }
+            Err(e) => {
+                sleep_duration += config.validate_query_backoff_secs;
+                warn!(
+                    "Attempt {attempt} / {attempts} to validate the query failed.  Retrying in {sleep_duration} seconds.  {e}"
+                );
+                tokio::time::sleep(Duration::from_secs(sleep_duration)).await;
+                attempt += 1;
+            }
+        }
+    };
 
     if offers.is_empty() {
         Err(anyhow!(
             "query returned 0 offers. Query might be incorrectly constructed or too strict"
         ))
-    } else if offers.len() < config.number_instances {
+    } else if offers.len() < config.number_instances && !config.allow_partial_startup {
         Err(anyhow!(
             "Query returned {} instance offers but this Magister is configured to run {} instances. Loosen the restrictions on the query to return more results.",
             offers.len(),
             config.number_instances
         ))
+    } else if offers.len() < config.number_instances {
+        warn!(
+            "Query returned {} instance offers but this Magister is configured to run {} instances.  Starting with fewer.  Loosen the restrictions on the query to run them all.",
+            offers.len(),
+            config.number_instances
+        );
+        Ok(())
     } else {
         info!(
             "Validation query returned {} offers in {:.2} seconds",
@@ -112,3 +317,203 @@ async fn validate_query(config: Config) -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vast::tests::{mock_config, mock_vast};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn shutdown_gives_up_on_a_destroy_that_never_returns() {
+        async fn destroy() -> axum::http::StatusCode {
+            std::future::pending().await
+        }
+        let router =
+            axum::Router::new().route("/instances/:instance_id/", axum::routing::delete(destroy));
+        let vast_client = mock_vast(mock_config(), router).await;
+
+        let dropping_client = vast_client.clone();
+        let drop_task = tokio::spawn(async move { dropping_client.drop_instance(7).await });
+        let shutdown = async move { drop_task.await? };
+        let shutdown_timeout = Duration::from_millis(100);
+
+        let abandoned = tokio::time::timeout(
+            Duration::from_secs(5),
+            bounded_shutdown(
+                shutdown,
+                tokio::time::sleep(shutdown_timeout),
+                shutdown_timeout,
+                &vast_client,
+            ),
+        )
+        .await
+        .expect("shutdown should give up after shutdown_timeout")
+        .unwrap();
+        assert_eq!(abandoned, vec![7]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_that_finishes_abandons_nothing() {
+        let vast_client = mock_vast(mock_config(), axum::Router::new()).await;
+
+        let abandoned = bounded_shutdown(
+            async { Ok(()) },
+            std::future::pending(),
+            Duration::from_secs(1),
+            &vast_client,
+        )
+        .await
+        .unwrap();
+        assert!(abandoned.is_empty());
+    }
+
+    #[test]
+    fn config_flag_wins_over_env_var_over_default() {
+        let args = |args: &[&str]| -> Vec<String> {
+            std::iter::once("magister")
+                .chain(args.iter().copied())
+                .map(String::from)
+                .collect()
+        };
+        let env_path = || Some("/etc/magister/env.toml".to_string());
+
+        assert_eq!(
+            config_path(args(&["--config", "/srv/flag.toml"]), env_path()),
+            "/srv/flag.toml"
+        );
+        assert_eq!(
+            config_path(args(&["--dry-run", "--config=/srv/flag.toml"]), env_path()),
+            "/srv/flag.toml"
+        );
+        assert_eq!(
+            config_path(args(&["--dry-run"]), env_path()),
+            "/etc/magister/env.toml"
+        );
+        assert_eq!(config_path(args(&[]), None), "magister.toml");
+    }
+
+    // a Vast whose offer search fails failures times, then finds offers
+    async fn flaky_vast(
+        config: Config,
+        failures: usize,
+        offers: Vec<types::Offer>,
+    ) -> (Arc<VastClient>, Arc<AtomicUsize>) {
+        use axum::{Json, http::StatusCode, response::IntoResponse, routing::post};
+
+        let searches = Arc::new(AtomicUsize::new(0));
+        let counted_searches = searches.clone();
+        let offers = serde_json::json!({ "offers": offers });
+        let router = axum::Router::new().route(
+            "/bundles/",
+            post(move || async move {
+                if counted_searches.fetch_add(1, Ordering::Relaxed) < failures {
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                } else {
+                    Json(offers).into_response()
+                }
+            }),
+        );
+        (mock_vast(config, router).await, searches)
+    }
+
+    fn validation_config() -> Config {
+        let mut config = mock_config();
+        config.validate_query_attempts = 3;
+        config.validate_query_backoff_secs = 0;
+        config
+    }
+
+    #[tokio::test]
+    async fn query_validation_retries_when_vast_is_unreachable() {
+        use crate::types::tests::test_offer;
+
+        let offers = vec![test_offer(1, 1, 1, 0.3), test_offer(2, 2, 2, 0.3)];
+        let config = validation_config();
+        let (vast_client, searches) = flaky_vast(config.clone(), 2, offers).await;
+
+        validate_query_with(&config, &vast_client).await.unwrap();
+        assert_eq!(searches.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn query_validation_fails_at_once_when_nothing_matches() {
+        let config = validation_config();
+        let (vast_client, searches) = flaky_vast(config.clone(), 0, Vec::new()).await;
+
+        let error = validate_query_with(&config, &vast_client)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("query returned 0 offers"),
+            "{error:#}"
+        );
+        assert_eq!(searches.load(Ordering::Relaxed), 1);
+    }
+
+    // Stands in for Vast for --check: accepts the API key if key_valid, returns offers to any
+    // search, and knows the templates with these hashes
+    async fn check_vast(
+        key_valid: bool,
+        offers: Vec<types::Offer>,
+        templates: &'static [&'static str],
+    ) -> Arc<VastClient> {
+        use axum::{
+            Json,
+            http::StatusCode,
+            response::IntoResponse,
+            routing::{get, post},
+        };
+
+        let offers = serde_json::json!({ "offers": offers });
+        let templates = serde_json::json!({
+            "templates": templates
+                .iter()
+                .map(|hash| serde_json::json!({ "hash_id": hash }))
+                .collect::<Vec<_>>(),
+        });
+        let router = axum::Router::new()
+            .route(
+                "/users/current/",
+                get(move || async move {
+                    if key_valid {
+                        Json(serde_json::json!({ "credit": 12.5 })).into_response()
+                    } else {
+                        StatusCode::UNAUTHORIZED.into_response()
+                    }
+                }),
+            )
+            .route("/bundles/", post(move || async move { Json(offers) }))
+            .route("/template/", get(move || async move { Json(templates) }));
+        mock_vast(mock_config(), router).await
+    }
+
+    #[tokio::test]
+    async fn check_passes_with_a_good_key_query_and_template() {
+        use crate::types::tests::test_offer;
+
+        let offers = vec![test_offer(1, 1, 1, 0.3), test_offer(2, 2, 2, 0.3)];
+        let vast_client = check_vast(true, offers, &["template"]).await;
+        assert!(check_with(&mock_config(), &vast_client).await);
+    }
+
+    #[tokio::test]
+    async fn check_fails_on_each_problem() {
+        use crate::types::tests::test_offer;
+
+        let offers = || vec![test_offer(1, 1, 1, 0.3)];
+        let config = mock_config();
+
+        // rejected API key
+        let vast_client = check_vast(false, offers(), &["template"]).await;
+        assert!(!check_with(&config, &vast_client).await);
+
+        // query that matches nothing
+        let vast_client = check_vast(true, Vec::new(), &["template"]).await;
+        assert!(!check_with(&config, &vast_client).await);
+
+        // template hash Vast doesn't know
+        let vast_client = check_vast(true, offers(), &["some-other-template"]).await;
+        assert!(!check_with(&config, &vast_client).await);
+    }
+}
@@ -0,0 +1,961 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, ProvisioningStrategy, VastQueryConfig},
+    types::Offer,
+};
+
+// create requests in a row that must fail on a machine before it's quarantined
+const MACHINE_QUARANTINE_FAILURES: u32 = 3;
+// caps the quarantine doubling at 2^5 times machine_quarantine_secs
+const MAX_QUARANTINE_DOUBLINGS: u32 = 5;
+
+// Holds everything offers are filtered and ordered by.  Some values come from config and some are
+// updated at runtime.
+#[derive(Clone)]
+pub struct OfferFilter {
+    bad_hosts: HashSet<u64>,
+    bad_machines: HashSet<u64>,
+    good_hosts: HashSet<u64>,
+    good_machines: HashSet<u64>,
+    // Keeps track of the last dropped instance machine_id so it isn't re-requested in the common
+    // scenario where there is only 1 instance.
+    pub last_dropped: u64,
+    // how instances on each host have turned out this session, keyed by host_id
+    host_stats: HashMap<u64, VerificationStats>,
+    // failed create requests keyed by machine_id.  Unlike bad_machines this is learned at runtime
+    // and expires.
+    machine_failures: HashMap<u64, MachineFailures>,
+    machine_quarantine: Duration,
+    provisioning_strategy: ProvisioningStrategy,
+    // one_instance_per_* folded into max_instances_per_*
+    max_instances_per_host: Option<usize>,
+    max_instances_per_machine: Option<usize>,
+    max_instances_per_geolocation: Option<usize>,
+    // lowercased.  When allowed_geolocations is set only offers matching one of them are kept.
+    allowed_geolocations: Option<Vec<String>>,
+    denied_geolocations: Vec<String>,
+}
+
+impl OfferFilter {
+    pub fn new(config: &Config) -> Self {
+        let to_set =
+            |list: &Option<Vec<u64>>| -> HashSet<u64> { list.iter().flatten().copied().collect() };
+
+        Self {
+            bad_hosts: to_set(&config.bad_hosts),
+            bad_machines: to_set(&config.bad_machines),
+            good_hosts: to_set(&config.good_hosts),
+            good_machines: to_set(&config.good_machines),
+            last_dropped: 0,
+            host_stats: HashMap::new(),
+            machine_failures: HashMap::new(),
+            machine_quarantine: Duration::from_secs(config.machine_quarantine_secs),
+            provisioning_strategy: config.provisioning_strategy,
+            max_instances_per_host: strictest_cap(
+                config.one_instance_per_host,
+                config.max_instances_per_host,
+            ),
+            max_instances_per_machine: strictest_cap(
+                config.one_instance_per_machine,
+                config.max_instances_per_machine,
+            ),
+            max_instances_per_geolocation: config.max_instances_per_geolocation,
+            allowed_geolocations: config
+                .allowed_geolocations
+                .as_ref()
+                .map(|allowed| allowed.iter().map(|entry| entry.to_lowercase()).collect()),
+            denied_geolocations: config
+                .denied_geolocations
+                .iter()
+                .flatten()
+                .map(|entry| entry.to_lowercase())
+                .collect(),
+        }
+    }
+
+    // returns true if the host wasn't already marked good
+    pub fn mark_good_host(&mut self, host_id: u64) -> bool {
+        self.good_hosts.insert(host_id)
+    }
+
+    // returns true if the host was marked good
+    pub fn unmark_good_host(&mut self, host_id: u64) -> bool {
+        self.good_hosts.remove(&host_id)
+    }
+
+    // returns true if the machine wasn't already marked good
+    pub fn mark_good_machine(&mut self, machine_id: u64) -> bool {
+        self.good_machines.insert(machine_id)
+    }
+
+    // returns true if the machine was marked good
+    pub fn unmark_good_machine(&mut self, machine_id: u64) -> bool {
+        self.good_machines.remove(&machine_id)
+    }
+
+    pub fn host_stats(&self) -> &HashMap<u64, VerificationStats> {
+        &self.host_stats
+    }
+
+    // restore stats learned before a restart
+    pub fn set_host_stats(&mut self, host_stats: HashMap<u64, VerificationStats>) {
+        self.host_stats = host_stats;
+    }
+
+    // a Contemplant on this host called /verify
+    pub fn record_verified(&mut self, host_id: u64) {
+        self.host_stats.entry(host_id).or_default().verified += 1;
+    }
+
+    // a Contemplant on this host never called /verify before the verification timeout
+    pub fn record_unverified(&mut self, host_id: u64) {
+        self.host_stats.entry(host_id).or_default().unverified += 1;
+    }
+
+    // a create request on this machine succeeded, so forget its failures
+    pub fn record_create_success(&mut self, machine_id: u64) {
+        self.machine_failures.remove(&machine_id);
+    }
+
+    // A create request on this machine failed.  After MACHINE_QUARANTINE_FAILURES in a row the
+    // machine is quarantined, for twice as long as last time if it has been quarantined before.
+    pub fn record_create_failure(&mut self, machine_id: u64) {
+        let failures = self.machine_failures.entry(machine_id).or_default();
+        failures.consecutive += 1;
+        if failures.consecutive < MACHINE_QUARANTINE_FAILURES {
+            return;
+        }
+
+        let cooldown = self.quarantine(machine_id);
+        warn!(
+            machine_id;
+            "Quarantining machine_id {machine_id} for {} seconds after {MACHINE_QUARANTINE_FAILURES} failed create requests in a row",
+            cooldown.as_secs()
+        );
+    }
+
+    // Skips the machine for machine_quarantine, doubled for each earlier quarantine.  Returns how
+    // long.
+    pub fn quarantine(&mut self, machine_id: u64) -> Duration {
+        let failures = self.machine_failures.entry(machine_id).or_default();
+        let doublings = failures.times_quarantined.min(MAX_QUARANTINE_DOUBLINGS);
+        let cooldown = self.machine_quarantine * 2u32.pow(doublings);
+        failures.consecutive = 0;
+        failures.times_quarantined += 1;
+        failures.quarantined_until = Some(Instant::now() + cooldown);
+        cooldown
+    }
+
+    // lets machines whose quarantine has run out be requested again
+    pub fn release_expired_quarantines(&mut self) {
+        let now = Instant::now();
+        for (machine_id, failures) in self.machine_failures.iter_mut() {
+            if failures.quarantined_until.is_some_and(|until| until <= now) {
+                failures.quarantined_until = None;
+                info!(machine_id = *machine_id; "Machine_id {machine_id} is out of quarantine");
+            }
+        }
+    }
+
+    fn is_quarantined(&self, machine_id: u64) -> bool {
+        self.machine_failures
+            .get(&machine_id)
+            .is_some_and(|failures| failures.quarantined_until.is_some())
+    }
+
+    // Removes offers we never want and orders the rest by how much we want them, in O(n log n).
+    // vast_query is the query the offers were found with, whose cost ceilings apply.
+    pub fn filter(&self, offers: Vec<Offer>, vast_query: &VastQueryConfig) -> Vec<Offer> {
+        let count_before_filter = offers.len();
+
+        let offers: Vec<Offer> = offers
+            .into_iter()
+            .filter(|offer| {
+                let fast_enough_up = vast_query
+                    .min_inet_up
+                    .is_none_or(|min_inet_up| offer.inet_up >= min_inet_up);
+                let fast_enough_down = vast_query
+                    .min_inet_down
+                    .is_none_or(|min_inet_down| offer.inet_down >= min_inet_down);
+                fast_enough_up && fast_enough_down
+            })
+            .collect();
+        let too_slow = count_before_filter - offers.len();
+        if too_slow > 0 {
+            info!("Filtered out {too_slow} offers below the minimum bandwidth");
+        }
+
+        let count_before_disk_filter = offers.len();
+        let offers: Vec<Offer> = offers
+            .into_iter()
+            .filter(|offer| {
+                let fast_enough_disk = vast_query
+                    .min_disk_bw
+                    .is_none_or(|min_disk_bw| offer.disk_bw >= min_disk_bw);
+                let solid_state = !vast_query.require_nvme || is_solid_state(&offer.disk_name);
+                fast_enough_disk && solid_state
+            })
+            .collect();
+        let slow_disk = count_before_disk_filter - offers.len();
+        if slow_disk > 0 {
+            info!("Filtered out {slow_disk} offers with slow or spinning disks");
+        }
+
+        let count_before_reliability_filter = offers.len();
+        let offers: Vec<Offer> = offers
+            .into_iter()
+            .filter(|offer| {
+                vast_query
+                    .min_reliability
+                    .is_none_or(|min_reliability| offer.reliability >= min_reliability)
+            })
+            .collect();
+        let unreliable = count_before_reliability_filter - offers.len();
+        if unreliable > 0 {
+            info!("Filtered out {unreliable} offers below min_reliability");
+        }
+
+        let count_before_value_filter = offers.len();
+        let offers: Vec<Offer> = offers
+            .into_iter()
+            .filter(|offer| {
+                vast_query
+                    .max_dph_per_dlperf
+                    .is_none_or(|max_dph_per_dlperf| {
+                        offer.dlperf_per_dphtotal * max_dph_per_dlperf >= 1.0
+                    })
+            })
+            .collect();
+        let poor_value = count_before_value_filter - offers.len();
+        if poor_value > 0 {
+            info!("Filtered out {poor_value} offers over max_dph_per_dlperf");
+        }
+
+        let count_before_geolocation_filter = offers.len();
+        let offers: Vec<Offer> = offers
+            .into_iter()
+            .filter(|offer| self.geolocation_permitted(&offer.geolocation))
+            .collect();
+        let wrong_geolocation = count_before_geolocation_filter - offers.len();
+        if wrong_geolocation > 0 {
+            info!(
+                "Filtered out {wrong_geolocation} offers outside allowed_geolocations or in denied_geolocations"
+            );
+        }
+
+        let mut offers: Vec<Offer> = offers
+            .into_iter()
+            .filter(|offer| {
+                let host_not_bad = !self.bad_hosts.contains(&offer.host_id);
+
+                let machine_not_bad = !self.bad_machines.contains(&offer.machine_id);
+
+                let machine_not_recently_dropped = offer.machine_id != self.last_dropped;
+
+                let machine_not_quarantined = !self.is_quarantined(offer.machine_id);
+
+                let under_hard_ceiling = offer.dph_total <= vast_query.cost_per_hour;
+
+                host_not_bad
+                    && machine_not_bad
+                    && machine_not_recently_dropped
+                    && machine_not_quarantined
+                    && under_hard_ceiling
+            })
+            .collect();
+
+        sort_offers(self.provisioning_strategy, &mut offers);
+
+        // stable sort so hosts that have verified more reliably come first, otherwise keeping
+        // strategy order.  Hosts without history are treated as reliable.
+        offers.sort_by(|a, b| {
+            let success_rate = |host_id| {
+                self.host_stats
+                    .get(&host_id)
+                    .map_or(1.0, VerificationStats::success_rate)
+            };
+            success_rate(b.host_id).total_cmp(&success_rate(a.host_id))
+        });
+
+        // stable sort so offers on good hosts or machines come first, otherwise keeping the order
+        // above
+        offers.sort_by_key(|offer| {
+            !(self.good_hosts.contains(&offer.host_id)
+                || self.good_machines.contains(&offer.machine_id))
+        });
+
+        // stable sort so offers under the soft ceiling come first, otherwise keeping the order above
+        offers.sort_by_key(|offer| vast_query.over_soft_ceiling(offer.dph_total));
+
+        // now that the best offer is first, keep only it for each machine or host
+        let offers = self.exclude_used(offers, &HashMap::new(), &HashMap::new());
+        let offers = self.limit_per_geolocation(offers, &HashMap::new());
+
+        let count_after_filter = offers.len();
+        debug!(
+            "Filtered out {} offers",
+            count_before_filter - count_after_filter
+        );
+
+        offers
+    }
+
+    // whether an offer in this geolocation passes allowed_geolocations and denied_geolocations
+    fn geolocation_permitted(&self, geolocation: &str) -> bool {
+        let geolocation = geolocation.to_lowercase();
+        let matches = |entries: &[String]| {
+            entries
+                .iter()
+                .any(|entry| geolocation_matches(&geolocation, entry))
+        };
+
+        let allowed = self.allowed_geolocations.as_deref().is_none_or(&matches);
+        allowed && !matches(&self.denied_geolocations)
+    }
+
+    // Keeps at most max_instances_per_host offers per host and max_instances_per_machine per
+    // machine, counting the instances already running on each in live_hosts and live_machines.
+    // Earlier offers are kept over later ones.
+    pub fn exclude_used(
+        &self,
+        offers: Vec<Offer>,
+        live_hosts: &HashMap<u64, usize>,
+        live_machines: &HashMap<u64, usize>,
+    ) -> Vec<Offer> {
+        let mut host_counts = live_hosts.clone();
+        let mut machine_counts = live_machines.clone();
+        offers
+            .into_iter()
+            .filter(|offer| {
+                let host_count = host_counts.entry(offer.host_id).or_default();
+                if self
+                    .max_instances_per_host
+                    .is_some_and(|max_instances_per_host| *host_count >= max_instances_per_host)
+                {
+                    debug!(
+                        offer_id = offer.id;
+                        "Skipping offer {} because host {} is at max_instances_per_host",
+                        offer.id,
+                        offer.host_id
+                    );
+                    return false;
+                }
+                let machine_count = machine_counts.entry(offer.machine_id).or_default();
+                if self
+                    .max_instances_per_machine
+                    .is_some_and(|max_instances_per_machine| {
+                        *machine_count >= max_instances_per_machine
+                    })
+                {
+                    debug!(
+                        offer_id = offer.id;
+                        "Skipping offer {} because machine {} is at max_instances_per_machine",
+                        offer.id,
+                        offer.machine_id
+                    );
+                    return false;
+                }
+
+                *host_count += 1;
+                *machine_count += 1;
+                true
+            })
+            .collect()
+    }
+
+    // Keeps at most max_instances_per_geolocation offers per geolocation, counting the instances
+    // already running there in live_geolocations.  Earlier offers are kept over later ones.
+    // offers left once exclude_used and limit_per_geolocation have capped them alongside live
+    pub fn within_caps(&self, offers: Vec<Offer>, live: &LiveUsage) -> Vec<Offer> {
+        let offers = self.exclude_used(offers, &live.hosts, &live.machines);
+        self.limit_per_geolocation(offers, &live.geolocations)
+    }
+
+    pub fn limit_per_geolocation(
+        &self,
+        offers: Vec<Offer>,
+        live_geolocations: &HashMap<String, usize>,
+    ) -> Vec<Offer> {
+        let Some(max_instances_per_geolocation) = self.max_instances_per_geolocation else {
+            return offers;
+        };
+
+        let mut counts = live_geolocations.clone();
+        offers
+            .into_iter()
+            .filter(|offer| {
+                let count = counts.entry(offer.geolocation.clone()).or_default();
+                if *count < max_instances_per_geolocation {
+                    *count += 1;
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect()
+    }
+}
+
+// How many instances we aren't about to drop are on each host, machine, and geolocation
+#[derive(Debug, Clone, Default)]
+pub struct LiveUsage {
+    pub hosts: HashMap<u64, usize>,
+    pub machines: HashMap<u64, usize>,
+    pub geolocations: HashMap<String, usize>,
+}
+
+// the lower of the one_instance_per_* flag's cap of 1 and the max_instances_per_* cap
+fn strictest_cap(one_instance: bool, max_instances: Option<usize>) -> Option<usize> {
+    one_instance
+        .then_some(1)
+        .into_iter()
+        .chain(max_instances)
+        .min()
+}
+
+// Failed create requests on one machine
+#[derive(Debug, Clone, Copy, Default)]
+struct MachineFailures {
+    // since the last success or quarantine
+    consecutive: u32,
+    times_quarantined: u32,
+    quarantined_until: Option<Instant>,
+}
+
+// Outcomes of instances created on one host, or from one template
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct VerificationStats {
+    pub verified: u32,
+    pub unverified: u32,
+}
+
+impl VerificationStats {
+    // verified creates / total creates that have finished verifying one way or the other
+    pub fn success_rate(&self) -> f64 {
+        let total = self.verified + self.unverified;
+        if total == 0 {
+            1.0
+        } else {
+            self.verified as f64 / total as f64
+        }
+    }
+}
+
+// Vast only reports the disk's model name, so guess from it.  "Samsung SSD 980 PRO" is NVMe too,
+// so any SSD counts.  A blank name is treated as unknown, and rejected.
+fn is_solid_state(disk_name: &str) -> bool {
+    let disk_name = disk_name.to_lowercase();
+    disk_name.contains("nvme") || disk_name.contains("ssd")
+}
+
+// Vast geolocations look like "Texas, US".  Two letter entries are country codes and have to
+// match the part after the last comma exactly, since as substrings "US" would match "Australia".
+// Longer entries match anywhere.  Both are expected to be lowercased.
+fn geolocation_matches(geolocation: &str, entry: &str) -> bool {
+    if entry.len() == 2 {
+        let country_code = geolocation.rsplit(',').next().unwrap_or_default().trim();
+        country_code == entry
+    } else {
+        geolocation.contains(entry)
+    }
+}
+
+// Orders offers by the configured provisioning strategy.  Score keeps the order Vast returned,
+// which is already by score.
+fn sort_offers(strategy: ProvisioningStrategy, offers: &mut [Offer]) {
+    match strategy {
+        ProvisioningStrategy::Score => {}
+        ProvisioningStrategy::Cheapest => {
+            offers.sort_by(|a, b| a.dph_total.total_cmp(&b.dph_total));
+        }
+        ProvisioningStrategy::Fastest => {
+            offers.sort_by(|a, b| b.dlperf.total_cmp(&a.dlperf));
+        }
+        ProvisioningStrategy::BestValue => {
+            offers.sort_by(|a, b| b.dlperf_per_dphtotal.total_cmp(&a.dlperf_per_dphtotal));
+        }
+    }
+}
+
+// Moves one offer from each host not in `used_hosts` to the front, until there are enough to
+// reach `min_distinct_hosts`.  The remaining offers keep their order behind them.
+pub fn spread_across_hosts(
+    offers: Vec<Offer>,
+    used_hosts: &HashSet<u64>,
+    min_distinct_hosts: usize,
+) -> Vec<Offer> {
+    let hosts_needed = min_distinct_hosts.saturating_sub(used_hosts.len());
+    let mut new_hosts = HashSet::new();
+    let (front, back): (Vec<Offer>, Vec<Offer>) = offers.into_iter().partition(|offer| {
+        if new_hosts.len() < hosts_needed
+            && !used_hosts.contains(&offer.host_id)
+            && !new_hosts.contains(&offer.host_id)
+        {
+            new_hosts.insert(offer.host_id);
+            true
+        } else {
+            false
+        }
+    });
+
+    front.into_iter().chain(back).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::tests::test_config, types::tests::test_offer};
+
+    fn ids(offers: &[Offer]) -> Vec<u64> {
+        offers.iter().map(|offer| offer.id).collect()
+    }
+
+    #[test]
+    fn spread_across_hosts_puts_unused_hosts_first() {
+        // offer id, machine id, host id
+        let offers = vec![
+            test_offer(1, 1, 1, 0.3),
+            test_offer(2, 2, 1, 0.3),
+            test_offer(3, 3, 2, 0.4),
+            test_offer(4, 4, 3, 0.5),
+            test_offer(5, 5, 4, 0.5),
+        ];
+
+        let spread = spread_across_hosts(offers.clone(), &HashSet::new(), 3);
+        assert_eq!(ids(&spread), vec![1, 3, 4, 2, 5]);
+
+        // host 2 already has an instance, so it doesn't count toward the new hosts needed
+        let spread = spread_across_hosts(offers, &HashSet::from([2]), 3);
+        assert_eq!(ids(&spread), vec![1, 4, 2, 3, 5]);
+    }
+
+    #[test]
+    fn over_soft_offers_are_used_only_under_the_hard_cap() {
+        let config = test_config();
+        let mut vast_query = config.vast_query.clone();
+        vast_query.cost_per_hour = 0.5;
+        vast_query.soft_cost_per_hour = Some(0.3);
+        let offer_filter = OfferFilter::new(&config);
+
+        let over_soft_only = vec![
+            test_offer(1, 1, 1, 0.45),
+            test_offer(2, 2, 2, 0.6),
+            test_offer(3, 3, 3, 0.4),
+        ];
+        let offers = offer_filter.filter(over_soft_only, &vast_query);
+        assert_eq!(ids(&offers), vec![1, 3]);
+
+        // anything under the soft cap goes first, whatever the strategy would have preferred
+        let mixed = vec![test_offer(1, 1, 1, 0.45), test_offer(2, 2, 2, 0.25)];
+        let offers = offer_filter.filter(mixed, &vast_query);
+        assert_eq!(ids(&offers), vec![2, 1]);
+    }
+
+    #[test]
+    fn each_strategy_picks_its_offer_first() {
+        let offer = |id, dph_total, dlperf: f64| {
+            let mut offer = test_offer(id, id, id, dph_total);
+            offer.dlperf = dlperf;
+            offer.dlperf_per_dphtotal = dlperf / dph_total;
+            offer
+        };
+        // in Vast's score order
+        let offers = vec![
+            offer(4, 0.3, 60.0),
+            offer(1, 0.2, 50.0),
+            offer(2, 0.4, 200.0),
+            offer(3, 0.49, 220.0),
+        ];
+
+        for (strategy, expected) in [
+            (ProvisioningStrategy::Score, 4),
+            (ProvisioningStrategy::Cheapest, 1),
+            (ProvisioningStrategy::Fastest, 3),
+            (ProvisioningStrategy::BestValue, 2),
+        ] {
+            let mut config = test_config();
+            config.provisioning_strategy = strategy;
+            let offer_filter = OfferFilter::new(&config);
+            let filtered = offer_filter.filter(offers.clone(), &config.vast_query);
+            assert_eq!(filtered[0].id, expected, "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn hosts_with_a_poor_history_go_last() {
+        let config = test_config();
+        let mut offer_filter = OfferFilter::new(&config);
+        offer_filter.record_verified(5);
+        for _ in 0..3 {
+            offer_filter.record_unverified(5);
+        }
+        offer_filter.record_verified(9);
+
+        // equal scores, with the unreliable host first in Vast's order
+        let offers = vec![
+            test_offer(1, 1, 5, 0.3),
+            test_offer(2, 2, 9, 0.3),
+            test_offer(3, 3, 7, 0.3),
+        ];
+        let filtered = offer_filter.filter(offers, &config.vast_query);
+        assert_eq!(ids(&filtered), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn good_hosts_and_machines_come_first_in_score_order() {
+        let mut config = test_config();
+        config.good_hosts = Some(vec![9]);
+        config.good_machines = Some(vec![4]);
+        let offer_filter = OfferFilter::new(&config);
+
+        // offer id, machine id, host id, in Vast's score order
+        let offers = vec![
+            test_offer(1, 1, 1, 0.3),
+            test_offer(2, 2, 9, 0.3),
+            test_offer(3, 3, 3, 0.3),
+            test_offer(4, 4, 4, 0.3),
+            test_offer(5, 5, 9, 0.3),
+        ];
+        let filtered = offer_filter.filter(offers, &config.vast_query);
+        assert_eq!(ids(&filtered), vec![2, 4, 5, 1, 3]);
+    }
+
+    #[test]
+    fn one_instance_per_machine_keeps_the_first_offer_on_each() {
+        let mut config = test_config();
+        config.one_instance_per_machine = true;
+        let offer_filter = OfferFilter::new(&config);
+
+        // offer id, machine id, host id.  Offers 1 and 2 are slices of the same machine.
+        let offers = vec![
+            test_offer(1, 1, 1, 0.3),
+            test_offer(2, 1, 1, 0.3),
+            test_offer(3, 2, 1, 0.4),
+            test_offer(4, 3, 2, 0.4),
+        ];
+        let kept = offer_filter.exclude_used(offers.clone(), &HashMap::new(), &HashMap::new());
+        assert_eq!(ids(&kept), vec![1, 3, 4]);
+
+        // machine 2 already runs an instance
+        let kept =
+            offer_filter.exclude_used(offers.clone(), &HashMap::new(), &HashMap::from([(2, 1)]));
+        assert_eq!(ids(&kept), vec![1, 4]);
+
+        config.one_instance_per_machine = false;
+        config.one_instance_per_host = true;
+        let offer_filter = OfferFilter::new(&config);
+        let kept = offer_filter.exclude_used(offers, &HashMap::new(), &HashMap::new());
+        assert_eq!(ids(&kept), vec![1, 4]);
+    }
+
+    #[test]
+    fn machines_are_quarantined_after_repeated_create_failures() {
+        let config = test_config();
+        let mut offer_filter = OfferFilter::new(&config);
+        let offers = vec![test_offer(1, 1, 1, 0.3), test_offer(2, 2, 2, 0.3)];
+
+        for _ in 1..MACHINE_QUARANTINE_FAILURES {
+            offer_filter.record_create_failure(1);
+        }
+        // a success starts machine 2's count over
+        offer_filter.record_create_failure(2);
+        offer_filter.record_create_success(2);
+        offer_filter.record_create_failure(2);
+        let filtered = offer_filter.filter(offers.clone(), &config.vast_query);
+        assert_eq!(ids(&filtered), vec![1, 2]);
+
+        offer_filter.record_create_failure(1);
+        let filtered = offer_filter.filter(offers.clone(), &config.vast_query);
+        assert_eq!(ids(&filtered), vec![2]);
+
+        // still quarantined until the cooldown runs out
+        offer_filter.release_expired_quarantines();
+        assert!(offer_filter.is_quarantined(1));
+        offer_filter
+            .machine_failures
+            .get_mut(&1)
+            .unwrap()
+            .quarantined_until = Some(Instant::now());
+        offer_filter.release_expired_quarantines();
+        let filtered = offer_filter.filter(offers, &config.vast_query);
+        assert_eq!(ids(&filtered), vec![1, 2]);
+
+        // a machine that keeps failing is quarantined for longer each time
+        assert_eq!(
+            offer_filter.quarantine(1),
+            offer_filter.machine_quarantine * 2
+        );
+    }
+
+    #[test]
+    fn offers_per_geolocation_are_capped() {
+        let mut config = test_config();
+        config.max_instances_per_geolocation = Some(2);
+        let offer_filter = OfferFilter::new(&config);
+        let offers: Vec<Offer> = [
+            "Oregon, US",
+            "Oregon, US",
+            "Quebec, CA",
+            "Oregon, US",
+            "Quebec, CA",
+            "Bavaria, DE",
+        ]
+        .into_iter()
+        .zip(1..)
+        .map(|(geolocation, id)| {
+            let mut offer = test_offer(id, id, id, 0.3);
+            offer.geolocation = geolocation.to_string();
+            offer
+        })
+        .collect();
+
+        let filtered = offer_filter.filter(offers.clone(), &config.vast_query);
+        assert_eq!(ids(&filtered), vec![1, 2, 3, 5, 6]);
+
+        // Quebec already has an instance, so it has room for one more
+        let live_geolocations = HashMap::from([("Quebec, CA".to_string(), 1)]);
+        let limited = offer_filter.limit_per_geolocation(offers, &live_geolocations);
+        assert_eq!(ids(&limited), vec![1, 2, 3, 6]);
+    }
+
+    #[test]
+    fn offers_below_the_minimum_bandwidth_are_dropped() {
+        let config = test_config();
+        let offer_filter = OfferFilter::new(&config);
+        let offer = |id, inet_up, inet_down| {
+            let mut offer = test_offer(id, id, id, 0.3);
+            offer.inet_up = inet_up;
+            offer.inet_down = inet_down;
+            offer
+        };
+        let offers = vec![
+            offer(1, 500.0, 500.0),
+            offer(2, 50.0, 500.0),
+            offer(3, 500.0, 50.0),
+            offer(4, 100.0, 200.0),
+        ];
+
+        let mut vast_query = config.vast_query.clone();
+        let filtered = offer_filter.filter(offers.clone(), &vast_query);
+        assert_eq!(ids(&filtered), vec![1, 2, 3, 4]);
+
+        vast_query.min_inet_up = Some(100.0);
+        let filtered = offer_filter.filter(offers.clone(), &vast_query);
+        assert_eq!(ids(&filtered), vec![1, 3, 4]);
+
+        vast_query.min_inet_down = Some(200.0);
+        let filtered = offer_filter.filter(offers, &vast_query);
+        assert_eq!(ids(&filtered), vec![1, 4]);
+    }
+
+    #[test]
+    fn slow_and_spinning_disks_are_dropped() {
+        let config = test_config();
+        let offer_filter = OfferFilter::new(&config);
+        let offer = |id, disk_bw, disk_name: &str| {
+            let mut offer = test_offer(id, id, id, 0.3);
+            offer.disk_bw = disk_bw;
+            offer.disk_name = disk_name.to_string();
+            offer
+        };
+        let offers = vec![
+            offer(1, 3000.0, "Samsung SSD 980 PRO"),
+            offer(2, 2500.0, "WD_BLACK SN850X NVMe"),
+            offer(3, 200.0, "ST4000DM004"),
+            offer(4, 4000.0, ""),
+            offer(5, 800.0, "KINGSTON SSD"),
+        ];
+
+        let mut vast_query = config.vast_query.clone();
+        vast_query.min_disk_bw = Some(1000.0);
+        let filtered = offer_filter.filter(offers.clone(), &vast_query);
+        assert_eq!(ids(&filtered), vec![1, 2, 4]);
+
+        // a blank disk name can't be shown to be solid state
+        vast_query.min_disk_bw = None;
+        vast_query.require_nvme = true;
+        let filtered = offer_filter.filter(offers, &vast_query);
+        assert_eq!(ids(&filtered), vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn offers_are_kept_to_allowed_and_out_of_denied_geolocations() {
+        let offers: Vec<Offer> = [
+            "Oregon, US",
+            "Quebec, CA",
+            "Bavaria, DE",
+            "Ontario, CA",
+            "Texas, US",
+        ]
+        .into_iter()
+        .zip(1..)
+        .map(|(geolocation, id)| {
+            let mut offer = test_offer(id, id, id, 0.3);
+            offer.geolocation = geolocation.to_string();
+            offer
+        })
+        .collect();
+        let filtered = |allowed: Option<&[&str]>, denied: Option<&[&str]>| {
+            let entries = |list: &[&str]| list.iter().map(|entry| entry.to_string()).collect();
+            let mut config = test_config();
+            config.allowed_geolocations = allowed.map(entries);
+            config.denied_geolocations = denied.map(entries);
+            let offer_filter = OfferFilter::new(&config);
+            ids(&offer_filter.filter(offers.clone(), &config.vast_query))
+        };
+
+        assert_eq!(filtered(None, None), vec![1, 2, 3, 4, 5]);
+
+        // two letters match the country code, anything longer part of the geolocation
+        assert_eq!(filtered(Some(&["us", "QUEBEC"]), None), vec![1, 2, 5]);
+        assert_eq!(filtered(Some(&["Ba"]), None), Vec::<u64>::new());
+
+        assert_eq!(filtered(None, Some(&["Ca"])), vec![1, 3, 5]);
+        assert_eq!(filtered(None, Some(&["texas"])), vec![1, 2, 3, 4]);
+
+        // denied wins over allowed
+        assert_eq!(
+            filtered(Some(&["CA", "us"]), Some(&["ontario", "oregon"])),
+            vec![2, 5]
+        );
+    }
+
+    #[test]
+    fn perf_target_picks_the_cheapest_good_value_offer() {
+        let mut config = test_config();
+        config.provisioning_strategy = ProvisioningStrategy::Cheapest;
+        config.vast_query.gpu_name = Vec::new();
+        config.vast_query.min_dlperf = Some(50.0);
+        // at least 100 dlperf per USD per hour
+        config.vast_query.max_dph_per_dlperf = Some(0.01);
+        let offer_filter = OfferFilter::new(&config);
+        let offer = |id, gpu_name: &str, dlperf: f64, dph_total: f64| {
+            let mut offer = test_offer(id, id, id, dph_total);
+            offer.gpu_name = gpu_name.to_string();
+            offer.dlperf = dlperf;
+            offer.dlperf_per_dphtotal = dlperf / dph_total;
+            offer
+        };
+        let offers = vec![
+            offer(1, "H100", 200.0, 0.5),
+            offer(2, "RTX 4090", 30.0, 0.4),
+            offer(3, "RTX 3090", 60.0, 0.3),
+            offer(4, "A100", 80.0, 0.45),
+        ];
+
+        // Vast applies min_dlperf.  2 is over max_dph_per_dlperf, and the rest are ordered
+        // cheapest first whatever their GPU.
+        let filtered = offer_filter.filter(offers, &config.vast_query);
+        assert_eq!(ids(&filtered), vec![3, 4, 1]);
+    }
+
+    #[test]
+    fn per_host_and_per_machine_caps_spread_the_fleet() {
+        let mut config = test_config();
+        config.max_instances_per_host = Some(2);
+        let offer_filter = OfferFilter::new(&config);
+        // offer id, machine id, host id.  Host 1 has four machines on offer.
+        let offers = vec![
+            test_offer(1, 1, 1, 0.3),
+            test_offer(2, 2, 1, 0.3),
+            test_offer(3, 3, 1, 0.3),
+            test_offer(4, 4, 1, 0.3),
+            test_offer(5, 5, 2, 0.3),
+        ];
+        let kept = offer_filter.exclude_used(offers.clone(), &HashMap::new(), &HashMap::new());
+        assert_eq!(ids(&kept), vec![1, 2, 5]);
+        // host 1 already runs one, so it has room for one more
+        let kept = offer_filter.exclude_used(offers, &HashMap::from([(1, 1)]), &HashMap::new());
+        assert_eq!(ids(&kept), vec![1, 5]);
+
+        config.max_instances_per_host = None;
+        config.max_instances_per_machine = Some(2);
+        let offer_filter = OfferFilter::new(&config);
+        // slices of machines 1 and 2
+        let offers = vec![
+            test_offer(1, 1, 1, 0.3),
+            test_offer(2, 1, 1, 0.3),
+            test_offer(3, 1, 1, 0.3),
+            test_offer(4, 2, 1, 0.3),
+            test_offer(5, 2, 1, 0.3),
+        ];
+        let kept = offer_filter.exclude_used(offers.clone(), &HashMap::new(), &HashMap::new());
+        assert_eq!(ids(&kept), vec![1, 2, 4, 5]);
+        let kept =
+            offer_filter.exclude_used(offers.clone(), &HashMap::new(), &HashMap::from([(2, 2)]));
+        assert_eq!(ids(&kept), vec![1, 2]);
+
+        // the one_instance_per_machine toggle is the stricter cap
+        config.one_instance_per_machine = true;
+        let offer_filter = OfferFilter::new(&config);
+        let kept = offer_filter.exclude_used(offers, &HashMap::new(), &HashMap::new());
+        assert_eq!(ids(&kept), vec![1, 4]);
+    }
+
+    #[test]
+    fn host_and_geolocation_caps_compose() {
+        let mut config = test_config();
+        config.max_instances_per_host = Some(1);
+        config.max_instances_per_geolocation = Some(2);
+        let offer_filter = OfferFilter::new(&config);
+        let offers: Vec<Offer> = [
+            (1, "Oregon, US"),
+            (1, "Oregon, US"),
+            (2, "Oregon, US"),
+            (3, "Oregon, US"),
+            (4, "Quebec, CA"),
+        ]
+        .into_iter()
+        .zip(1..)
+        .map(|((host_id, geolocation), id)| {
+            let mut offer = test_offer(id, id, host_id, 0.3);
+            offer.geolocation = geolocation.to_string();
+            offer
+        })
+        .collect();
+
+        // 2 shares host 1 with 1, and 4 would be a third in Oregon
+        let kept = offer_filter.exclude_used(offers, &HashMap::new(), &HashMap::new());
+        let kept = offer_filter.limit_per_geolocation(kept, &HashMap::new());
+        assert_eq!(ids(&kept), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn min_reliability_checks_reliability_apart_from_reliability2() {
+        let config = test_config();
+        let offer_filter = OfferFilter::new(&config);
+        let offer = |id, reliability, reliability2| {
+            let mut offer = test_offer(id, id, id, 0.3);
+            offer.reliability = reliability;
+            offer.reliability2 = reliability2;
+            offer
+        };
+        // all of them pass the query's reliability2 floor of 0.9
+        let offers = vec![
+            offer(1, 0.97, 0.92),
+            offer(2, 0.93, 0.99),
+            offer(3, 0.95, 0.91),
+            offer(4, 0.8, 0.95),
+        ];
+
+        let mut vast_query = config.vast_query.clone();
+        vast_query.reliability = 0.9;
+        let filtered = offer_filter.filter(offers.clone(), &vast_query);
+        assert_eq!(ids(&filtered), vec![1, 2, 3, 4]);
+
+        vast_query.min_reliability = Some(0.95);
+        let filtered = offer_filter.filter(offers, &vast_query);
+        assert_eq!(ids(&filtered), vec![1, 3]);
+        // the search itself still only asks for reliability2
+        let query: serde_json::Value = serde_json::from_str(&vast_query.to_query_string()).unwrap();
+        assert_eq!(query["reliability2"]["gte"], 0.9);
+        assert!(query.get("reliability").is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::types::unix_now;
+
+// A change to how many instances are maintained, through /scale or /desired-count
+#[derive(Debug, Serialize, Clone)]
+pub struct ScaleEvent {
+    pub from: usize,
+    pub to: usize,
+    // whatever the caller gave, e.g. an autoscaler's "queue depth 40"
+    pub reason: Option<String>,
+    // unix seconds
+    pub timestamp: u64,
+}
+
+// The most recent scaling decisions, oldest first, kept apart from the drop history so a busy
+// autoscaler can't push drops out of it or the other way around.  Holds up to capacity entries.
+// Only held in memory.
+pub struct ScaleHistory {
+    events: VecDeque<ScaleEvent>,
+    capacity: usize,
+}
+
+impl ScaleHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, from: usize, to: usize, reason: Option<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(ScaleEvent {
+            from,
+            to,
+            reason,
+            timestamp: unix_now(),
+        });
+    }
+
+    pub fn events(&self) -> Vec<ScaleEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent_capacity_events() {
+        let mut scale_history = ScaleHistory::new(2);
+        scale_history.record(2, 4, None);
+        scale_history.record(4, 3, Some("queue drained".to_string()));
+        scale_history.record(3, 1, None);
+
+        let events = scale_history.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].from, events[0].to), (4, 3));
+        assert_eq!(events[0].reason.as_deref(), Some("queue drained"));
+        assert_eq!((events[1].from, events[1].to), (3, 1));
+    }
+
+    #[test]
+    fn zero_capacity_records_nothing() {
+        let mut scale_history = ScaleHistory::new(0);
+        scale_history.record(2, 4, None);
+        assert!(scale_history.events().is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{offer_filter::VerificationStats, types::ManifestInstance};
+
+// Everything the instance controller needs to pick up where it left off after a restart
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateFile {
+    pub instances: Vec<ManifestInstance>,
+    // keyed by host_id
+    #[serde(default)]
+    pub host_stats: HashMap<u64, VerificationStats>,
+    // keyed by template hash
+    #[serde(default)]
+    pub template_stats: HashMap<String, VerificationStats>,
+}
+
+impl StateFile {
+    // Ok(None) if nothing has been saved at `path` yet
+    pub fn load(path: &str) -> Result<Option<Self>> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read state file {path}"))?;
+        let state = serde_json::from_str(&contents).with_context(|| {
+            format!("Failed to parse state file {path}.  Fix or delete it to start fresh.")
+        })?;
+
+        Ok(Some(state))
+    }
+
+    // writes to a temporary file first so a crash mid-write can't leave a truncated state file
+    pub fn save(&self, path: &str) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self).context("Serialize state")?;
+        let tmp_path = format!("{path}.tmp");
+        fs::write(&tmp_path, contents)
+            .with_context(|| format!("Failed to write state file {tmp_path}"))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to move {tmp_path} to {path}"))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::tests::test_instance;
+
+    #[test]
+    fn host_stats_survive_a_save_and_load() {
+        let path = std::env::temp_dir().join(format!("magister-state-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let state = StateFile {
+            instances: vec![test_instance(1, 0.3).into()],
+            host_stats: HashMap::from([(
+                5,
+                VerificationStats {
+                    verified: 1,
+                    unverified: 3,
+                },
+            )]),
+            template_stats: HashMap::new(),
+        };
+
+        state.save(path).unwrap();
+        let loaded = StateFile::load(path).unwrap().unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.instances.len(), 1);
+        assert_eq!(loaded.host_stats[&5].verified, 1);
+        assert_eq!(loaded.host_stats[&5].unverified, 3);
+        assert_eq!(loaded.host_stats[&5].success_rate(), 0.25);
+    }
+
+    #[test]
+    fn missing_state_file_loads_as_nothing() {
+        assert!(
+            StateFile::load("/nonexistent/magister-state.json")
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -1,45 +1,148 @@
-use crate::instance_controller::InstanceControllerClient;
+use crate::{instance_controller::InstanceControllerClient, vast::VastClient};
 use anyhow::Result;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::fmt;
-use tokio::time::Instant;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{Duration, Instant};
 
-use crate::config::Config;
+use crate::{config::Config, offer_filter::VerificationStats};
 
 pub const VAST_BASE_URL: &str = "https://console.vast.ai/api/v0";
 pub const VAST_OFFERS_ENDPOINT: &str = "/bundles";
 pub const VAST_CREATE_INSTANCE_ENDPOINT: &str = "/asks";
 pub const VAST_INSTANCE_ENDPOINT: &str = "/instances";
+pub const VAST_CURRENT_USER_ENDPOINT: &str = "/users/current";
+pub const VAST_TEMPLATE_ENDPOINT: &str = "/template";
+
+// where the Contemplant's sshd listens for ssh_authorized_keys, and who it logs in as
+pub const CONTEMPLANT_SSH_PORT: u16 = 2222;
+pub const CONTEMPLANT_SSH_USER: &str = "contemplant";
 
 #[derive(Clone)]
 pub struct MagisterState {
     pub instance_controller_client: InstanceControllerClient,
+    pub vast_client: Arc<VastClient>,
+    pub config: Config,
 }
 
 impl MagisterState {
     pub async fn new(config: Config) -> Result<Self> {
-        let instance_controller_client = InstanceControllerClient::new(config.clone()).await?;
+        // shared with the controller so its offer cache and last offer count are visible here
+        let vast_client = Arc::new(VastClient::new(config.clone())?);
+        let instance_controller_client =
+            InstanceControllerClient::new(config.clone(), vast_client.clone()).await?;
         Ok(Self {
             instance_controller_client,
+            vast_client,
+            config,
         })
     }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VastCreateInstanceResponse {
+    // defaulted so a malformed response is rejected as unsuccessful rather than failing to parse
+    #[serde(default)]
     pub success: bool,
+    #[serde(default)]
     pub new_contract: u64,
+    // why an unsuccessful request failed.  Vast uses either field depending on the endpoint.
+    #[serde(default)]
+    pub msg: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl VastCreateInstanceResponse {
+    pub fn message(&self) -> &str {
+        self.msg
+            .as_deref()
+            .or(self.error.as_deref())
+            .unwrap_or("no message")
+    }
+
+    // Vast sometimes throttles with a 200 and success false instead of a 429
+    pub fn is_throttled(&self) -> bool {
+        let message = self.message().to_lowercase();
+        ["rate limit", "too many requests", "throttl"]
+            .iter()
+            .any(|needle| message.contains(needle))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VastCurrentUserResponse {
+    // remaining account balance in USD
+    pub credit: f64,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VastGetInstancesResponse {
+    // across every page
     pub instances_found: u64,
     pub instances: Vec<VastResponseInstance>,
+    // set when there are more pages, passed back as after_token to get the next one
+    #[serde(default)]
+    pub next_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VastResponseInstance {
     pub id: u64,
+    // ex: "running", "loading", "exited".  Null while the instance is still being created.
+    #[serde(default)]
+    pub actual_status: Option<String>,
+    // set from config.instance_label when we create the instance
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub public_ipaddr: Option<String>,
+    // ex: { "9011/tcp": [{ "HostIp": "0.0.0.0", "HostPort": "41234" }] }.  Left as a Value since
+    // Vast isn't consistent about its shape and it's only used for health checks.
+    #[serde(default)]
+    pub ports: Option<serde_json::Value>,
+    // current USD per hour, which may have changed since the instance was rented
+    #[serde(default)]
+    pub dph_total: Option<f64>,
+}
+
+impl VastResponseInstance {
+    pub fn is_running(&self) -> bool {
+        self.actual_status.as_deref() == Some("running")
+    }
+
+    // Where the Contemplant health server listening on container_port can be reached, through
+    // the host port Vast mapped it to if there is one.  None until Vast reports a public IP.
+    pub fn health_url(&self, container_port: u16) -> Option<String> {
+        let ip = self.public_ipaddr.as_deref()?.trim();
+        if ip.is_empty() {
+            return None;
+        }
+
+        let port = host_port(self.ports.as_ref(), container_port).unwrap_or(container_port);
+
+        Some(format!("http://{ip}:{port}/health"))
+    }
+}
+
+// the host port Vast mapped container_port to in an instance's ports, if it did
+fn host_port(ports: Option<&serde_json::Value>, container_port: u16) -> Option<u16> {
+    ports?
+        .get(format!("{container_port}/tcp"))?
+        .get(0)?
+        .get("HostPort")?
+        .as_str()?
+        .parse()
+        .ok()
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -51,10 +154,44 @@ pub struct VastInstance {
     pub contemplant_verified: bool,
     #[serde(skip_serializing)]
     pub creation_time: Instant,
+    // When the Contemplant's contemplant_verification_timeout_secs window to call /verify opened.
+    // creation_time, except for instances adopted from a manifest or a restart, whose window
+    // restarts when they're adopted.
+    #[serde(skip_serializing)]
+    pub verification_started: Instant,
+    // when Vast first reported this instance as anything other than running, if it isn't now
+    #[serde(skip_serializing)]
+    pub not_running_since: Option<Instant>,
+    // health checks failed in a row
+    #[serde(skip_serializing)]
+    pub health_failures: u32,
+    // polling cycles in which destroying this instance failed
+    #[serde(skip_serializing)]
+    pub drop_failures: u32,
+    // polling cycles in a row that Vast's instance list has left this instance out
+    #[serde(skip_serializing)]
+    pub missing_polls: u32,
+    // how long after creation_time the Contemplant first called /verify
+    #[serde(skip_serializing)]
+    pub time_to_verification: Option<Duration>,
+    // what the Contemplant reported about itself when it verified, if anything
+    pub contemplant_info: Option<ContemplantInfo>,
+    // what the instance was launched with.  None if it was adopted without that being recorded.
+    pub template_hash: Option<String>,
+    pub prover_type: Option<String>,
+    // host port mappings from Vast's last instance list, in the same shape as
+    // VastResponseInstance.ports.  Can change along with the public IP when the instance reboots.
+    #[serde(skip_serializing)]
+    pub ports: Option<serde_json::Value>,
 }
 
 impl VastInstance {
-    pub fn new(instance_id: u64, offer: Offer) -> Self {
+    pub fn new(
+        instance_id: u64,
+        offer: Offer,
+        template_hash: Option<String>,
+        prover_type: Option<String>,
+    ) -> Self {
         let should_drop = false;
         let creation_time = Instant::now();
         let contemplant_verified = false;
@@ -63,9 +200,99 @@ impl VastInstance {
             offer,
             should_drop,
             creation_time,
+            verification_started: creation_time,
             contemplant_verified,
+            not_running_since: None,
+            health_failures: 0,
+            drop_failures: 0,
+            missing_polls: 0,
+            time_to_verification: None,
+            contemplant_info: None,
+            template_hash,
+            prover_type,
+            ports: None,
         }
     }
+
+    // how long this Magister has been tracking the instance
+    pub fn uptime(&self) -> Duration {
+        self.creation_time.elapsed()
+    }
+
+    // Where to SSH into the Contemplant, from the last public IP and port mapping Vast reported.
+    // None until Vast has reported both.
+    pub fn ssh_info(&self) -> Option<SshResponse> {
+        let host = self.offer.public_ipaddr.trim();
+        if host.is_empty() {
+            return None;
+        }
+        let port = host_port(self.ports.as_ref(), CONTEMPLANT_SSH_PORT)?;
+
+        Some(SshResponse {
+            instance_id: self.instance_id,
+            command: format!("ssh -p {port} {CONTEMPLANT_SSH_USER}@{host}"),
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    // After a reboot the Contemplant has to verify again, with a fresh window to do it in
+    pub fn restart_verification(&mut self) {
+        self.contemplant_verified = false;
+        self.verification_started = Instant::now();
+        self.not_running_since = None;
+        self.health_failures = 0;
+    }
+
+    // rough USD spent on this instance so far, assuming its price hasn't changed
+    pub fn accumulated_cost(&self) -> f64 {
+        self.uptime().as_secs_f64() / 3600.0 * self.offer.dph_total
+    }
+}
+
+// seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+// Optional body of /verify/:id, describing the Contemplant as it sees itself
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ContemplantInfo {
+    pub name: Option<String>,
+    // ex: "RTX 4090"
+    pub gpu: Option<String>,
+    pub moongate_version: Option<String>,
+}
+
+// A VastInstance as returned by /instances, with derived uptime and cost
+#[derive(Debug, Serialize, Clone)]
+pub struct InstanceResponse {
+    #[serde(flatten)]
+    pub instance: VastInstance,
+    pub uptime_secs: u64,
+    pub accumulated_cost: f64,
+}
+
+impl From<VastInstance> for InstanceResponse {
+    fn from(instance: VastInstance) -> Self {
+        Self {
+            uptime_secs: instance.uptime().as_secs(),
+            accumulated_cost: instance.accumulated_cost(),
+            instance,
+        }
+    }
+}
+
+// USD per hour spent on every instance that we aren't about to drop
+pub fn total_cost_per_hour<'a>(instances: impl IntoIterator<Item = &'a VastInstance>) -> f64 {
+    instances
+        .into_iter()
+        .filter(|instance| !instance.should_drop)
+        .map(|instance| instance.offer.dph_total)
+        .sum()
 }
 
 impl fmt::Display for VastInstance {
@@ -83,153 +310,434 @@ impl fmt::Display for VastInstance {
     }
 }
 
+// Portable description of a fleet, used to hand running instances over to another Magister.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub instances: Vec<ManifestInstance>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ManifestInstance {
+    pub instance_id: u64,
+    pub offer: Offer,
+    pub should_drop: bool,
+    pub contemplant_verified: bool,
+    // absent from manifests written before they were recorded
+    #[serde(default)]
+    pub template_hash: Option<String>,
+    #[serde(default)]
+    pub prover_type: Option<String>,
+    // unix seconds, so uptime survives a restart
+    #[serde(default)]
+    pub created_at: Option<u64>,
+}
+
+impl From<VastInstance> for ManifestInstance {
+    fn from(instance: VastInstance) -> Self {
+        ManifestInstance {
+            created_at: Some(unix_now().saturating_sub(instance.uptime().as_secs())),
+            instance_id: instance.instance_id,
+            offer: instance.offer,
+            should_drop: instance.should_drop,
+            contemplant_verified: instance.contemplant_verified,
+            template_hash: instance.template_hash,
+            prover_type: instance.prover_type,
+        }
+    }
+}
+
+impl From<ManifestInstance> for VastInstance {
+    // Uptime carries over when the manifest recorded it.  The verification window restarts at
+    // import, so instances that are still unverified get a full
+    // contemplant_verification_timeout_secs to call /verify rather than being dropped for time
+    // spent before they were adopted.  Verified ones stay verified and aren't checked again.
+    fn from(manifest_instance: ManifestInstance) -> Self {
+        let mut instance = VastInstance::new(
+            manifest_instance.instance_id,
+            manifest_instance.offer,
+            manifest_instance.template_hash,
+            manifest_instance.prover_type,
+        );
+        if let Some(created_at) = manifest_instance.created_at
+            && let Some(creation_time) = Instant::now()
+                .checked_sub(Duration::from_secs(unix_now().saturating_sub(created_at)))
+        {
+            instance.creation_time = creation_time;
+        }
+        instance.should_drop = manifest_instance.should_drop;
+        instance.contemplant_verified = manifest_instance.contemplant_verified;
+        instance
+    }
+}
+
+// Body of every error response: { "error": "message", "code": 400 }
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiError {
+    pub error: String,
+    pub code: u16,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            code: status.as_u16(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, axum::Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportResponse {
+    pub imported: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueryResponse {
+    // exactly what's sent to Vast's offer search
+    pub query: String,
+    // vast_query_fallbacks, in the order they're tried
+    pub fallback_queries: Vec<String>,
+    // offers left after filtering in the last search, None before the first
+    pub last_offer_count: Option<usize>,
+}
+
+// Query parameters of /summary, applied to instance_overview only
+#[derive(Debug, Deserialize, Clone)]
+pub struct SummaryQuery {
+    // one of SummarySort
+    pub sort: Option<String>,
+    // keeps instances whose geolocation contains this, ignoring case.  ex: "US"
+    pub geolocation: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SummarySort {
+    CostAsc,
+    CostDesc,
+    UptimeAsc,
+    UptimeDesc,
+}
+
+impl SummarySort {
+    pub fn sort(self, instances: &mut [VastInstance]) {
+        match self {
+            SummarySort::CostAsc => {
+                instances.sort_by(|a, b| a.offer.dph_total.total_cmp(&b.offer.dph_total))
+            }
+            SummarySort::CostDesc => {
+                instances.sort_by(|a, b| b.offer.dph_total.total_cmp(&a.offer.dph_total))
+            }
+            SummarySort::UptimeAsc => instances.sort_by_key(VastInstance::uptime),
+            SummarySort::UptimeDesc => {
+                instances.sort_by_key(|instance| std::cmp::Reverse(instance.uptime()))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for SummarySort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cost_asc" => Ok(SummarySort::CostAsc),
+            "cost_desc" => Ok(SummarySort::CostDesc),
+            "uptime_asc" => Ok(SummarySort::UptimeAsc),
+            "uptime_desc" => Ok(SummarySort::UptimeDesc),
+            other => anyhow::bail!(
+                "unknown sort \"{other}\", expected \"cost_asc\", \"cost_desc\", \"uptime_asc\", or \"uptime_desc\""
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DesiredCount {
+    pub count: usize,
+}
+
+// POST /scale body
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScaleRequest {
+    pub count: usize,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MaxDph {
+    pub cost_per_hour: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DropAllResponse {
+    pub marked: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DropAllQuery {
+    // how many instances there are, to show the caller knows what they're dropping
+    pub confirm: Option<String>,
+}
+
+// offers are parsed one at a time so one bad offer doesn't fail the rest
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VastOfferResponse {
-    pub offers: Vec<Offer>,
+    pub offers: Vec<serde_json::Value>,
 }
 
+// Only the fields in REQUIRED_OFFER_FIELDS are required, so Vast dropping any of the rest doesn't
+// fail every offer
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct Offer {
     pub id: u64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub ask_contract_id: u64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub bundle_id: u64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub bundled_results: Option<u64>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub bw_nvlink: f64,
+    #[serde(default)]
     pub compute_cap: u32,
+    #[serde(default)]
     pub cpu_arch: String,
+    #[serde(default)]
     pub cpu_cores: Option<u32>,
+    #[serde(default)]
     pub cpu_cores_effective: f64,
+    #[serde(default)]
     pub cpu_ghz: Option<f64>,
+    #[serde(default)]
     pub cpu_name: Option<String>,
+    #[serde(default)]
     pub cpu_ram: u64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub credit_discount_max: f64,
+    #[serde(default)]
     pub cuda_max_good: f64,
+    #[serde(default)]
     pub direct_port_count: u32,
+    #[serde(default)]
     pub disk_bw: f64,
+    #[serde(default)]
     pub disk_name: String,
+    #[serde(default)]
     pub disk_space: f64,
     pub dlperf: f64,
     pub dlperf_per_dphtotal: f64,
+    #[serde(default)]
     pub dph_base: f64,
     pub dph_total: f64,
+    #[serde(default)]
     pub driver_version: String,
+    #[serde(default)]
     pub driver_vers: u64,
+    #[serde(default)]
     pub duration: f64,
+    #[serde(default)]
     pub end_date: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub external: Option<serde_json::Value>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub flops_per_dphtotal: f64,
     pub geolocation: String,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub geolocode: u64,
+    #[serde(default)]
     pub gpu_arch: String,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub gpu_display_active: bool,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub gpu_frac: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub gpu_ids: Vec<u64>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub gpu_lanes: u32,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub gpu_mem_bw: f64,
     pub gpu_name: String,
+    #[serde(default)]
     pub gpu_ram: u64,
+    #[serde(default)]
     pub gpu_total_ram: u64,
+    #[serde(default)]
     pub gpu_max_power: f64,
+    #[serde(default)]
     pub gpu_max_temp: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub has_avx: u32,
     pub host_id: u64,
+    #[serde(default)]
     pub hosting_type: u32,
+    #[serde(default)]
     pub hostname: Option<String>,
+    #[serde(default)]
     pub inet_down: f64,
+    #[serde(default)]
     pub inet_down_cost: f64,
+    #[serde(default)]
     pub inet_up: f64,
+    #[serde(default)]
     pub inet_up_cost: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub is_bid: bool,
+    #[serde(default)]
     pub logo: String,
     pub machine_id: u64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub min_bid: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub mobo_name: Option<String>,
+    #[serde(default)]
     pub num_gpus: u32,
+    #[serde(default)]
     pub os_version: String,
+    #[serde(default)]
     pub pci_gen: f64,
+    #[serde(default)]
     pub pcie_bw: f64,
+    #[serde(default)]
     pub public_ipaddr: String,
+    #[serde(default)]
     pub reliability: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub reliability_mult: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub rentable: bool,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub rented: bool,
     pub score: f64,
+    #[serde(default)]
     pub start_date: Option<f64>,
+    #[serde(default)]
     pub static_ip: bool,
+    #[serde(default)]
     pub storage_cost: f64,
+    #[serde(default)]
     pub storage_total_cost: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub total_flops: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub verification: String,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub vericode: u32,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub vram_costperhour: f64,
+    #[serde(default)]
     pub webpage: Option<String>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub vms_enabled: bool,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub expected_reliability: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub is_vm_deverified: bool,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub resource_type: String,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub cluster_id: Option<serde_json::Value>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub avail_vol_ask_id: Option<u64>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub avail_vol_dph: Option<f64>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub avail_vol_size: Option<f64>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub rn: u32,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub dph_total_adj: f64,
+    #[serde(default)]
     pub reliability2: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub discount_rate: Option<f64>,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub discounted_hourly: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub discounted_dph_total: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub search: CostBreakdown,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub instance: CostBreakdown,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub time_remaining: String,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub time_remaining_isbid: String,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub internet_up_cost_per_tb: f64,
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub internet_down_cost_per_tb: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Offer fields Magister relies on.  An offer missing one, or with one it can't parse, is skipped.
+pub const REQUIRED_OFFER_FIELDS: [&str; 9] = [
+    "id",
+    "dph_total",
+    "machine_id",
+    "host_id",
+    "geolocation",
+    "gpu_name",
+    "score",
+    "dlperf",
+    "dlperf_per_dphtotal",
+];
+
+// Parses each offer on its own so one malformed offer doesn't fail the batch.  Any other field
+// that can't be parsed, e.g. because Vast changed its type, is dropped and takes its default.
+pub fn parse_offers(values: Vec<serde_json::Value>) -> Vec<Offer> {
+    let count_before_parse = values.len();
+    let mut ignored_fields = BTreeSet::new();
+    let mut last_error = None;
+
+    let offers: Vec<Offer> = values
+        .into_iter()
+        .filter_map(|mut value| {
+            loop {
+                let err = match serde_path_to_error::deserialize::<_, Offer>(&value) {
+                    Ok(offer) => return Some(offer),
+                    Err(err) => err,
+                };
+                let field = match err.path().iter().next() {
+                    Some(serde_path_to_error::Segment::Map { key }) => key.clone(),
+                    _ => String::new(),
+                };
+                // a missing required field is reported against the offer itself
+                let removed = !REQUIRED_OFFER_FIELDS.contains(&field.as_str())
+                    && value
+                        .as_object_mut()
+                        .and_then(|offer| offer.remove(&field))
+                        .is_some();
+                if !removed {
+                    last_error = Some(format!("{}: {}", err.path(), err.inner()));
+                    return None;
+                }
+                ignored_fields.insert(field);
+            }
+        })
+        .collect();
+
+    if !ignored_fields.is_empty() {
+        warn!("Ignored offer fields that failed to parse: {ignored_fields:?}");
+    }
+    if let Some(last_error) = last_error {
+        warn!(
+            "Skipped {} / {count_before_parse} offers that failed to parse.  Last error at {last_error}",
+            count_before_parse - offers.len()
+        );
+    }
+
+    offers
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CostBreakdown {
     #[serde(rename = "gpuCostPerHour")]
     pub gpu_cost_per_hour: f64,
@@ -247,9 +755,44 @@ pub struct CostBreakdown {
 pub struct SummaryResponse {
     pub total_cost_per_hour: f64,
     pub num_instances: usize,
+    pub distinct_hosts: usize,
+    // rough USD spent so far on the instances below
+    pub total_accumulated_cost: f64,
+    // whether provisioning and verification drops are paused
+    pub paused: bool,
+    // over tracked instances whose Contemplant has called /verify.  None if none have.
+    pub avg_time_to_verification_secs: Option<f64>,
+    pub max_time_to_verification_secs: Option<f64>,
+    // live instances keyed by gpu_name and by geolocation
+    pub by_gpu: BTreeMap<String, GroupSummary>,
+    pub by_region: BTreeMap<String, GroupSummary>,
+    // verified and unverified instances from each template, keyed by hash
+    pub template_stats: BTreeMap<String, VerificationStats>,
     pub instance_overview: Vec<InstanceOverview>,
 }
 
+// How many instances share a GPU model or region, and what they cost together
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GroupSummary {
+    pub count: usize,
+    // USD per hour
+    pub dph: f64,
+}
+
+// count and cost of the instances grouped by `key`
+pub fn group_instances(
+    instances: &[VastInstance],
+    key: impl Fn(&VastInstance) -> &str,
+) -> BTreeMap<String, GroupSummary> {
+    let mut groups: BTreeMap<String, GroupSummary> = BTreeMap::new();
+    for instance in instances {
+        let group = groups.entry(key(instance).to_string()).or_default();
+        group.count += 1;
+        group.dph += instance.offer.dph_total;
+    }
+    groups
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InstanceOverview {
     instance_id: u64,
@@ -259,11 +802,18 @@ pub struct InstanceOverview {
     machine_id: u64,
     host_id: u64,
     cost_per_hour: f64,
+    uptime_secs: u64,
+    accumulated_cost: f64,
+    template_hash: Option<String>,
+    prover_type: Option<String>,
+    time_to_verification_secs: Option<f64>,
 }
 
 impl From<VastInstance> for InstanceOverview {
     fn from(instance: VastInstance) -> Self {
         InstanceOverview {
+            uptime_secs: instance.uptime().as_secs(),
+            accumulated_cost: instance.accumulated_cost(),
             instance_id: instance.instance_id,
             offer_id: instance.offer.id,
             gpu: instance.offer.gpu_name,
@@ -271,6 +821,164 @@ impl From<VastInstance> for InstanceOverview {
             machine_id: instance.offer.machine_id,
             host_id: instance.offer.host_id,
             cost_per_hour: instance.offer.dph_total,
+            template_hash: instance.template_hash,
+            prover_type: instance.prover_type,
+            time_to_verification_secs: instance
+                .time_to_verification
+                .map(|duration| duration.as_secs_f64()),
         }
     }
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SshResponse {
+    pub instance_id: u64,
+    // ready to paste, e.g. "ssh -p 41022 contemplant@203.0.113.7"
+    pub command: String,
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunwayResponse {
+    pub balance: f64,
+    pub total_cost_per_hour: f64,
+    // None when nothing is running, so the balance isn't being spent
+    pub runway_hours: Option<f64>,
+    pub runway_days: Option<f64>,
+}
+
+impl RunwayResponse {
+    pub fn new(balance: f64, total_cost_per_hour: f64) -> Self {
+        let runway_hours = if total_cost_per_hour > 0.0 {
+            Some(balance.max(0.0) / total_cost_per_hour)
+        } else {
+            None
+        };
+        Self {
+            balance,
+            total_cost_per_hour,
+            runway_hours,
+            runway_days: runway_hours.map(|hours| hours / 24.0),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn test_offer(id: u64, machine_id: u64, host_id: u64, dph_total: f64) -> Offer {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "dph_total": dph_total,
+            "machine_id": machine_id,
+            "host_id": host_id,
+            "geolocation": "Oregon, US",
+            "gpu_name": "RTX 4090",
+            "score": 1.0,
+            "dlperf": 100.0,
+            "dlperf_per_dphtotal": 100.0 / dph_total,
+        }))
+        .unwrap()
+    }
+
+    // an instance with the same id as its offer, machine and host
+    pub(crate) fn test_instance(id: u64, dph_total: f64) -> VastInstance {
+        VastInstance::new(id, test_offer(id, id, id, dph_total), None, None)
+    }
+
+    #[test]
+    fn runway_is_unbounded_when_nothing_is_spent() {
+        let runway = RunwayResponse::new(50.0, 0.0);
+        assert!(runway.runway_hours.is_none());
+        assert!(runway.runway_days.is_none());
+
+        // an overdrawn account has no runway left rather than a negative one
+        let runway = RunwayResponse::new(-3.0, 2.0);
+        assert_eq!(runway.runway_hours, Some(0.0));
+    }
+
+    #[test]
+    fn new_instance_starts_unverified_and_healthy() {
+        let instance = VastInstance::new(
+            7,
+            test_offer(7, 8, 9, 0.4),
+            Some("hash".to_string()),
+            Some("sp1".to_string()),
+        );
+        assert_eq!(instance.instance_id, 7);
+        assert_eq!(instance.offer.id, 7);
+        assert!(!instance.should_drop);
+        assert!(!instance.contemplant_verified);
+        assert_eq!(instance.verification_started, instance.creation_time);
+        assert!(instance.uptime() < Duration::from_secs(5));
+        assert!(instance.not_running_since.is_none());
+        assert_eq!(instance.health_failures, 0);
+        assert_eq!(instance.drop_failures, 0);
+        assert_eq!(instance.missing_polls, 0);
+        assert!(instance.time_to_verification.is_none());
+        assert!(instance.contemplant_info.is_none());
+        assert!(instance.ports.is_none());
+        assert_eq!(instance.template_hash.as_deref(), Some("hash"));
+        assert_eq!(instance.prover_type.as_deref(), Some("sp1"));
+
+        // the clock-based fields stay out of the API
+        let json = serde_json::to_value(&instance).unwrap();
+        assert!(json.get("creation_time").is_none());
+        assert!(json.get("contemplant_verified").is_none());
+        assert_eq!(json["should_drop"], false);
+    }
+
+    #[test]
+    fn accumulated_cost_is_uptime_hours_times_price() {
+        let mut instance = test_instance(1, 0.5);
+        instance.creation_time = Instant::now()
+            .checked_sub(Duration::from_secs(2 * 3600))
+            .unwrap();
+        assert!((instance.accumulated_cost() - 1.0).abs() < 0.001);
+
+        let json = serde_json::to_value(InstanceResponse::from(instance)).unwrap();
+        assert_eq!(json["instance_id"], 1);
+        assert!((7200..7210).contains(&json["uptime_secs"].as_u64().unwrap()));
+        assert!((json["accumulated_cost"].as_f64().unwrap() - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn offers_parse_without_or_with_mistyped_cosmetic_fields() {
+        let offer = |id: u64| {
+            serde_json::json!({
+                "id": id,
+                "dph_total": 0.3,
+                "machine_id": id,
+                "host_id": id,
+                "geolocation": "Oregon, US",
+                "gpu_name": "RTX 4090",
+                "score": 1.0,
+                "dlperf": 100.0,
+                "dlperf_per_dphtotal": 333.0,
+            })
+        };
+        // only the required fields
+        let bare = offer(1);
+        let mut mistyped = offer(2);
+        mistyped["cpu_name"] = serde_json::json!(5);
+        mistyped["compute_cap"] = serde_json::json!("eight");
+        mistyped["inet_up"] = serde_json::json!(900.0);
+        let mut missing_required = offer(3);
+        missing_required
+            .as_object_mut()
+            .unwrap()
+            .remove("dph_total");
+
+        let offers = parse_offers(vec![bare, mistyped, missing_required]);
+        assert_eq!(
+            offers.iter().map(|offer| offer.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(offers[1].cpu_name.is_none());
+        assert_eq!(offers[1].compute_cap, 0);
+        // fields that did parse are kept
+        assert_eq!(offers[1].inet_up, 900.0);
+    }
+}
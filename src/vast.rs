@@ -1,82 +1,669 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-    config::Config,
+    config::{BackoffResetPolicy, Config, VastQueryConfig, WeightedTemplate},
+    logging::{log_instance, log_offer},
+    offer_filter::{LiveUsage, OfferFilter, spread_across_hosts},
     types::{
-        Offer, VAST_BASE_URL, VAST_CREATE_INSTANCE_ENDPOINT, VAST_INSTANCE_ENDPOINT,
-        VAST_OFFERS_ENDPOINT, VastCreateInstanceResponse, VastGetInstancesResponse, VastInstance,
-        VastOfferResponse,
+        Offer, VAST_BASE_URL, VAST_CREATE_INSTANCE_ENDPOINT, VAST_CURRENT_USER_ENDPOINT,
+        VAST_INSTANCE_ENDPOINT, VAST_OFFERS_ENDPOINT, VAST_TEMPLATE_ENDPOINT,
+        VastCreateInstanceResponse, VastCurrentUserResponse, VastGetInstancesResponse,
+        VastInstance, VastOfferResponse, VastResponseInstance, parse_offers, unix_now,
     },
 };
 use anyhow::{Context, Result, anyhow};
 use axum::http::StatusCode;
-use log::{debug, error, info, warn};
+use log::{Level, debug, error, info, warn};
+use serde::Serialize;
+use tokio::{sync::Semaphore, task::JoinSet};
+
+pub enum CreateInstanceOutcome {
+    // template_hash and prover_type are what the instance was launched with
+    Created {
+        instance_id: u64,
+        template_hash: String,
+        prover_type: String,
+    },
+    // we are making too many requests and need to wait.  retry_after is how long Vast asked us to
+    // wait, if it said.
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    // another request for this offer hasn't finished yet, so none was made
+    AlreadyRequested,
+}
+
+// The kinds of Vast call whose outcomes are tracked for /vast-status
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum VastOperation {
+    Offers,
+    Create,
+    Destroy,
+    List,
+}
+
+// How the last call of one VastOperation went
+#[derive(Debug, Serialize, Clone)]
+pub struct VastOperationStatus {
+    pub last_call_succeeded: bool,
+    // unix seconds
+    pub last_call_at: u64,
+    // kept after later successes, so an error that came and went can still be seen
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+}
+
+// Spaces out the start of every Vast request, across all tasks sharing the VastClient, so
+// together they stay under Vast's rate limit.  A token bucket holding a single token.
+struct RateLimiter {
+    interval: Duration,
+    // when the next request may start
+    next_permit: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_permit: Mutex::new(Instant::now()),
+        }
+    }
+
+    // waits until this request may start, reserving the slot after it for the next one
+    async fn acquire(&self) {
+        let permit = {
+            let mut next_permit = self.next_permit.lock().unwrap();
+            let permit = (*next_permit).max(Instant::now());
+            *next_permit = permit + self.interval;
+            permit
+        };
+        tokio::time::sleep(permit.saturating_duration_since(Instant::now())).await;
+    }
+
+    // no request starts for at least duration, e.g. after Vast rate limited us
+    fn cool_down(&self, duration: Duration) {
+        let mut next_permit = self.next_permit.lock().unwrap();
+        *next_permit = (*next_permit).max(Instant::now() + duration);
+    }
+}
+
+// Spaces out successful instance launches by config.instance_launch_stagger_secs plus jitter.  Each
+// create request reserves the next launch slot, and gives it back if nothing launched, so failures
+// and rate limits don't push later launches back.  The wait is to a deadline, like RateLimiter's,
+// so it overlaps with a rate limit cool-down instead of adding to it.
+struct LaunchStagger {
+    interval: Duration,
+    // when the next launch may start
+    next_launch: Mutex<Instant>,
+}
+
+// A reserved launch slot
+struct LaunchSlot {
+    start: Instant,
+    // next_launch after this reservation, to tell whether a later one was made since
+    reserved_until: Instant,
+}
+
+impl LaunchStagger {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_launch: Mutex::new(Instant::now()),
+        }
+    }
+
+    // waits for this launch's slot, reserving the one after it for the next launch
+    async fn reserve(&self) -> LaunchSlot {
+        let slot = {
+            let mut next_launch = self.next_launch.lock().unwrap();
+            let start = (*next_launch).max(Instant::now());
+            if !self.interval.is_zero() {
+                *next_launch = start + self.interval + jitter(self.interval / 5);
+            }
+            LaunchSlot {
+                start,
+                reserved_until: *next_launch,
+            }
+        };
+        tokio::time::sleep(slot.start.saturating_duration_since(Instant::now())).await;
+        slot
+    }
+
+    // hands back a slot that didn't launch anything, unless a later launch has reserved past it
+    fn release(&self, slot: LaunchSlot) {
+        let mut next_launch = self.next_launch.lock().unwrap();
+        if *next_launch == slot.reserved_until {
+            *next_launch = slot.start;
+        }
+    }
+}
+
+// A pseudo-random duration up to max.  Only used to spread things out, so the clock's nanoseconds
+// are random enough.
+fn jitter(max: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    max.mul_f64(f64::from(nanos) / 1e9)
+}
+
+// How long create_initial_instances cools down after each rate limited request.  The guess grows
+// by config.vast_api_call_backoff_secs each time, and config.backoff_reset_policy decides how much
+// of it a request that wasn't rate limited takes back.
+struct CreateBackoff {
+    step_secs: u64,
+    reset_policy: BackoffResetPolicy,
+    reset_streak: u32,
+    current_secs: u64,
+    // requests in a row that weren't rate limited
+    clean_streak: u32,
+}
+
+impl CreateBackoff {
+    fn new(config: &Config) -> Self {
+        Self {
+            step_secs: config.vast_api_call_backoff_secs,
+            reset_policy: config.backoff_reset_policy,
+            reset_streak: config.backoff_reset_streak,
+            current_secs: 0,
+            clean_streak: 0,
+        }
+    }
+
+    // how long to cool down: as long as Vast asked if it told us, otherwise the next guess
+    fn rate_limited(&mut self, retry_after: Option<Duration>) -> Duration {
+        self.clean_streak = 0;
+        retry_after.unwrap_or_else(|| {
+            self.current_secs += self.step_secs;
+            Duration::from_secs(self.current_secs)
+        })
+    }
+
+    fn not_rate_limited(&mut self) {
+        self.clean_streak += 1;
+        self.current_secs =
+            self.reset_policy
+                .reset(self.current_secs, self.clean_streak, self.reset_streak);
+    }
+}
+
+// drop_instance's retries wait at most 1/DROP_RETRY_SLEEP_SHARE of a polling interval in all
+const DROP_RETRY_SLEEP_SHARE: u64 = 5;
+
+// a listing with more pages than this is assumed to be looping rather than that big
+const MAX_INSTANCE_PAGES: usize = 100;
+
+// caps the circuit breaker's cooldown at 2^4 times config.vast_circuit_cooldown_secs
+const MAX_CIRCUIT_COOLDOWN_DOUBLINGS: u32 = 4;
+
+// Stops calling Vast while it looks down.  After config.vast_circuit_failures calls in a row fail
+// to connect or get a 5xx the circuit opens, and calls fail without reaching Vast until the
+// cooldown is over.  Then one call is let through: if it works the circuit closes, otherwise it
+// opens again for twice as long.
+struct CircuitBreaker {
+    failure_threshold: u32,
+    base_cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    // Some while open, and after the cooldown until the probe call comes back
+    open_until: Option<Instant>,
+    cooldown_doublings: u32,
+    // a call has been let through after the cooldown and hasn't come back yet
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    fn new(failure_threshold: u32, base_cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            base_cooldown,
+            state: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    // Err if this call shouldn't reach Vast.  The permit records how the call went.
+    fn allow(&self) -> Result<CircuitPermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let Some(open_until) = state.open_until else {
+            return Ok(CircuitPermit {
+                breaker: self,
+                probe: false,
+            });
+        };
+
+        let remaining = open_until.saturating_duration_since(Instant::now());
+        if !remaining.is_zero() {
+            return Err(anyhow!(
+                "Vast appears down.  Not calling it for another {} seconds",
+                remaining.as_secs()
+            ));
+        }
+        if state.probing {
+            return Err(anyhow!(
+                "Vast appears down.  Waiting to see if it's back before calling it again"
+            ));
+        }
+        state.probing = true;
+        Ok(CircuitPermit {
+            breaker: self,
+            probe: true,
+        })
+    }
+
+    // records whether a call that was allowed reached a working Vast
+    fn record(&self, vast_up: bool) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+
+        if vast_up {
+            if state.open_until.is_some() {
+                info!("Vast is responding again");
+            }
+            *state = CircuitState::default();
+            return;
+        }
+
+        state.consecutive_failures += 1;
+        if state.probing {
+            state.probing = false;
+            state.cooldown_doublings =
+                (state.cooldown_doublings + 1).min(MAX_CIRCUIT_COOLDOWN_DOUBLINGS);
+        } else if state.open_until.is_some() || state.consecutive_failures < self.failure_threshold
+        {
+            return;
+        }
+
+        let cooldown = self.base_cooldown * 2u32.pow(state.cooldown_doublings);
+        state.open_until = Some(Instant::now() + cooldown);
+        warn!(
+            "{} Vast API calls in a row failed.  Vast appears down, backing off for {} seconds",
+            state.consecutive_failures,
+            cooldown.as_secs()
+        );
+    }
+
+    // whether calls are being held back until the cooldown is over
+    fn is_open(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .open_until
+            .is_some_and(|open_until| Instant::now() < open_until)
+    }
+}
+
+// A call the circuit breaker has let through.  If it's dropped without recording an outcome, e.g.
+// because the caller was cancelled, a probe call gives up its turn so the next call can probe.
+struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl CircuitPermit<'_> {
+    fn record(mut self, vast_up: bool) {
+        self.probe = false;
+        self.breaker.record(vast_up);
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.state.lock().unwrap().probing = false;
+        }
+    }
+}
+
+// A template chosen for a create request.  Dropped without being committed, e.g. because the
+// request failed or was rate limited, the choice is undone so the template's turn comes round
+// again.  Each choice only adds to the weights, so undoing one is exact even if others were made
+// since.
+struct TemplateChoice<'a> {
+    client: &'a VastClient,
+    index: usize,
+    committed: bool,
+}
+
+impl TemplateChoice<'_> {
+    fn hash(&self) -> &str {
+        &self.client.config.template_hash[self.index].hash
+    }
+
+    // keeps the choice and returns the template's hash
+    fn commit(mut self) -> String {
+        self.committed = true;
+        self.hash().to_string()
+    }
+}
+
+impl Drop for TemplateChoice<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let templates = &self.client.config.template_hash;
+        let mut current = self.client.template_weights.lock().unwrap();
+        step_template_weights(&mut current, templates, -1);
+        current[self.index] += template_weight_total(templates);
+    }
+}
+
+// adds each template's weight, times direction, to its current weight
+fn step_template_weights(current: &mut [i64], templates: &[WeightedTemplate], direction: i64) {
+    for (current, template) in current.iter_mut().zip(templates) {
+        *current += direction * i64::from(template.weight);
+    }
+}
+
+fn template_weight_total(templates: &[WeightedTemplate]) -> i64 {
+    templates
+        .iter()
+        .map(|template| i64::from(template.weight))
+        .sum()
+}
+
+// Why a destroy request failed.  Transient failures are worth retrying right away.
+enum DestroyError {
+    Transient(anyhow::Error),
+    Permanent(anyhow::Error),
+}
 
 pub struct VastClient {
     config: Config,
     client: reqwest::Client,
+    // VAST_BASE_URL, or a stand-in for Vast in tests
+    base_url: String,
+    // synthetic instance ids handed out in dry run mode, standing in for Vast's instance list
+    dry_run_instances: Mutex<HashSet<u64>>,
+    dry_run_next_id: AtomicU64,
+    // unfiltered offers from the last search and when they were fetched
+    offer_cache: Mutex<HashMap<usize, (Instant, Vec<Offer>)>>,
+    // offers left after filtering in the last find_offers
+    last_offer_count: Mutex<Option<usize>>,
+    // every request to Vast waits its turn here
+    rate_limiter: RateLimiter,
+    // every request to Vast is turned away here while Vast looks down
+    circuit_breaker: CircuitBreaker,
+    // every create request waits its turn here too
+    launch_stagger: LaunchStagger,
+    // vast_query.cost_per_hour, which PUT /max-dph can change at runtime
+    cost_per_hour: Mutex<f64>,
+    // offers with a create request in flight, so the same offer is never rented twice
+    offers_in_flight: Mutex<HashSet<u64>>,
+    // instances with a destroy request in flight, so a shutdown that gives up can say which
+    drops_in_flight: Mutex<HashSet<u64>>,
+    // the outcome of the last call of each kind, for /vast-status
+    operation_status: Mutex<BTreeMap<VastOperation, VastOperationStatus>>,
+    // smooth weighted round robin state, one entry per config.template_hash
+    template_weights: Mutex<Vec<i64>>,
 }
 
 impl VastClient {
-    pub fn new(config: Config) -> Self {
-        let client = reqwest::Client::new();
-        Self { config, client }
+    pub fn new(config: Config) -> Result<Self> {
+        // a hung connection would otherwise stall the instance controller's event loop
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(config.vast_connect_timeout_secs))
+            .timeout(Duration::from_secs(config.vast_request_timeout_secs));
+        // every Vast call (offers, create, destroy, balance, instances) goes through this client
+        if let Some(ref https_proxy) = config.https_proxy {
+            let proxy = reqwest::Proxy::all(https_proxy).context("Invalid https_proxy")?;
+            builder = builder.proxy(proxy);
+        }
+        let client = builder.build().context("Build reqwest client")?;
+        let rate_limiter = RateLimiter::new(Duration::from_millis(config.vast_api_min_interval_ms));
+        let launch_stagger =
+            LaunchStagger::new(Duration::from_secs(config.instance_launch_stagger_secs));
+        Ok(Self {
+            client,
+            base_url: VAST_BASE_URL.to_string(),
+            dry_run_instances: Mutex::new(HashSet::new()),
+            dry_run_next_id: AtomicU64::new(1),
+            offer_cache: Mutex::new(HashMap::new()),
+            last_offer_count: Mutex::new(None),
+            rate_limiter,
+            circuit_breaker: CircuitBreaker::new(
+                config.vast_circuit_failures,
+                Duration::from_secs(config.vast_circuit_cooldown_secs),
+            ),
+            launch_stagger,
+            offers_in_flight: Mutex::new(HashSet::new()),
+            drops_in_flight: Mutex::new(HashSet::new()),
+            cost_per_hour: Mutex::new(config.vast_query.cost_per_hour),
+            operation_status: Mutex::new(BTreeMap::new()),
+            template_weights: Mutex::new(vec![0; config.template_hash.len()]),
+            config,
+        })
+    }
+
+    // Sends a request to Vast once the circuit breaker and rate limiter allow it.  Failing to
+    // connect and 5xx responses count towards opening the circuit breaker.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let permit = self.circuit_breaker.allow()?;
+        self.rate_limiter.acquire().await;
+        let result = request.send().await;
+        permit.record(
+            result
+                .as_ref()
+                .is_ok_and(|response| !response.status().is_server_error()),
+        );
+        Ok(result?)
+    }
+
+    // whether Vast looks down and calls to it are being held back
+    pub fn vast_down(&self) -> bool {
+        self.circuit_breaker.is_open()
     }
 
-    pub async fn create_initial_instances(&self, count: usize) -> Result<Vec<(u64, VastInstance)>> {
-        let offers = self.find_offers(0).await?;
+    // config.vast_query with the current cost_per_hour
+    pub fn vast_query(&self) -> VastQueryConfig {
+        let mut vast_query = self.config.vast_query.clone();
+        vast_query.cost_per_hour = *self.cost_per_hour.lock().unwrap();
+        vast_query
+    }
+
+    // Changes the primary query's cost_per_hour until restarted.  Cached offers were filtered with
+    // the old ceiling, so the next find_offers searches again.
+    pub fn set_cost_per_hour(&self, cost_per_hour: f64) {
+        *self.cost_per_hour.lock().unwrap() = cost_per_hour;
+        self.invalidate_offer_cache();
+    }
+
+    // Records how the latest call of operation went.  error is None on a success.
+    fn record_outcome(&self, operation: VastOperation, error: Option<String>) {
+        let now = unix_now();
+        let mut operation_status = self.operation_status.lock().unwrap();
+        let status = operation_status
+            .entry(operation)
+            .or_insert(VastOperationStatus {
+                last_call_succeeded: true,
+                last_call_at: now,
+                last_error: None,
+                last_error_at: None,
+            });
+        status.last_call_succeeded = error.is_none();
+        status.last_call_at = now;
+        if error.is_some() {
+            status.last_error = error;
+            status.last_error_at = Some(now);
+        }
+    }
+
+    // the outcome of the last call of each kind that has been made
+    pub fn operation_status(&self) -> BTreeMap<VastOperation, VastOperationStatus> {
+        self.operation_status.lock().unwrap().clone()
+    }
+
+    // The template for the next instance.  Smooth weighted round robin, so templates are handed
+    // out in proportion to their weights and evenly interleaved rather than in runs.  The choice
+    // only counts once committed, so failed creates don't use up a template's share.
+    fn choose_template(&self) -> TemplateChoice<'_> {
+        let mut current = self.template_weights.lock().unwrap();
+        step_template_weights(&mut current, &self.config.template_hash, 1);
+        let index = (0..current.len())
+            .max_by_key(|&i| (current[i], std::cmp::Reverse(i)))
+            .unwrap_or_default();
+        current[index] -= template_weight_total(&self.config.template_hash);
+        TemplateChoice {
+            client: self,
+            index,
+            committed: false,
+        }
+    }
+
+    // Rents count instances, with up to config.create_concurrency requests in flight at once.  A
+    // rate limited request pauses every Vast request until the cool-down is over, then is retried.
+    pub async fn create_initial_instances(
+        self: &Arc<Self>,
+        count: usize,
+        offer_filter: &OfferFilter,
+    ) -> Result<Vec<(u64, VastInstance)>> {
+        let offers = self
+            .find_offers(offer_filter, count, &LiveUsage::default())
+            .await?;
 
         if offers.len() < count {
-            let err = format!(
-                "Only found {} offers but {} instances were requested. Restart with a less restrictive query.",
-                offers.len(),
-                count
+            if let Some(max_instances_per_geolocation) = self.config.max_instances_per_geolocation {
+                warn!(
+                    "max_instances_per_geolocation of {max_instances_per_geolocation} limits how many offers can be used"
+                );
+            }
+            if !self.config.allow_partial_startup {
+                let err = format!(
+                    "Only found {} offers but {} instances were requested. Restart with a less restrictive query.",
+                    offers.len(),
+                    count
+                );
+                error!("{err}");
+                return Err(anyhow!(err));
+            }
+            warn!(
+                "Only found {} offers but {count} instances were requested.  Creating as many as possible.",
+                offers.len()
             );
-            error!("{err}");
-            return Err(anyhow!(err));
         }
 
+        let offers = match self.config.min_distinct_hosts {
+            Some(min_distinct_hosts) => {
+                spread_across_hosts(offers, &HashSet::new(), min_distinct_hosts)
+            }
+            None => offers,
+        };
+
+        let semaphore = Arc::new(Semaphore::new(self.config.create_concurrency.max(1)));
+        let mut in_flight = JoinSet::new();
+        // rate limited offers to try again before moving on to new ones
+        let mut retry_offers: VecDeque<Offer> = VecDeque::new();
+        let mut offers = offers.into_iter();
+
         let mut new_instances = Vec::new();
-        let mut i = 0;
-        let backoff = self.config.vast_api_call_backoff_secs;
-        let mut current_sleep_duration = backoff;
-        let mut last_run_rate_limited = false;
+        let mut backoff = CreateBackoff::new(&self.config);
+        // includes offers with a request in flight, so concurrent requests can't overrun the budget
+        let mut total_dph = 0.0;
+        let mut skipped_over_budget = false;
         while new_instances.len() != count {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            let offer = match offers.get(i) {
-                Some(o) => o,
-                None => {
-                    return Err(anyhow!(
-                        "Ran out of offers.  Try a less restrictive query or try again later."
-                    ));
+            // start requests until enough are in flight to reach count
+            while new_instances.len() + in_flight.len() < count {
+                let offer = match retry_offers.pop_front() {
+                    Some(offer) => offer,
+                    None => match offers.next() {
+                        Some(offer) => offer,
+                        None => break,
+                    },
+                };
+
+                if self.over_budget(total_dph, offer.dph_total) {
+                    skipped_over_budget = true;
+                    continue;
+                }
+                total_dph += offer.dph_total;
+
+                let permit = semaphore.clone().acquire_owned().await?;
+                let vast_client = self.clone();
+                in_flight.spawn(async move {
+                    let outcome = vast_client.request_new_instance(offer.id).await;
+                    drop(permit);
+                    (offer, outcome)
+                });
+            }
+
+            let Some(joined) = in_flight.join_next().await else {
+                let reason = if skipped_over_budget {
+                    format!(
+                        "max_total_dph of ${:.2}/hour doesn't allow {count} instances.  Raise it or request fewer instances.",
+                        self.config.max_total_dph.unwrap_or_default()
+                    )
+                } else {
+                    "Ran out of offers.  Try a less restrictive query or try again later."
+                        .to_string()
+                };
+                if !self.config.allow_partial_startup {
+                    return Err(anyhow!(reason));
                 }
+                warn!(
+                    "Only created {} / {count} initial instances.  {reason}  Starting anyway and requesting the rest every polling cycle.",
+                    new_instances.len()
+                );
+                break;
             };
+            let (offer, outcome) = joined.context("Instance creation task")?;
             let offer_id = offer.id;
 
-            match self.request_new_instance(offer_id).await {
-                Ok(Some(instance_id)) => {
-                    last_run_rate_limited = false;
-                    let new_instance = VastInstance::new(instance_id, offer.clone());
-                    info!("Accepted offer {offer_id} for {new_instance}");
+            match outcome {
+                Ok(CreateInstanceOutcome::Created {
+                    instance_id,
+                    template_hash,
+                    prover_type,
+                }) => {
+                    backoff.not_rate_limited();
+                    let new_instance = VastInstance::new(
+                        instance_id,
+                        offer,
+                        Some(template_hash),
+                        Some(prover_type),
+                    );
+                    log_instance!(
+                        Level::Info,
+                        new_instance,
+                        "Accepted offer {offer_id} for {new_instance}"
+                    );
+                    self.warn_if_over_soft_ceiling(&new_instance);
                     new_instances.push((instance_id, new_instance));
                 }
-                Ok(None) => {
-                    if last_run_rate_limited {
-                        current_sleep_duration += backoff;
-                    } else {
-                        current_sleep_duration = backoff;
-                    }
-                    last_run_rate_limited = true;
+                Ok(CreateInstanceOutcome::RateLimited { retry_after }) => {
+                    let sleep_duration = backoff.rate_limited(retry_after);
                     warn!(
-                        "Reached vast rate limit.  Sleeping for {current_sleep_duration} seconds then trying again"
+                        "Reached vast rate limit.  Pausing new requests for {} seconds then trying again",
+                        sleep_duration.as_secs()
                     );
-                    tokio::time::sleep(Duration::from_secs(current_sleep_duration)).await;
-                    // loop without incrementing i to attempt this machine again
-                    continue;
+                    self.rate_limiter.cool_down(sleep_duration);
+                    // attempt this machine again
+                    total_dph -= offer.dph_total;
+                    retry_offers.push_back(offer);
+                }
+                Ok(CreateInstanceOutcome::AlreadyRequested) => {
+                    total_dph -= offer.dph_total;
                 }
                 Err(e) => {
-                    last_run_rate_limited = false;
-                    warn!(
+                    backoff.not_rate_limited();
+                    total_dph -= offer.dph_total;
+                    log_offer!(
+                        Level::Warn,
+                        offer,
                         "Unable to request offer {offer_id} of a {} in {} with machine_id {} and host_id {} for ${:.2}/hour.\nError: {e}",
                         offer.gpu_name,
                         offer.geolocation,
@@ -86,70 +673,252 @@ impl VastClient {
                     );
                 }
             }
-
-            i += 1;
         }
 
         Ok(new_instances)
     }
 
+    // whether adding an offer at dph_total to current_total_dph would exceed config.max_total_dph
+    pub fn over_budget(&self, current_total_dph: f64, dph_total: f64) -> bool {
+        self.config
+            .max_total_dph
+            .is_some_and(|max_total_dph| current_total_dph + dph_total > max_total_dph)
+    }
+
+    pub fn warn_if_over_soft_ceiling(&self, instance: &VastInstance) {
+        if self
+            .config
+            .vast_query
+            .over_soft_ceiling(instance.offer.dph_total)
+        {
+            log_instance!(
+                Level::Warn,
+                instance,
+                "{instance} exceeds the soft cost ceiling of ${:.2}/hour because no cheaper offers were available",
+                self.config
+                    .vast_query
+                    .soft_cost_per_hour
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    // Destroys the instance.  An instance Vast no longer knows about counts as destroyed.
     pub async fn drop_instance(&self, instance_id: u64) -> Result<()> {
-        self.request_destroy_instance(instance_id).await
+        self.drops_in_flight.lock().unwrap().insert(instance_id);
+        let result = self.destroy_with_retries(instance_id).await;
+        self.drops_in_flight.lock().unwrap().remove(&instance_id);
+        result
     }
 
-    pub async fn find_offers(&self, last_dropped: u64) -> Result<Vec<Offer>> {
-        let offers = self
-            .request_offers()
-            .await
-            .context("Call to request offers")?;
-        let filtered_offers = filter_offers(self.config.clone(), offers, last_dropped);
-        info!("found {} offers", filtered_offers.len());
-        Ok(filtered_offers)
+    // instances whose destroy request hasn't finished, lowest id first
+    pub fn drops_in_flight(&self) -> Vec<u64> {
+        let mut instance_ids: Vec<u64> = self
+            .drops_in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect();
+        instance_ids.sort_unstable();
+        instance_ids
     }
 
-    async fn request_destroy_instance(&self, instance_id: u64) -> Result<()> {
-        let url = format!(
-            "{VAST_BASE_URL}{VAST_INSTANCE_ENDPOINT}/{instance_id}/"
+    // Retries transient failures up to config.drop_retry_attempts times.  The controller waits on
+    // this, so retries stop early rather than wait more than 1/DROP_RETRY_SLEEP_SHARE of a polling
+    // interval in all, leaving the rest to the next cycle.
+    async fn destroy_with_retries(&self, instance_id: u64) -> Result<()> {
+        let attempts = self.config.drop_retry_attempts.max(1);
+        let max_total_sleep = self.config.task_polling_interval_secs / DROP_RETRY_SLEEP_SHARE;
+        let mut sleep_duration = 0;
+        let mut total_sleep = 0;
+        let mut attempt = 1;
+        loop {
+            let result = self.request_destroy_instance(instance_id).await;
+            self.record_outcome(
+                VastOperation::Destroy,
+                match result {
+                    Ok(()) => None,
+                    Err(DestroyError::Transient(ref e) | DestroyError::Permanent(ref e)) => {
+                        Some(format!("{e:#}"))
+                    }
+                },
+            );
+            match result {
+                Ok(()) => return Ok(()),
+                Err(DestroyError::Permanent(e)) => return Err(e),
+                Err(DestroyError::Transient(e)) if attempt >= attempts => {
+                    return Err(e.context(format!("Gave up after {attempts} attempts")));
+                }
+                Err(DestroyError::Transient(e))
+                    if total_sleep + sleep_duration + self.config.drop_retry_backoff_secs
+                        > max_total_sleep =>
+                {
+                    return Err(e.context(format!(
+                        "Gave up after {attempt} attempts to keep the polling cycle moving"
+                    )));
+                }
+                Err(DestroyError::Transient(e)) => {
+                    sleep_duration += self.config.drop_retry_backoff_secs;
+                    total_sleep += sleep_duration;
+                    warn!(
+                        instance_id;
+                        "Attempt {attempt} / {attempts} to destroy instance {instance_id} failed.  Retrying in {sleep_duration} seconds.  {e}"
+                    );
+                    tokio::time::sleep(Duration::from_secs(sleep_duration)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Tries config.vast_query, then each of config.vast_query_fallbacks in order, until at least
+    // needed offers are found that can be rented alongside the live instances.  Offers from
+    // earlier queries come first.
+    pub async fn find_offers(
+        &self,
+        offer_filter: &OfferFilter,
+        needed: usize,
+        live: &LiveUsage,
+    ) -> Result<Vec<Offer>> {
+        let mut found_offers: Vec<Offer> = Vec::new();
+        let mut seen_offers = HashSet::new();
+
+        let vast_queries =
+            std::iter::once(self.vast_query()).chain(self.config.vast_query_fallbacks.clone());
+        for (tier, vast_query) in vast_queries.enumerate() {
+            let offers = match self.cached_offers(tier, &vast_query).await {
+                Ok(offers) => offers,
+                // the primary query failing is an error, a fallback failing just stops the search
+                Err(e) if tier == 0 => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Error finding offers with fallback query {tier}.  Using the offers found so far\n{e}"
+                    );
+                    break;
+                }
+            };
+
+            let offers = offer_filter.filter(offers, &vast_query);
+            debug!("Query tier {tier} found {} offers", offers.len());
+            found_offers.extend(
+                offers
+                    .into_iter()
+                    .filter(|offer| seen_offers.insert(offer.id)),
+            );
+
+            // offers that can't be rented alongside the live instances or each other don't count
+            let usable = offer_filter.within_caps(found_offers.clone(), live).len();
+            if usable >= needed {
+                if tier == 0 {
+                    debug!("Primary query satisfied the request for {needed} offers");
+                } else {
+                    info!("Fallback query {tier} satisfied the request for {needed} offers");
+                }
+                break;
+            }
+        }
+
+        // an offer from a fallback may share a host or machine with one from an earlier query
+        let found_offers =
+            offer_filter.exclude_used(found_offers, &HashMap::new(), &HashMap::new());
+        info!("found {} offers", found_offers.len());
+        *self.last_offer_count.lock().unwrap() = Some(found_offers.len());
+        Ok(found_offers)
+    }
+
+    pub fn last_offer_count(&self) -> Option<usize> {
+        *self.last_offer_count.lock().unwrap()
+    }
+
+    // Offers from the last search with the query at tier if it was within
+    // config.offer_cache_ttl_secs, otherwise a new search.  Unfiltered, since what's filtered out
+    // changes between calls.
+    async fn cached_offers(&self, tier: usize, vast_query: &VastQueryConfig) -> Result<Vec<Offer>> {
+        let ttl = Duration::from_secs(self.config.offer_cache_ttl_secs);
+        if let Some((fetched_at, offers)) = self.offer_cache.lock().unwrap().get(&tier)
+            && fetched_at.elapsed() < ttl
+        {
+            debug!(
+                "Reusing {} offers found {} seconds ago",
+                offers.len(),
+                fetched_at.elapsed().as_secs()
+            );
+            return Ok(offers.clone());
+        }
+
+        let result = self.request_offers(vast_query).await;
+        self.record_outcome(
+            VastOperation::Offers,
+            result.as_ref().err().map(|e| format!("{e:#}")),
         );
+        let offers = result.context("Call to request offers")?;
+        self.offer_cache
+            .lock()
+            .unwrap()
+            .insert(tier, (Instant::now(), offers.clone()));
+        Ok(offers)
+    }
+
+    // the next find_offers searches Vast again
+    fn invalidate_offer_cache(&self) {
+        self.offer_cache.lock().unwrap().clear();
+    }
+
+    async fn request_destroy_instance(&self, instance_id: u64) -> Result<(), DestroyError> {
+        if self.config.dry_run {
+            self.dry_run_instances.lock().unwrap().remove(&instance_id);
+            info!(instance_id; "Dry run: would have destroyed instance {instance_id}");
+            return Ok(());
+        }
+
+        let url = format!("{}{VAST_INSTANCE_ENDPOINT}/{instance_id}/", self.base_url);
 
         let response = self
-            .client
-            .delete(&url)
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.vast_api_key),
+            .send(
+                self.client
+                    .delete(&url)
+                    .header("Accept", "application/json")
+                    .header("Content-Type", "application/json")
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", self.config.vast_api_key),
+                    ),
             )
-            .send()
-            .await?;
+            .await
+            .map_err(DestroyError::Transient)?;
 
-        if response.status().is_success() {
-            Ok(())
+        let status = response.status();
+        // already gone, which is what we wanted
+        if status.is_success() || status == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+
+        let error_text = response.text().await.unwrap_or_default();
+        let err = anyhow!("API request for {url} failed with status {status}: {error_text}");
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(DestroyError::Transient(err))
         } else {
-            let status = response.status();
-            let error_text = response.text().await?;
-            Err(anyhow!(
-                "API request for {url} failed with status {status}: {error_text}"
-            ))
+            Err(DestroyError::Permanent(err))
         }
     }
 
-    async fn request_offers(&self) -> Result<Vec<Offer>> {
-        let query = self.config.vast_query.to_query_string();
-        let url = format!("{VAST_BASE_URL}{VAST_OFFERS_ENDPOINT}/");
+    async fn request_offers(&self, vast_query: &VastQueryConfig) -> Result<Vec<Offer>> {
+        let query = vast_query.to_query_string();
+        let url = format!("{}{VAST_OFFERS_ENDPOINT}/", self.base_url);
 
         let response = self
-            .client
-            .post(&url)
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.vast_api_key),
+            .send(
+                self.client
+                    .post(&url)
+                    .header("Accept", "application/json")
+                    .header("Content-Type", "application/json")
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", self.config.vast_api_key),
+                    )
+                    .body(query.clone()),
             )
-            .body(query)
-            .send()
             .await
             .context("Reqwest call to get vast offers")?;
 
@@ -159,45 +928,43 @@ impl VastClient {
                 .await
                 .context("Failed to parse Vast API response as JSON")?;
             debug!("Found {} offers", vast_response.offers.len());
-            Ok(vast_response.offers)
+            Ok(parse_offers(vast_response.offers))
         } else {
             let status = response.status();
             let error_text = response.text().await?;
             Err(anyhow!(
-                "Vast API request failed with status {status}: {error_text}"
+                "Vast API request failed with status {status}: {error_text}\nQuery: {query}"
             ))
         }
     }
 
-    // returns ids of instances according to vast
-    pub async fn get_instances(&self) -> Result<Vec<u64>> {
-        let url = format!("{VAST_BASE_URL}{VAST_INSTANCE_ENDPOINT}/");
+    // Restarts the instance's container on the same machine, keeping the rental
+    pub async fn reboot_instance(&self, instance_id: u64) -> Result<()> {
+        if self.config.dry_run {
+            info!(instance_id; "Dry run: would have rebooted instance {instance_id}");
+            return Ok(());
+        }
+
+        let url = format!(
+            "{}{VAST_INSTANCE_ENDPOINT}/reboot/{instance_id}/",
+            self.base_url
+        );
 
         let response = self
-            .client
-            .get(&url)
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.vast_api_key),
+            .send(
+                self.client
+                    .put(&url)
+                    .header("Accept", "application/json")
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", self.config.vast_api_key),
+                    ),
             )
-            .send()
             .await
-            .context("Reqwest call to get vast instances")?;
+            .context("Reqwest call to reboot vast instance")?;
 
         if response.status().is_success() {
-            let vast_response: VastGetInstancesResponse = match response.json().await {
-                Ok(x) => x,
-                Err(e) => {
-                    let err =
-                        format!("Error parsing vast response from get instances as json: {e}");
-                    error!("{err}");
-                    return Err(anyhow!(err));
-                }
-            };
-            let instance_ids = vast_response.instances.iter().map(|i| i.id).collect();
-            Ok(instance_ids)
+            Ok(())
         } else {
             let status = response.status();
             let error_text = response.text().await?;
@@ -207,76 +974,365 @@ impl VastClient {
         }
     }
 
-    // returns instance_id of the offer on a success
-    // if Ok(None), then we are making too many requests and need to wait
-    pub async fn request_new_instance(&self, offer_id: u64) -> Result<Option<u64>> {
-        let url = format!(
-            "{VAST_BASE_URL}{VAST_CREATE_INSTANCE_ENDPOINT}/{offer_id}/"
-        );
-
-        // remove a trailing / if it exists on the address
-        let this_magister_addr = self
-            .config
-            .this_magister_addr
-            .strip_suffix('/')
-            .unwrap_or(&self.config.this_magister_addr);
+    // returns the remaining credit on the Vast account in USD
+    pub async fn get_balance(&self) -> Result<f64> {
+        let url = format!("{}{VAST_CURRENT_USER_ENDPOINT}/", self.base_url);
 
-        // this onstart overrides the onstart from the template.  We have to pass in
-        // MAGISTER_DROP_ENDPOINT and HIEROPHANT_WS_ADDRESS here instead of the the `extra_env` field because the `extra_env` field
-        // doesn't properly combine envs if the template already has an ENV.
-        let contemplant_env = self.config.contemplant.to_env_exports();
-        let onstart = format!(
-            r#""su contemplant -c 'export HOME=/home/contemplant; export TMUX_TMPDIR=/home/contemplant/.tmux; export MAGISTER_DROP_ENDPOINT=\"{}:{}/drop/{}\"; export HIEROPHANT_WS_ADDRESS=\"ws://{}:{}/ws\"; {}; /usr/local/bin/contemplant-entrypoint.sh'""#,
-            this_magister_addr,
-            self.config.http_port,
-            offer_id,
-            self.config.hierophant_ip,
-            self.config.hierophant_http_port,
-            contemplant_env
-        );
-        debug!("onstart command: \n{onstart}");
+        let response = self
+            .send(
+                self.client
+                    .get(&url)
+                    .header("Accept", "application/json")
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", self.config.vast_api_key),
+                    ),
+            )
+            .await
+            .context("Reqwest call to get vast account balance")?;
 
-        // unfortunately these all have to be passed in as null
-        let body = format!(
-            r#"{{
-            "template_id": null,
-            "template_hash_id": "{}",
-            "client_id": null,
+        if response.status().is_success() {
+            let user: VastCurrentUserResponse = response
+                .json()
+                .await
+                .context("Failed to parse Vast current user response as JSON")?;
+            Ok(user.credit)
+        } else {
+            let status = response.status();
+            let error_text = response.text().await?;
+            Err(anyhow!(
+                "API request for {url} failed with status {status}: {error_text}"
+            ))
+        }
+    }
+
+    // whether Vast knows a template with this hash
+    pub async fn template_exists(&self, template_hash: &str) -> Result<bool> {
+        let url = format!("{}{VAST_TEMPLATE_ENDPOINT}/", self.base_url);
+        let select_filters = serde_json::json!({ "hash_id": { "eq": template_hash } }).to_string();
+
+        let response = self
+            .send(
+                self.client
+                    .get(&url)
+                    .query(&[("select_filters", select_filters)])
+                    .header("Accept", "application/json")
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", self.config.vast_api_key),
+                    ),
+            )
+            .await
+            .context("Reqwest call to get vast template")?;
+
+        if response.status().is_success() {
+            let body: serde_json::Value = response
+                .json()
+                .await
+                .context("Failed to parse Vast template response as JSON")?;
+            let found = body["templates"].as_array().is_some_and(|templates| {
+                templates
+                    .iter()
+                    .any(|template| template["hash_id"].as_str() == Some(template_hash))
+            });
+            Ok(found)
+        } else {
+            let status = response.status();
+            let error_text = response.text().await?;
+            Err(anyhow!(
+                "API request for {url} failed with status {status}: {error_text}"
+            ))
+        }
+    }
+
+    // returns the id and status of every instance labeled config.instance_label according to vast
+    pub async fn get_instances(&self) -> Result<Vec<VastResponseInstance>> {
+        let result = self.request_instances().await;
+        self.record_outcome(
+            VastOperation::List,
+            result.as_ref().err().map(|e| format!("{e:#}")),
+        );
+        result
+    }
+
+    async fn request_instances(&self) -> Result<Vec<VastResponseInstance>> {
+        // the synthetic instances are always running, and real ones aren't ours to manage
+        if self.config.dry_run {
+            let instances = self
+                .dry_run_instances
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|&id| VastResponseInstance {
+                    id,
+                    actual_status: Some("running".to_string()),
+                    label: Some(self.config.instance_label.clone()),
+                    public_ipaddr: None,
+                    ports: None,
+                    dph_total: None,
+                })
+                .collect();
+            return Ok(instances);
+        }
+
+        // Anything missing from the list is treated as a zombie and forgotten, so every page is
+        // read, and a list shorter than Vast says it should be is an error rather than a guess.
+        let mut instances: Vec<VastResponseInstance> = Vec::new();
+        let mut seen_instances = HashSet::new();
+        let mut instances_found = 0;
+        let mut after_token: Option<String> = None;
+        for page in 1.. {
+            if page > MAX_INSTANCE_PAGES {
+                return Err(anyhow!(
+                    "Vast still had more instances after {MAX_INSTANCE_PAGES} pages"
+                ));
+            }
+
+            let vast_response = self.request_instances_page(after_token.as_deref()).await?;
+            instances_found = vast_response.instances_found;
+            instances.extend(
+                vast_response
+                    .instances
+                    .into_iter()
+                    .filter(|instance| seen_instances.insert(instance.id)),
+            );
+
+            match vast_response.next_token {
+                Some(next_token) if !next_token.is_empty() => after_token = Some(next_token),
+                _ => break,
+            }
+        }
+        if (instances.len() as u64) < instances_found {
+            return Err(anyhow!(
+                "Vast reported {instances_found} instances but only listed {}",
+                instances.len()
+            ));
+        }
+
+        // only instances this Magister labeled, so other Magisters' instances or manual
+        // rentals on the same account are left alone
+        let instances = instances
+            .into_iter()
+            .filter(|instance| {
+                instance.label.as_deref() == Some(self.config.instance_label.as_str())
+            })
+            .collect();
+        Ok(instances)
+    }
+
+    // One page of every instance on the account, starting after after_token if given
+    async fn request_instances_page(
+        &self,
+        after_token: Option<&str>,
+    ) -> Result<VastGetInstancesResponse> {
+        let url = format!("{}{VAST_INSTANCE_ENDPOINT}/", self.base_url);
+
+        let mut request = self
+            .client
+            .get(&url)
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+            .header(
+                "Authorization",
+                format!("Bearer {}", self.config.vast_api_key),
+            );
+        if let Some(after_token) = after_token {
+            request = request.query(&[("after_token", after_token)]);
+        }
+        let response = self
+            .send(request)
+            .await
+            .context("Reqwest call to get vast instances")?;
+
+        if response.status().is_success() {
+            match response.json().await {
+                Ok(vast_response) => Ok(vast_response),
+                Err(e) => {
+                    let err =
+                        format!("Error parsing vast response from get instances as json: {e}");
+                    error!("{err}");
+                    Err(anyhow!(err))
+                }
+            }
+        } else {
+            let status = response.status();
+            let error_text = response.text().await?;
+            Err(anyhow!(
+                "API request for {url} failed with status {status}: {error_text}"
+            ))
+        }
+    }
+
+    // returns instance_id of the offer on a success, or how long to wait if rate limited.  Waits
+    // for config.instance_launch_stagger_secs after the previous launch first.
+    pub async fn request_new_instance(&self, offer_id: u64) -> Result<CreateInstanceOutcome> {
+        if !self.offers_in_flight.lock().unwrap().insert(offer_id) {
+            debug!(offer_id; "Offer {offer_id} is already being requested.  Skipping it");
+            return Ok(CreateInstanceOutcome::AlreadyRequested);
+        }
+
+        let slot = self.launch_stagger.reserve().await;
+        let outcome = self.send_create_request(offer_id).await;
+        self.offers_in_flight.lock().unwrap().remove(&offer_id);
+        if !matches!(outcome, Ok(CreateInstanceOutcome::Created { .. })) {
+            self.launch_stagger.release(slot);
+        }
+        let error = match outcome {
+            Ok(CreateInstanceOutcome::Created { .. }) => None,
+            Ok(CreateInstanceOutcome::RateLimited { .. }) => {
+                Some("Rate limited by Vast".to_string())
+            }
+            // no request reached Vast, so there's nothing to record
+            Ok(CreateInstanceOutcome::AlreadyRequested) => return outcome,
+            Err(ref e) => Some(format!("{e:#}")),
+        };
+        self.record_outcome(VastOperation::Create, error);
+        outcome
+    }
+
+    async fn send_create_request(&self, offer_id: u64) -> Result<CreateInstanceOutcome> {
+        let url = format!(
+            "{}{VAST_CREATE_INSTANCE_ENDPOINT}/{offer_id}/",
+            self.base_url
+        );
+
+        // remove a trailing / if it exists on the address
+        let this_magister_addr = self
+            .config
+            .this_magister_addr
+            .strip_suffix('/')
+            .unwrap_or(&self.config.this_magister_addr);
+
+        // the Contemplant can't set headers on its drop request, so the api token rides along as
+        // a query parameter
+        let drop_token_query = match self.config.api_token {
+            Some(ref api_token) => format!("?token={api_token}"),
+            None => String::new(),
+        };
+        let drop_endpoint = format!(
+            "{this_magister_addr}:{}/drop/{offer_id}{drop_token_query}",
+            self.config.http_port
+        );
+        let hierophant_ws_address = format!(
+            "ws://{}:{}/ws",
+            self.config.hierophant_ip, self.config.hierophant_http_port
+        );
+        // this onstart overrides the onstart from the template.  We have to pass in
+        // MAGISTER_DROP_ENDPOINT and HIEROPHANT_WS_ADDRESS here instead of the the `extra_env` field because the `extra_env` field
+        // doesn't properly combine envs if the template already has an ENV.
+        let onstart = self.config.onstart(&drop_endpoint, &hierophant_ws_address);
+        debug!(
+            "onstart command: \n{}",
+            self.config.redact_api_token(&onstart)
+        );
+
+        let template = self.choose_template();
+        let vast_query = self.vast_query();
+
+        // unfortunately these all have to be passed in as null
+        let body = format!(
+            r#"{{
+            "template_id": null,
+            "template_hash_id": {},
+            "client_id": null,
             "image": null,
-            "extra_env": null,
+            "extra_env": {},
             "args_str": null,
-            "onstart": {onstart},
+            "onstart": {},
             "runtype": null,
             "image_login": null,
             "use_jupyter_lab": false,
             "jupyter_dir": null,
             "python_utf8": null,
             "lang_utf8": null,
-            "label": "magister",
-            "disk": {}
+            "label": {},
+            "disk": {},
+            "price": {}
         }}"#,
-            self.config.template_hash, self.config.vast_query.disk_space
+            serde_json::json!(template.hash()),
+            // null leaves the template's ENV alone
+            serde_json::json!(self.config.extra_env),
+            serde_json::json!(onstart),
+            serde_json::json!(self.config.instance_label),
+            vast_query.provision_disk_gb(),
+            // null rents on-demand
+            serde_json::json!(vast_query.bid_price())
+        );
+
+        debug!(
+            "New instance request body:\n{}",
+            self.config.redact_api_token(&body)
         );
 
-        debug!("New instance request body:\n{body}");
+        if self.config.dry_run {
+            let instance_id = self.dry_run_next_id.fetch_add(1, Ordering::Relaxed);
+            self.dry_run_instances.lock().unwrap().insert(instance_id);
+            info!(
+                offer_id, instance_id;
+                "Dry run: would have rented offer {offer_id}.  Using synthetic instance id {instance_id}"
+            );
+            self.invalidate_offer_cache();
+            return Ok(CreateInstanceOutcome::Created {
+                instance_id,
+                template_hash: template.commit(),
+                prover_type: self.config.contemplant.prover_type.clone(),
+            });
+        }
 
         let response = self
-            .client
-            .put(&url)
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.vast_api_key),
+            .send(
+                self.client
+                    .put(&url)
+                    .header("Accept", "application/json")
+                    .header("Content-Type", "application/json")
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", self.config.vast_api_key),
+                    )
+                    .body(body.clone()),
             )
-            .body(body.clone())
-            .send()
             .await?;
         if response.status().is_success() {
             let resp: VastCreateInstanceResponse = response.json().await?;
-            Ok(Some(resp.new_contract))
+            if !resp.success && resp.is_throttled() {
+                warn!(
+                    offer_id;
+                    "Vast throttled the create request for offer {offer_id}: {}",
+                    resp.message()
+                );
+                let backoff = Duration::from_secs(self.config.vast_api_call_backoff_secs);
+                self.rate_limiter.cool_down(backoff);
+                return Ok(CreateInstanceOutcome::RateLimited { retry_after: None });
+            }
+            // a contract id of 0 can't be dropped later, so never track it as an instance
+            if !resp.success || resp.new_contract == 0 {
+                return Err(anyhow!(
+                    "Vast returned success {} with contract id {} for offer {offer_id}: {}",
+                    resp.success,
+                    resp.new_contract,
+                    resp.message()
+                ));
+            }
+            // the offer we just rented is gone, so don't hand it out again
+            self.invalidate_offer_cache();
+            Ok(CreateInstanceOutcome::Created {
+                instance_id: resp.new_contract,
+                template_hash: template.commit(),
+                prover_type: self.config.contemplant.prover_type.clone(),
+            })
         } else if response.status() == StatusCode::TOO_MANY_REQUESTS {
-            Ok(None)
+            // only the delay-seconds form is handled.  An HTTP date falls back to our own backoff.
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs);
+            // hold back every other Vast call too, not just the next create request
+            self.rate_limiter.cool_down(
+                retry_after.unwrap_or(Duration::from_secs(self.config.vast_api_call_backoff_secs)),
+            );
+            Ok(CreateInstanceOutcome::RateLimited { retry_after })
         } else {
             let status = response.status();
             let error_text = response.text().await?;
@@ -287,38 +1343,1216 @@ impl VastClient {
     }
 }
 
-// TODO: instead of config & last_dropped we should pass a struct that holds all our filtering
-// logic.  It will have some values from config and some that are dynamically updated.
-// This struct should have a function to filter machines based on our criteria in O(n) time.
-// Break out the filter logic into it's own module
-fn filter_offers(config: Config, offers: Vec<Offer>, last_dropped: u64) -> Vec<Offer> {
-    let count_before_filter = offers.len();
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::tests::test_config;
+    use std::sync::atomic::AtomicUsize;
 
-    let bad_hosts = config.bad_hosts;
-    let bad_machines = config.bad_machines;
+    // A VastClient talking to router on a local port instead of Vast
+    pub(crate) async fn mock_vast(config: Config, router: axum::Router) -> Arc<VastClient> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
 
-    let offers: Vec<Offer> = offers
-        .into_iter()
-        .filter(|offer| {
-            let host_not_bad = bad_hosts
-                .as_ref()
-                .is_none_or(|bad_list| !bad_list.contains(&offer.host_id));
+        let mut vast_client = VastClient::new(config).unwrap();
+        vast_client.base_url = base_url;
+        Arc::new(vast_client)
+    }
 
-            let machine_not_bad = bad_machines
-                .as_ref()
-                .is_none_or(|bad_list| !bad_list.contains(&offer.machine_id));
+    pub(crate) fn mock_config() -> Config {
+        let mut config = test_config();
+        config.vast_api_min_interval_ms = 0;
+        config
+    }
 
-            let machine_not_recently_dropped = offer.machine_id != last_dropped;
+    const COOLDOWN: Duration = Duration::from_millis(50);
 
-            host_not_bad && machine_not_bad && machine_not_recently_dropped
-        })
-        .collect();
+    // fails threshold calls in a row so the circuit opens
+    fn open_circuit(circuit_breaker: &CircuitBreaker, threshold: u32) {
+        for _ in 0..threshold {
+            circuit_breaker.allow().unwrap().record(false);
+        }
+    }
+
+    #[test]
+    fn circuit_opens_after_threshold_failures() {
+        let circuit_breaker = CircuitBreaker::new(3, COOLDOWN);
+        open_circuit(&circuit_breaker, 2);
+        assert!(circuit_breaker.allow().is_ok());
+        assert!(!circuit_breaker.is_open());
+
+        circuit_breaker.allow().unwrap().record(false);
+        assert!(circuit_breaker.is_open());
+        assert!(circuit_breaker.allow().is_err());
+    }
+
+    #[test]
+    fn circuit_success_resets_the_failure_count() {
+        let circuit_breaker = CircuitBreaker::new(3, COOLDOWN);
+        open_circuit(&circuit_breaker, 2);
+        circuit_breaker.allow().unwrap().record(true);
+        open_circuit(&circuit_breaker, 2);
+        assert!(!circuit_breaker.is_open());
+    }
+
+    #[test]
+    fn half_open_circuit_lets_one_probe_through() {
+        let circuit_breaker = CircuitBreaker::new(1, COOLDOWN);
+        open_circuit(&circuit_breaker, 1);
+        std::thread::sleep(COOLDOWN);
+
+        let probe = circuit_breaker.allow().unwrap();
+        assert!(circuit_breaker.allow().is_err());
+
+        probe.record(true);
+        assert!(circuit_breaker.allow().is_ok());
+        assert!(!circuit_breaker.is_open());
+    }
+
+    #[test]
+    fn failed_probe_reopens_for_longer() {
+        let circuit_breaker = CircuitBreaker::new(1, COOLDOWN);
+        open_circuit(&circuit_breaker, 1);
+        std::thread::sleep(COOLDOWN);
+
+        circuit_breaker.allow().unwrap().record(false);
+        assert!(circuit_breaker.is_open());
+        std::thread::sleep(COOLDOWN);
+        // the cooldown doubled, so it's still open
+        assert!(circuit_breaker.allow().is_err());
+        std::thread::sleep(COOLDOWN);
+        assert!(circuit_breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn cancelled_probe_lets_the_next_call_probe() {
+        let circuit_breaker = CircuitBreaker::new(1, COOLDOWN);
+        open_circuit(&circuit_breaker, 1);
+        std::thread::sleep(COOLDOWN);
+
+        drop(circuit_breaker.allow().unwrap());
+        assert!(circuit_breaker.allow().is_ok());
+    }
+
+    fn weighted_templates() -> Vec<WeightedTemplate> {
+        [("a", 3), ("b", 1)]
+            .map(|(hash, weight)| WeightedTemplate {
+                hash: hash.to_string(),
+                weight,
+            })
+            .to_vec()
+    }
+
+    #[test]
+    fn templates_are_chosen_in_proportion_to_weight() {
+        let mut config = mock_config();
+        config.template_hash = weighted_templates();
+        let vast_client = VastClient::new(config).unwrap();
+
+        let chosen: Vec<String> = (0..8)
+            .map(|_| vast_client.choose_template().commit())
+            .collect();
+        assert_eq!(chosen, ["a", "a", "b", "a", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn uncommitted_template_choices_are_undone() {
+        let mut config = mock_config();
+        config.template_hash = weighted_templates();
+        let vast_client = VastClient::new(config).unwrap();
 
-    let count_after_filter = offers.len();
-    debug!(
-        "Filtered out {} offers",
-        count_before_filter - count_after_filter
-    );
+        let mut chosen = Vec::new();
+        for _ in 0..4 {
+            // a failed create in between changes nothing
+            drop(vast_client.choose_template());
+            chosen.push(vast_client.choose_template().commit());
+        }
+        assert_eq!(chosen, ["a", "a", "b", "a"]);
+    }
+
+    #[tokio::test]
+    async fn failed_create_keeps_the_template_turn() {
+        use axum::{Json, extract::State, routing::put};
+
+        // fails the first create request, then accepts the rest
+        async fn create(
+            State(calls): State<Arc<AtomicUsize>>,
+            body: String,
+        ) -> Json<serde_json::Value> {
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert!(body["template_hash_id"].is_string());
+            let call = calls.fetch_add(1, Ordering::Relaxed) as u64;
+            Json(serde_json::json!({ "success": call > 0, "new_contract": 100 + call }))
+        }
+        let router = axum::Router::new()
+            .route("/asks/:offer_id/", put(create))
+            .with_state(Arc::new(AtomicUsize::new(0)));
+        let mut config = mock_config();
+        config.template_hash = weighted_templates();
+        config.template_hash[0].hash = r#"a "quoted" hash"#.to_string();
+        let vast_client = mock_vast(config, router).await;
+
+        assert!(vast_client.request_new_instance(1).await.is_err());
+        let mut chosen = Vec::new();
+        for offer_id in 2..6 {
+            match vast_client.request_new_instance(offer_id).await.unwrap() {
+                CreateInstanceOutcome::Created { template_hash, .. } => chosen.push(template_hash),
+                _ => panic!("expected offer {offer_id} to be created"),
+            }
+        }
+        assert_eq!(chosen[2], "b");
+        assert_eq!(
+            chosen.iter().filter(|hash| hash.starts_with("a ")).count(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_retries_stop_well_under_the_polling_interval() {
+        use axum::{extract::State, routing::delete};
+
+        async fn destroy(State(calls): State<Arc<AtomicUsize>>) -> StatusCode {
+            calls.fetch_add(1, Ordering::Relaxed);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = axum::Router::new()
+            .route("/instances/:instance_id/", delete(destroy))
+            .with_state(calls.clone());
+        let mut config = mock_config();
+        config.drop_retry_attempts = 5;
+        config.drop_retry_backoff_secs = 1;
+        config.task_polling_interval_secs = 10;
+        let vast_client = mock_vast(config, router).await;
+
+        let started = tokio::time::Instant::now();
+        let error = vast_client.drop_instance(7).await.unwrap_err();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(format!("{error:#}").contains("keep the polling cycle moving"));
+    }
+
+    fn listed(id: u64, label: &str) -> serde_json::Value {
+        serde_json::json!({ "id": id, "actual_status": "running", "label": label })
+    }
+
+    #[tokio::test]
+    async fn get_instances_reads_every_page() {
+        use axum::{Json, extract::Query, routing::get};
+        use std::collections::HashMap;
+
+        async fn list(Query(params): Query<HashMap<String, String>>) -> Json<serde_json::Value> {
+            match params.get("after_token").map(String::as_str) {
+                None => Json(serde_json::json!({
+                    "instances_found": 4,
+                    "instances": [listed(1, "magister"), listed(2, "magister")],
+                    "next_token": "page-2",
+                })),
+                Some("page-2") => Json(serde_json::json!({
+                    "instances_found": 4,
+                    // 2 again, as if it moved between pages while listing
+                    "instances": [listed(2, "magister"), listed(3, "someone-else"), listed(4, "magister")],
+                })),
+                Some(other) => panic!("unexpected after_token {other}"),
+            }
+        }
+        let router = axum::Router::new().route("/instances/", get(list));
+        let vast_client = mock_vast(mock_config(), router).await;
+
+        let ids: Vec<u64> = vast_client
+            .get_instances()
+            .await
+            .unwrap()
+            .iter()
+            .map(|instance| instance.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn get_instances_errors_when_vast_lists_fewer_than_it_found() {
+        use axum::{Json, routing::get};
+
+        async fn list() -> Json<serde_json::Value> {
+            Json(serde_json::json!({
+                "instances_found": 3,
+                "instances": [listed(1, "magister"), listed(2, "magister")],
+            }))
+        }
+        let router = axum::Router::new().route("/instances/", get(list));
+        let vast_client = mock_vast(mock_config(), router).await;
+
+        let error = vast_client.get_instances().await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Vast reported 3 instances but only listed 2"),
+            "{error:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unsuccessful_create_surfaces_vasts_message() {
+        use axum::{Json, extract::Path, routing::put};
+
+        async fn create(Path(offer_id): Path<u64>) -> Json<serde_json::Value> {
+            let msg = match offer_id {
+                1 => "no_such_ask: offer 1 is no longer available",
+                _ => "API requests too frequent, rate limit exceeded",
+            };
+            Json(serde_json::json!({ "success": false, "new_contract": 7, "msg": msg }))
+        }
+        let router = axum::Router::new().route("/asks/:offer_id/", put(create));
+        let vast_client = mock_vast(mock_config(), router).await;
+
+        let Err(error) = vast_client.request_new_instance(1).await else {
+            panic!("expected an unsuccessful create to be an error");
+        };
+        assert!(
+            error.to_string().contains("offer 1 is no longer available"),
+            "{error:#}"
+        );
+        // throttling last, since it makes the client cool down
+        assert!(matches!(
+            vast_client.request_new_instance(2).await.unwrap(),
+            CreateInstanceOutcome::RateLimited { retry_after: None }
+        ));
+    }
+
+    // A mock of Vast whose offer search always finds offers, and which rents any of them as
+    // instance 1000 + offer_id
+    pub(crate) fn renting_router(offers: Vec<Offer>) -> axum::Router {
+        use axum::{
+            Json,
+            extract::Path,
+            routing::{post, put},
+        };
+
+        async fn create(Path(offer_id): Path<u64>) -> Json<serde_json::Value> {
+            Json(serde_json::json!({ "success": true, "new_contract": 1000 + offer_id }))
+        }
+        let offers = serde_json::json!({ "offers": offers });
+        axum::Router::new()
+            .route("/bundles/", post(move || async move { Json(offers) }))
+            .route("/asks/:offer_id/", put(create))
+    }
+
+    #[tokio::test]
+    async fn provisions_over_the_soft_ceiling_when_nothing_cheaper_is_left() {
+        use crate::types::tests::test_offer;
+
+        let offers = vec![test_offer(1, 1, 1, 0.45), test_offer(2, 2, 2, 0.6)];
+        let router = renting_router(offers);
+        let mut config = mock_config();
+        config.vast_query.cost_per_hour = 0.5;
+        config.vast_query.soft_cost_per_hour = Some(0.3);
+        config.allow_partial_startup = true;
+        let offer_filter = OfferFilter::new(&config);
+        let vast_client = mock_vast(config, router).await;
+
+        // asks for both, but the second is over the hard cap
+        let created = vast_client
+            .create_initial_instances(2, &offer_filter)
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].0, 1001);
+        assert!(
+            vast_client
+                .config
+                .vast_query
+                .over_soft_ceiling(created[0].1.offer.dph_total)
+        );
+    }
 
-    offers
+    #[tokio::test]
+    async fn create_without_a_contract_id_is_rejected() {
+        use axum::{Json, extract::Path, routing::put};
+
+        async fn create(Path(offer_id): Path<u64>) -> Json<serde_json::Value> {
+            match offer_id {
+                1 => Json(serde_json::json!({ "success": true, "new_contract": 0 })),
+                _ => Json(serde_json::json!({ "success": true })),
+            }
+        }
+        let router = axum::Router::new().route("/asks/:offer_id/", put(create));
+        let vast_client = mock_vast(mock_config(), router).await;
+
+        for offer_id in [1, 2] {
+            let Err(error) = vast_client.request_new_instance(offer_id).await else {
+                panic!("expected offer {offer_id}'s create to be rejected");
+            };
+            assert!(error.to_string().contains("contract id 0"), "{error:#}");
+        }
+    }
+
+    #[test]
+    fn backoff_reset_policies_under_intermittent_rate_limits() {
+        // two rate limited requests around each lucky success
+        let outcomes = [false, false, true, false, false, true, false, false];
+        let total_sleep = |reset_policy| {
+            let mut config = mock_config();
+            config.vast_api_call_backoff_secs = 10;
+            config.backoff_reset_policy = reset_policy;
+            config.backoff_reset_streak = 3;
+            let mut backoff = CreateBackoff::new(&config);
+            outcomes
+                .iter()
+                .map(|&succeeded| {
+                    if succeeded {
+                        backoff.not_rate_limited();
+                        0
+                    } else {
+                        backoff.rate_limited(None).as_secs()
+                    }
+                })
+                .sum::<u64>()
+        };
+
+        assert_eq!(total_sleep(BackoffResetPolicy::OnSuccess), 90);
+        assert_eq!(total_sleep(BackoffResetPolicy::Decay), 140);
+        assert_eq!(total_sleep(BackoffResetPolicy::Never), 210);
+    }
+
+    #[test]
+    fn never_resets_after_a_clean_streak() {
+        let mut config = mock_config();
+        config.vast_api_call_backoff_secs = 10;
+        config.backoff_reset_policy = BackoffResetPolicy::Never;
+        config.backoff_reset_streak = 2;
+        let mut backoff = CreateBackoff::new(&config);
+
+        backoff.rate_limited(None);
+        backoff.rate_limited(None);
+        backoff.not_rate_limited();
+        assert_eq!(backoff.current_secs, 20);
+        backoff.not_rate_limited();
+        assert_eq!(backoff.current_secs, 0);
+        // Vast saying how long to wait doesn't change the guess
+        assert_eq!(
+            backoff.rate_limited(Some(Duration::from_secs(3))),
+            Duration::from_secs(3)
+        );
+        assert_eq!(backoff.current_secs, 0);
+    }
+
+    #[tokio::test]
+    async fn hung_vast_call_times_out() {
+        use axum::routing::get;
+
+        let router = axum::Router::new().route(
+            "/users/current/",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                "{}"
+            }),
+        );
+        let mut config = mock_config();
+        config.vast_request_timeout_secs = 1;
+        let vast_client = mock_vast(config, router).await;
+
+        let started = std::time::Instant::now();
+        let error = vast_client.get_balance().await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10), "{error:#}");
+        assert!(format!("{error:#}").contains("timed out"), "{error:#}");
+    }
+
+    #[tokio::test]
+    async fn initial_instances_stop_at_max_total_dph() {
+        use crate::types::tests::test_offer;
+
+        let offers: Vec<Offer> = (1..=3).map(|id| test_offer(id, id, id, 0.3)).collect();
+        let mut config = mock_config();
+        config.max_total_dph = Some(0.7);
+        config.allow_partial_startup = false;
+        let offer_filter = OfferFilter::new(&config);
+        let vast_client = mock_vast(config.clone(), renting_router(offers.clone())).await;
+
+        let Err(error) = vast_client.create_initial_instances(3, &offer_filter).await else {
+            panic!("expected max_total_dph to stop the third instance");
+        };
+        assert!(format!("{error:#}").contains("max_total_dph"), "{error:#}");
+
+        config.allow_partial_startup = true;
+        let vast_client = mock_vast(config, renting_router(offers)).await;
+        let created = vast_client
+            .create_initial_instances(3, &offer_filter)
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 2);
+    }
+
+    // Accepts every create request, keeping each request body for the test to look at
+    fn recording_router() -> (axum::Router, Arc<Mutex<Vec<serde_json::Value>>>) {
+        use axum::{Json, extract::Path, routing::put};
+
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = bodies.clone();
+        let router = axum::Router::new().route(
+            "/asks/:offer_id/",
+            put(
+                move |Path(offer_id): Path<u64>, Json(body): Json<serde_json::Value>| async move {
+                    recorded.lock().unwrap().push(body);
+                    Json(serde_json::json!({ "success": true, "new_contract": 1000 + offer_id }))
+                },
+            ),
+        );
+        (router, bodies)
+    }
+
+    #[tokio::test]
+    async fn instances_carry_and_are_listed_by_our_label() {
+        use axum::{Json, routing::get};
+
+        let (router, bodies) = recording_router();
+        let router = router.route(
+            "/instances/",
+            get(|| async {
+                Json(serde_json::json!({
+                    "instances_found": 4,
+                    "instances": [
+                        listed(1, "eu-magister"),
+                        listed(2, "magister"),
+                        { "id": 3, "actual_status": "running" },
+                        listed(4, "eu-magister"),
+                    ],
+                }))
+            }),
+        );
+        let mut config = mock_config();
+        config.instance_label = "eu-magister".to_string();
+        let vast_client = mock_vast(config, router).await;
+
+        assert!(matches!(
+            vast_client.request_new_instance(5).await.unwrap(),
+            CreateInstanceOutcome::Created {
+                instance_id: 1005,
+                ..
+            }
+        ));
+        assert_eq!(bodies.lock().unwrap()[0]["label"], "eu-magister");
+
+        let ids: Vec<u64> = vast_client
+            .get_instances()
+            .await
+            .unwrap()
+            .iter()
+            .map(|instance| instance.id)
+            .collect();
+        assert_eq!(ids, vec![1, 4]);
+    }
+
+    #[tokio::test]
+    async fn bid_mode_puts_the_bid_price_in_create_requests() {
+        let (router, bodies) = recording_router();
+        let vast_client = mock_vast(mock_config(), router.clone()).await;
+        vast_client.request_new_instance(5).await.unwrap();
+        assert!(bodies.lock().unwrap()[0]["price"].is_null());
+
+        let mut config = mock_config();
+        config.vast_query.use_bid = true;
+        config.vast_query.bid_price = Some(0.2);
+        let vast_client = mock_vast(config, router).await;
+        vast_client.request_new_instance(6).await.unwrap();
+        assert_eq!(bodies.lock().unwrap()[1]["price"], 0.2);
+    }
+
+    // A client whose destroy endpoint answers each call with the next of statuses, then 200.
+    // Also returns how many calls it has had.
+    async fn mock_destroy(statuses: Vec<StatusCode>) -> (Arc<VastClient>, Arc<AtomicUsize>) {
+        use axum::{extract::State, routing::delete};
+
+        type Calls = (Arc<AtomicUsize>, Arc<Vec<StatusCode>>);
+        async fn destroy(State((calls, statuses)): State<Calls>) -> StatusCode {
+            let call = calls.fetch_add(1, Ordering::Relaxed);
+            statuses.get(call).copied().unwrap_or(StatusCode::OK)
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        let router = axum::Router::new()
+            .route("/instances/:instance_id/", delete(destroy))
+            .with_state((calls.clone(), Arc::new(statuses)));
+        let mut config = mock_config();
+        config.drop_retry_attempts = 3;
+        config.drop_retry_backoff_secs = 0;
+        (mock_vast(config, router).await, calls)
+    }
+
+    #[tokio::test]
+    async fn destroying_an_instance_vast_no_longer_has_succeeds() {
+        let (vast_client, calls) = mock_destroy(vec![StatusCode::NOT_FOUND]).await;
+        vast_client.drop_instance(7).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn destroy_retries_server_errors_but_not_client_errors() {
+        let (vast_client, calls) = mock_destroy(vec![
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+        ])
+        .await;
+        vast_client.drop_instance(7).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert!(vast_client.drops_in_flight().is_empty());
+
+        let (vast_client, calls) = mock_destroy(vec![StatusCode::FORBIDDEN]).await;
+        vast_client.drop_instance(7).await.unwrap_err();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn dry_run_searches_but_never_rents_or_destroys() {
+        use crate::types::tests::test_offer;
+        use axum::{
+            Json,
+            routing::{delete, post, put},
+        };
+
+        let offers = serde_json::json!({
+            "offers": [test_offer(1, 1, 1, 0.3), test_offer(2, 2, 2, 0.3)],
+        });
+        let searches = Arc::new(AtomicUsize::new(0));
+        let mutations = Arc::new(AtomicUsize::new(0));
+        let (counted_searches, create_mutations, destroy_mutations, reboot_mutations) = (
+            searches.clone(),
+            mutations.clone(),
+            mutations.clone(),
+            mutations.clone(),
+        );
+        let router = axum::Router::new()
+            .route(
+                "/bundles/",
+                post(move || async move {
+                    counted_searches.fetch_add(1, Ordering::Relaxed);
+                    Json(offers)
+                }),
+            )
+            .route(
+                "/asks/:offer_id/",
+                put(move || async move {
+                    create_mutations.fetch_add(1, Ordering::Relaxed);
+                }),
+            )
+            .route(
+                "/instances/:instance_id/",
+                delete(move || async move {
+                    destroy_mutations.fetch_add(1, Ordering::Relaxed);
+                }),
+            )
+            .route(
+                "/instances/reboot/:instance_id/",
+                put(move || async move {
+                    reboot_mutations.fetch_add(1, Ordering::Relaxed);
+                }),
+            );
+        let mut config = mock_config();
+        config.dry_run = true;
+        let offer_filter = OfferFilter::new(&config);
+        let vast_client = mock_vast(config, router).await;
+
+        let created = vast_client
+            .create_initial_instances(2, &offer_filter)
+            .await
+            .unwrap();
+        let mut ids: Vec<u64> = created
+            .iter()
+            .map(|(instance_id, _)| *instance_id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(searches.load(Ordering::Relaxed) > 0);
+
+        vast_client.drop_instance(1).await.unwrap();
+        vast_client.reboot_instance(2).await.unwrap();
+        let listed: Vec<u64> = vast_client
+            .get_instances()
+            .await
+            .unwrap()
+            .iter()
+            .map(|instance| instance.id)
+            .collect();
+        assert_eq!(listed, vec![2]);
+        assert_eq!(mutations.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn offers_are_cached_until_the_ttl_or_a_rental() {
+        use crate::types::tests::test_offer;
+        use axum::{Json, routing::post};
+
+        let offers = serde_json::json!({ "offers": [test_offer(1, 1, 1, 0.3)] });
+        let searches = Arc::new(AtomicUsize::new(0));
+        let counted_searches = searches.clone();
+        let (router, _) = recording_router();
+        let router = router.route(
+            "/bundles/",
+            post(move || async move {
+                counted_searches.fetch_add(1, Ordering::Relaxed);
+                Json(offers)
+            }),
+        );
+        let mut config = mock_config();
+        config.offer_cache_ttl_secs = 60;
+        let offer_filter = OfferFilter::new(&config);
+        let vast_client = mock_vast(config, router).await;
+
+        for _ in 0..2 {
+            assert_eq!(
+                vast_client
+                    .find_offers(&offer_filter, 1, &LiveUsage::default())
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+        }
+        assert_eq!(searches.load(Ordering::Relaxed), 1);
+
+        // as if the search was longer ago than the ttl
+        for (fetched_at, _) in vast_client.offer_cache.lock().unwrap().values_mut() {
+            *fetched_at = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+        }
+        vast_client
+            .find_offers(&offer_filter, 1, &LiveUsage::default())
+            .await
+            .unwrap();
+        assert_eq!(searches.load(Ordering::Relaxed), 2);
+
+        // the offer may be gone now
+        vast_client.request_new_instance(1).await.unwrap();
+        vast_client
+            .find_offers(&offer_filter, 1, &LiveUsage::default())
+            .await
+            .unwrap();
+        assert_eq!(searches.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn fallback_queries_make_up_what_the_primary_lacks() {
+        use crate::types::tests::test_offer;
+        use axum::{Json, routing::post};
+
+        // the primary query's GPU has one offer, the fallback's has two
+        async fn search(Json(query): Json<serde_json::Value>) -> Json<serde_json::Value> {
+            let offers = match query["gpu_name"]["in"][0].as_str() {
+                Some("RTX 4090") => vec![test_offer(1, 1, 1, 0.3)],
+                Some("RTX 3090") => vec![test_offer(2, 2, 2, 0.4), test_offer(3, 3, 3, 0.4)],
+                other => panic!("unexpected gpu_name {other:?}"),
+            };
+            Json(serde_json::json!({ "offers": offers }))
+        }
+        let router = axum::Router::new().route("/bundles/", post(search));
+        let mut config = mock_config();
+        let mut fallback = config.vast_query.clone();
+        fallback.gpu_name = vec!["RTX 3090".to_string()];
+        config.vast_query_fallbacks = vec![fallback];
+        let offer_filter = OfferFilter::new(&config);
+        let vast_client = mock_vast(config, router).await;
+
+        let offers = vast_client
+            .find_offers(&offer_filter, 1, &LiveUsage::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            offers.iter().map(|offer| offer.id).collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        let offers = vast_client
+            .find_offers(&offer_filter, 3, &LiveUsage::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            offers.iter().map(|offer| offer.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn capped_primary_offers_dont_stop_the_fallbacks() {
+        use crate::types::tests::test_offer;
+        use axum::{Json, routing::post};
+
+        // every primary offer is on host 1, the fallback's is on host 3
+        async fn search(Json(query): Json<serde_json::Value>) -> Json<serde_json::Value> {
+            let offers = match query["gpu_name"]["in"][0].as_str() {
+                Some("RTX 4090") => vec![test_offer(1, 1, 1, 0.3), test_offer(2, 2, 1, 0.3)],
+                Some("RTX 3090") => vec![test_offer(3, 3, 3, 0.4)],
+                other => panic!("unexpected gpu_name {other:?}"),
+            };
+            Json(serde_json::json!({ "offers": offers }))
+        }
+        let router = axum::Router::new().route("/bundles/", post(search));
+        let mut config = mock_config();
+        config.max_instances_per_host = Some(1);
+        let mut fallback = config.vast_query.clone();
+        fallback.gpu_name = vec!["RTX 3090".to_string()];
+        config.vast_query_fallbacks = vec![fallback];
+        let offer_filter = OfferFilter::new(&config);
+        let vast_client = mock_vast(config, router).await;
+
+        // host 1 already has a live instance
+        let live = LiveUsage {
+            hosts: HashMap::from([(1, 1)]),
+            ..LiveUsage::default()
+        };
+        let offers = vast_client
+            .find_offers(&offer_filter, 1, &live)
+            .await
+            .unwrap();
+        assert!(offers.iter().any(|offer| offer.id == 3));
+
+        // two offers on one host only count once
+        let offers = vast_client
+            .find_offers(&offer_filter, 2, &LiveUsage::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            offers.iter().map(|offer| offer.id).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn vast_calls_go_through_the_proxy() {
+        use crate::types::tests::test_offer;
+        use axum::{
+            Json,
+            extract::Request,
+            routing::{delete, post, put},
+        };
+
+        // the proxy sees the absolute URI of every request sent through it
+        let proxied = Arc::new(Mutex::new(Vec::new()));
+        let record = |proxied: Arc<Mutex<Vec<String>>>, response: serde_json::Value| {
+            move |request: Request| async move {
+                proxied.lock().unwrap().push(request.uri().to_string());
+                Json(response)
+            }
+        };
+        let proxy = axum::Router::new()
+            .route(
+                "/bundles/",
+                post(record(
+                    proxied.clone(),
+                    serde_json::json!({ "offers": [test_offer(1, 1, 1, 0.3)] }),
+                )),
+            )
+            .route(
+                "/asks/:offer_id/",
+                put(record(
+                    proxied.clone(),
+                    serde_json::json!({ "success": true, "new_contract": 1001 }),
+                )),
+            )
+            .route(
+                "/instances/:instance_id/",
+                delete(record(proxied.clone(), serde_json::json!({}))),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, proxy).await });
+
+        let mut config = mock_config();
+        config.https_proxy = Some(proxy_url);
+        let offer_filter = OfferFilter::new(&config);
+        let mut vast_client = VastClient::new(config).unwrap();
+        // only reachable through the proxy
+        vast_client.base_url = "http://vast.invalid".to_string();
+
+        assert_eq!(
+            vast_client
+                .find_offers(&offer_filter, 1, &LiveUsage::default())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        vast_client.request_new_instance(1).await.unwrap();
+        vast_client.drop_instance(1001).await.unwrap();
+        assert_eq!(
+            *proxied.lock().unwrap(),
+            vec![
+                "http://vast.invalid/bundles/",
+                "http://vast.invalid/asks/1/",
+                "http://vast.invalid/instances/1001/",
+            ]
+        );
+    }
+
+    // Creates take 50ms, so concurrent ones overlap.  Calls are recorded as (offer_id, when, how
+    // many were in flight), and the first call for offer 1 is rate limited for a second.
+    type CreateCalls = Arc<Mutex<Vec<(u64, std::time::Instant, usize)>>>;
+    async fn mock_slow_creates(config: Config, offer_count: u64) -> (Arc<VastClient>, CreateCalls) {
+        use crate::types::tests::test_offer;
+        use axum::{
+            Json,
+            extract::{Path, State},
+            response::IntoResponse,
+            routing::{post, put},
+        };
+
+        async fn create(
+            State((calls, in_flight)): State<(CreateCalls, Arc<AtomicUsize>)>,
+            Path(offer_id): Path<u64>,
+        ) -> axum::response::Response {
+            let concurrent = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            let first_for_offer = {
+                let mut calls = calls.lock().unwrap();
+                let first = !calls.iter().any(|(id, _, _)| *id == offer_id);
+                calls.push((offer_id, std::time::Instant::now(), concurrent));
+                first
+            };
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            if offer_id == 1 && first_for_offer {
+                return (StatusCode::TOO_MANY_REQUESTS, [("Retry-After", "1")]).into_response();
+            }
+            Json(serde_json::json!({ "success": true, "new_contract": 1000 + offer_id }))
+                .into_response()
+        }
+
+        let offers: Vec<Offer> = (1..=offer_count)
+            .map(|id| test_offer(id, id, id, 0.3))
+            .collect();
+        let offers = serde_json::json!({ "offers": offers });
+        let calls = CreateCalls::default();
+        let router = axum::Router::new()
+            .route("/bundles/", post(move || async move { Json(offers) }))
+            .route("/asks/:offer_id/", put(create))
+            .with_state((calls.clone(), Arc::new(AtomicUsize::new(0))));
+        (mock_vast(config, router).await, calls)
+    }
+
+    #[tokio::test]
+    async fn initial_creates_run_concurrently_up_to_the_limit() {
+        let mut config = mock_config();
+        config.create_concurrency = 2;
+        let offer_filter = OfferFilter::new(&config);
+        let (vast_client, calls) = mock_slow_creates(config, 6).await;
+
+        let created = vast_client
+            .create_initial_instances(4, &offer_filter)
+            .await
+            .unwrap();
+        let mut ids: Vec<u64> = created
+            .iter()
+            .map(|(instance_id, _)| *instance_id)
+            .collect();
+        ids.sort();
+        // offer 1 was rate limited then retried, so the first four offers are rented
+        assert_eq!(ids, vec![1001, 1002, 1003, 1004]);
+        let max_concurrent = calls.lock().unwrap().iter().map(|(_, _, n)| *n).max();
+        assert_eq!(max_concurrent, Some(2));
+    }
+
+    #[tokio::test]
+    async fn a_rate_limited_create_holds_back_the_rest() {
+        let mut config = mock_config();
+        config.create_concurrency = 1;
+        let offer_filter = OfferFilter::new(&config);
+        let (vast_client, calls) = mock_slow_creates(config, 3).await;
+
+        let created = vast_client
+            .create_initial_instances(3, &offer_filter)
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 3);
+
+        let calls = calls.lock().unwrap();
+        // the rate limited call, then nothing until Retry-After has passed
+        assert_eq!(calls[0].0, 1);
+        let waited = calls[1].1 - calls[0].1;
+        assert!(waited >= Duration::from_millis(900), "{waited:?}");
+        assert_eq!(calls.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn bursts_of_vast_calls_are_spaced_out() {
+        use axum::{Json, routing::get};
+
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let recorded = starts.clone();
+        let router = axum::Router::new().route(
+            "/users/current/",
+            get(move || async move {
+                recorded.lock().unwrap().push(Instant::now());
+                Json(serde_json::json!({ "credit": 10.0 }))
+            }),
+        );
+        let mut config = mock_config();
+        config.vast_api_min_interval_ms = 100;
+        let vast_client = mock_vast(config, router).await;
+
+        let mut calls = JoinSet::new();
+        for _ in 0..4 {
+            let vast_client = vast_client.clone();
+            calls.spawn(async move { vast_client.get_balance().await.unwrap() });
+        }
+        while let Some(call) = calls.join_next().await {
+            call.unwrap();
+        }
+
+        let mut starts = starts.lock().unwrap().clone();
+        starts.sort();
+        assert_eq!(starts.len(), 4);
+        for pair in starts.windows(2) {
+            let gap = pair[1] - pair[0];
+            assert!(gap >= Duration::from_millis(90), "{gap:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cool_down_holds_back_the_next_permit() {
+        let rate_limiter = RateLimiter::new(Duration::ZERO);
+        rate_limiter.acquire().await;
+        rate_limiter.cool_down(Duration::from_millis(200));
+
+        let started = Instant::now();
+        rate_limiter.acquire().await;
+        assert!(started.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn extra_env_is_sent_alongside_the_drop_endpoint() {
+        let (router, bodies) = recording_router();
+        let vast_client = mock_vast(mock_config(), router.clone()).await;
+        vast_client.request_new_instance(5).await.unwrap();
+
+        let mut config = mock_config();
+        config.extra_env = Some(HashMap::from([
+            ("RUST_LOG".to_string(), "debug".to_string()),
+            ("PROOF_DIR".to_string(), "/data/proofs".to_string()),
+        ]));
+        let vast_client = mock_vast(config, router).await;
+        vast_client.request_new_instance(6).await.unwrap();
+
+        let bodies = bodies.lock().unwrap();
+        // left alone unless configured, since it doesn't combine with the template's ENV
+        assert!(bodies[0]["extra_env"].is_null());
+        assert_eq!(
+            bodies[1]["extra_env"],
+            serde_json::json!({ "RUST_LOG": "debug", "PROOF_DIR": "/data/proofs" })
+        );
+        // the drop endpoint always rides in onstart
+        for body in bodies.iter() {
+            assert!(
+                body["onstart"]
+                    .as_str()
+                    .unwrap()
+                    .contains("MAGISTER_DROP_ENDPOINT"),
+                "{body}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn launches_are_staggered_and_unused_slots_given_back() {
+        let launch_stagger = LaunchStagger::new(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let mut starts = Vec::new();
+        for _ in 0..3 {
+            launch_stagger.reserve().await;
+            starts.push(started.elapsed());
+        }
+        for pair in starts.windows(2) {
+            let gap = pair[1] - pair[0];
+            // up to a fifth of the interval is jitter
+            assert!(gap >= Duration::from_millis(95), "{gap:?}");
+            assert!(gap < Duration::from_millis(200), "{gap:?}");
+        }
+
+        // a slot that launched nothing doesn't hold back the next launch
+        launch_stagger.reserve().await;
+        let slot = launch_stagger.reserve().await;
+        launch_stagger.release(slot);
+        let started = Instant::now();
+        launch_stagger.reserve().await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn successful_creates_wait_for_the_launch_stagger() {
+        use crate::types::tests::test_offer;
+
+        let offers = vec![test_offer(1, 1, 1, 0.3), test_offer(2, 2, 2, 0.3)];
+        let mut config = mock_config();
+        config.instance_launch_stagger_secs = 1;
+        let vast_client = mock_vast(config, renting_router(offers)).await;
+
+        let started = Instant::now();
+        for offer_id in [1, 2] {
+            let outcome = vast_client.request_new_instance(offer_id).await.unwrap();
+            assert!(matches!(outcome, CreateInstanceOutcome::Created { .. }));
+        }
+        assert!(started.elapsed() >= Duration::from_millis(950));
+    }
+
+    #[tokio::test]
+    async fn failed_offer_search_is_recorded_as_the_last_error() {
+        use axum::{Json, http::StatusCode, response::IntoResponse, routing::post};
+
+        let searches = Arc::new(AtomicUsize::new(0));
+        let counted_searches = searches.clone();
+        let router = axum::Router::new().route(
+            "/bundles/",
+            post(move || async move {
+                if counted_searches.fetch_add(1, Ordering::Relaxed) == 0 {
+                    (StatusCode::BAD_REQUEST, "invalid query").into_response()
+                } else {
+                    Json(serde_json::json!({ "offers": [] })).into_response()
+                }
+            }),
+        );
+        let config = mock_config();
+        let offer_filter = OfferFilter::new(&config);
+        let vast_client = mock_vast(config, router).await;
+        assert!(vast_client.operation_status().is_empty());
+
+        assert!(
+            vast_client
+                .find_offers(&offer_filter, 1, &LiveUsage::default())
+                .await
+                .is_err()
+        );
+        let status = vast_client.operation_status()[&VastOperation::Offers].clone();
+        assert!(!status.last_call_succeeded);
+        let last_error = status.last_error.unwrap();
+        assert!(last_error.contains("invalid query"), "{last_error}");
+        assert_eq!(status.last_error_at, Some(status.last_call_at));
+
+        // a later success is recorded, keeping the error for reference
+        vast_client
+            .find_offers(&offer_filter, 1, &LiveUsage::default())
+            .await
+            .unwrap();
+        let status = vast_client.operation_status()[&VastOperation::Offers].clone();
+        assert!(status.last_call_succeeded);
+        assert!(status.last_error.is_some());
+        assert_eq!(vast_client.operation_status().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn provision_disk_gb_overrides_the_disk_space_filter() {
+        let (router, bodies) = recording_router();
+        let mut config = mock_config();
+        config.vast_query.disk_space = 100;
+        let vast_client = mock_vast(config.clone(), router.clone()).await;
+        vast_client.request_new_instance(1).await.unwrap();
+
+        config.vast_query.provision_disk_gb = Some(30);
+        let vast_client = mock_vast(config.clone(), router).await;
+        vast_client.request_new_instance(2).await.unwrap();
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies[0]["disk"], 100);
+        assert_eq!(bodies[1]["disk"], 30);
+        // the search still asks for machines with disk_space free
+        let query: serde_json::Value =
+            serde_json::from_str(&config.vast_query.to_query_string()).unwrap();
+        assert_eq!(query["disk_space"]["gte"], 100.0);
+    }
+
+    #[tokio::test]
+    async fn an_offer_already_being_requested_is_skipped() {
+        let (vast_client, calls) = mock_slow_creates(mock_config(), 3).await;
+
+        let (first, second) = tokio::join!(
+            vast_client.request_new_instance(2),
+            vast_client.request_new_instance(2)
+        );
+        let outcomes = [first.unwrap(), second.unwrap()];
+        assert_eq!(
+            outcomes
+                .iter()
+                .filter(|outcome| matches!(outcome, CreateInstanceOutcome::Created { .. }))
+                .count(),
+            1
+        );
+        assert!(
+            outcomes
+                .iter()
+                .any(|outcome| matches!(outcome, CreateInstanceOutcome::AlreadyRequested))
+        );
+        assert_eq!(calls.lock().unwrap().len(), 1);
+
+        // cleared once the first request finished
+        let outcome = vast_client.request_new_instance(2).await.unwrap();
+        assert!(matches!(outcome, CreateInstanceOutcome::Created { .. }));
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn vast_calls_go_through_a_socks5_proxy() {
+        use crate::types::tests::test_offer;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // a no-auth SOCKS5 proxy that records where each CONNECT asks to go and sends it to Vast
+        async fn socks5_connect(
+            mut client: tokio::net::TcpStream,
+            vast_addr: std::net::SocketAddr,
+        ) -> std::io::Result<String> {
+            let mut greeting = [0; 2];
+            client.read_exact(&mut greeting).await?;
+            let mut methods = vec![0; greeting[1] as usize];
+            client.read_exact(&mut methods).await?;
+            client.write_all(&[5, 0]).await?;
+
+            // version, CONNECT, reserved, and a domain name address
+            let mut request = [0; 4];
+            client.read_exact(&mut request).await?;
+            assert_eq!(request, [5, 1, 0, 3]);
+            let mut host = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut host).await?;
+            let port = client.read_u16().await?;
+            client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+
+            let mut vast = tokio::net::TcpStream::connect(vast_addr).await?;
+            tokio::spawn(
+                async move { tokio::io::copy_bidirectional(&mut client, &mut vast).await },
+            );
+            Ok(format!("{}:{port}", String::from_utf8_lossy(&host)))
+        }
+
+        let vast_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let vast_addr = vast_listener.local_addr().unwrap();
+        let vast = renting_router(vec![test_offer(1, 1, 1, 0.3)]);
+        tokio::spawn(async move { axum::serve(vast_listener, vast).await });
+
+        let connects = Arc::new(Mutex::new(Vec::new()));
+        let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("socks5h://{}", proxy_listener.local_addr().unwrap());
+        let recorded = connects.clone();
+        tokio::spawn(async move {
+            while let Ok((client, _)) = proxy_listener.accept().await {
+                let target = socks5_connect(client, vast_addr).await.unwrap();
+                recorded.lock().unwrap().push(target);
+            }
+        });
+
+        let mut config = mock_config();
+        // socks5:// resolves hosts itself, so only that it's accepted is checked with it
+        config.https_proxy = Some("socks5://127.0.0.1:1080".to_string());
+        assert!(VastClient::new(config.clone()).is_ok());
+
+        config.https_proxy = Some(proxy_url);
+        let offer_filter = OfferFilter::new(&config);
+        let mut vast_client = VastClient::new(config).unwrap();
+        // only reachable through the proxy
+        vast_client.base_url = "http://vast.invalid".to_string();
+
+        let offers = vast_client
+            .find_offers(&offer_filter, 1, &LiveUsage::default())
+            .await
+            .unwrap();
+        assert_eq!(offers.len(), 1);
+        let connects = connects.lock().unwrap();
+        assert!(!connects.is_empty());
+        assert!(
+            connects.iter().all(|target| target == "vast.invalid:80"),
+            "{connects:?}"
+        );
+    }
 }